@@ -1,6 +1,7 @@
 use std::{
     ffi::CStr,
     io,
+    mem::size_of,
     os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
 };
 
@@ -37,3 +38,171 @@ pub fn openat(
     // SAFETY: fd is a new, open file descriptor.
     Ok(unsafe { OwnedFd::from_raw_fd(fd) })
 }
+
+/// Argument to [`openat2`] that describes how to open the file.
+#[allow(missing_docs, non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+pub struct open_how
+{
+    pub flags: u64,
+    pub mode: u64,
+    pub resolve: u64,
+}
+
+/// Fail with `EXDEV` if resolution would cross a mount point.
+pub const RESOLVE_NO_XDEV: u64 = 0x01;
+
+/// Fail with `ELOOP` if resolution would follow a magic link.
+pub const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+
+/// Fail with `ELOOP` if resolution would follow any symbolic link.
+///
+/// This includes the final component, even without `O_NOFOLLOW`.
+pub const RESOLVE_NO_SYMLINKS: u64 = 0x04;
+
+/// Fail with `EXDEV` if resolution would leave the directory.
+pub const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Treat the directory as the root directory during resolution.
+pub const RESOLVE_IN_ROOT: u64 = 0x10;
+
+/// Call openat2(2) with the given arguments.
+///
+/// If `dirfd` is [`None`], `AT_FDCWD` is passed.
+pub fn openat2(
+    dirfd:    Option<BorrowedFd>,
+    pathname: &CStr,
+    how:      &open_how,
+) -> io::Result<OwnedFd>
+{
+    let dirfd = dirfd.map(|fd| fd.as_raw_fd()).unwrap_or(libc::AT_FDCWD);
+    let how = open_how{flags: how.flags | libc::O_CLOEXEC as u64, ..*how};
+
+    // SAFETY: path is NUL-terminated and how is valid for reads.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            dirfd,
+            pathname.as_ptr(),
+            &how as *const open_how,
+            size_of::<open_how>(),
+        )
+    };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fd is a new, open file descriptor.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Call fcntl(2) with `F_ADD_SEALS` and the given arguments.
+pub fn fcntl_add_seals(fd: BorrowedFd, seals: libc::c_int) -> io::Result<()>
+{
+    // SAFETY: F_ADD_SEALS takes an int argument.
+    let result = unsafe {
+        libc::fcntl(fd.as_raw_fd(), libc::F_ADD_SEALS, seals)
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Call fcntl(2) with `F_GET_SEALS` and the given arguments.
+pub fn fcntl_get_seals(fd: BorrowedFd) -> io::Result<libc::c_int>
+{
+    // SAFETY: F_GET_SEALS takes no argument.
+    let seals = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GET_SEALS) };
+
+    if seals == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(seals)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{fstatat, mkdtemp, symlinkat},
+        std::{ffi::CString, os::unix::io::AsFd},
+    };
+
+    #[test]
+    fn openat2_beneath()
+    {
+        let cstr = |s: &[u8]| CString::new(s).unwrap();
+
+        let path = mkdtemp(cstr(b"/tmp/os-ext-test-XXXXXX")).unwrap();
+        let dir = open(&path, libc::O_DIRECTORY | libc::O_PATH, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+        symlinkat(&cstr(b"/"), dirfd, &cstr(b"root")).unwrap();
+        symlinkat(&cstr(b"."), dirfd, &cstr(b"self")).unwrap();
+
+        let how = open_how{
+            flags: (libc::O_PATH | libc::O_NOFOLLOW) as u64,
+            resolve: RESOLVE_BENEATH,
+            ..open_how::default()
+        };
+        for escape in [&b".."[..], b"root/tmp", b"/tmp"] {
+            let error = openat2(dirfd, &cstr(escape), &how).unwrap_err();
+            assert_eq!(error.raw_os_error(), Some(libc::EXDEV));
+        }
+        for beneath in [&b"."[..], b"root", b"self/self"] {
+            openat2(dirfd, &cstr(beneath), &how).unwrap();
+        }
+    }
+
+    #[test]
+    fn openat2_no_symlinks()
+    {
+        let cstr = |s: &[u8]| CString::new(s).unwrap();
+
+        let path = mkdtemp(cstr(b"/tmp/os-ext-test-XXXXXX")).unwrap();
+        let dir = open(&path, libc::O_DIRECTORY | libc::O_PATH, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+        symlinkat(&cstr(b"."), dirfd, &cstr(b"self")).unwrap();
+
+        let how = open_how{
+            flags: libc::O_PATH as u64,
+            resolve: RESOLVE_NO_SYMLINKS,
+            ..open_how::default()
+        };
+        for symlink in [&b"self"[..], b"self/."] {
+            let error = openat2(dirfd, &cstr(symlink), &how).unwrap_err();
+            assert_eq!(error.raw_os_error(), Some(libc::ELOOP));
+        }
+        openat2(dirfd, &cstr(b"."), &how).unwrap();
+    }
+
+    #[test]
+    fn openat2_in_root()
+    {
+        let cstr = |s: &[u8]| CString::new(s).unwrap();
+
+        let path = mkdtemp(cstr(b"/tmp/os-ext-test-XXXXXX")).unwrap();
+        let dir = open(&path, libc::O_DIRECTORY | libc::O_PATH, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+        symlinkat(&cstr(b"/"), dirfd, &cstr(b"root")).unwrap();
+
+        // Both .. and absolute symbolic links stay within the directory.
+        let how = open_how{
+            flags: libc::O_PATH as u64,
+            resolve: RESOLVE_IN_ROOT,
+            ..open_how::default()
+        };
+        let root = openat2(dirfd, &cstr(b"../root"), &how).unwrap();
+        let root = fstatat(Some(root.as_fd()), &cstr(b""),
+                           libc::AT_EMPTY_PATH).unwrap();
+        let dir = fstatat(dirfd, &cstr(b""), libc::AT_EMPTY_PATH)
+            .unwrap();
+        assert_eq!((root.st_dev, root.st_ino), (dir.st_dev, dir.st_ino));
+    }
+}
@@ -1,10 +1,14 @@
 //! Working with file descriptors.
 
-use std::{
-    ffi::CString,
-    io,
-    mem::ManuallyDrop,
-    os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+use {
+    crate::{copy_file_range, ioctl_ficlone},
+    std::{
+        ffi::CString,
+        fs::File,
+        io,
+        mem::ManuallyDrop,
+        os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    },
 };
 
 /// Return the path to the `/proc/self/fd` entry for the file descriptor.
@@ -14,6 +18,56 @@ pub fn magic_link(fd: BorrowedFd) -> CString
         .expect("RawFd as Display should not write nul")
 }
 
+/// Copy the contents of a file into an empty file.
+///
+/// On file systems that support it, such as Btrfs and XFS,
+/// the copy shares storage with the original using ioctl_ficlone(2).
+/// Otherwise the contents are copied using copy_file_range(2),
+/// or using read(2) and write(2) if the files are on different file systems
+/// and the kernel does not support copying across file systems.
+/// The file offsets of `source` and `target` are ignored.
+pub fn reflink_or_copy(source: BorrowedFd, target: BorrowedFd)
+    -> io::Result<()>
+{
+    match ioctl_ficlone(target, source) {
+        Ok(()) => return Ok(()),
+        Err(err) if is_unsupported(&err) => { },
+        Err(err) => return Err(err),
+    }
+
+    let (mut off_in, mut off_out) = (0, 0);
+    loop {
+        let result = copy_file_range(
+            source, Some(&mut off_in),
+            target, Some(&mut off_out),
+            1 << 30, 0,
+        );
+        match result {
+            Ok(0) => return Ok(()),
+            Ok(_) => continue,
+            Err(err) if off_in == 0 && is_unsupported(&err) => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    let mut source = File::from(source.try_to_owned()?);
+    let mut target = File::from(target.try_to_owned()?);
+    io::Seek::rewind(&mut source)?;
+    io::Seek::rewind(&mut target)?;
+    io::copy(&mut source, &mut target)?;
+    Ok(())
+}
+
+/// Whether an error means that the file system does not support the call.
+fn is_unsupported(err: &io::Error) -> bool
+{
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EINVAL | libc::ENOSYS | libc::ENOTTY |
+             libc::EOPNOTSUPP | libc::EXDEV),
+    )
+}
+
 /// Extra methods for [`BorrowedFd`].
 pub trait BorrowedFdExt: Sized
 {
@@ -30,3 +84,30 @@ impl BorrowedFdExt for BorrowedFd<'_>
         ManuallyDrop::new(owned).try_clone()
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::memfd_create,
+        std::{ffi::CStr, io::{Read, Seek, Write}, os::unix::io::AsFd},
+    };
+
+    #[test]
+    fn reflink_or_copy_contents()
+    {
+        let name = CStr::from_bytes_with_nul(b"test\0").unwrap();
+        let mut source = File::from(memfd_create(name, 0).unwrap());
+        let mut target = File::from(memfd_create(name, 0).unwrap());
+
+        // The file offset of the source is at the end after writing.
+        source.write_all(b"Hello, world!\n").unwrap();
+        reflink_or_copy(source.as_fd(), target.as_fd()).unwrap();
+
+        let mut contents = Vec::new();
+        target.rewind().unwrap();
+        target.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"Hello, world!\n");
+    }
+}
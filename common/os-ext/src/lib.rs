@@ -32,15 +32,28 @@
 #![warn(missing_docs)]
 
 pub use {
-    self::{dirent_::*, fcntl::*, stdio::*, stdlib::*, sys_stat::*, unistd::*},
+    self::{
+        dirent_::*, fcntl::*, signal::*, stdio::*, stdlib::*,
+        sys_fanotify::*, sys_file::*, sys_ioctl::*, sys_mman::*,
+        sys_pidfd::*, sys_prctl::*, sys_resource::*, sys_seccomp::*,
+        sys_signalfd::*, sys_stat::*, unistd::*,
+    },
     libc::{
-        AT_SYMLINK_FOLLOW, AT_SYMLINK_NOFOLLOW,
-        O_CREAT, O_DIRECTORY, O_NOFOLLOW, O_PATH,
-        O_RDONLY, O_RDWR, O_TMPFILE, O_WRONLY,
+        AT_EMPTY_PATH, AT_REMOVEDIR, AT_SYMLINK_FOLLOW, AT_SYMLINK_NOFOLLOW,
+        F_SEAL_GROW, F_SEAL_SEAL, F_SEAL_SHRINK, F_SEAL_WRITE,
+        LOCK_EX, LOCK_NB, LOCK_SH, LOCK_UN,
+        MFD_ALLOW_SEALING,
+        O_APPEND, O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_PATH,
+        O_RDONLY, O_RDWR, O_TMPFILE, O_TRUNC, O_WRONLY,
         RENAME_NOREPLACE,
+        RLIM_INFINITY, RLIMIT_AS, RLIMIT_CPU, RLIMIT_FSIZE, RLIMIT_NOFILE,
         S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IXUSR,
         S_ISGID, S_ISUID, S_ISVTX,
-        gid_t, uid_t,
+        SFD_NONBLOCK, SIG_BLOCK, SIG_SETMASK, SIG_UNBLOCK,
+        STATX_ATIME, STATX_BASIC_STATS, STATX_BLOCKS, STATX_BTIME,
+        STATX_CTIME, STATX_GID, STATX_INO, STATX_MODE, STATX_MTIME,
+        STATX_NLINK, STATX_SIZE, STATX_TYPE, STATX_UID,
+        gid_t, pid_t, rlimit, signalfd_siginfo, sigset_t, timespec, uid_t,
     },
 };
 
@@ -49,8 +62,18 @@ pub mod io;
 
 mod dirent_;
 mod fcntl;
+mod signal;
 mod stdio;
 mod stdlib;
+mod sys_fanotify;
+mod sys_file;
+mod sys_ioctl;
+mod sys_mman;
+mod sys_pidfd;
+mod sys_prctl;
+mod sys_resource;
+mod sys_seccomp;
+mod sys_signalfd;
 mod sys_stat;
 mod unistd;
 
@@ -0,0 +1,122 @@
+use std::{io, mem::MaybeUninit, ptr::null};
+
+/// Call sigemptyset(3) to obtain an empty signal set.
+pub fn sigemptyset() -> libc::sigset_t
+{
+    let mut set = MaybeUninit::uninit();
+
+    // SAFETY: set is valid for writes, and sigemptyset cannot fail.
+    unsafe { libc::sigemptyset(set.as_mut_ptr()) };
+
+    // SAFETY: sigemptyset initialized set.
+    unsafe { set.assume_init() }
+}
+
+/// Call sigaddset(3) with the given arguments.
+pub fn sigaddset(set: &mut libc::sigset_t, signum: libc::c_int)
+    -> io::Result<()>
+{
+    // SAFETY: set is valid for reads and writes.
+    let result = unsafe { libc::sigaddset(set, signum) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Call sigismember(3) with the given arguments.
+pub fn sigismember(set: &libc::sigset_t, signum: libc::c_int)
+    -> io::Result<bool>
+{
+    // SAFETY: set is valid for reads.
+    let result = unsafe { libc::sigismember(set, signum) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(result == 1)
+}
+
+/// Call pthread_sigmask(3) with the given arguments.
+///
+/// If `set` is [`None`], the signal mask is not changed.
+/// Returns the signal mask as it was before the call.
+pub fn pthread_sigmask(how: libc::c_int, set: Option<&libc::sigset_t>)
+    -> io::Result<libc::sigset_t>
+{
+    let set = set.map_or(null(), |s| s as *const _);
+    let mut oldset = MaybeUninit::uninit();
+
+    // SAFETY: set is null or valid for reads,
+    //         and oldset is valid for writes.
+    let result = unsafe {
+        libc::pthread_sigmask(how, set, oldset.as_mut_ptr())
+    };
+
+    // pthread_sigmask returns the error number rather than setting errno.
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result));
+    }
+
+    // SAFETY: pthread_sigmask initialized oldset.
+    Ok(unsafe { oldset.assume_init() })
+}
+
+/// Call sigprocmask(2) with the given arguments.
+///
+/// If `set` is [`None`], the signal mask is not changed.
+/// Returns the signal mask as it was before the call.
+///
+/// In a multithreaded program, this only affects the calling thread.
+/// Prefer [`pthread_sigmask`], which makes that explicit.
+pub fn sigprocmask(how: libc::c_int, set: Option<&libc::sigset_t>)
+    -> io::Result<libc::sigset_t>
+{
+    let set = set.map_or(null(), |s| s as *const _);
+    let mut oldset = MaybeUninit::uninit();
+
+    // SAFETY: set is null or valid for reads,
+    //         and oldset is valid for writes.
+    let result = unsafe { libc::sigprocmask(how, set, oldset.as_mut_ptr()) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: sigprocmask initialized oldset.
+    Ok(unsafe { oldset.assume_init() })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn sigset()
+    {
+        let mut set = sigemptyset();
+        assert!(!sigismember(&set, libc::SIGUSR1).unwrap());
+        sigaddset(&mut set, libc::SIGUSR1).unwrap();
+        assert!(sigismember(&set, libc::SIGUSR1).unwrap());
+        assert!(!sigismember(&set, libc::SIGUSR2).unwrap());
+        assert!(sigaddset(&mut set, 0xFFFF).is_err());
+    }
+
+    #[test]
+    fn mask()
+    {
+        // Tests run on their own threads, so this does not affect others.
+        let mut set = sigemptyset();
+        sigaddset(&mut set, libc::SIGUSR2).unwrap();
+        let old = pthread_sigmask(libc::SIG_BLOCK, Some(&set)).unwrap();
+        assert!(!sigismember(&old, libc::SIGUSR2).unwrap());
+        let current = pthread_sigmask(libc::SIG_SETMASK, Some(&old)).unwrap();
+        assert!(sigismember(&current, libc::SIGUSR2).unwrap());
+        let current = sigprocmask(libc::SIG_BLOCK, None).unwrap();
+        assert!(!sigismember(&current, libc::SIGUSR2).unwrap());
+    }
+}
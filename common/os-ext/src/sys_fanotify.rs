@@ -0,0 +1,122 @@
+use std::{
+    ffi::CStr,
+    io,
+    os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    ptr::null,
+};
+
+/// Value of `FAN_CLOEXEC`, which not every libc version defines.
+const FAN_CLOEXEC: libc::c_uint = 0x01;
+
+/// Call fanotify_init(2) with the given arguments.
+///
+/// Depending on the flags, this requires `CAP_SYS_ADMIN`.
+/// Without it, only `FAN_CLASS_NOTIF` with `FAN_REPORT_FID` is allowed,
+/// and only on kernels that support unprivileged fanotify.
+pub fn fanotify_init(flags: libc::c_uint, event_f_flags: libc::c_uint)
+    -> io::Result<OwnedFd>
+{
+    let flags = flags | FAN_CLOEXEC;
+    let event_f_flags = event_f_flags | libc::O_CLOEXEC as libc::c_uint;
+
+    // SAFETY: fanotify_init takes two unsigned int arguments.
+    let fd = unsafe {
+        libc::syscall(libc::SYS_fanotify_init, flags, event_f_flags)
+    };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fd is a new, open file descriptor.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Call fanotify_mark(2) with the given arguments.
+///
+/// If `dirfd` is [`None`], `AT_FDCWD` is passed.
+/// If `pathname` is [`None`], a null pointer is passed,
+/// so that `dirfd` itself is marked.
+pub fn fanotify_mark(
+    fanotify_fd: BorrowedFd,
+    flags:       libc::c_uint,
+    mask:        u64,
+    dirfd:       Option<BorrowedFd>,
+    pathname:    Option<&CStr>,
+) -> io::Result<()>
+{
+    let dirfd = dirfd.map(|fd| fd.as_raw_fd()).unwrap_or(libc::AT_FDCWD);
+    let pathname = pathname.map_or(null(), CStr::as_ptr);
+
+    // SAFETY: pathname is null or NUL-terminated.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_fanotify_mark,
+            fanotify_fd.as_raw_fd(),
+            flags,
+            mask,
+            dirfd,
+            pathname,
+        )
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{
+            O_CREAT, O_DIRECTORY, O_RDONLY, O_WRONLY,
+            mkdtemp, open, openat,
+        },
+        std::{
+            ffi::CString,
+            fs::File,
+            io::{ErrorKind::PermissionDenied, Read, Write},
+            os::unix::io::AsFd,
+        },
+    };
+
+    #[test]
+    fn modify()
+    {
+        // These are the flags that unprivileged processes may use,
+        // with FAN_NONBLOCK so that the test fails rather than hangs.
+        // FAN_CLASS_NOTIF is zero, so it is implied.
+        let flags = 0x0000_0002  // FAN_NONBLOCK
+                  | 0x0000_0200; // FAN_REPORT_FID
+        let fanotify = match fanotify_init(flags, O_RDONLY as libc::c_uint) {
+            Err(err) if err.kind() == PermissionDenied => return,
+            result => result.unwrap(),
+        };
+
+        let path = CString::new("/tmp/os-ext-test-XXXXXX").unwrap();
+        let path = mkdtemp(path).unwrap();
+        let dir = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let name = CString::new("file").unwrap();
+        let file = openat(Some(dir.as_fd()), &name, O_CREAT | O_WRONLY, 0o644)
+            .unwrap();
+
+        let mark_add = 0x0000_0001;  // FAN_MARK_ADD
+        let mask = 0x0000_0002;      // FAN_MODIFY
+        fanotify_mark(fanotify.as_fd(), mark_add, mask,
+                      Some(dir.as_fd()), Some(&name)).unwrap();
+
+        File::from(file).write_all(b"hello").unwrap();
+
+        // The event starts with struct fanotify_event_metadata,
+        // whose mask field is at offset 8.
+        let mut buf = [0; 4096];
+        let nread = File::from(fanotify).read(&mut buf).unwrap();
+        assert!(nread >= 24);
+        let event_mask = u64::from_ne_bytes(buf[8 .. 16].try_into().unwrap());
+        assert_eq!(event_mask & mask, mask);
+    }
+}
@@ -0,0 +1,41 @@
+use std::{io, os::unix::io::{AsRawFd, BorrowedFd}};
+
+/// Call flock(2) with the given arguments.
+pub fn flock(fd: BorrowedFd, operation: libc::c_int) -> io::Result<()>
+{
+    // SAFETY: flock takes an int and a file descriptor.
+    let result = unsafe { libc::flock(fd.as_raw_fd(), operation) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{O_RDONLY, open},
+        std::{ffi::CString, os::unix::io::AsFd},
+    };
+
+    #[test]
+    fn exclusive()
+    {
+        // Locks are held by open file descriptions,
+        // so opening the file twice gives two competing lock holders.
+        let root = CString::new("/").unwrap();
+        let a = open(&root, O_RDONLY, 0).unwrap();
+        let b = open(&root, O_RDONLY, 0).unwrap();
+
+        flock(a.as_fd(), libc::LOCK_EX).unwrap();
+        let result = flock(b.as_fd(), libc::LOCK_EX | libc::LOCK_NB);
+        assert_eq!(result.unwrap_err().raw_os_error(), Some(libc::EWOULDBLOCK));
+
+        flock(a.as_fd(), libc::LOCK_UN).unwrap();
+        flock(b.as_fd(), libc::LOCK_EX | libc::LOCK_NB).unwrap();
+    }
+}
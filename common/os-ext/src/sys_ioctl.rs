@@ -0,0 +1,20 @@
+use std::{io, os::unix::io::{AsRawFd, BorrowedFd}};
+
+// Not yet defined by the libc crate.
+const FICLONE: libc::c_ulong = 0x40049409;
+
+/// Call ioctl_ficlone(2) with the given arguments.
+pub fn ioctl_ficlone(dest_fd: BorrowedFd, src_fd: BorrowedFd)
+    -> io::Result<()>
+{
+    // SAFETY: FICLONE takes an int argument.
+    let result = unsafe {
+        libc::ioctl(dest_fd.as_raw_fd(), FICLONE, src_fd.as_raw_fd())
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
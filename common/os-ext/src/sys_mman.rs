@@ -0,0 +1,54 @@
+use std::{
+    ffi::CStr,
+    io,
+    os::unix::io::{FromRawFd, OwnedFd},
+};
+
+/// Call memfd_create(2) with the given arguments.
+pub fn memfd_create(name: &CStr, flags: libc::c_uint) -> io::Result<OwnedFd>
+{
+    let flags = flags | libc::MFD_CLOEXEC;
+
+    // SAFETY: name is NUL-terminated.
+    let fd = unsafe { libc::memfd_create(name.as_ptr(), flags) };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fd is a new, open file descriptor.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{
+            F_SEAL_GROW, F_SEAL_SEAL, F_SEAL_SHRINK, F_SEAL_WRITE,
+            MFD_ALLOW_SEALING,
+            fcntl_add_seals, fcntl_get_seals,
+        },
+        std::{fs::File, io::Write, os::unix::io::AsFd},
+    };
+
+    #[test]
+    fn sealed_memfd()
+    {
+        let name = CStr::from_bytes_with_nul(b"test\0").unwrap();
+        let fd = memfd_create(name, MFD_ALLOW_SEALING).unwrap();
+        let mut file = File::from(fd);
+        file.write_all(b"Hello, world!\n").unwrap();
+
+        let seals = F_SEAL_GROW | F_SEAL_SEAL | F_SEAL_SHRINK | F_SEAL_WRITE;
+        fcntl_add_seals(file.as_fd(), seals).unwrap();
+        assert_eq!(fcntl_get_seals(file.as_fd()).unwrap(), seals);
+
+        // Sealed files can no longer be modified.
+        let err = file.write_all(b"Goodbye!\n").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        let err = fcntl_add_seals(file.as_fd(), 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    io,
+    os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    ptr::null,
+};
+
+/// Call pidfd_open(2) with the given arguments.
+pub fn pidfd_open(pid: libc::pid_t, flags: libc::c_uint) -> io::Result<OwnedFd>
+{
+    // SAFETY: pidfd_open takes a pid and flags.
+    let result = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, flags) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: pidfd_open returned a new file descriptor,
+    //         which always has FD_CLOEXEC set.
+    Ok(unsafe { OwnedFd::from_raw_fd(result as libc::c_int) })
+}
+
+/// Call pidfd_send_signal(2) with the given arguments.
+///
+/// No siginfo is passed, so the signal is sent
+/// as if by kill(2), and no flags are passed.
+pub fn pidfd_send_signal(pidfd: BorrowedFd, signal: libc::c_int)
+    -> io::Result<()>
+{
+    // SAFETY: The pidfd is valid and no siginfo is passed.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            signal,
+            null::<libc::siginfo_t>(),
+            0 as libc::c_uint,
+        )
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        std::{
+            os::unix::{io::AsFd, process::ExitStatusExt},
+            process::Command,
+        },
+    };
+
+    #[test]
+    fn send_signal()
+    {
+        let mut child = Command::new("sleep").arg("60").spawn().unwrap();
+        let pidfd = pidfd_open(child.id() as libc::pid_t, 0).unwrap();
+
+        // Signal zero only checks that the process exists.
+        pidfd_send_signal(pidfd.as_fd(), 0).unwrap();
+
+        pidfd_send_signal(pidfd.as_fd(), libc::SIGKILL).unwrap();
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+
+        // Once reaped, the process can no longer be signaled.
+        let err = pidfd_send_signal(pidfd.as_fd(), 0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+
+/// Call prctl(2) with `PR_SET_PDEATHSIG` and the given arguments.
+///
+/// The signal is sent to the calling process when its parent dies.
+/// Pass zero to clear the setting.
+pub fn prctl_set_pdeathsig(signal: libc::c_int) -> io::Result<()>
+{
+    let signal = signal as libc::c_ulong;
+
+    // SAFETY: PR_SET_PDEATHSIG takes an unsigned long argument.
+    let result = unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, signal) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Call prctl(2) with `PR_GET_PDEATHSIG`.
+pub fn prctl_get_pdeathsig() -> io::Result<libc::c_int>
+{
+    let mut signal = 0;
+
+    // SAFETY: PR_GET_PDEATHSIG takes a pointer to an int.
+    let result = unsafe {
+        libc::prctl(libc::PR_GET_PDEATHSIG, &mut signal as *mut libc::c_int)
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(signal)
+}
+
+/// Call prctl(2) with `PR_SET_NO_NEW_PRIVS`.
+///
+/// Once set, execve(2) no longer grants privileges,
+/// and the setting cannot be cleared again.
+pub fn prctl_set_no_new_privs() -> io::Result<()>
+{
+    // SAFETY: PR_SET_NO_NEW_PRIVS takes unsigned long arguments.
+    let result = unsafe {
+        libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as libc::c_ulong,
+                    0 as libc::c_ulong, 0 as libc::c_ulong,
+                    0 as libc::c_ulong)
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn pdeathsig()
+    {
+        let old = prctl_get_pdeathsig().unwrap();
+        prctl_set_pdeathsig(libc::SIGKILL).unwrap();
+        assert_eq!(prctl_get_pdeathsig().unwrap(), libc::SIGKILL);
+        prctl_set_pdeathsig(old).unwrap();
+        assert_eq!(prctl_get_pdeathsig().unwrap(), old);
+    }
+}
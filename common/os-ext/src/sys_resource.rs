@@ -0,0 +1,85 @@
+use std::{io, mem::MaybeUninit, ptr::null};
+
+/// Call getrlimit(2) with the given arguments.
+pub fn getrlimit(resource: libc::__rlimit_resource_t)
+    -> io::Result<libc::rlimit>
+{
+    let mut rlim = MaybeUninit::uninit();
+
+    // SAFETY: rlim is valid for writes.
+    let result = unsafe { libc::getrlimit(resource, rlim.as_mut_ptr()) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: getrlimit initialized rlim.
+    Ok(unsafe { rlim.assume_init() })
+}
+
+/// Call setrlimit(2) with the given arguments.
+pub fn setrlimit(resource: libc::__rlimit_resource_t, rlim: &libc::rlimit)
+    -> io::Result<()>
+{
+    // SAFETY: rlim is valid for reads.
+    let result = unsafe { libc::setrlimit(resource, rlim) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Call prlimit(2) with the given arguments.
+///
+/// If `new_limit` is [`None`], the limit is not changed.
+/// Returns the limit as it was before the call.
+pub fn prlimit(
+    pid:       libc::pid_t,
+    resource:  libc::__rlimit_resource_t,
+    new_limit: Option<&libc::rlimit>,
+) -> io::Result<libc::rlimit>
+{
+    let new_limit = new_limit.map_or(null(), |l| l as *const _);
+    let mut old_limit = MaybeUninit::uninit();
+
+    // SAFETY: new_limit is null or valid for reads,
+    //         and old_limit is valid for writes.
+    let result = unsafe {
+        libc::prlimit(pid, resource, new_limit, old_limit.as_mut_ptr())
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: prlimit initialized old_limit.
+    Ok(unsafe { old_limit.assume_init() })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {super::*, crate::RLIMIT_NOFILE};
+
+    #[test]
+    fn limits()
+    {
+        // Setting a limit to its current value is always allowed.
+        let limit = getrlimit(RLIMIT_NOFILE).unwrap();
+        setrlimit(RLIMIT_NOFILE, &limit).unwrap();
+
+        // prlimit returns the old limit, which was not changed.
+        let old_limit = prlimit(0, RLIMIT_NOFILE, Some(&limit)).unwrap();
+        assert_eq!(old_limit.rlim_cur, limit.rlim_cur);
+        assert_eq!(old_limit.rlim_max, limit.rlim_max);
+
+        // Raising the hard limit requires privileges.
+        let raised = libc::rlimit{rlim_cur: 0, rlim_max: libc::RLIM_INFINITY};
+        if limit.rlim_max != libc::RLIM_INFINITY {
+            let err = prlimit(0, RLIMIT_NOFILE, Some(&raised)).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::io;
+
+// Not all of these are available in the version of libc we use.
+// Values taken from <linux/filter.h>, <linux/bpf_common.h>,
+// <linux/seccomp.h>, and <linux/audit.h>.
+
+#[allow(missing_docs)] pub const BPF_LD:  u16 = 0x00;
+#[allow(missing_docs)] pub const BPF_JMP: u16 = 0x05;
+#[allow(missing_docs)] pub const BPF_RET: u16 = 0x06;
+#[allow(missing_docs)] pub const BPF_W:   u16 = 0x00;
+#[allow(missing_docs)] pub const BPF_ABS: u16 = 0x20;
+#[allow(missing_docs)] pub const BPF_JEQ: u16 = 0x10;
+#[allow(missing_docs)] pub const BPF_JGE: u16 = 0x30;
+#[allow(missing_docs)] pub const BPF_K:   u16 = 0x00;
+
+#[allow(missing_docs)] pub const SECCOMP_SET_MODE_FILTER:  libc::c_uint = 1;
+#[allow(missing_docs)] pub const SECCOMP_RET_KILL_PROCESS: u32 = 0x80000000;
+#[allow(missing_docs)] pub const SECCOMP_RET_ERRNO:        u32 = 0x00050000;
+#[allow(missing_docs)] pub const SECCOMP_RET_ALLOW:        u32 = 0x7fff0000;
+
+/// The audit architecture of the target, as found in `seccomp_data`.
+#[cfg(target_arch = "x86_64")]
+pub const AUDIT_ARCH_NATIVE: u32 = 0xc000003e;
+
+/// The audit architecture of the target, as found in `seccomp_data`.
+#[cfg(target_arch = "aarch64")]
+pub const AUDIT_ARCH_NATIVE: u32 = 0xc00000b7;
+
+/// Offset of the `nr` field in `seccomp_data`.
+pub const SECCOMP_DATA_NR: u32 = 0;
+
+/// Offset of the `arch` field in `seccomp_data`.
+pub const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Instruction of a classic BPF program.
+#[allow(missing_docs, non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct sock_filter
+{
+    pub code: u16,
+    pub jt: u8,
+    pub jf: u8,
+    pub k: u32,
+}
+
+impl sock_filter
+{
+    /// Create a statement, like the `BPF_STMT` macro.
+    pub const fn stmt(code: u16, k: u32) -> Self
+    {
+        Self{code, jt: 0, jf: 0, k}
+    }
+
+    /// Create a jump, like the `BPF_JUMP` macro.
+    pub const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> Self
+    {
+        Self{code, jt, jf, k}
+    }
+}
+
+#[repr(C)]
+struct sock_fprog
+{
+    len: libc::c_ushort,
+    filter: *const sock_filter,
+}
+
+/// Call seccomp(2) with `SECCOMP_SET_MODE_FILTER` and the given arguments.
+///
+/// The filter applies to the calling thread and is inherited
+/// by its children. Unless the caller has `CAP_SYS_ADMIN`,
+/// [`prctl_set_no_new_privs`] must be called first.
+/// This function does not allocate, so it may be called
+/// in a child process after forking a multithreaded process.
+///
+/// [`prctl_set_no_new_privs`]: `crate::prctl_set_no_new_privs`
+pub fn seccomp_set_mode_filter(flags: libc::c_uint, filter: &[sock_filter])
+    -> io::Result<()>
+{
+    let len = filter.len().try_into()
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    let prog = sock_fprog{len, filter: filter.as_ptr()};
+
+    // SAFETY: prog points to a valid filter of len instructions.
+    let result = unsafe {
+        libc::syscall(libc::SYS_seccomp, SECCOMP_SET_MODE_FILTER, flags,
+                      &prog as *const sock_fprog)
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {super::*, crate::prctl_set_no_new_privs, std::thread};
+
+    #[test]
+    fn filter()
+    {
+        // Filters apply to the calling thread only,
+        // so install it on a separate thread.
+        thread::spawn(|| {
+            let filter = [
+                sock_filter::stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+                sock_filter::jump(BPF_JMP | BPF_JEQ | BPF_K,
+                                  libc::SYS_getpid as u32, 0, 1),
+                sock_filter::stmt(BPF_RET | BPF_K,
+                                  SECCOMP_RET_ERRNO | libc::EPERM as u32),
+                sock_filter::stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW),
+            ];
+            prctl_set_no_new_privs().unwrap();
+            seccomp_set_mode_filter(0, &filter).unwrap();
+
+            // SAFETY: getpid takes no arguments.
+            let result = unsafe { libc::syscall(libc::SYS_getpid) };
+            assert_eq!(result, -1);
+            let error = io::Error::last_os_error();
+            assert_eq!(error.raw_os_error(), Some(libc::EPERM));
+
+            // SAFETY: getppid takes no arguments.
+            let result = unsafe { libc::syscall(libc::SYS_getppid) };
+            assert_ne!(result, -1);
+        }).join().unwrap();
+    }
+}
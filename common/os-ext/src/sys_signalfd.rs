@@ -0,0 +1,84 @@
+use std::{
+    io,
+    mem::{MaybeUninit, size_of},
+    os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+};
+
+/// Call signalfd(2) with the given arguments, creating a new signalfd.
+///
+/// The signals in the mask must be blocked using [`pthread_sigmask`]
+/// in every thread, or they are delivered in the usual way instead.
+///
+/// [`pthread_sigmask`]: `crate::pthread_sigmask`
+pub fn signalfd(mask: &libc::sigset_t, flags: libc::c_int)
+    -> io::Result<OwnedFd>
+{
+    let flags = flags | libc::SFD_CLOEXEC;
+
+    // SAFETY: mask is valid for reads.
+    let fd = unsafe { libc::signalfd(-1, mask, flags) };
+
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fd is a fresh file descriptor.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Call read(2) on a signalfd to dequeue a single signal.
+pub fn signalfd_read(fd: BorrowedFd) -> io::Result<libc::signalfd_siginfo>
+{
+    let mut info = MaybeUninit::<libc::signalfd_siginfo>::uninit();
+    let size = size_of::<libc::signalfd_siginfo>();
+
+    // SAFETY: info is valid for writes of size bytes.
+    let nread = unsafe {
+        libc::read(fd.as_raw_fd(), info.as_mut_ptr().cast(), size)
+    };
+
+    if nread == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // A signalfd only ever returns whole signalfd_siginfo structures.
+    debug_assert_eq!(nread as usize, size);
+
+    // SAFETY: read initialized info.
+    Ok(unsafe { info.assume_init() })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{pthread_sigmask, sigaddset, sigemptyset},
+        std::os::unix::io::AsFd,
+    };
+
+    #[test]
+    fn read_signal()
+    {
+        let mut mask = sigemptyset();
+        sigaddset(&mut mask, libc::SIGUSR1).unwrap();
+        let old = pthread_sigmask(libc::SIG_BLOCK, Some(&mask)).unwrap();
+
+        let fd = signalfd(&mask, libc::SFD_NONBLOCK).unwrap();
+        let fd = fd.as_fd();
+        let error = signalfd_read(fd).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+
+        // Send the signal to this thread only, since other threads
+        // do not have it blocked and would be terminated by it.
+        // SAFETY: pthread_self is always valid.
+        let result = unsafe { libc::pthread_kill(libc::pthread_self(),
+                                                 libc::SIGUSR1) };
+        assert_eq!(result, 0);
+
+        let info = signalfd_read(fd).unwrap();
+        assert_eq!(info.ssi_signo, libc::SIGUSR1 as u32);
+
+        pthread_sigmask(libc::SIG_SETMASK, Some(&old)).unwrap();
+    }
+}
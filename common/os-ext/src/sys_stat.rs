@@ -8,6 +8,46 @@ use {
     },
 };
 
+/// Call fchmodat(2) with the given arguments.
+///
+/// If `dirfd` is [`None`], `AT_FDCWD` is passed.
+pub fn fchmodat(
+    dirfd: Option<BorrowedFd>,
+    pathname: &CStr,
+    mode: libc::mode_t,
+    flags: libc::c_int,
+) -> io::Result<()>
+{
+    let dirfd = dirfd.map(|fd| fd.as_raw_fd()).unwrap_or(libc::AT_FDCWD);
+
+    // SAFETY: path is NUL-terminated.
+    let result = unsafe {
+        libc::fchmodat(dirfd, pathname.as_ptr(), mode, flags)
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Call fstat(2) with the given arguments.
+pub fn fstat(fd: BorrowedFd) -> io::Result<stat>
+{
+    let mut statbuf = MaybeUninit::uninit();
+
+    // SAFETY: statbuf is large enough.
+    let result = unsafe { libc::fstat(fd.as_raw_fd(), statbuf.as_mut_ptr()) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: fstat initialized statbuf.
+    Ok(unsafe { statbuf.assume_init() })
+}
+
 /// Call fstatat(2) with the given arguments.
 ///
 /// If `dirfd` is [`None`], `AT_FDCWD` is passed.
@@ -39,6 +79,88 @@ pub fn fstatat(
     Ok(unsafe { statbuf.assume_init() })
 }
 
+/// Result of [`statx`][`statx()`], corresponding to `struct statx`.
+///
+/// Only the fields indicated by `stx_mask` are filled in.
+#[allow(missing_docs, non_camel_case_types)]
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct statx
+{
+    pub stx_mask: u32,
+    pub stx_blksize: u32,
+    pub stx_attributes: u64,
+    pub stx_nlink: u32,
+    pub stx_uid: u32,
+    pub stx_gid: u32,
+    pub stx_mode: u16,
+    __spare0: u16,
+    pub stx_ino: u64,
+    pub stx_size: u64,
+    pub stx_blocks: u64,
+    pub stx_attributes_mask: u64,
+    pub stx_atime: statx_timestamp,
+    pub stx_btime: statx_timestamp,
+    pub stx_ctime: statx_timestamp,
+    pub stx_mtime: statx_timestamp,
+    pub stx_rdev_major: u32,
+    pub stx_rdev_minor: u32,
+    pub stx_dev_major: u32,
+    pub stx_dev_minor: u32,
+    pub stx_mnt_id: u64,
+    __spare3: [u64; 13],
+}
+
+/// Timestamp in [`statx`], corresponding to `struct statx_timestamp`.
+#[allow(missing_docs, non_camel_case_types)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(C)]
+pub struct statx_timestamp
+{
+    pub tv_sec: i64,
+    pub tv_nsec: u32,
+    __reserved: i32,
+}
+
+/// Want `stx_mnt_id`; see [`statx`][`statx()`].
+pub const STATX_MNT_ID: libc::c_uint = 0x1000;
+
+/// Call statx(2) with the given arguments.
+///
+/// If `dirfd` is [`None`], `AT_FDCWD` is passed.
+/// The file system may not provide every field requested in `mask`,
+/// so check `stx_mask` in the result before using a field.
+pub fn statx(
+    dirfd: Option<BorrowedFd>,
+    pathname: &CStr,
+    flags: libc::c_int,
+    mask: libc::c_uint,
+) -> io::Result<statx>
+{
+    let dirfd = dirfd.map(|fd| fd.as_raw_fd()).unwrap_or(libc::AT_FDCWD);
+
+    let mut statxbuf = MaybeUninit::<statx>::uninit();
+
+    // SAFETY: path is NUL-terminated, statxbuf is valid for writes.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_statx,
+            dirfd,
+            pathname.as_ptr(),
+            flags,
+            mask,
+            statxbuf.as_mut_ptr(),
+        )
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: statx initialized statxbuf.
+    Ok(unsafe { statxbuf.assume_init() })
+}
+
 /// Equivalent to [`mkdirat`] with [`None`] passed for `dirfd`.
 pub fn mkdir(pathname: &CStr, mode: libc::mode_t) -> io::Result<()>
 {
@@ -91,3 +213,76 @@ pub fn mknodat(
 
     Ok(())
 }
+
+/// Call utimensat(2) with the given arguments.
+///
+/// If `dirfd` is [`None`], `AT_FDCWD` is passed.
+pub fn utimensat(
+    dirfd: Option<BorrowedFd>,
+    pathname: &CStr,
+    times: &[libc::timespec; 2],
+    flags: libc::c_int,
+) -> io::Result<()>
+{
+    let dirfd = dirfd.map(|fd| fd.as_raw_fd()).unwrap_or(libc::AT_FDCWD);
+
+    // SAFETY: path is NUL-terminated, times has two elements.
+    let result = unsafe {
+        libc::utimensat(dirfd, pathname.as_ptr(), times.as_ptr(), flags)
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{O_RDWR, O_TMPFILE, mkdtemp, openat},
+        std::{
+            ffi::CString,
+            fs::File,
+            io::Write,
+            mem::size_of,
+            os::unix::io::AsFd,
+        },
+    };
+
+    #[test]
+    fn statx_size()
+    {
+        assert_eq!(size_of::<statx>(), 256);
+    }
+
+    #[test]
+    fn statx_file()
+    {
+        let path = CString::new("/tmp/os-ext-test-XXXXXX").unwrap();
+        let path = mkdtemp(path).unwrap();
+        let file = openat(None, &path, O_RDWR | O_TMPFILE, 0o644).unwrap();
+        File::from(file.try_clone().unwrap()).write_all(b"hello").unwrap();
+
+        let empty = CString::new("").unwrap();
+        let mask = libc::STATX_BASIC_STATS | STATX_MNT_ID;
+        let flags = libc::AT_EMPTY_PATH;
+        let statxbuf = statx(Some(file.as_fd()), &empty, flags, mask).unwrap();
+        let statbuf = fstatat(Some(file.as_fd()), &empty, flags).unwrap();
+        assert_eq!(fstat(file.as_fd()).unwrap().st_ino, statbuf.st_ino);
+
+        let basic = libc::STATX_BASIC_STATS;
+        assert_eq!(statxbuf.stx_mask & basic, basic);
+        assert_eq!(statxbuf.stx_size, 5);
+        assert_eq!(statxbuf.stx_ino, statbuf.st_ino);
+        assert_eq!(statxbuf.stx_mode as u32, statbuf.st_mode);
+        assert_eq!(statxbuf.stx_mtime.tv_sec, statbuf.st_mtime);
+        assert_eq!(statxbuf.stx_mtime.tv_nsec as i64, statbuf.st_mtime_nsec);
+        if statxbuf.stx_mask & STATX_MNT_ID != 0 {
+            assert_ne!(statxbuf.stx_mnt_id, 0);
+        }
+    }
+}
@@ -4,9 +4,67 @@ use {
         ffi::{CStr, CString},
         io,
         os::unix::io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+        ptr::null_mut,
     },
 };
 
+/// Call copy_file_range(2) with the given arguments.
+///
+/// If `off_in` or `off_out` is [`None`], `NULL` is passed.
+pub fn copy_file_range(
+    fd_in:   BorrowedFd,
+    off_in:  Option<&mut libc::loff_t>,
+    fd_out:  BorrowedFd,
+    off_out: Option<&mut libc::loff_t>,
+    len:     usize,
+    flags:   libc::c_uint,
+) -> io::Result<usize>
+{
+    let off_in = off_in.map_or(null_mut(), |off| off as *mut _);
+    let off_out = off_out.map_or(null_mut(), |off| off as *mut _);
+
+    // SAFETY: Offsets are either null or valid.
+    let result = unsafe {
+        libc::copy_file_range(
+            fd_in.as_raw_fd(), off_in,
+            fd_out.as_raw_fd(), off_out,
+            len, flags,
+        )
+    };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(result as usize)
+}
+
+/// Call fdatasync(2) with the given arguments.
+pub fn fdatasync(fd: BorrowedFd) -> io::Result<()>
+{
+    // SAFETY: This is always safe.
+    let result = unsafe { libc::fdatasync(fd.as_raw_fd()) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Call fsync(2) with the given arguments.
+pub fn fsync(fd: BorrowedFd) -> io::Result<()>
+{
+    // SAFETY: This is always safe.
+    let result = unsafe { libc::fsync(fd.as_raw_fd()) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
 /// Call getgid(2).
 pub fn getgid() -> gid_t
 {
@@ -154,11 +212,52 @@ pub fn symlinkat(
     Ok(())
 }
 
+/// Call syncfs(2) with the given arguments.
+pub fn syncfs(fd: BorrowedFd) -> io::Result<()>
+{
+    // SAFETY: This is always safe.
+    let result = unsafe { libc::syncfs(fd.as_raw_fd()) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Call unlinkat(2) with the given arguments.
+///
+/// If `dirfd` is [`None`], `AT_FDCWD` is passed.
+pub fn unlinkat(dirfd: Option<BorrowedFd>, pathname: &CStr, flags: libc::c_int)
+    -> io::Result<()>
+{
+    let dirfd = dirfd.map(|fd| fd.as_raw_fd()).unwrap_or(libc::AT_FDCWD);
+
+    // SAFETY: pathname is NUL-terminated.
+    let result = unsafe { libc::unlinkat(dirfd, pathname.as_ptr(), flags) };
+
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
 
 #[cfg(test)]
 mod tests
 {
-    use super::*;
+    use {super::*, crate::open, std::os::unix::io::AsFd};
+
+    #[test]
+    fn sync()
+    {
+        let path = CString::new("testdata").unwrap();
+        let flags = libc::O_DIRECTORY | libc::O_RDONLY;
+        let dir = open(&path, flags, 0).unwrap();
+        fdatasync(dir.as_fd()).unwrap();
+        fsync(dir.as_fd()).unwrap();
+        syncfs(dir.as_fd()).unwrap();
+    }
 
     #[test]
     fn readlinkat_loop()
@@ -17,9 +17,14 @@ in
         ];
 
         SNOWFLAKE_BASH      = nixpkgs.bash;
+        SNOWFLAKE_CACERT    = nixpkgs.cacert;
         SNOWFLAKE_COREUTILS = nixpkgs.coreutils;
+        SNOWFLAKE_CURL      = nixpkgs.curl;
         SNOWFLAKE_GNUM4     = nixpkgs.gnum4;
+        SNOWFLAKE_GNUTAR    = nixpkgs.gnutar;
+        SNOWFLAKE_GZIP      = nixpkgs.gzip;
         SNOWFLAKE_MINIFY    = nixpkgs.minify;
         SNOWFLAKE_SASSC     = nixpkgs.sassc;
+        SNOWFLAKE_UNZIP     = nixpkgs.unzip;
 
     }
@@ -1,9 +1,13 @@
 use {
+    crate::encoding::encode_action,
     anyhow::Context,
     os_ext::{cstring, symlinkat},
-    snowflake_core::action::{
-        Action, InputPath, Outputs,
-        Perform, Result, Success,
+    snowflake_core::{
+        action::{
+            Action, EncodedAction, InputPath, Outputs,
+            Perform, Result, Success,
+        },
+        executor::{ReadExt, WriteExt},
     },
     snowflake_util::hash::{Blake3, Hash},
     std::ffi::CString,
@@ -16,6 +20,16 @@ pub struct CreateSymbolicLink
     pub target: CString,
 }
 
+impl CreateSymbolicLink
+{
+    /// Decode an action encoded with [`Action::encode`].
+    pub fn decode(data: &mut &[u8]) -> anyhow::Result<Self>
+    {
+        let target = data.get_cstring()?;
+        Ok(Self{target})
+    }
+}
+
 impl Action for CreateSymbolicLink
 {
     fn inputs(&self) -> usize
@@ -34,7 +48,11 @@ impl Action for CreateSymbolicLink
         let output_path = cstring!(b"output");
         symlinkat(&self.target, Some(perform.scratch), &output_path)
             .context("Create symbolic link")?;
-        Ok(Success{output_paths: vec![output_path], warnings: false})
+        Ok(Success{
+            output_paths: vec![output_path],
+            warnings: false,
+            dependencies: vec![],
+        })
     }
 
     fn hash(&self, input_hashes: &[Hash]) -> Hash
@@ -50,4 +68,10 @@ impl Action for CreateSymbolicLink
         h.put_cstr(target);
         h.finalize()
     }
+
+    fn encode(&self) -> Option<EncodedAction>
+    {
+        let Self{target} = self;
+        Some(encode_action("CreateSymbolicLink", |w| w.put_cstr(target)))
+    }
 }
@@ -0,0 +1,143 @@
+//! Parsing Makefile-style depfiles.
+
+use {std::mem::take, thiserror::Error};
+
+/// Returned when a depfile could not be parsed.
+#[derive(Debug, Error)]
+#[error("Depfile is not a valid Makefile-style depfile")]
+pub struct DepfileError;
+
+/// Parse a depfile and return the prerequisites of all its rules.
+///
+/// Depfiles as written by GCC and Clang are supported:
+/// a backslash followed by a newline continues the line,
+/// `\ ` and `\#` escape a space and a number sign,
+/// and `$$` escapes a dollar sign.
+/// The targets of the rules are not returned.
+pub fn parse_depfile(depfile: &[u8]) -> Result<Vec<Vec<u8>>, DepfileError>
+{
+    let mut parser = Parser::default();
+
+    let mut i = 0;
+    while i < depfile.len() {
+        let byte = depfile[i];
+        let next = depfile.get(i + 1).copied();
+        i += 1;
+        match (byte, next) {
+            (b'\0', _) =>
+                return Err(DepfileError),
+            (b'\\', Some(b'\n')) => {
+                i += 1;
+                parser.end_word();
+            },
+            (b'\\', Some(b'\r')) if depfile.get(i + 1) == Some(&b'\n') => {
+                i += 2;
+                parser.end_word();
+            },
+            (b'\\', Some(escaped @ (b' ' | b'#'))) |
+            (b'$', Some(escaped @ b'$')) => {
+                i += 1;
+                parser.word.push(escaped);
+            },
+            (b' ' | b'\t' | b'\r', _) =>
+                parser.end_word(),
+            (b'\n', _) => {
+                parser.end_word();
+                parser.end_rule()?;
+            },
+            (b':', None | Some(b' ' | b'\t' | b'\r' | b'\n'))
+                if !parser.in_prerequisites => {
+                parser.end_word();
+                if !parser.has_targets {
+                    return Err(DepfileError);
+                }
+                parser.in_prerequisites = true;
+            },
+            (byte, _) =>
+                parser.word.push(byte),
+        }
+    }
+
+    parser.end_word();
+    parser.end_rule()?;
+
+    Ok(parser.prerequisites)
+}
+
+#[derive(Default)]
+struct Parser
+{
+    /// The word currently being parsed.
+    word: Vec<u8>,
+
+    /// Whether the current rule has any targets.
+    has_targets: bool,
+
+    /// Whether the colon of the current rule has been parsed.
+    in_prerequisites: bool,
+
+    /// The prerequisites parsed so far.
+    prerequisites: Vec<Vec<u8>>,
+}
+
+impl Parser
+{
+    fn end_word(&mut self)
+    {
+        if self.word.is_empty() {
+            return;
+        }
+        if self.in_prerequisites {
+            self.prerequisites.push(take(&mut self.word));
+        } else {
+            self.has_targets = true;
+            self.word.clear();
+        }
+    }
+
+    fn end_rule(&mut self) -> Result<(), DepfileError>
+    {
+        if self.has_targets && !self.in_prerequisites {
+            return Err(DepfileError);
+        }
+        self.has_targets = false;
+        self.in_prerequisites = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn examples()
+    {
+        let examples: &[(&[u8], Option<&[&[u8]]>)] = &[
+            // Valid depfiles.
+            (b"", Some(&[])),
+            (b"main.o:", Some(&[])),
+            (b"main.o: main.c\n", Some(&[b"main.c"])),
+            (b"main.o: main.c util.h\n", Some(&[b"main.c", b"util.h"])),
+            (b"main.o: main.c \\\n  util.h\n", Some(&[b"main.c", b"util.h"])),
+            (b"main.o: main.c \\\r\n  util.h\r\n", Some(&[b"main.c", b"util.h"])),
+            (b"main.o: my\\ file.c\n", Some(&[b"my file.c"])),
+            (b"main.o: \\#.h $$.h\n", Some(&[b"#.h", b"$.h"])),
+            (b"main.o: C:/main.c\n", Some(&[b"C:/main.c"])),
+            (b"main.o: main.c util.h\n\nutil.h:\n", Some(&[b"main.c", b"util.h"])),
+
+            // Invalid depfiles.
+            (b"main.o\n", None),
+            (b": main.c\n", None),
+            (b"main.o: main\0.c\n", None),
+        ];
+        for &(depfile, expected) in examples {
+            let actual = parse_depfile(depfile).ok();
+            let expected =
+                expected.map(|e| e.iter().map(|p| p.to_vec()).collect());
+            let depfile = String::from_utf8_lossy(depfile);
+            assert_eq!(actual, expected, "{depfile:?}");
+        }
+    }
+}
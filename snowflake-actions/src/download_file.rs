@@ -0,0 +1,208 @@
+use {
+    crate::run_command::{RunCommand, perform_run_command},
+    anyhow::Context,
+    os_ext::{cstring, unlinkat},
+    snowflake_core::action::{
+        Action, Error, InputPath, Outputs,
+        Perform, Resources, Result, RetryPolicy,
+    },
+    snowflake_util::{
+        basename::Basename,
+        hash::{Blake3, Hash, hash_file_at},
+    },
+    std::{
+        ffi::{CStr, CString},
+        os::unix::io::BorrowedFd,
+        time::Duration,
+    },
+};
+
+/// Exit codes of curl that indicate transient network errors.
+///
+/// These are, in order: could not resolve host, could not connect,
+/// operation timed out, TLS handshake failed, got nothing from server,
+/// and failure receiving network data.
+const CURL_TRANSIENT_EXIT_CODES: [i32; 6] = [6, 7, 28, 35, 52, 56];
+
+/// Action that downloads a file.
+///
+/// The download is performed in a container that, unlike the containers
+/// of other actions, has access to the network.
+/// This is acceptable because the downloaded file is checked
+/// against a hash that is known in advance.
+///
+/// Because the network is unreliable, the download is attempted
+/// up to three times if it fails with a transient network error.
+pub struct DownloadFile
+{
+    /// The URL to download the file from.
+    pub url: CString,
+
+    /// The expected hash of the downloaded file.
+    ///
+    /// If the downloaded file has a different hash, the action fails.
+    /// The hash is as computed by [`hash_file_at`].
+    pub hash: Hash,
+
+    /// How much time the download may spend.
+    ///
+    /// If the download takes longer than this,
+    /// it is aborted and the action fails.
+    pub timeout: Duration,
+}
+
+impl Action for DownloadFile
+{
+    fn inputs(&self) -> usize
+    {
+        0
+    }
+
+    fn outputs(&self) -> Outputs<usize>
+    {
+        Outputs::Outputs(1)
+    }
+
+    fn retry_policy(&self) -> RetryPolicy
+    {
+        RetryPolicy{
+            max_attempts: 3,
+            exit_codes: Some(CURL_TRANSIENT_EXIT_CODES.to_vec()),
+        }
+    }
+
+    fn perform(&self, perform: &Perform, input_paths: &[InputPath]) -> Result
+    {
+        debug_assert_eq!(input_paths.len(), 0);
+
+        let cacert = concat!(env!("SNOWFLAKE_CACERT"),
+                             "/etc/ssl/certs/ca-bundle.crt");
+        let curl = concat!(env!("SNOWFLAKE_CURL"), "/bin/curl");
+
+        let command = RunCommand{
+            inputs: vec![],
+            outputs: Outputs::Outputs(vec![
+                Basename::new(cstring!(b"output")).unwrap(),
+            ]),
+            optional_outputs: vec![],
+            output_groups: vec![],
+            program: CString::new(curl).unwrap(),
+            arguments: vec![
+                cstring!(b"curl"),
+                cstring!(b"--fail"),
+                cstring!(b"--location"),
+                cstring!(b"--show-error"),
+                cstring!(b"--silent"),
+                cstring!(b"--output"),
+                cstring!(b"output"),
+                cstring!(b"--"),
+                self.url.clone(),
+            ],
+            environment: vec![
+                CString::new(format!("SSL_CERT_FILE={cacert}")).unwrap(),
+            ],
+            passthrough: vec![],
+            timeout: self.timeout,
+            warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: true,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
+        };
+
+        let success = perform_run_command(perform, &command, &[])?;
+
+        let output_path = &success.output_paths[0];
+        verify_download(perform.scratch, output_path, self.hash)?;
+
+        Ok(success)
+    }
+
+    fn hash(&self, input_hashes: &[Hash]) -> Hash
+    {
+        // NOTE: See the manual chapter on avoiding hash collisions.
+
+        let Self{url, hash, timeout} = self;
+
+        debug_assert_eq!(input_hashes.len(), 0);
+
+        let mut h = Blake3::new();
+        h.put_str("DownloadFile");
+        h.put_cstr(url);
+        h.put_hash(*hash);
+
+        // The timeout cannot affect the output of the action,
+        // so there is no need to include it in the hash.
+        let _ = timeout;
+
+        h.finalize()
+    }
+}
+
+/// Check that a downloaded file has the expected hash.
+///
+/// If it does not, the file is removed from the scratch directory,
+/// so that it cannot be mistaken for an output of the action.
+fn verify_download(scratch: BorrowedFd, path: &CStr, expected: Hash)
+    -> std::result::Result<(), Error>
+{
+    let actual = hash_file_at(Some(scratch), path)
+        .context("Compute hash of downloaded file")?;
+    if actual != expected {
+        unlinkat(Some(scratch), path, 0)
+            .context("Remove downloaded file")?;
+        return Err(Error::HashMismatch{expected, actual});
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        os_ext::{
+            AT_SYMLINK_NOFOLLOW, O_CREAT, O_DIRECTORY, O_PATH, O_WRONLY,
+            cstr, fstatat, mkdtemp, open, openat,
+        },
+        std::{
+            assert_matches::assert_matches,
+            fs::File,
+            io::{ErrorKind::NotFound, Write},
+            os::unix::io::AsFd,
+        },
+    };
+
+    #[test]
+    fn hash_mismatch()
+    {
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let scratch = open(&path, O_DIRECTORY | O_PATH, 0).unwrap();
+        let scratch = scratch.as_fd();
+
+        // Pretend that curl downloaded this file.
+        let output_path = cstr!(b"output");
+        let flags = O_CREAT | O_WRONLY;
+        let file = openat(Some(scratch), output_path, flags, 0o644).unwrap();
+        File::from(file).write_all(b"Hello, world!\n").unwrap();
+        let actual = hash_file_at(Some(scratch), output_path).unwrap();
+
+        // A download with the expected hash is accepted.
+        verify_download(scratch, output_path, actual).unwrap();
+        fstatat(Some(scratch), output_path, AT_SYMLINK_NOFOLLOW).unwrap();
+
+        // A download with a different hash is rejected and removed.
+        let expected = Hash([0; 32]);
+        let result = verify_download(scratch, output_path, expected);
+        assert_matches!(
+            result,
+            Err(Error::HashMismatch{expected: e, actual: a})
+                if e == expected && a == actual
+        );
+        let statbuf = fstatat(Some(scratch), output_path, AT_SYMLINK_NOFOLLOW);
+        assert_eq!(statbuf.err().map(|err| err.kind()), Some(NotFound));
+    }
+}
@@ -0,0 +1,189 @@
+use {
+    crate::{CreateSymbolicLink, RunCommand, RunTest, WriteRegularFile},
+    anyhow::{Result, bail},
+    snowflake_core::{
+        action::{Action, EncodedAction, Resources, RetryPolicy},
+        executor::{ReadExt, WriteExt},
+    },
+    std::{io, time::Duration},
+};
+
+/// Decode an action encoded with [`Action::encode`].
+///
+/// This is the decode function to pass to
+/// [`serve_connection`][`snowflake_core::executor::serve_connection`]
+/// on a build server, and it supports every action in this crate
+/// that can be encoded.
+pub fn decode_action(encoded: &EncodedAction) -> Result<Box<dyn Action>>
+{
+    let EncodedAction{kind, data} = encoded;
+    let data = &mut &data[..];
+    let action: Box<dyn Action> = match kind.as_str() {
+        "CreateSymbolicLink" => Box::new(CreateSymbolicLink::decode(data)?),
+        "RunCommand"         => Box::new(RunCommand::decode(data)?),
+        "RunTest"            => Box::new(RunTest::decode(data)?),
+        "WriteRegularFile"   => Box::new(WriteRegularFile::decode(data)?),
+        _ => bail!("Unknown type of action: {kind:?}"),
+    };
+    if !data.is_empty() {
+        bail!("Encoded {kind} action has trailing data");
+    }
+    Ok(action)
+}
+
+/// Encode an action with the given function.
+pub (crate) fn encode_action<F>(kind: &str, f: F) -> EncodedAction
+    where F: FnOnce(&mut Vec<u8>) -> io::Result<()>
+{
+    let mut data = Vec::new();
+    f(&mut data).expect("Writing to a vector should not fail");
+    EncodedAction{kind: kind.to_owned(), data}
+}
+
+pub (crate) fn put_duration(w: &mut Vec<u8>, value: Duration)
+    -> io::Result<()>
+{
+    w.put_u64(value.as_secs())?;
+    w.put_u64(value.subsec_nanos().into())
+}
+
+pub (crate) fn get_duration(r: &mut &[u8]) -> Result<Duration>
+{
+    let secs = r.get_u64()?;
+    let nanos = r.get_u64()?;
+    if nanos >= 1_000_000_000 {
+        bail!("Duration has too many nanoseconds");
+    }
+    Ok(Duration::new(secs, nanos as u32))
+}
+
+pub (crate) fn put_resources(w: &mut Vec<u8>, value: &Resources)
+    -> io::Result<()>
+{
+    let Resources{cpus, memory, exclusive} = value;
+    w.put_u64((*cpus).into())?;
+    w.put_u64(*memory)?;
+    w.put_bool(*exclusive)
+}
+
+pub (crate) fn get_resources(r: &mut &[u8]) -> Result<Resources>
+{
+    Ok(Resources{
+        cpus: r.get_u64()?.try_into()?,
+        memory: r.get_u64()?,
+        exclusive: r.get_bool()?,
+    })
+}
+
+pub (crate) fn put_retry_policy(w: &mut Vec<u8>, value: &RetryPolicy)
+    -> io::Result<()>
+{
+    let RetryPolicy{max_attempts, exit_codes} = value;
+    w.put_u64((*max_attempts).into())?;
+    w.put_bool(exit_codes.is_some())?;
+    if let Some(exit_codes) = exit_codes {
+        w.put_slice(exit_codes, |w, &c| w.put_u64(c as u32 as u64))?;
+    }
+    Ok(())
+}
+
+pub (crate) fn get_retry_policy(r: &mut &[u8]) -> Result<RetryPolicy>
+{
+    let max_attempts = r.get_u64()?.try_into()?;
+    let exit_codes = if r.get_bool()? {
+        let exit_code = |r: &mut &[u8]| -> Result<_> {
+            Ok(u32::try_from(r.get_u64()?)? as i32)
+        };
+        Some(r.get_vec(exit_code)?)
+    } else {
+        None
+    };
+    Ok(RetryPolicy{max_attempts, exit_codes})
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{Depfile, LogPaths},
+        os_ext::cstring,
+        regex::bytes::Regex,
+        snowflake_core::action::{ActionExt, OutputGroup, Outputs},
+        snowflake_util::{basename::Basename, hash::Hash},
+        std::ffi::CString,
+    };
+
+    #[test]
+    fn round_trip()
+    {
+        let basename = |name| Basename::new(CString::new(name).unwrap())
+            .unwrap();
+        let run_command = RunCommand{
+            inputs: vec![basename("main.c"), basename("include")],
+            outputs: Outputs::Outputs(vec![basename("main.o")]),
+            optional_outputs: vec![0],
+            output_groups: vec![
+                OutputGroup{name: "objects".to_owned(), outputs: vec![0]},
+            ],
+            program: cstring!(b"/usr/bin/cc"),
+            arguments: vec![cstring!(b"cc"), cstring!(b"-c")],
+            environment: vec![cstring!(b"LANG=C")],
+            passthrough: vec![cstring!(b"PATH")],
+            timeout: Duration::new(60, 500),
+            warnings: Some(Regex::new("warning:").unwrap()),
+            depfile: Some(Depfile{path: basename("main.d"), inputs: vec![1]}),
+            log_paths: Some(LogPaths{
+                regex: Regex::new("[a-z.]+").unwrap(),
+                workspace_paths: vec![Some(cstring!(b"src/main.c")), None],
+            }),
+            resources: Resources{cpus: 2, memory: 1 << 30, exclusive: true},
+            retry_policy: RetryPolicy{
+                max_attempts: 3,
+                exit_codes: Some(vec![-1, 75]),
+            },
+            network: true,
+            writable_inputs: true,
+            allowed_syscalls: vec!["ptrace".to_owned()],
+        };
+        let run_test = RunTest{
+            inputs: vec![basename("test")],
+            program: cstring!(b"test"),
+            arguments: vec![cstring!(b"test")],
+            environment: vec![],
+            timeout: Duration::from_secs(10),
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+        };
+        let write_regular_file =
+            WriteRegularFile{content: b"hello".to_vec(), executable: true};
+        let create_symbolic_link =
+            CreateSymbolicLink{target: cstring!(b"../target")};
+
+        let actions: [&dyn Action; 4] = [
+            &run_command, &run_test,
+            &write_regular_file, &create_symbolic_link,
+        ];
+        for action in actions {
+            // The decoded action is indistinguishable from the original.
+            let input_hashes = vec![Hash([0; 32]); action.inputs()];
+            let encoded = action.encode().unwrap();
+            let decoded = decode_action(&encoded).unwrap();
+            assert_eq!(decoded.encode().unwrap(), encoded);
+            assert_eq!(decoded.hash(&input_hashes), action.hash(&input_hashes));
+            assert_eq!(decoded.is_lint(), action.is_lint());
+            assert_eq!(decoded.is_test(), action.is_test());
+
+            // Malformed encodings are rejected.
+            let mut trailing = encoded.clone();
+            trailing.data.push(0);
+            assert!(decode_action(&trailing).is_err());
+            let mut truncated = encoded.clone();
+            truncated.data.pop();
+            assert!(decode_action(&truncated).is_err());
+        }
+
+        let unknown = EncodedAction{kind: "Unknown".to_owned(), data: vec![]};
+        assert!(decode_action(&unknown).is_err());
+    }
+}
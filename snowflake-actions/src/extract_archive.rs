@@ -0,0 +1,304 @@
+use {
+    crate::run_command::{RunCommand, perform_run_command},
+    anyhow::{Context, bail},
+    os_ext::{
+        AT_SYMLINK_NOFOLLOW,
+        O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_RDONLY, O_WRONLY,
+        S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_IXUSR,
+        cstring, fchmodat, fstatat,
+        openat, timespec, unlinkat, utimensat,
+        io::reflink_or_copy,
+    },
+    snowflake_core::{
+        action::{
+            Action, InputPath, Outputs,
+            Perform, Resources, Result, RetryPolicy,
+        },
+        fs_util::read_entries,
+    },
+    snowflake_util::{basename::Basename, hash::{Blake3, Hash}},
+    std::{
+        ffi::{CStr, CString},
+        os::unix::io::{AsFd, BorrowedFd},
+        time::Duration,
+    },
+};
+
+/// Format of an archive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat
+{
+    /// Tar archive compressed with gzip.
+    TarGz,
+
+    /// Zip archive.
+    Zip,
+}
+
+/// Action that extracts an archive into a directory.
+///
+/// The extracted files are normalized so that the output depends only
+/// on the names, contents, and executable bits of the archived files.
+/// Ownership, permissions, and modification times are not preserved,
+/// and hard links are replaced by copies.
+pub struct ExtractArchive
+{
+    /// The format of the archive.
+    pub format: ArchiveFormat,
+
+    /// How much time the extraction may spend.
+    ///
+    /// If the extraction takes longer than this,
+    /// it is aborted and the action fails.
+    pub timeout: Duration,
+}
+
+impl Action for ExtractArchive
+{
+    fn inputs(&self) -> usize
+    {
+        1
+    }
+
+    fn outputs(&self) -> Outputs<usize>
+    {
+        Outputs::Outputs(1)
+    }
+
+    fn perform(&self, perform: &Perform, input_paths: &[InputPath]) -> Result
+    {
+        debug_assert_eq!(input_paths.len(), 1);
+
+        let command = match self.format {
+            ArchiveFormat::TarGz => {
+                let gzip = env!("SNOWFLAKE_GZIP");
+                let tar = concat!(env!("SNOWFLAKE_GNUTAR"), "/bin/tar");
+                RunCommand{
+                    inputs: vec![
+                        Basename::new(cstring!(b"archive.tar.gz")).unwrap(),
+                    ],
+                    outputs: Outputs::Outputs(vec![
+                        Basename::new(cstring!(b"output")).unwrap(),
+                    ]),
+                    optional_outputs: vec![],
+                    output_groups: vec![],
+                    program: CString::new(tar).unwrap(),
+                    arguments: vec![
+                        cstring!(b"tar"),
+                        cstring!(b"--extract"),
+                        cstring!(b"--gzip"),
+                        cstring!(b"--file=archive.tar.gz"),
+                        cstring!(b"--one-top-level=output"),
+                        cstring!(b"--no-same-owner"),
+                        cstring!(b"--no-same-permissions"),
+                    ],
+                    environment: vec![
+                        CString::new(format!("PATH={gzip}/bin")).unwrap(),
+                    ],
+                    passthrough: vec![],
+                    timeout: self.timeout,
+                    warnings: None,
+                    depfile: None,
+                    log_paths: None,
+                    resources: Resources::default(),
+                    retry_policy: RetryPolicy::default(),
+                    network: false,
+                    writable_inputs: false,
+                    allowed_syscalls: vec![],
+                }
+            },
+            ArchiveFormat::Zip => {
+                let unzip = concat!(env!("SNOWFLAKE_UNZIP"), "/bin/unzip");
+                RunCommand{
+                    inputs: vec![
+                        Basename::new(cstring!(b"archive.zip")).unwrap(),
+                    ],
+                    outputs: Outputs::Outputs(vec![
+                        Basename::new(cstring!(b"output")).unwrap(),
+                    ]),
+                    optional_outputs: vec![],
+                    output_groups: vec![],
+                    program: CString::new(unzip).unwrap(),
+                    arguments: vec![
+                        cstring!(b"unzip"),
+                        cstring!(b"-q"),
+                        cstring!(b"archive.zip"),
+                        cstring!(b"-d"),
+                        cstring!(b"output"),
+                    ],
+                    environment: vec![],
+                    passthrough: vec![],
+                    timeout: self.timeout,
+                    warnings: None,
+                    depfile: None,
+                    log_paths: None,
+                    resources: Resources::default(),
+                    retry_policy: RetryPolicy::default(),
+                    network: false,
+                    writable_inputs: false,
+                    allowed_syscalls: vec![],
+                }
+            },
+        };
+
+        let success =
+            perform_run_command(perform, &command, input_paths)?;
+
+        normalize_file_at(Some(perform.scratch), &success.output_paths[0])
+            .context("Normalize extracted files")?;
+
+        Ok(success)
+    }
+
+    fn hash(&self, input_hashes: &[Hash]) -> Hash
+    {
+        // NOTE: See the manual chapter on avoiding hash collisions.
+
+        const FORMAT_TAR_GZ: u8 = 0;
+        const FORMAT_ZIP:    u8 = 1;
+
+        let Self{format, timeout} = self;
+
+        debug_assert_eq!(input_hashes.len(), 1);
+
+        let mut h = Blake3::new();
+        h.put_str("ExtractArchive");
+
+        match format {
+            ArchiveFormat::TarGz => h.put_u8(FORMAT_TAR_GZ),
+            ArchiveFormat::Zip   => h.put_u8(FORMAT_ZIP),
+        };
+
+        h.put_hash(input_hashes[0]);
+
+        // The timeout cannot affect the output of the action,
+        // so there is no need to include it in the hash.
+        let _ = timeout;
+
+        h.finalize()
+    }
+}
+
+/// Normalize the metadata of an extracted file, recursively.
+///
+/// Regular files get permissions 755 or 644 depending on
+/// whether they were executable, and directories get permissions 755.
+/// Hard links are broken up, and modification times are set to the epoch.
+/// Directory entries are visited in sorted order,
+/// so that any errors are reported deterministically.
+fn normalize_file_at(dirfd: Option<BorrowedFd>, path: &CStr)
+    -> anyhow::Result<()>
+{
+    let statbuf = fstatat(dirfd, path, AT_SYMLINK_NOFOLLOW)
+        .with_context(|| format!("Find file type of {path:?}"))?;
+
+    match statbuf.st_mode & S_IFMT {
+        S_IFREG => {
+            let executable = statbuf.st_mode & S_IXUSR != 0;
+            let mode = if executable { 0o755 } else { 0o644 };
+            fchmodat(dirfd, path, mode, 0)
+                .with_context(|| format!("Change mode of {path:?}"))?;
+            if statbuf.st_nlink != 1 {
+                break_hard_link(dirfd, path, mode)
+                    .with_context(|| format!("Break hard link {path:?}"))?;
+            }
+        },
+        S_IFDIR => {
+            // The directory must be accessible before it can be read.
+            fchmodat(dirfd, path, 0o755, 0)
+                .with_context(|| format!("Change mode of {path:?}"))?;
+            let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+            let dir = openat(dirfd, path, flags, 0)
+                .with_context(|| format!("Open directory {path:?}"))?;
+            let entries = read_entries(dir.as_fd())
+                .with_context(|| format!("Read directory {path:?}"))?;
+            for entry in entries {
+                normalize_file_at(Some(dir.as_fd()), &entry)?;
+            }
+        },
+        S_IFLNK => {
+            // Symbolic links have no permissions.
+        },
+        _ =>
+            bail!("Archive contains {path:?}, which is \
+                   not a regular file, directory, or symbolic link"),
+    }
+
+    // Must come last, because modifying the entries
+    // of a directory updates its modification time.
+    let epoch = timespec{tv_sec: 0, tv_nsec: 0};
+    utimensat(dirfd, path, &[epoch, epoch], AT_SYMLINK_NOFOLLOW)
+        .with_context(|| format!("Reset modification time of {path:?}"))?;
+
+    Ok(())
+}
+
+/// Replace a hard link to a regular file by a copy of the file.
+fn break_hard_link(
+    dirfd: Option<BorrowedFd>,
+    path: &CStr,
+    mode: libc::mode_t,
+) -> anyhow::Result<()>
+{
+    let source = openat(dirfd, path, O_NOFOLLOW | O_RDONLY, 0)
+        .context("Open hard link")?;
+    unlinkat(dirfd, path, 0)
+        .context("Remove hard link")?;
+    let flags = O_CREAT | O_EXCL | O_NOFOLLOW | O_WRONLY;
+    let target = openat(dirfd, path, flags, mode)
+        .context("Create copy of hard link")?;
+    reflink_or_copy(source.as_fd(), target.as_fd())
+        .context("Copy contents of hard link")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        os_ext::{
+            S_IFIFO,
+            cstr, cstring, linkat, mkdirat, mkdtemp, mknodat, symlinkat,
+        },
+    };
+
+    #[test]
+    fn normalize()
+    {
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dirfd = openat(None, &path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(dirfd.as_fd());
+
+        // Create a directory tree with all sorts of unusual metadata.
+        mkdirat(dirfd, cstr!(b"output"),                         0o700   ).unwrap();
+        mknodat(dirfd, cstr!(b"output/regular"),     S_IFREG | 0o600, 0).unwrap();
+        mknodat(dirfd, cstr!(b"output/executable"),  S_IFREG | 0o700, 0).unwrap();
+        mkdirat(dirfd, cstr!(b"output/directory"),               0o500   ).unwrap();
+        symlinkat(cstr!(b"regular"), dirfd, cstr!(b"output/symlink")).unwrap();
+        linkat(dirfd, cstr!(b"output/regular"),
+               dirfd, cstr!(b"output/hardlink"), 0).unwrap();
+
+        normalize_file_at(dirfd, cstr!(b"output")).unwrap();
+
+        // Check that all metadata was normalized.
+        let check = |path, mode| {
+            let statbuf = fstatat(dirfd, path, AT_SYMLINK_NOFOLLOW).unwrap();
+            assert_eq!(statbuf.st_mode & !S_IFMT, mode, "{path:?}");
+            assert_eq!(statbuf.st_mtime, 0, "{path:?}");
+            if statbuf.st_mode & S_IFMT != S_IFDIR {
+                assert_eq!(statbuf.st_nlink, 1, "{path:?}");
+            }
+        };
+        check(cstr!(b"output"),            0o755);
+        check(cstr!(b"output/regular"),    0o644);
+        check(cstr!(b"output/executable"), 0o755);
+        check(cstr!(b"output/directory"),  0o755);
+        check(cstr!(b"output/symlink"),    0o777);
+        check(cstr!(b"output/hardlink"),   0o644);
+
+        // Files of unsupported types must be rejected.
+        mknodat(dirfd, cstr!(b"output/fifo"), S_IFIFO | 0o644, 0).unwrap();
+        normalize_file_at(dirfd, cstr!(b"output")).unwrap_err();
+    }
+}
@@ -12,8 +12,21 @@
 #![feature(type_ascription)]
 #![warn(missing_docs)]
 
-pub use self::{create_symbolic_link::*, run_command::*, write_regular_file::*};
+pub use self::{
+    create_symbolic_link::*,
+    download_file::*,
+    encoding::decode_action,
+    extract_archive::*,
+    run_command::*,
+    run_test::*,
+    write_regular_file::*,
+};
 
 mod create_symbolic_link;
+mod depfile;
+mod download_file;
+mod encoding;
+mod extract_archive;
 mod run_command;
+mod run_test;
 mod write_regular_file;
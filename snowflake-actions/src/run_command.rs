@@ -1,34 +1,55 @@
 use {
-    anyhow::Context,
+    crate::{
+        depfile::parse_depfile,
+        encoding::{
+            encode_action, get_duration, get_resources, get_retry_policy,
+            put_duration, put_resources, put_retry_policy,
+        },
+    },
+    anyhow::{Context, bail},
     os_ext::{
-        AT_SYMLINK_NOFOLLOW,
+        AUDIT_ARCH_NATIVE,
+        BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+        O_NOFOLLOW, O_PATH, O_RDONLY,
+        RESOLVE_BENEATH,
+        SECCOMP_DATA_ARCH, SECCOMP_DATA_NR,
+        SECCOMP_RET_ALLOW, SECCOMP_RET_ERRNO, SECCOMP_RET_KILL_PROCESS,
         S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
-        cstr, cstr_cow, fstatat, getgid, getuid, mkdirat,
-        mknodat, pipe2, readlink, readlinkat, symlinkat,
+        cstr, cstr_cow, cstring, fstat, getgid, getuid, mkdirat,
+        mknodat, open_how, openat, openat2, pidfd_send_signal, pipe2,
+        prctl_set_no_new_privs, prctl_set_pdeathsig, readlink, readlinkat,
+        seccomp_set_mode_filter, sigemptyset, sock_filter, symlinkat,
         cstr::CStrExt,
         io::{BorrowedFdExt, magic_link},
     },
-    regex::bytes::Regex,
+    regex::bytes::{Captures, Regex},
     scope_exit::ScopeExit,
-    snowflake_core::action::{
-        Action, Error, InputPath, Outputs, Perform, Success,
-        Result as AResult,
+    snowflake_core::{
+        action::{
+            Action, Dependency, EncodedAction, Error, InputPath, OutputGroup,
+            Outputs, Perform, Resources, RetryPolicy, Success,
+            Result as AResult,
+        },
+        executor::{ReadExt, WriteExt},
     },
     snowflake_util::{basename::Basename, hash::{Blake3, Hash}},
     std::{
         borrow::Cow,
-        ffi::{CStr, CString},
+        env,
+        ffi::{CStr, CString, OsStr},
         fs::File,
-        io::{self, BufRead, BufReader, Read, Seek},
+        io::{self, BufRead, BufReader, Read, Seek, Write},
         mem::{forget, size_of_val, zeroed},
         os::unix::{
-            io::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+            ffi::OsStrExt,
+            io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
             process::ExitStatusExt,
         },
         panic::always_abort,
         process::ExitStatus,
         ptr::{addr_of, addr_of_mut, null, null_mut},
-        time::Duration,
+        thread,
+        time::{Duration, Instant},
     },
 };
 
@@ -41,6 +62,16 @@ pub struct RunCommand
     /// What the outputs are called in the command's working directory.
     pub outputs: Outputs<Vec<Basename<CString>>>,
 
+    /// Indices of the outputs that the program may not create.
+    ///
+    /// See [`Action::optional_outputs`].
+    pub optional_outputs: Vec<usize>,
+
+    /// Named groups of outputs.
+    ///
+    /// See [`Action::output_groups`].
+    pub output_groups: Vec<OutputGroup>,
+
     /// Absolute path to the program to run.
     pub program: CString,
 
@@ -52,11 +83,23 @@ pub struct RunCommand
 
     /// The environment variables to the program.
     ///
-    /// This specifies the *exact* environment to the program.
+    /// This specifies the *exact* environment to the program,
+    /// together with [`passthrough`][`Self::passthrough`].
     /// No extra environment variables are set by the
     /// [`perform`][`RunCommand::perform`] method.
     pub environment: Vec<CString>,
 
+    /// Names of environment variables passed through from Snowflake.
+    ///
+    /// Each of these variables that is set in the environment of Snowflake
+    /// is also set in the environment of the program, with the same value.
+    /// The values are part of the action hash, so changing them
+    /// causes the action to be performed again.
+    /// These variables must not also be set in [`environment`].
+    ///
+    /// [`environment`]: `Self::environment`
+    pub passthrough: Vec<CString>,
+
     /// How much time the program may spend.
     ///
     /// If the program spends more time than this,
@@ -67,8 +110,188 @@ pub struct RunCommand
     ///
     /// If [`None`], no warnings are assumed to have been emitted.
     pub warnings: Option<Regex>,
+
+    /// Depfile in which the program lists the files it used.
+    ///
+    /// If [`None`], the program is assumed to use all of every input.
+    pub depfile: Option<Depfile>,
+
+    /// How to rewrite paths to inputs in the build log.
+    ///
+    /// If [`None`], the build log is left as written by the program.
+    pub log_paths: Option<LogPaths>,
+
+    /// The resources the program needs while it is running.
+    ///
+    /// These are used by the driver to avoid running
+    /// too many heavy programs at once; see [`Action::resources`].
+    pub resources: Resources,
+
+    /// When to run the program again after it failed.
+    ///
+    /// See [`Action::retry_policy`].
+    pub retry_policy: RetryPolicy,
+
+    /// Whether the program has access to the network.
+    ///
+    /// If not set, the program runs in its own network namespace,
+    /// in which only the loopback interface exists.
+    /// Access to the network makes the output of the program depend on
+    /// things other than its inputs, so this should be reserved for
+    /// programs whose output is verified after the fact,
+    /// such as [`DownloadFile`][`crate::DownloadFile`].
+    pub network: bool,
+
+    /// Whether the program may modify directory inputs.
+    ///
+    /// If set, each directory input is mounted with overlayfs,
+    /// with the input as the lower layer and a directory in the
+    /// scratch directory as the upper layer. The program then sees
+    /// a writable tree, but its changes stay in the scratch directory
+    /// and never reach the input. If not set, inputs are read-only.
+    /// Regular file inputs are read-only either way.
+    pub writable_inputs: bool,
+
+    /// Names of system calls in [`BLOCKED_SYSCALLS`]
+    /// that the program may nevertheless make.
+    ///
+    /// The program runs under a seccomp filter that makes
+    /// the blocked system calls fail with `EPERM`.
+    /// Programs that need some of them, such as debuggers
+    /// that need `ptrace`, can be allowed to make them here.
+    pub allowed_syscalls: Vec<String>,
+}
+
+/// System calls that programs run by [`RunCommand`] may not make,
+/// unless allowed by [`RunCommand::allowed_syscalls`].
+///
+/// None of these are needed to build software,
+/// and all of them interfere with the container or the host.
+pub const BLOCKED_SYSCALLS: &[(&str, libc::c_long)] = &[
+    ("delete_module", libc::SYS_delete_module),
+    ("finit_module",  libc::SYS_finit_module),
+    ("init_module",   libc::SYS_init_module),
+    ("kexec_load",    libc::SYS_kexec_load),
+    ("mount",         libc::SYS_mount),
+    ("pivot_root",    libc::SYS_pivot_root),
+    ("ptrace",        libc::SYS_ptrace),
+    ("reboot",        libc::SYS_reboot),
+    ("swapoff",       libc::SYS_swapoff),
+    ("swapon",        libc::SYS_swapon),
+    ("umount2",       libc::SYS_umount2),
+];
+
+/// Makefile-style file in which a program lists the files it used,
+/// such as those written by `gcc -MD`.
+pub struct Depfile
+{
+    /// What the depfile is called in the command's working directory.
+    ///
+    /// The program must write the depfile if it terminates successfully.
+    pub path: Basename<CString>,
+
+    /// Indices of the inputs of which the program only uses some files.
+    ///
+    /// Files within these inputs are only dependencies of the action
+    /// if they are listed in the depfile; see [`Action::partial_inputs`].
+    /// All files within the other inputs are always dependencies.
+    pub inputs: Vec<usize>,
+}
+
+/// How to rewrite paths to inputs in the build log.
+///
+/// The program refers to inputs by the names they have in its
+/// working directory, which usually differ from their paths
+/// in the workspace. Rewriting them in the build log makes
+/// diagnostics refer to files the user can actually open.
+pub struct LogPaths
+{
+    /// Regular expression that matches paths in the build log.
+    ///
+    /// Each match that starts with the name of an input,
+    /// optionally preceded by `/build/`, has that prefix replaced
+    /// by the workspace path of the input.
+    pub regex: Regex,
+
+    /// The workspace path of each input.
+    ///
+    /// Paths to inputs whose workspace path is [`None`],
+    /// such as outputs of other actions, are left alone.
+    pub workspace_paths: Vec<Option<CString>>,
+}
+
+impl RunCommand
+{
+    /// Decode an action encoded with [`Action::encode`].
+    ///
+    /// The decoded action passes through no environment variables;
+    /// their values are already in [`environment`][`Self::environment`].
+    pub fn decode(data: &mut &[u8]) -> anyhow::Result<Self>
+    {
+        let basename = |r: &mut &[u8]| -> anyhow::Result<_> {
+            Ok(Basename::new(r.get_cstring()?)?)
+        };
+        let cstring = |r: &mut &[u8]| r.get_cstring();
+        let usize = |r: &mut &[u8]| r.get_usize();
+        let regex = |r: &mut &[u8]| -> anyhow::Result<_> {
+            Ok(Regex::new(&r.get_string()?)?)
+        };
+
+        let inputs = data.get_vec(basename)?;
+
+        let outputs = match data.get_u8()? {
+            OUTPUTS_TYPE_OUTPUTS => Outputs::Outputs(data.get_vec(basename)?),
+            OUTPUTS_TYPE_LINT    => Outputs::Lint,
+            _ => bail!("Invalid type of outputs"),
+        };
+        let optional_outputs = data.get_vec(usize)?;
+        let output_groups = data.get_vec(|r| -> io::Result<_> {
+            Ok(OutputGroup{name: r.get_string()?, outputs: r.get_vec(usize)?})
+        })?;
+
+        let program = data.get_cstring()?;
+        let arguments = data.get_vec(cstring)?;
+        let environment = data.get_vec(cstring)?;
+        let timeout = get_duration(data)?;
+
+        let warnings =
+            if data.get_bool()? { Some(regex(data)?) } else { None };
+
+        let depfile = if data.get_bool()? {
+            let path = basename(data)?;
+            let inputs = data.get_vec(usize)?;
+            Some(Depfile{path, inputs})
+        } else {
+            None
+        };
+
+        let log_paths = if data.get_bool()? {
+            let regex = regex(data)?;
+            let workspace_paths = data.get_vec(|r| -> io::Result<_> {
+                Ok(if r.get_bool()? { Some(r.get_cstring()?) } else { None })
+            })?;
+            Some(LogPaths{regex, workspace_paths})
+        } else {
+            None
+        };
+
+        let resources = get_resources(data)?;
+        let retry_policy = get_retry_policy(data)?;
+        let network = data.get_bool()?;
+        let writable_inputs = data.get_bool()?;
+        let allowed_syscalls = data.get_vec(|r| r.get_string())?;
+
+        Ok(Self{inputs, outputs, optional_outputs, output_groups, program,
+                arguments, environment, passthrough: vec![], timeout,
+                warnings, depfile, log_paths, resources, retry_policy,
+                network, writable_inputs, allowed_syscalls})
+    }
 }
 
+// Byte which indicates the type of outputs.
+const OUTPUTS_TYPE_OUTPUTS: u8 = 0;
+const OUTPUTS_TYPE_LINT:    u8 = 1;
+
 impl Action for RunCommand
 {
     fn inputs(&self) -> usize
@@ -81,6 +304,14 @@ impl Action for RunCommand
         self.outputs.as_ref().map(Vec::len)
     }
 
+    fn output_names(&self) -> Option<Vec<Basename<CString>>>
+    {
+        match &self.outputs {
+            Outputs::Outputs(outputs) => Some(outputs.clone()),
+            Outputs::Lint => None,
+        }
+    }
+
     fn perform(&self, perform: &Perform, input_paths: &[InputPath]) -> AResult
     {
         perform_run_command(perform, self, input_paths)
@@ -90,11 +321,10 @@ impl Action for RunCommand
     {
         // NOTE: See the manual chapter on avoiding hash collisions.
 
-        const OUTPUTS_TYPE_OUTPUTS: u8 = 0;
-        const OUTPUTS_TYPE_LINT:    u8 = 1;
-
-        let Self{inputs, outputs, program, arguments,
-                 environment, timeout, warnings} = self;
+        let Self{inputs, outputs, optional_outputs, output_groups, program,
+                 arguments, environment, passthrough, timeout, warnings,
+                 depfile, log_paths, resources, retry_policy, network,
+                 writable_inputs, allowed_syscalls} = self;
 
         debug_assert_eq!(input_hashes.len(), inputs.len());
 
@@ -117,34 +347,200 @@ impl Action for RunCommand
                 h.put_u8(OUTPUTS_TYPE_LINT);
             },
         }
+        h.put_slice(optional_outputs, |h, &o| h.put_usize(o));
 
         h.put_cstr(program);
         h.put_slice(arguments, |h, a| h.put_cstr(a));
-        h.put_slice(environment, |h, e| h.put_cstr(e));
+        let environment = effective_environment(environment, passthrough);
+        h.put_slice(&environment, |h, e| h.put_cstr(e));
 
-        // The timeout cannot affect the output of the action,
-        // so there is no need to include it in the hash.
-        let _ = timeout;
+        // The output groups, timeout, resources, and retry policy
+        // cannot affect the output of the action, so there is no need
+        // to include them in the hash.
+        let _ = (output_groups, timeout, resources, retry_policy);
 
         h.put_bool(warnings.is_some());
         if let Some(warnings) = warnings {
             h.put_str(warnings.as_str());
         }
 
+        h.put_bool(depfile.is_some());
+        if let Some(Depfile{path, inputs}) = depfile {
+            h.put_cstr(path);
+            h.put_slice(inputs, |h, i| h.put_usize(*i));
+        }
+
+        h.put_bool(*network);
+        h.put_bool(*writable_inputs);
+        h.put_slice(allowed_syscalls, |h, s| h.put_str(s));
+
+        h.put_bool(log_paths.is_some());
+        if let Some(LogPaths{regex, workspace_paths}) = log_paths {
+            h.put_str(regex.as_str());
+            h.put_slice(workspace_paths, |h, p| {
+                h.put_bool(p.is_some());
+                if let Some(p) = p { h.put_cstr(p); }
+                h
+            });
+        }
+
         h.finalize()
     }
+
+    fn encode(&self) -> Option<EncodedAction>
+    {
+        let Self{inputs, outputs, optional_outputs, output_groups, program,
+                 arguments, environment, passthrough, timeout, warnings,
+                 depfile, log_paths, resources, retry_policy, network,
+                 writable_inputs, allowed_syscalls} = self;
+
+        Some(encode_action("RunCommand", |w| {
+            w.put_slice(inputs, |w, i| w.put_cstr(i))?;
+
+            match outputs {
+                Outputs::Outputs(outputs) => {
+                    w.put_u8(OUTPUTS_TYPE_OUTPUTS)?;
+                    w.put_slice(outputs, |w, o| w.put_cstr(o))?;
+                },
+                Outputs::Lint => {
+                    w.put_u8(OUTPUTS_TYPE_LINT)?;
+                },
+            }
+            w.put_slice(optional_outputs, |w, &o| w.put_usize(o))?;
+            w.put_slice(output_groups, |w, g| {
+                w.put_str(&g.name)?;
+                w.put_slice(&g.outputs, |w, &o| w.put_usize(o))
+            })?;
+
+            // The build server has a different environment,
+            // so the passed through variables are sent along.
+            w.put_cstr(program)?;
+            w.put_slice(arguments, |w, a| w.put_cstr(a))?;
+            let environment = effective_environment(environment, passthrough);
+            w.put_slice(&environment, |w, e| w.put_cstr(e))?;
+            put_duration(w, *timeout)?;
+
+            w.put_bool(warnings.is_some())?;
+            if let Some(warnings) = warnings {
+                w.put_str(warnings.as_str())?;
+            }
+
+            w.put_bool(depfile.is_some())?;
+            if let Some(Depfile{path, inputs}) = depfile {
+                w.put_cstr(path)?;
+                w.put_slice(inputs, |w, &i| w.put_usize(i))?;
+            }
+
+            w.put_bool(log_paths.is_some())?;
+            if let Some(LogPaths{regex, workspace_paths}) = log_paths {
+                w.put_str(regex.as_str())?;
+                w.put_slice(workspace_paths, |w, p| {
+                    w.put_bool(p.is_some())?;
+                    if let Some(p) = p { w.put_cstr(p)?; }
+                    Ok(())
+                })?;
+            }
+
+            put_resources(w, resources)?;
+            put_retry_policy(w, retry_policy)?;
+            w.put_bool(*network)?;
+            w.put_bool(*writable_inputs)?;
+            w.put_slice(allowed_syscalls, |w, s| w.put_str(s))
+        }))
+    }
+
+    fn partial_inputs(&self) -> Vec<usize>
+    {
+        self.depfile.as_ref()
+            .map(|depfile| depfile.inputs.clone())
+            .unwrap_or_default()
+    }
+
+    fn optional_outputs(&self) -> Vec<usize>
+    {
+        self.optional_outputs.clone()
+    }
+
+    fn output_groups(&self) -> Vec<OutputGroup>
+    {
+        self.output_groups.clone()
+    }
+
+    fn resources(&self) -> Resources
+    {
+        self.resources
+    }
+
+    fn retry_policy(&self) -> RetryPolicy
+    {
+        self.retry_policy.clone()
+    }
+
+    fn report_files(&self) -> Vec<(&'static str, Vec<u8>)>
+    {
+        // Same format as the files of the same names in /proc/<pid>.
+        let nul_terminated = |strings: &[CString]| {
+            strings.iter()
+                .flat_map(|string| string.as_bytes_with_nul())
+                .copied()
+                .collect()
+        };
+        let environment =
+            effective_environment(&self.environment, &self.passthrough);
+        vec![
+            ("program", self.program.as_bytes().to_vec()),
+            ("cmdline", nul_terminated(&self.arguments)),
+            ("environ", nul_terminated(&environment)),
+        ]
+    }
+
+    fn command_elements(&self) -> Vec<(String, String)>
+    {
+        let lossy = |string: &CStr|
+            String::from_utf8_lossy(string.to_bytes()).into_owned();
+
+        let program = ("program".to_owned(), lossy(&self.program));
+
+        let arguments = self.arguments.iter().enumerate()
+            .map(|(i, argument)| (format!("argument {i}"), lossy(argument)));
+
+        // Environment variables are named by their names rather than
+        // their positions, so that adding one does not shift the others.
+        let environment =
+            effective_environment(&self.environment, &self.passthrough);
+        let environment = environment.into_iter().map(|variable| {
+            let variable = lossy(&variable);
+            match variable.split_once('=') {
+                Some((name, value)) =>
+                    (format!("environment {name}"), value.to_owned()),
+                None =>
+                    (format!("environment {variable}"), String::new()),
+            }
+        });
+
+        [program].into_iter().chain(arguments).chain(environment).collect()
+    }
+
+    fn replay(&self, scratch: BorrowedFd, input_paths: &[InputPath])
+        -> Result<(), Error>
+    {
+        debug_assert_eq!(input_paths.len(), self.inputs.len());
+        replay_run_command(scratch, self, input_paths)
+    }
 }
 
-fn perform_run_command(
+/// Perform a run command action.
+pub (crate) fn perform_run_command(
     perform: &Perform,
     action: &RunCommand,
     input_paths: &[InputPath],
 ) -> AResult
 {
     // Unpack the arguments into convenient variables.
-    let Perform{build_log, scratch} = perform;
-    let RunCommand{inputs, outputs, program, arguments,
-                   environment, timeout, warnings} = action;
+    let Perform{build_log, scratch, cancel, stream_log} = perform;
+    let RunCommand{inputs, outputs, program, arguments, environment,
+                   passthrough, timeout, warnings, depfile, log_paths,
+                   network, allowed_syscalls, ..} = action;
 
     // Mounting must happen in the child process,
     // so we collect all the mount calls in here.
@@ -152,22 +548,86 @@ fn perform_run_command(
     let mut mounts = Vec::new();
 
     // Perform the run command action.
-    let scratch_path = resolve_magic(*scratch)                                  .with_context(|| "Find path to scratch directory")?;
-    populate_root_directory(*scratch)?;
-    populate_dev_directory(*scratch, &mut mounts)?;
-    install_blessed_programs(*scratch)?;
-    repair_root_mount(&mut mounts);
-    mount_proc(&mut mounts);
-    mount_nix_store(&mut mounts);
-    mount_inputs(*scratch, inputs, input_paths, &mut mounts)?;
-    run_command(*build_log, &scratch_path, program,
-                arguments, environment, *timeout,
-                mounts)?;
+    let scratch_path =
+        prepare_container(*scratch, action, input_paths, &mut mounts)?;
+    let environment = effective_environment(environment, passthrough);
+    let seccomp_filter = seccomp_filter(allowed_syscalls)?;
+    run_command(Some(*build_log), &scratch_path, program,
+                arguments, &environment, Some(*timeout),
+                *network, *cancel, *stream_log, &seccomp_filter, mounts)?;
     let output_paths = output_paths(outputs);
+    if let Some(log_paths) = log_paths {
+        rewrite_build_log(*build_log, inputs, log_paths)?;
+    }
     let warnings = find_warnings(*build_log, warnings.as_ref())?;
+    let dependencies = match depfile {
+        Some(depfile) => read_depfile(*scratch, inputs, depfile)?,
+        None => Vec::new(),
+    };
 
     // Summarize the result.
-    Ok(Success{output_paths, warnings})
+    Ok(Success{output_paths, warnings, dependencies})
+}
+
+/// Start an interactive shell in the container of a run command action.
+///
+/// The shell is `/bin/sh` in the container, started in `/build`
+/// with the environment of the command. Its positional parameters
+/// are the program and the arguments of the command,
+/// so the command can be run from the shell with `"$@"`.
+/// The shell inherits the standard streams of Snowflake
+/// and is not subject to the timeout of the command.
+pub (crate) fn replay_run_command(
+    scratch: BorrowedFd,
+    action: &RunCommand,
+    input_paths: &[InputPath],
+) -> Result<(), Error>
+{
+    let RunCommand{program, arguments, environment, passthrough,
+                   network, allowed_syscalls, ..} = action;
+
+    let mut mounts = Vec::new();
+    let scratch_path =
+        prepare_container(scratch, action, input_paths, &mut mounts)?;
+    let environment = effective_environment(environment, passthrough);
+    let seccomp_filter = seccomp_filter(allowed_syscalls)?;
+
+    // "$@" cannot pass a zeroth argument, so the program takes its place.
+    let mut shell_arguments = vec![
+        cstring!(b"sh"), cstring!(b"-i"), cstring!(b"-s"), cstring!(b"--"),
+        program.clone(),
+    ];
+    shell_arguments.extend(arguments.iter().skip(1).cloned());
+
+    run_command(None, &scratch_path, cstr!(b"/bin/sh"),
+                &shell_arguments, &environment, None,
+                *network, None, None, &seccomp_filter, mounts)
+}
+
+/// Set up the container in the scratch directory.
+///
+/// The mounts are collected into `mounts`, to be applied by the child.
+/// Returns the path to the scratch directory.
+fn prepare_container(
+    scratch: BorrowedFd,
+    action: &RunCommand,
+    input_paths: &[InputPath],
+    mounts: &mut Vec<Mount>,
+) -> Result<CString, Error>
+{
+    let scratch_path = resolve_magic(scratch)                                   .with_context(|| "Find path to scratch directory")?;
+    populate_root_directory(scratch)?;
+    populate_dev_directory(scratch, mounts)?;
+    install_blessed_programs(scratch)?;
+    repair_root_mount(mounts);
+    mount_proc(mounts);
+    mount_nix_store(mounts);
+    if action.network {
+        mount_network_files(scratch, mounts)?;
+    }
+    mount_inputs(scratch, &action.inputs, input_paths,
+                 action.writable_inputs, mounts)?;
+    Ok(scratch_path)
 }
 
 /// Arguments to mount.
@@ -179,6 +639,29 @@ struct Mount<'a>
     filesystemtype: Cow<'a, CStr>,
     mountflags: libc::c_ulong,
     data: Cow<'a, CStr>,
+
+    /// File to which `source` or `data` refers by magic link, if any.
+    source_file: Option<MountSource>,
+}
+
+/// Input file that is mounted through its magic link.
+///
+/// A file descriptor opened outside the mount namespace of the container
+/// cannot be mounted from inside it, so the child process opens the file
+/// again by its path, and checks that it finds the same file.
+/// It then places the new file descriptor at the number of the old one,
+/// so that the magic link of the mount refers to the checked file.
+struct MountSource
+{
+    /// The file as it was opened and inspected.
+    fd: OwnedFd,
+
+    /// Absolute path to the file, at the time it was opened.
+    path: CString,
+
+    /// Device and inode number of the file.
+    dev: libc::dev_t,
+    ino: libc::ino_t,
 }
 
 impl<'a> Mount<'a>
@@ -206,6 +689,44 @@ impl<'a> Mount<'a>
             },
         ]
     }
+
+    /// Create an overlay mount with a single lower layer.
+    ///
+    /// Backslashes, commas, and colons in the paths are escaped,
+    /// as overlayfs would otherwise interpret them as separators.
+    pub fn overlay(
+        lowerdir: &CStr,
+        upperdir: &CStr,
+        workdir:  &CStr,
+        target:   Cow<'a, CStr>,
+    ) -> Self
+    {
+        let mut data = Vec::new();
+        let options = [
+            (&b"lowerdir="[..], lowerdir),
+            (b",upperdir=", upperdir),
+            (b",workdir=", workdir),
+        ];
+        for (option, path) in options {
+            data.extend_from_slice(option);
+            for &byte in path.to_bytes() {
+                if matches!(byte, b'\\' | b',' | b':') {
+                    data.push(b'\\');
+                }
+                data.push(byte);
+            }
+        }
+        // Required for mounting overlayfs inside a user namespace.
+        data.extend_from_slice(b",userxattr");
+
+        Self{
+            source: cstr_cow!(b"overlay"),
+            target,
+            filesystemtype: cstr_cow!(b"overlay"),
+            data: CString::new(data).unwrap().into(),
+            ..Mount::default()
+        }
+    }
 }
 
 /// Populate the container's `/` directory.
@@ -279,6 +800,25 @@ fn install_blessed_programs(scratch: BorrowedFd) -> Result<(), Error>
     Ok(())
 }
 
+/// Compute the environment of the program.
+///
+/// The static environment variables come first,
+/// followed by those passed through that are set.
+fn effective_environment(environment: &[CString], passthrough: &[CString])
+    -> Vec<CString>
+{
+    let passed = passthrough.iter().filter_map(|name| {
+        let name = OsStr::from_bytes(name.to_bytes());
+        let value = env::var_os(name)?;
+        let mut variable = name.as_bytes().to_vec();
+        variable.push(b'=');
+        variable.extend_from_slice(value.as_bytes());
+        // Environment variables cannot contain nul bytes.
+        Some(CString::new(variable).unwrap())
+    });
+    environment.iter().cloned().chain(passed).collect()
+}
+
 /// Prevent mount events from propagating out of the container.
 ///
 /// `/` is usually mounted with `MS_SHARED`, but we want `MS_PRIVATE`.
@@ -316,18 +856,48 @@ fn mount_nix_store(mounts: &mut Vec<Mount>)
     mounts.extend(mountz);
 }
 
+/// Mount the files needed for name resolution in the container's `/etc`.
+///
+/// This is only needed when the container has access to the network.
+fn mount_network_files(scratch: BorrowedFd, mounts: &mut Vec<Mount>)
+    -> Result<(), Error>
+{
+    mkdirat(Some(scratch), cstr!(b"etc"), 0o755)                                .with_context(|| "Create \"etc\" inside container")?;
+
+    let network_files = [
+        cstr!(b"hosts"),
+        cstr!(b"resolv.conf"),
+    ];
+    for basename in network_files {
+        let source = cstr!(b"/etc").join(basename);
+        let target = cstr!(b"etc").join(basename);
+
+        mknodat(Some(scratch), &target, S_IFREG | 0o644, 0)                     .with_context(|| format!("Create {target:?} inside container"))?;
+
+        let mount = Mount::rdonly_bind_mount(source.into(), target.into());
+        mounts.extend(mount);
+    }
+
+    Ok(())
+}
+
 /// Mount every input in the container's `/build` directory.
 fn mount_inputs(
     scratch: BorrowedFd,
     inputs: &[Basename<CString>],
     input_paths: &[InputPath],
+    writable: bool,
     mounts: &mut Vec<Mount>,
 ) -> Result<(), Error>
 {
     debug_assert_eq!(input_paths.len(), inputs.len());
 
+    if writable {
+        mkdirat(Some(scratch), cstr!(b"overlay"), 0o755)                        .with_context(|| "Create overlay directory")?;
+    }
+
     for (input_basename, input_path) in inputs.iter().zip(input_paths) {
-        mount_input(scratch, input_basename, input_path, mounts)
+        mount_input(scratch, input_basename, input_path, writable, mounts)
             .with_context(|| format!("Mount input at {input_basename:?}"))?;
     }
 
@@ -335,42 +905,95 @@ fn mount_inputs(
 }
 
 /// Mount an input in the container's `/build` directory.
+///
+/// If `writable` is set, directory inputs are mounted with overlayfs,
+/// with their upper and work directories in the scratch directory.
 fn mount_input(
     scratch: BorrowedFd,
     input_basename: &Basename<CString>,
     input_path: &InputPath,
+    writable: bool,
     mounts: &mut Vec<Mount>,
 ) -> anyhow::Result<()>
 {
-    // Make the input path absolute so it can be mounted.
-    // There is unfortunately no "mountat" system call.
-    let input_path_dir =
-        resolve_magic(input_path.dirfd)
-        .with_context(|| "Find path to input path dir")?;
-    let input_path = input_path_dir.join(&input_path.path);
+    // Open the input without leaving the directory it is relative to,
+    // so that symbolic links and .. components in the input path
+    // cannot make the command see files outside its declared inputs.
+    // The input itself is not followed if it is a symbolic link.
+    let how = open_how{
+        flags: (O_NOFOLLOW | O_PATH) as u64,
+        resolve: RESOLVE_BENEATH,
+        ..open_how::default()
+    };
+    let input = match openat2(Some(input_path.dirfd), &input_path.path, &how) {
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) =>
+            return Err(err)                                                     .with_context(|| "Input path escapes its directory"),
+        result => result                                                        .with_context(|| "Open input")?,
+    };
+
+    // From here on, the input is only accessed through the file descriptor.
+    // Looking up the input path again could find a different file,
+    // if a component of it was replaced after it was opened.
+    let statbuf = fstat(input.as_fd())
+        .with_context(|| "Find file type of input")?;
+
+    // Mounting requires a path, as there is unfortunately no "mountat"
+    // system call. The magic link refers to the opened file itself.
+    // See the documentation of MountSource for how it is used.
+    let source = magic_link(input.as_fd());
+    let source_file = MountSource{
+        path: resolve_magic(input.as_fd())?,
+        fd: input,
+        dev: statbuf.st_dev,
+        ino: statbuf.st_ino,
+    };
 
     // Make the target relative to the /build directory.
     let target = cstr!(b"build").join(input_basename);
 
     // How to mount the input depends on what type of file it is.
-    let statbuf = fstatat(None, &input_path, AT_SYMLINK_NOFOLLOW)               .with_context(|| "Find file type of input")?;
     match statbuf.st_mode & S_IFMT {
         S_IFREG => {
             // If it's a regular file, the target must be a regular file.
             mknodat(Some(scratch), &target, S_IFREG | 0o644, 0)                 .with_context(|| "Create mount target")?;
-            let mount = Mount::rdonly_bind_mount(input_path.into(), target.into());
+            let mut mount =
+                Mount::rdonly_bind_mount(source.into(), target.into());
+            mount[0].source_file = Some(source_file);
             mounts.extend(mount);
         },
+        S_IFDIR if writable => {
+            // Changes to the input end up in the upper directory.
+            // Paths are relative to the scratch directory,
+            // which is the working directory while mounting.
+            let layers = cstr!(b"overlay").join(input_basename);
+            let upperdir = layers.join(cstr!(b"upper"));
+            let workdir = layers.join(cstr!(b"work"));
+            for path in [&target, &layers, &upperdir, &workdir] {
+                mkdirat(Some(scratch), path, 0o755)                             .with_context(|| format!("Create {path:?}"))?;
+            }
+            let mut mount =
+                Mount::overlay(&source, &upperdir, &workdir, target.into());
+            mount.source_file = Some(source_file);
+            mounts.push(mount);
+        },
         S_IFDIR => {
             // If it's a directory, the target must be a directory.
             mkdirat(Some(scratch), &target, 0o755)                              .with_context(|| "Create mount target")?;
-            let mount = Mount::rdonly_bind_mount(input_path.into(), target.into());
+            let mut mount =
+                Mount::rdonly_bind_mount(source.into(), target.into());
+            mount[0].source_file = Some(source_file);
             mounts.extend(mount);
         },
         S_IFLNK => {
             // If it's a symbolic link, we're fucked as they can't be mounted.
             // Copy the symbolic link instead (should be fast; they're small).
-            let symlink_target = readlinkat(None, &input_path)                  .with_context(|| "Find target of symbolic link")?;
+            // An empty path makes readlinkat read the link opened as input.
+            let link = Some(source_file.fd.as_fd());
+            let symlink_target = readlinkat(link, cstr!(b""))                   .with_context(|| "Find target of symbolic link")?;
+            if !is_beneath(&symlink_target) {
+                bail!("Symbolic link input points outside the inputs: \
+                       {symlink_target:?}");
+            }
             symlinkat(&symlink_target, Some(scratch), &target)                  .with_context(|| "Create copy of symbolic link")?;
         },
         _ =>
@@ -381,6 +1004,34 @@ fn mount_input(
     Ok(())
 }
 
+/// Whether a symbolic link target stays within the directory of the link.
+///
+/// Inputs are staged next to each other in `/build`, which contains
+/// nothing else before the command runs. So a symbolic link input
+/// that is relative and does not climb out of `/build`
+/// can only point to other inputs, or to nothing.
+fn is_beneath(symlink_target: &CStr) -> bool
+{
+    let bytes = symlink_target.to_bytes();
+    if bytes.starts_with(b"/") {
+        return false;
+    }
+
+    let mut depth = 0usize;
+    for component in bytes.split(|&b| b == b'/') {
+        match component {
+            b"" | b"." => { },
+            b".." => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            _ => depth += 1,
+        }
+    }
+
+    true
+}
+
 /// Compute the scratch-relative path at which each output is created.
 fn output_paths(outputs: &Outputs<Vec<Basename<CString>>>) -> Vec<CString>
 {
@@ -390,6 +1041,54 @@ fn output_paths(outputs: &Outputs<Vec<Basename<CString>>>) -> Vec<CString>
         .get()
 }
 
+/// Rewrite paths to inputs in the build log.
+fn rewrite_build_log(
+    build_log: BorrowedFd,
+    inputs: &[Basename<CString>],
+    log_paths: &LogPaths,
+) -> Result<(), Error>
+{
+    let build_log = build_log.try_to_owned()                                    .with_context(|| "Duplicate build log file descriptor")?;
+    let mut build_log = File::from(build_log);
+
+    let mut contents = Vec::new();
+    build_log.rewind()                                                          .with_context(|| "Rewind build log")?;
+    build_log.read_to_end(&mut contents)                                        .with_context(|| "Read build log")?;
+
+    let rewritten = rewrite_paths(&contents, inputs, log_paths);
+    if let Cow::Owned(rewritten) = rewritten {
+        build_log.set_len(0)                                                    .with_context(|| "Truncate build log")?;
+        build_log.rewind()                                                      .with_context(|| "Rewind build log")?;
+        build_log.write_all(&rewritten)                                         .with_context(|| "Write build log")?;
+    }
+
+    Ok(())
+}
+
+/// Rewrite paths to inputs in a build log.
+fn rewrite_paths<'a>(
+    log: &'a [u8],
+    inputs: &[Basename<CString>],
+    log_paths: &LogPaths,
+) -> Cow<'a, [u8]>
+{
+    log_paths.regex.replace_all(log, |captures: &Captures| {
+        let path = &captures[0];
+        let relative = path.strip_prefix(b"/build/").unwrap_or(path);
+        let end = relative.iter().position(|&b| b == b'/')
+            .unwrap_or(relative.len());
+        let (basename, rest) = relative.split_at(end);
+        let workspace_path = inputs.iter()
+            .position(|i| i.to_bytes() == basename)
+            .and_then(|i| log_paths.workspace_paths.get(i))
+            .and_then(Option::as_ref);
+        match workspace_path {
+            Some(workspace_path) => [workspace_path.to_bytes(), rest].concat(),
+            None => path.to_vec(),
+        }
+    })
+}
+
 /// Look for warnings in the build log.
 fn find_warnings(build_log: BorrowedFd, warnings: Option<&Regex>)
     -> Result<bool, Error>
@@ -419,6 +1118,83 @@ fn find_warnings(build_log: BorrowedFd, warnings: Option<&Regex>)
     }
 }
 
+/// Find the files listed in the depfile within the partial inputs.
+fn read_depfile(
+    scratch: BorrowedFd,
+    inputs: &[Basename<CString>],
+    depfile: &Depfile,
+) -> Result<Vec<Dependency>, Error>
+{
+    let path = cstr!(b"build").join(&depfile.path);
+    let file = openat(Some(scratch), &path, O_NOFOLLOW | O_RDONLY, 0)           .with_context(|| "Open depfile")?;
+    let mut contents = Vec::new();
+    File::from(file).read_to_end(&mut contents)                                 .with_context(|| "Read depfile")?;
+    let paths = parse_depfile(&contents)                                        .with_context(|| "Parse depfile")?;
+
+    let mut dependencies = Vec::new();
+    for path in paths {
+        if let Some(dependency) = resolve_dependency(inputs, depfile, path)? {
+            dependencies.push(dependency);
+        }
+    }
+
+    Ok(dependencies)
+}
+
+/// Find the input that contains a file listed in the depfile.
+///
+/// Returns [`None`] if the file need not be a dependency of the action,
+/// because it is in the Nix store or in an input that is used in full.
+/// Returns an error if the file is not within any input.
+fn resolve_dependency(
+    inputs: &[Basename<CString>],
+    depfile: &Depfile,
+    path: Vec<u8>,
+) -> Result<Option<Dependency>, Error>
+{
+    // Paths in the depfile are as seen by the program,
+    // which runs in the container's /build directory.
+    let relative = match path.strip_prefix(b"/build/") {
+        Some(relative) => relative,
+        // The Nix store is immutable, so its files never change.
+        None if path.starts_with(b"/nix/store/") => return Ok(None),
+        None if path.starts_with(b"/") => &[][..],
+        None => &path[..],
+    };
+
+    // Resolve `.` and `..` components.
+    let mut components = Vec::new();
+    for component in relative.split(|&b| b == b'/') {
+        match component {
+            b"" | b"." => { },
+            b".." => { components.pop(); },
+            _ => components.push(component),
+        }
+    }
+
+    // Parsed depfiles do not contain nuls.
+    let undeclared = || Error::UndeclaredDependency(
+        CString::new(path.clone()).unwrap()
+    );
+
+    let Some((basename, rest)) = components.split_first()
+        else { return Err(undeclared()) };
+    let Some(input) = inputs.iter().position(|i| i.to_bytes() == *basename)
+        else { return Err(undeclared()) };
+
+    if !depfile.inputs.contains(&input) {
+        return Ok(None);
+    }
+
+    let path = if rest.is_empty() {
+        None
+    } else {
+        Some(CString::new(rest.join(&b'/')).unwrap())
+    };
+
+    Ok(Some(Dependency{input, path}))
+}
+
 /// Obtain the path to the file referred to by a file descriptor.
 ///
 /// Some system calls do not work well with file descriptors:
@@ -431,14 +1207,64 @@ fn resolve_magic(fd: BorrowedFd) -> Result<CString, Error>
         .map_err(|err| Error::from(anyhow::Error::from(err)))
 }
 
+/// Build the seccomp filter that blocks [`BLOCKED_SYSCALLS`],
+/// except for those that are explicitly allowed.
+fn seccomp_filter(allowed_syscalls: &[String])
+    -> anyhow::Result<Vec<sock_filter>>
+{
+    for allowed in allowed_syscalls {
+        if !BLOCKED_SYSCALLS.iter().any(|&(name, _)| name == allowed) {
+            bail!("Allowed system call {allowed:?} is not blocked");
+        }
+    }
+
+    // System call numbers differ between architectures,
+    // so kill the program if it uses a different one.
+    let mut filter = vec![
+        sock_filter::stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_ARCH),
+        sock_filter::jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH_NATIVE, 1, 0),
+        sock_filter::stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+        sock_filter::stmt(BPF_LD | BPF_W | BPF_ABS, SECCOMP_DATA_NR),
+    ];
+
+    // On x86-64, the x32 ABI shares the audit architecture,
+    // but sets a bit in the system call number.
+    #[cfg(target_arch = "x86_64")]
+    filter.extend([
+        sock_filter::jump(BPF_JMP | os_ext::BPF_JGE | BPF_K, 0x40000000, 0, 1),
+        sock_filter::stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+    ]);
+
+    for &(name, nr) in BLOCKED_SYSCALLS {
+        if allowed_syscalls.iter().any(|allowed| allowed == name) {
+            continue;
+        }
+        filter.extend([
+            sock_filter::jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1),
+            sock_filter::stmt(BPF_RET | BPF_K,
+                              SECCOMP_RET_ERRNO | libc::EPERM as u32),
+        ]);
+    }
+
+    filter.push(sock_filter::stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+
+    Ok(filter)
+}
+
 /// Run the command in the already set up container.
+#[allow(clippy::too_many_arguments)]
 fn run_command(
-    build_log: BorrowedFd,
+    // If None, the command inherits the standard streams.
+    build_log: Option<BorrowedFd>,
     scratch_path: &CStr,
     program: &CStr,
     arguments: &[CString],
     environment: &[CString],
-    timeout: Duration,
+    timeout: Option<Duration>,
+    network: bool,
+    cancel: Option<BorrowedFd>,
+    stream_log: Option<BorrowedFd>,
+    seccomp_filter: &[sock_filter],
     // By value, to prevent accidentally adding
     // mounts *after* running the command. :)
     mounts: Vec<Mount>,
@@ -459,6 +1285,17 @@ fn run_command(
     // Since CLOEXEC is enabled, the parent knows execve has succeeded.
     let (pipe_r, pipe_w) = pipe2(0)                                             .with_context(|| "Create pipe for parent-child communication")?;
 
+    // When streaming the build log, the child writes to this pipe instead.
+    // The parent copies from the pipe to the build log and the stream log.
+    let stream_pipe = match (build_log, stream_log) {
+        (Some(_), Some(_)) => Some(pipe2(0)                                     .with_context(|| "Create pipe for streaming build log")?),
+        _ => None,
+    };
+    let output = match &stream_pipe {
+        Some((_, stream_w)) => Some(stream_w.as_fd()),
+        None => build_log,
+    };
+
     // Zero-initialize this because we don't use most of its features.
     let mut cl_args = unsafe { zeroed::<clone_args>() };
 
@@ -466,13 +1303,27 @@ fn run_command(
     cl_args.flags |= (
         libc::CLONE_NEWCGROUP |  // New cgroup namespace.
         libc::CLONE_NEWIPC    |  // New IPC namespace.
-        libc::CLONE_NEWNET    |  // New network namespace.
         libc::CLONE_NEWNS     |  // New mount namespace.
         libc::CLONE_NEWPID    |  // New PID namespace.
         libc::CLONE_NEWUSER   |  // New user namespace.
         libc::CLONE_NEWUTS       // New UTS namespace.
     ) as u64;
 
+    // Unless network access was requested, isolate the network too.
+    if !network {
+        cl_args.flags |= libc::CLONE_NEWNET as u64;
+    }
+
+    // Snowflake may block signals to consume them through a signalfd.
+    // The signal mask is inherited across execve, so the child clears it.
+    let sigmask = sigemptyset();
+
+    // Prepare the request that brings up the loopback interface.
+    // A new network namespace has a loopback interface, but it is down.
+    let mut loopback = unsafe { zeroed::<ifreq>() };
+    loopback.ifr_name[.. 2].copy_from_slice(b"lo");
+    loopback.ifr_flags = libc::IFF_UP as libc::c_short;
+
     // Atomically create a pidfd for use with ppoll.
     // The pidfd will have CLOEXEC enabled, yay!
     let mut pidfd = -1;
@@ -535,6 +1386,17 @@ fn run_command(
             }
         };
 
+        // Kill the child if the thread that spawned it terminates,
+        // so that commands are not left running when Snowflake exits.
+        let pdeathsig = prctl_set_pdeathsig(libc::SIGKILL);
+        enforce("prctl", pdeathsig.is_ok());
+
+        // Unblock all signals, so that the command can be terminated.
+        let sigprocmask = unsafe {
+            libc::sigprocmask(libc::SIG_SETMASK, &sigmask, null_mut())
+        };
+        enforce("sigprocmask", sigprocmask != -1);
+
         // Write the /proc/self/\* files prepared above.
         unsafe {
             let write_file = |pathname: &'static [u8], data: &[u8]| {
@@ -549,13 +1411,29 @@ fn run_command(
             write_file(b"/proc/self/gid_map\0", gid_map.as_bytes());
         }
 
+        // Bring up the loopback interface in the new network namespace.
+        // Programs such as test suites often rely on it being available.
+        if !network {
+            unsafe {
+                let flags = libc::SOCK_DGRAM | libc::SOCK_CLOEXEC;
+                let socket = libc::socket(libc::AF_INET, flags, 0);
+                enforce("socket", socket != -1);
+                let request = addr_of!(loopback);
+                let ioctl = libc::ioctl(socket, libc::SIOCSIFFLAGS, request);
+                enforce("ioctl", ioctl != -1);
+                libc::close(socket);
+            }
+        }
+
         // Configure the standard streams stdin, stdout, and stderr.
         // dup2 turns off CLOEXEC which is exactly what we need.
-        let build_log = build_log.as_raw_fd();
-        unsafe {
-            enforce("close stdin", libc::close(0) != -1);
-            enforce("dup2 stdout", libc::dup2(build_log, 1) != -1);
-            enforce("dup2 stderr", libc::dup2(build_log, 2) != -1);
+        if let Some(output) = output {
+            let output = output.as_raw_fd();
+            unsafe {
+                enforce("close stdin", libc::close(0) != -1);
+                enforce("dup2 stdout", libc::dup2(output, 1) != -1);
+                enforce("dup2 stderr", libc::dup2(output, 2) != -1);
+            }
         }
 
         // Change the working directory.
@@ -563,6 +1441,28 @@ fn run_command(
 
         // Apply the prepared mounts.
         for mount in mounts {
+            if let Some(source_file) = &mount.source_file {
+                let flags = libc::O_CLOEXEC | libc::O_NOFOLLOW | libc::O_PATH;
+                let reopen = unsafe {
+                    libc::open(source_file.path.as_ptr(), flags)
+                };
+                enforce("open input", reopen != -1);
+                let mut statbuf = unsafe { zeroed::<libc::stat>() };
+                let fstat = unsafe { libc::fstat(reopen, &mut statbuf) };
+                enforce("fstat input", fstat != -1);
+                let same = statbuf.st_dev == source_file.dev
+                        && statbuf.st_ino == source_file.ino;
+                if !same {
+                    unsafe { *libc::__errno_location() = libc::ESTALE; }
+                }
+                enforce("input was replaced", same);
+                let source_fd = source_file.fd.as_raw_fd();
+                let dup3 = unsafe {
+                    libc::dup3(reopen, source_fd, libc::O_CLOEXEC)
+                };
+                enforce("dup3 input", dup3 != -1);
+                unsafe { libc::close(reopen); }
+            }
             let mount = unsafe {
                 libc::mount(mount.source.as_ptr(), mount.target.as_ptr(),
                             mount.filesystemtype.as_ptr(), mount.mountflags,
@@ -579,6 +1479,12 @@ fn run_command(
         let chdir = unsafe { libc::chdir(b"/build\0".as_ptr().cast()) };
         enforce("chdir", chdir != -1);
 
+        // Block dangerous system calls. This must come last,
+        // because setting up the container needs some of them.
+        enforce("prctl", prctl_set_no_new_privs().is_ok());
+        let seccomp = seccomp_set_mode_filter(0, seccomp_filter);
+        enforce("seccomp", seccomp.is_ok());
+
         // Run the specified program.
         unsafe { libc::execve(program.as_ptr(), execve_argv, execve_envp) };
         enforce("execve", false);
@@ -613,6 +1519,19 @@ fn run_command(
     // Close the write end of the pipe.
     drop(pipe_w);
 
+    // Start copying the output of the child to the logs.
+    // The pipe reaches EOF once every process in the container has exited.
+    let tee = match (stream_pipe, build_log, stream_log) {
+        (Some((stream_r, stream_w)), Some(build_log), Some(stream_log)) => {
+            drop(stream_w);
+            let build_log = build_log.try_to_owned()                            .with_context(|| "Duplicate build log")?;
+            let stream_log = stream_log.try_to_owned()                          .with_context(|| "Duplicate stream log")?;
+            let tee = move || tee_output(stream_r, build_log, stream_log);
+            Some(thread::spawn(tee))
+        },
+        _ => None,
+    };
+
     // Read from the read end of the pipe.
     // On EOF, we know that execve was successful.
     // On data, the child has written an error to us.
@@ -630,35 +1549,32 @@ fn run_command(
             .map_err(Error::from);
     }
 
-    // A pidfd reports "readable" when the child terminates.
-    // We don't need to actually read from the pidfd, only ppoll.
-    let mut pollfd = libc::pollfd{
-        fd: pidfd.as_raw_fd(),
-        events: libc::POLLIN,
-        revents: 0,
-    };
-
-    // Convert timeout from Duration to libc::timespec.
-    let ptimeout = libc::timespec{
-        tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
-        tv_nsec: timeout.subsec_nanos().try_into().unwrap_or(libc::c_long::MAX),
-    };
-
     // Wait for the child to terminate or the timeout to occur.
-    let ppoll = unsafe { libc::ppoll(&mut pollfd, 1, &ptimeout, null()) };
-    if ppoll == -1 {
-        let error = io::Error::last_os_error();
-        return Err(anyhow::Error::from(error))
-            .with_context(|| "Poll child process")
-            .map_err(Error::from);
-    }
-    if ppoll == 0 {
-        return Err(Error::Timeout(timeout));
+    match wait_for_child(pidfd.as_fd(), timeout, cancel)? {
+        Wait::Terminated => { },
+        Wait::TimedOut => {
+            let timeout = timeout.expect("Only a timeout can time out");
+            return Err(Error::Timeout(timeout));
+        },
+        Wait::Cancelled => {
+            // Give the child a chance to stop by itself.
+            // If it doesn't, the child guard kills it.
+            // Only a pid 1 that handles SIGTERM receives it.
+            let _ = pidfd_send_signal(pidfd.as_fd(), libc::SIGTERM);
+            let grace_period = Some(CANCEL_GRACE_PERIOD);
+            let _ = wait_for_child(pidfd.as_fd(), grace_period, None);
+            return Err(Error::Cancelled);
+        },
     }
 
     // The child has terminated, so no need to kill it.
     forget(child_guard);
 
+    // Wait for the remaining output to be copied to the logs.
+    if let Some(tee) = tee {
+        tee.join().unwrap()                                                     .with_context(|| "Copy output of command")?;
+    }
+
     // Clean up the child process and obtain its wait status.
     // Check that the child terminated successfully.
     let mut wstatus = 0;
@@ -669,6 +1585,102 @@ fn run_command(
     Ok(())
 }
 
+/// Copy the output of the child to both the build log and the stream log.
+///
+/// Failing to write to the stream log does not fail the action,
+/// since the stream log is only for watching the action's progress.
+fn tee_output(output: OwnedFd, build_log: OwnedFd, stream_log: OwnedFd)
+    -> io::Result<()>
+{
+    let mut output = File::from(output);
+    let mut build_log = File::from(build_log);
+    let mut stream_log = File::from(stream_log);
+    let mut buf = [0; 4096];
+    loop {
+        let nread = match output.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(nread) => nread,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        build_log.write_all(&buf[.. nread])?;
+        let _ = stream_log.write_all(&buf[.. nread]);
+    }
+}
+
+/// How long a cancelled command may take to stop before it is killed.
+const CANCEL_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Why [`wait_for_child`] returned.
+enum Wait
+{
+    Terminated,
+    TimedOut,
+    Cancelled,
+}
+
+/// Wait for the child to terminate, the timeout to occur,
+/// or the build to be cancelled, whichever happens first.
+/// Without a timeout, the child may take as long as it likes.
+fn wait_for_child(
+    pidfd: BorrowedFd,
+    timeout: Option<Duration>,
+    cancel: Option<BorrowedFd>,
+) -> Result<Wait, Error>
+{
+    // A pidfd reports "readable" when the child terminates.
+    // We don't need to actually read from the pidfd, only ppoll.
+    // Negative file descriptors are ignored by ppoll.
+    let mut pollfds = [
+        libc::pollfd{
+            fd: pidfd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd{
+            fd: cancel.map_or(-1, |cancel| cancel.as_raw_fd()),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+
+    // ppoll is interrupted by signal handlers, even with SA_RESTART.
+    // In that case, wait again for the remainder of the timeout.
+    // A null timeout makes ppoll wait indefinitely.
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        // Convert timeout from Duration to libc::timespec.
+        let ptimeout = deadline.map(|deadline| {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            libc::timespec{
+                tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+                tv_nsec: timeout.subsec_nanos().try_into().unwrap_or(libc::c_long::MAX),
+            }
+        });
+        let ptimeout = ptimeout.as_ref().map_or(null(), |t| t as *const _);
+
+        let nfds = pollfds.len() as libc::nfds_t;
+        let ppoll = unsafe {
+            libc::ppoll(pollfds.as_mut_ptr(), nfds, ptimeout, null())
+        };
+        if ppoll == -1 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(anyhow::Error::from(error))
+                .with_context(|| "Poll child process")
+                .map_err(Error::from);
+        }
+
+        return Ok(
+            if ppoll == 0 { Wait::TimedOut }
+            else if pollfds[0].revents != 0 { Wait::Terminated }
+            else { Wait::Cancelled }
+        );
+    }
+}
+
 /// Arguments to the clone3 system call.
 ///
 /// This struct is unfortunately not part of the libc crate.
@@ -688,6 +1700,18 @@ struct clone_args
     cgroup:       u64,
 }
 
+/// Argument to the SIOCSIFFLAGS ioctl.
+///
+/// This is the `struct ifreq` from netdevice(7),
+/// with the union member for interface flags.
+#[repr(C)]
+struct ifreq
+{
+    ifr_name:  [u8; 16],
+    ifr_flags: libc::c_short,
+    _padding:  [u8; 22],
+}
+
 /// Prepare the argv or envp arguments to `execve`.
 ///
 /// `execve` expects these to be arrays of nul-terminated strings,
@@ -718,6 +1742,7 @@ mod tests
             O_DIRECTORY, O_PATH, O_RDWR, O_TMPFILE,
             cstr, cstring, mkdtemp, open,
         },
+        snowflake_core::cancel::Cancel,
         std::{
             assert_matches::assert_matches,
             io::Seek,
@@ -740,6 +1765,8 @@ mod tests
         let perform = Perform{
             build_log: build_log.as_fd(),
             scratch: scratch.as_fd(),
+            cancel: None,
+            stream_log: None,
         };
 
         let result = perform_run_command(&perform, action, input_paths);
@@ -781,6 +1808,8 @@ mod tests
         let action = RunCommand{
             inputs,
             outputs: Outputs::Outputs(vec![]),
+            optional_outputs: vec![],
+            output_groups: vec![],
             program: cstring!(b"/bin/sh"),
             arguments: vec![
                 cstring!(b"sh"),
@@ -795,8 +1824,16 @@ mod tests
             environment: vec![
                 CString::new(format!("PATH={coreutils}/bin")).unwrap(),
             ],
+            passthrough: vec![],
             timeout: Duration::from_millis(50),
             warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
         };
 
         let (result, mut build_log) =
@@ -809,12 +1846,112 @@ mod tests
                          regular.txt\nenoent.txt\n");
     }
 
+    #[test]
+    fn input_escape()
+    {
+        let coreutils = CString::new(env!("SNOWFLAKE_COREUTILS")).unwrap();
+        let source_root =
+            open(cstr!(b"testdata/inputs"), O_DIRECTORY | O_PATH, 0)
+                .unwrap();
+
+        for path in [cstr!(b"../inputs/regular.txt"), cstr!(b"/etc/passwd")] {
+            let action = RunCommand{
+                inputs: vec![Basename::new(cstring!(b"input")).unwrap()],
+                outputs: Outputs::Outputs(vec![]),
+                optional_outputs: vec![],
+                output_groups: vec![],
+                program: coreutils.join(cstr!(b"bin/true")),
+                arguments: vec![cstring!(b"true")],
+                environment: vec![],
+                passthrough: vec![],
+                timeout: Duration::from_secs(1),
+                warnings: None,
+                depfile: None,
+                log_paths: None,
+                resources: Resources::default(),
+                retry_policy: RetryPolicy::default(),
+                network: false,
+                writable_inputs: false,
+                allowed_syscalls: vec![],
+            };
+            let input_paths = [InputPath{
+                dirfd: source_root.as_fd(),
+                path: Cow::Borrowed(path),
+            }];
+            let (result, _) = call_perform_run_command(&action, &input_paths);
+            let Err(Error::Unexpected(error)) = result
+                else { panic!("{result:?}") };
+            assert!(format!("{error:?}").contains("escapes"), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn symlink_escape()
+    {
+        let coreutils = CString::new(env!("SNOWFLAKE_COREUTILS")).unwrap();
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_PATH, 0).unwrap();
+        let dirfd = Some(source_root.as_fd());
+        symlinkat(cstr!(b"/etc/passwd"), dirfd, cstr!(b"absolute")).unwrap();
+        symlinkat(cstr!(b"a/../../b"), dirfd, cstr!(b"relative")).unwrap();
+
+        for path in [cstr!(b"absolute"), cstr!(b"relative")] {
+            let action = RunCommand{
+                inputs: vec![Basename::new(cstring!(b"input")).unwrap()],
+                outputs: Outputs::Outputs(vec![]),
+                optional_outputs: vec![],
+                output_groups: vec![],
+                program: coreutils.join(cstr!(b"bin/true")),
+                arguments: vec![cstring!(b"true")],
+                environment: vec![],
+                passthrough: vec![],
+                timeout: Duration::from_secs(1),
+                warnings: None,
+                depfile: None,
+                log_paths: None,
+                resources: Resources::default(),
+                retry_policy: RetryPolicy::default(),
+                network: false,
+                writable_inputs: false,
+                allowed_syscalls: vec![],
+            };
+            let input_paths = [InputPath{
+                dirfd: source_root.as_fd(),
+                path: Cow::Borrowed(path),
+            }];
+            let (result, _) = call_perform_run_command(&action, &input_paths);
+            let Err(Error::Unexpected(error)) = result
+                else { panic!("{result:?}") };
+            assert!(format!("{error:?}").contains("outside"), "{error:?}");
+        }
+    }
+
+    #[test]
+    fn symlink_is_beneath()
+    {
+        let examples = [
+            (cstr!(b"regular.txt"), true),
+            (cstr!(b"directory/../regular.txt"), true),
+            (cstr!(b"./directory//file"), true),
+            (cstr!(b"enoent.txt"), true),
+            (cstr!(b"/etc/passwd"), false),
+            (cstr!(b".."), false),
+            (cstr!(b"directory/../../etc/passwd"), false),
+        ];
+        for (symlink_target, expected) in examples {
+            assert_eq!(is_beneath(symlink_target), expected,
+                       "{symlink_target:?}");
+        }
+    }
+
     #[test]
     fn pid_1()
     {
         let action = RunCommand{
             inputs: vec![],
             outputs: Outputs::Outputs(vec![]),
+            optional_outputs: vec![],
+            output_groups: vec![],
             program: cstring!(b"/bin/sh"),
             arguments: vec![
                 cstring!(b"sh"),
@@ -822,8 +1959,16 @@ mod tests
                 cstring!(b"echo $$"),
             ],
             environment: vec![],
+            passthrough: vec![],
             timeout: Duration::from_millis(50),
             warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
         };
         let (result, mut build_log) = call_perform_run_command(&action, &[]);
         assert_matches!(result, Ok(Success{warnings: false, ..}));
@@ -839,16 +1984,119 @@ mod tests
         let action = RunCommand{
             inputs: vec![],
             outputs: Outputs::Outputs(vec![]),
+            optional_outputs: vec![],
+            output_groups: vec![],
             program: coreutils.join(cstr!(b"bin/sleep")),
             arguments: vec![cstring!(b"sleep"), cstring!(b"0.060")],
             environment: vec![],
+            passthrough: vec![],
             timeout: Duration::from_millis(50),
             warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
         };
         let (result, _) = call_perform_run_command(&action, &[]);
         assert_matches!(result, Err(Error::Timeout(_)));
     }
 
+    #[test]
+    fn cancellation()
+    {
+        let coreutils = CString::new(env!("SNOWFLAKE_COREUTILS")).unwrap();
+        let action = RunCommand{
+            inputs: vec![],
+            outputs: Outputs::Outputs(vec![]),
+            optional_outputs: vec![],
+            output_groups: vec![],
+            program: coreutils.join(cstr!(b"bin/sleep")),
+            arguments: vec![cstring!(b"sleep"), cstring!(b"10")],
+            environment: vec![],
+            passthrough: vec![],
+            timeout: Duration::from_secs(10),
+            warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
+        };
+
+        let path      = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let build_log = open(cstr!(b"."), O_RDWR | O_TMPFILE, 0o644).unwrap();
+        let scratch   = open(&path, O_DIRECTORY | O_PATH, 0).unwrap();
+        let cancel    = Cancel::new().unwrap();
+        cancel.cancel();
+
+        let perform = Perform{
+            build_log: build_log.as_fd(),
+            scratch: scratch.as_fd(),
+            cancel: Some(cancel.as_fd()),
+            stream_log: None,
+        };
+
+        // The command is stopped long before it finishes sleeping.
+        let start = Instant::now();
+        let result = perform_run_command(&perform, &action, &[]);
+        assert_matches!(result, Err(Error::Cancelled));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn stream_log()
+    {
+        let coreutils = CString::new(env!("SNOWFLAKE_COREUTILS")).unwrap();
+        let action = RunCommand{
+            inputs: vec![],
+            outputs: Outputs::Outputs(vec![]),
+            optional_outputs: vec![],
+            output_groups: vec![],
+            program: coreutils.join(cstr!(b"bin/echo")),
+            arguments: vec![cstring!(b"echo"), cstring!(b"hello")],
+            environment: vec![],
+            passthrough: vec![],
+            timeout: Duration::from_secs(1),
+            warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
+        };
+
+        let path       = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let build_log  = open(cstr!(b"."), O_RDWR | O_TMPFILE, 0o644).unwrap();
+        let stream_log = open(cstr!(b"."), O_RDWR | O_TMPFILE, 0o644).unwrap();
+        let scratch    = open(&path, O_DIRECTORY | O_PATH, 0).unwrap();
+
+        let perform = Perform{
+            build_log: build_log.as_fd(),
+            scratch: scratch.as_fd(),
+            cancel: None,
+            stream_log: Some(stream_log.as_fd()),
+        };
+
+        let result = perform_run_command(&perform, &action, &[]);
+        assert_matches!(result, Ok(_));
+
+        // The output ends up in both the build log and the stream log.
+        for log in [build_log, stream_log] {
+            let mut log = File::from(log);
+            log.rewind().unwrap();
+            let mut contents = String::new();
+            log.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "hello\n");
+        }
+    }
+
     #[test]
     fn unsuccessful_termination()
     {
@@ -856,11 +2104,21 @@ mod tests
         let action = RunCommand{
             inputs: vec![],
             outputs: Outputs::Outputs(vec![]),
+            optional_outputs: vec![],
+            output_groups: vec![],
             program: coreutils.join(cstr!(b"bin/false")),
             arguments: vec![cstring!(b"false")],
             environment: vec![],
+            passthrough: vec![],
             timeout: Duration::from_millis(50),
             warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
         };
         let (result, _) = call_perform_run_command(&action, &[]);
         assert_matches!(result, Err(Error::ExitStatus(_)));
@@ -872,6 +2130,8 @@ mod tests
         let action = RunCommand{
             inputs: vec![],
             outputs: Outputs::Outputs(vec![]),
+            optional_outputs: vec![],
+            output_groups: vec![],
             program: cstring!(b"/bin/sh"),
             arguments: vec![
                 cstring!(b"sh"),
@@ -879,10 +2139,159 @@ mod tests
                 cstring!(b"echo hello; echo 'warning: boo'"),
             ],
             environment: vec![],
+            passthrough: vec![],
             timeout: Duration::from_millis(50),
             warnings: Some(Regex::new("^warning:").unwrap()),
+            depfile: None,
+            log_paths: None,
+            resources: Resources::default(),
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
         };
         let (result, _) = call_perform_run_command(&action, &[]);
         assert_matches!(result, Ok(Success{warnings: true, ..}));
     }
+
+    #[test]
+    fn resolve_dependencies()
+    {
+        let inputs: Vec<Basename<CString>> =
+            [cstring!(b"main.c"), cstring!(b"include"), cstring!(b"lib")]
+            .into_iter()
+            .map(|i| Basename::new(i).unwrap())
+            .collect();
+
+        let depfile = Depfile{
+            path: Basename::new(cstring!(b"main.d")).unwrap(),
+            inputs: vec![1, 2],
+        };
+
+        let resolve = |path: &[u8]| {
+            resolve_dependency(&inputs, &depfile, path.to_vec())
+                .map_err(|err| err.to_string())
+        };
+        let dependency = |input, path: Option<&[u8]>| Ok(Some(Dependency{
+            input,
+            path: path.map(|p| CString::new(p).unwrap()),
+        }));
+
+        // Files within partial inputs are dependencies.
+        assert_eq!(resolve(b"include/util.h"), dependency(1, Some(b"util.h")));
+        assert_eq!(resolve(b"/build/include/a/b.h"),
+                   dependency(1, Some(b"a/b.h")));
+        assert_eq!(resolve(b"./include/../lib/./c.h"),
+                   dependency(2, Some(b"c.h")));
+        assert_eq!(resolve(b"lib"), dependency(2, None));
+
+        // Other inputs and the Nix store need not be recorded.
+        assert_eq!(resolve(b"main.c"), Ok(None));
+        assert_eq!(resolve(b"/nix/store/abc-glibc/include/stdio.h"), Ok(None));
+
+        // Files outside of inputs are not allowed.
+        resolve(b"main.o").unwrap_err();
+        resolve(b"/usr/include/stdio.h").unwrap_err();
+        resolve(b"../secret.h").unwrap_err();
+        resolve(b"/build").unwrap_err();
+    }
+
+    #[test]
+    fn log_paths()
+    {
+        let inputs: Vec<Basename<CString>> =
+            [cstring!(b"main.c"), cstring!(b"include"), cstring!(b"util.h")]
+            .into_iter()
+            .map(|i| Basename::new(i).unwrap())
+            .collect();
+
+        let log_paths = LogPaths{
+            regex: Regex::new(r"[^ \t\n:]+\.[ch]").unwrap(),
+            workspace_paths: vec![
+                Some(cstring!(b"src/main.c")),
+                Some(cstring!(b"src/include")),
+                None,
+            ],
+        };
+
+        let log = b"main.c:1:1: warning: empty file\n\
+                    In file included from /build/include/a.h:2:\n\
+                    util.h:3:4: error: oops\n\
+                    other.c:5:6: note: not an input\n";
+        let rewritten = rewrite_paths(log, &inputs, &log_paths);
+        assert_eq!(
+            String::from_utf8_lossy(&rewritten),
+            "src/main.c:1:1: warning: empty file\n\
+             In file included from src/include/a.h:2:\n\
+             util.h:3:4: error: oops\n\
+             other.c:5:6: note: not an input\n",
+        );
+    }
+
+    #[test]
+    fn passthrough()
+    {
+        env::set_var("SNOWFLAKE_TEST_PASSTHROUGH", "hello");
+        env::remove_var("SNOWFLAKE_TEST_PASSTHROUGH_UNSET");
+
+        let environment = [cstring!(b"PATH=/bin")];
+        let passthrough = [
+            cstring!(b"SNOWFLAKE_TEST_PASSTHROUGH"),
+            cstring!(b"SNOWFLAKE_TEST_PASSTHROUGH_UNSET"),
+        ];
+        assert_eq!(
+            effective_environment(&environment, &passthrough),
+            [cstring!(b"PATH=/bin"),
+             cstring!(b"SNOWFLAKE_TEST_PASSTHROUGH=hello")],
+        );
+    }
+
+    #[test]
+    fn overlay_mount()
+    {
+        let mount = Mount::overlay(
+            cstr!(b"/in,put:s\\"),
+            cstr!(b"overlay/a/upper"),
+            cstr!(b"overlay/a/work"),
+            cstr_cow!(b"build/a"),
+        );
+        assert_eq!(mount.filesystemtype.deref(), cstr!(b"overlay"));
+        assert_eq!(mount.target.deref(), cstr!(b"build/a"));
+        assert_eq!(
+            mount.data.deref(),
+            cstr!(b"lowerdir=/in\\,put\\:s\\\\,\
+                    upperdir=overlay/a/upper,\
+                    workdir=overlay/a/work,userxattr"),
+        );
+    }
+
+    #[test]
+    fn seccomp()
+    {
+        // PTRACE_PEEKUSER on pid 0 fails with ESRCH unless blocked.
+        fn ptrace() -> Option<libc::c_int>
+        {
+            // SAFETY: PTRACE_PEEKUSER does not write to memory.
+            unsafe {
+                libc::syscall(libc::SYS_ptrace, libc::PTRACE_PEEKUSER,
+                              0, 0, 0);
+            }
+            io::Error::last_os_error().raw_os_error()
+        }
+
+        // Filters apply to the calling thread only,
+        // so install them on separate threads.
+        let errno = |allowed_syscalls: Vec<String>| {
+            thread::spawn(move || {
+                let filter = seccomp_filter(&allowed_syscalls).unwrap();
+                prctl_set_no_new_privs().unwrap();
+                seccomp_set_mode_filter(0, &filter).unwrap();
+                ptrace()
+            }).join().unwrap()
+        };
+
+        assert_eq!(errno(vec![]), Some(libc::EPERM));
+        assert_eq!(errno(vec!["ptrace".into()]), Some(libc::ESRCH));
+        assert!(seccomp_filter(&["getpid".into()]).is_err());
+    }
 }
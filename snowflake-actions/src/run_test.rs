@@ -0,0 +1,192 @@
+use {
+    crate::{
+        encoding::{
+            encode_action, get_duration, get_resources, get_retry_policy,
+            put_duration, put_resources, put_retry_policy,
+        },
+        run_command::{RunCommand, perform_run_command},
+    },
+    snowflake_core::{
+        action::{
+            Action, EncodedAction, Error, InputPath, Outputs,
+            Perform, Resources, Result, RetryPolicy,
+        },
+        executor::{ReadExt, WriteExt},
+    },
+    snowflake_util::{basename::Basename, hash::{Blake3, Hash}},
+    std::{ffi::CString, os::unix::io::BorrowedFd, time::Duration},
+};
+
+/// Action that runs a test in a container.
+///
+/// The test is a command that passes if it exits with status zero.
+/// Unlike other actions, the results of tests are cached
+/// even if they fail, so that failing tests are not run again
+/// until their inputs change.
+pub struct RunTest
+{
+    /// What to call the inputs in the command's working directory.
+    pub inputs: Vec<Basename<CString>>,
+
+    /// Absolute path to the program to run.
+    pub program: CString,
+
+    /// Arguments to the program, including the zeroth argument.
+    pub arguments: Vec<CString>,
+
+    /// The environment variables to the program.
+    pub environment: Vec<CString>,
+
+    /// How much time the test may spend.
+    ///
+    /// If the test takes longer than this, it is killed and it fails.
+    pub timeout: Duration,
+
+    /// The resources the test needs while it runs.
+    pub resources: Resources,
+
+    /// When to run the test again after it failed.
+    ///
+    /// Tests that fail and then pass when run again are reported as flaky.
+    pub retry_policy: RetryPolicy,
+}
+
+impl Action for RunTest
+{
+    fn inputs(&self) -> usize
+    {
+        self.inputs.len()
+    }
+
+    fn outputs(&self) -> Outputs<usize>
+    {
+        Outputs::Lint
+    }
+
+    fn resources(&self) -> Resources
+    {
+        self.resources
+    }
+
+    fn retry_policy(&self) -> RetryPolicy
+    {
+        self.retry_policy.clone()
+    }
+
+    fn is_test(&self) -> bool
+    {
+        true
+    }
+
+    fn perform(&self, perform: &Perform, input_paths: &[InputPath]) -> Result
+    {
+        debug_assert_eq!(input_paths.len(), self.inputs.len());
+        perform_run_command(perform, &self.command(), input_paths)
+    }
+
+    fn report_files(&self) -> Vec<(&'static str, Vec<u8>)>
+    {
+        self.command().report_files()
+    }
+
+    fn command_elements(&self) -> Vec<(String, String)>
+    {
+        self.command().command_elements()
+    }
+
+    fn replay(&self, scratch: BorrowedFd, input_paths: &[InputPath])
+        -> std::result::Result<(), Error>
+    {
+        self.command().replay(scratch, input_paths)
+    }
+
+    fn hash(&self, input_hashes: &[Hash]) -> Hash
+    {
+        // NOTE: See the manual chapter on avoiding hash collisions.
+
+        let Self{inputs, program, arguments, environment, timeout,
+                 resources, retry_policy} = self;
+
+        debug_assert_eq!(input_hashes.len(), inputs.len());
+
+        let mut h = Blake3::new();
+        h.put_str("RunTest");
+
+        h.put_usize(inputs.len());
+        for (basename, hash) in inputs.iter().zip(input_hashes) {
+            h.put_cstr(basename);
+            h.put_hash(*hash);
+        }
+
+        h.put_cstr(program);
+        h.put_slice(arguments, |h, a| h.put_cstr(a));
+        h.put_slice(environment, |h, e| h.put_cstr(e));
+
+        // The timeout, resources, and retry policy cannot affect
+        // the result of the test, so there is no need to include them
+        // in the hash.
+        let _ = (timeout, resources, retry_policy);
+
+        h.finalize()
+    }
+
+    fn encode(&self) -> Option<EncodedAction>
+    {
+        let Self{inputs, program, arguments, environment, timeout,
+                 resources, retry_policy} = self;
+        Some(encode_action("RunTest", |w| {
+            w.put_slice(inputs, |w, i| w.put_cstr(i))?;
+            w.put_cstr(program)?;
+            w.put_slice(arguments, |w, a| w.put_cstr(a))?;
+            w.put_slice(environment, |w, e| w.put_cstr(e))?;
+            put_duration(w, *timeout)?;
+            put_resources(w, resources)?;
+            put_retry_policy(w, retry_policy)
+        }))
+    }
+}
+
+impl RunTest
+{
+    /// Decode an action encoded with [`Action::encode`].
+    pub fn decode(data: &mut &[u8]) -> anyhow::Result<Self>
+    {
+        let basename = |r: &mut &[u8]| -> anyhow::Result<_> {
+            Ok(Basename::new(r.get_cstring()?)?)
+        };
+        let cstring = |r: &mut &[u8]| r.get_cstring();
+        Ok(Self{
+            inputs: data.get_vec(basename)?,
+            program: data.get_cstring()?,
+            arguments: data.get_vec(cstring)?,
+            environment: data.get_vec(cstring)?,
+            timeout: get_duration(data)?,
+            resources: get_resources(data)?,
+            retry_policy: get_retry_policy(data)?,
+        })
+    }
+
+    /// The command that runs the test.
+    fn command(&self) -> RunCommand
+    {
+        RunCommand{
+            inputs: self.inputs.clone(),
+            outputs: Outputs::Lint,
+            optional_outputs: vec![],
+            output_groups: vec![],
+            program: self.program.clone(),
+            arguments: self.arguments.clone(),
+            environment: self.environment.clone(),
+            passthrough: vec![],
+            timeout: self.timeout,
+            warnings: None,
+            depfile: None,
+            log_paths: None,
+            resources: self.resources,
+            retry_policy: RetryPolicy::default(),
+            network: false,
+            writable_inputs: false,
+            allowed_syscalls: vec![],
+        }
+    }
+}
@@ -1,9 +1,13 @@
 use {
+    crate::encoding::encode_action,
     anyhow::Context,
     os_ext::{O_CREAT, O_WRONLY, cstring, openat},
-    snowflake_core::action::{
-        Action, InputPath, Outputs,
-        Perform, Result, Success,
+    snowflake_core::{
+        action::{
+            Action, EncodedAction, InputPath, Outputs,
+            Perform, Result, Success,
+        },
+        executor::{ReadExt, WriteExt},
     },
     snowflake_util::hash::{Blake3, Hash},
     std::{fs::File, io::Write},
@@ -20,6 +24,17 @@ pub struct WriteRegularFile
     pub executable: bool,
 }
 
+impl WriteRegularFile
+{
+    /// Decode an action encoded with [`Action::encode`].
+    pub fn decode(data: &mut &[u8]) -> anyhow::Result<Self>
+    {
+        let content = data.get_bytes()?;
+        let executable = data.get_bool()?;
+        Ok(Self{content, executable})
+    }
+}
+
 impl Action for WriteRegularFile
 {
     fn inputs(&self) -> usize
@@ -42,7 +57,11 @@ impl Action for WriteRegularFile
             .context("Open regular file")?;
         File::from(file).write_all(&self.content)
             .context("Write regular file")?;
-        Ok(Success{output_paths: vec![output_path], warnings: false})
+        Ok(Success{
+            output_paths: vec![output_path],
+            warnings: false,
+            dependencies: vec![],
+        })
     }
 
     fn hash(&self, input_hashes: &[Hash]) -> Hash
@@ -59,4 +78,13 @@ impl Action for WriteRegularFile
         h.put_bool(*executable);
         h.finalize()
     }
+
+    fn encode(&self) -> Option<EncodedAction>
+    {
+        let Self{content, executable} = self;
+        Some(encode_action("WriteRegularFile", |w| {
+            w.put_bytes(content)?;
+            w.put_bool(*executable)
+        }))
+    }
 }
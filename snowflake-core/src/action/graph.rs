@@ -1,7 +1,13 @@
 use {
     crate::label::{ActionLabel, ActionOutputLabel},
     super::{Action, ActionExt},
-    std::{collections::{HashMap, HashSet}, ffi::CString, fmt},
+    snowflake_util::workspace_path::WorkspacePath,
+    std::{
+        collections::{HashMap, HashSet},
+        ffi::{CStr, CString},
+        fmt,
+        io::{self, Write},
+    },
 };
 
 /// Action graph encoded as an adjacency list.
@@ -28,7 +34,15 @@ pub enum Input
     /// The path is interpreted to be relative to the [source root].
     ///
     /// [source root]: `crate::drive::Context::source_root`
-    StaticFile(CString),
+    StaticFile(WorkspacePath<CString>),
+
+    /// Regular file with the given contents.
+    ///
+    /// The driver writes the contents to the output cache,
+    /// so that small generated files, such as files with flags,
+    /// need no separate action. Like dependencies, such inputs
+    /// are hashed by their contents rather than by their path.
+    Inline(Vec<u8>),
 }
 
 impl Input
@@ -38,7 +52,7 @@ impl Input
     {
         match self {
             Self::Dependency(d) => Some(d),
-            Self::StaticFile(..) => None,
+            Self::StaticFile(..) | Self::Inline(..) => None,
         }
     }
 }
@@ -82,6 +96,61 @@ impl ActionGraph
         // Throw away all non-live actions.
         self.actions.retain(|k, _| live.contains(k));
     }
+
+    /// Find the output of an action with the given name.
+    ///
+    /// Returns [`None`] if there is no such action,
+    /// if the action does not [name its outputs][`Action::output_names`],
+    /// or if it has no output with the given name.
+    pub fn output_label(&self, action: &ActionLabel, name: &CStr)
+        -> Option<ActionOutputLabel>
+    {
+        let (action_, _) = self.actions.get(action)?;
+        let output = action_.output_names()?.iter()
+            .position(|n| n.as_c_str() == name)?;
+        Some(ActionOutputLabel{action: action.clone(), output})
+    }
+
+    /// Write a textual description of the action graph.
+    ///
+    /// Actions, inputs, and artifacts are written in sorted order,
+    /// one per line, so that the descriptions of two graphs can be diffed.
+    /// Each action is described by its [command hash], so changes
+    /// to commands can be told apart from changes to dependencies.
+    ///
+    /// [command hash]: `ActionExt::command_hash`
+    pub fn write_dump(&self, mut writer: impl Write) -> io::Result<()>
+    {
+        let mut actions: Vec<_> = self.actions.iter().collect();
+        actions.sort_by_key(|a| a.0);
+        for (label, (action, inputs)) in actions {
+            let kind = if action.is_lint() { "lint" } else { "action" };
+            writeln!(writer, "{kind} {label} {}", action.command_hash())?;
+            for (index, name) in action.output_names().iter().flatten()
+                .enumerate() {
+                writeln!(writer, "  output {index} {name:?}")?;
+            }
+            for (index, input) in inputs.iter().enumerate() {
+                match input {
+                    Input::Dependency(dependency) =>
+                        writeln!(writer, "  input {index} {dependency}")?,
+                    Input::StaticFile(path) =>
+                        writeln!(writer, "  input {index} static {path:?}")?,
+                    Input::Inline(contents) =>
+                        writeln!(writer, "  input {index} inline \"{}\"",
+                                 contents.escape_ascii())?,
+                }
+            }
+        }
+
+        let mut artifacts: Vec<_> = self.artifacts.iter().collect();
+        artifacts.sort();
+        for artifact in artifacts {
+            writeln!(writer, "artifact {artifact}")?;
+        }
+
+        Ok(())
+    }
 }
 
 impl fmt::Display for ActionGraph
@@ -117,3 +186,114 @@ impl fmt::Display for ActionGraph
         write!(f, "}}")
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::action::{self, InputPath, Outputs, Perform},
+        snowflake_util::{basename::Basename, hash::Hash},
+    };
+
+    /// Action that is never performed.
+    struct Dummy(usize, Outputs<usize>);
+
+    impl Action for Dummy
+    {
+        fn inputs(&self) -> usize { self.0 }
+        fn outputs(&self) -> Outputs<usize> { self.1 }
+        fn perform(&self, _: &Perform, _: &[InputPath]) -> action::Result
+            { unreachable!() }
+        fn hash(&self, _: &[Hash]) -> Hash { Hash([self.0 as u8; 32]) }
+    }
+
+    /// Action with named outputs that is never performed.
+    struct Named(&'static [&'static str]);
+
+    impl Action for Named
+    {
+        fn inputs(&self) -> usize { 0 }
+        fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(self.0.len()) }
+        fn output_names(&self) -> Option<Vec<Basename<CString>>>
+        {
+            let names = self.0.iter().map(|&n| CString::new(n).unwrap());
+            Some(names.map(|n| Basename::new(n).unwrap()).collect())
+        }
+        fn perform(&self, _: &Perform, _: &[InputPath]) -> action::Result
+            { unreachable!() }
+        fn hash(&self, _: &[Hash]) -> Hash { unreachable!() }
+    }
+
+    #[test]
+    fn output_label()
+    {
+        let labels = [ActionLabel{action: 0}, ActionLabel{action: 1}];
+        let graph = ActionGraph{
+            actions: HashMap::from([
+                (labels[0].clone(), (
+                    Box::new(Named(&["a.o", "a.d"])) as Box<dyn Action>,
+                    vec![],
+                )),
+                (labels[1].clone(), (
+                    Box::new(Dummy(0, Outputs::Outputs(1))),
+                    vec![],
+                )),
+            ]),
+            artifacts: HashSet::new(),
+        };
+        let name = |n| CString::new(n).unwrap();
+
+        let label = graph.output_label(&labels[0], &name("a.d"));
+        assert_eq!(label, Some(ActionOutputLabel{action: labels[0].clone(),
+                                                 output: 1}));
+        assert_eq!(graph.output_label(&labels[0], &name("a.c")), None);
+        assert_eq!(graph.output_label(&labels[1], &name("0")), None);
+        assert_eq!(graph.output_label(&ActionLabel{action: 2}, &name("a.o")),
+                   None);
+    }
+
+    #[test]
+    fn write_dump()
+    {
+        let output = |action, output| ActionOutputLabel{
+            action: ActionLabel{action},
+            output,
+        };
+        let graph = ActionGraph{
+            actions: HashMap::from([
+                (ActionLabel{action: 1}, (
+                    Box::new(Dummy(2, Outputs::Lint)) as Box<dyn Action>,
+                    vec![
+                        Input::Dependency(output(0, 1)),
+                        Input::Inline(b"a\"b\n".to_vec()),
+                    ],
+                )),
+                (ActionLabel{action: 0}, (
+                    Box::new(Dummy(1, Outputs::Outputs(2))),
+                    vec![Input::StaticFile(
+                        WorkspacePath::try_from("src").unwrap(),
+                    )],
+                )),
+            ]),
+            artifacts: HashSet::from([output(0, 1), output(0, 0)]),
+        };
+
+        let mut dump = Vec::new();
+        graph.write_dump(&mut dump).unwrap();
+        assert_eq!(
+            String::from_utf8(dump).unwrap(),
+            format!(
+                "action #0 {}\n  \
+                   input 0 static \"src\"\n\
+                 lint #1 {}\n  \
+                   input 0 #0|1\n  \
+                   input 1 inline \"a\\\"b\\n\"\n\
+                 artifact #0|0\n\
+                 artifact #0|1\n",
+                Dummy(1, Outputs::Outputs(2)).command_hash(),
+                Dummy(2, Outputs::Lint).command_hash(),
+            ),
+        );
+    }
+}
@@ -3,7 +3,8 @@
 pub use self::{graph::*, outputs::*};
 
 use {
-    snowflake_util::hash::Hash,
+    serde::{Deserialize, Serialize},
+    snowflake_util::{basename::Basename, hash::{Blake3, Hash}},
     std::{
         borrow::Cow,
         ffi::{CStr, CString},
@@ -17,8 +18,22 @@ use {
 mod graph;
 mod outputs;
 
+/// Version of the way actions are hashed.
+///
+/// This is mixed into the hash of every action by the driver
+/// (see [`ActionExt::versioned_hash`]), so that implementations
+/// of [`Action::hash`] need not do so themselves.
+/// Increment it whenever a change to hashing could make different
+/// actions collide with hashes computed by an earlier version,
+/// or when the format of the caches changes incompatibly.
+/// Entries from earlier versions are then simply never looked up.
+pub const HASH_VERSION: u64 = 1;
+
 /// Object-safe trait for actions.
-pub trait Action
+///
+/// Actions must be [`Sync`] because the driver
+/// performs independent actions concurrently.
+pub trait Action: Sync
 {
     /// The number of inputs to this action.
     fn inputs(&self) -> usize;
@@ -26,6 +41,20 @@ pub trait Action
     /// The number of outputs of this action.
     fn outputs(&self) -> Outputs<usize>;
 
+    /// The names of the outputs of this action.
+    ///
+    /// Outputs are identified by index, but if they are named,
+    /// they can also be looked up by name with [`ActionGraph::output_label`]
+    /// and they are materialized under their names.
+    /// If names are given, there must be one per output, in order,
+    /// and they must be unique. Because the names affect where outputs
+    /// are materialized, they must be reflected in the hash.
+    /// By default, outputs are not named.
+    fn output_names(&self) -> Option<Vec<Basename<CString>>>
+    {
+        None
+    }
+
     /// Perform the action.
     ///
     /// This method takes paths to inputs and produces outputs.
@@ -45,7 +74,151 @@ pub trait Action
     ///
     /// The number of input hashes must equal [`inputs`][`Self::inputs`]
     /// and their order must match that of the inputs in [`ActionGraph`].
+    /// The hash should start with the name of the type of action,
+    /// so that different types of actions cannot collide.
+    /// The driver does not use this hash directly;
+    /// it uses [`ActionExt::versioned_hash`] instead.
     fn hash(&self, input_hashes: &[Hash]) -> Hash;
+
+    /// Inputs of which the action only uses some files.
+    ///
+    /// The contents of these inputs are not passed to [`hash`].
+    /// Instead, performing the action reports which files it used
+    /// within these inputs (see [`Success::dependencies`]),
+    /// and later builds look up the action in the action cache
+    /// using the contents of only those files.
+    /// By default, the action uses all of every input.
+    ///
+    /// [`hash`]: `Self::hash`
+    fn partial_inputs(&self) -> Vec<usize>
+    {
+        Vec::new()
+    }
+
+    /// The resources the action needs while it is being performed.
+    ///
+    /// The driver does not perform actions concurrently
+    /// if together they would need more resources than available.
+    /// By default, the action needs a single CPU.
+    fn resources(&self) -> Resources
+    {
+        Resources::default()
+    }
+
+    /// When to perform the action again after it failed.
+    ///
+    /// By default, the action is performed only once.
+    fn retry_policy(&self) -> RetryPolicy
+    {
+        RetryPolicy::default()
+    }
+
+    /// Outputs that the action may or may not produce.
+    ///
+    /// Some tools produce certain files only under some circumstances.
+    /// If such an output is missing after performing the action,
+    /// the action still succeeds, and the output is recorded as absent.
+    /// Actions that depend on an absent output fail.
+    /// Because this affects whether the action succeeds,
+    /// it must be reflected in the hash.
+    /// By default, every output must be produced.
+    fn optional_outputs(&self) -> Vec<usize>
+    {
+        Vec::new()
+    }
+
+    /// Named groups of outputs of this action.
+    ///
+    /// Groups let users request that only some outputs are materialized,
+    /// for example only debug information or only coverage reports.
+    /// An output may be in any number of groups.
+    /// Groups do not affect the outputs, so they need not be hashed.
+    /// By default, the action has no groups.
+    fn output_groups(&self) -> Vec<OutputGroup>
+    {
+        Vec::new()
+    }
+
+    /// Which outputs remain executable when they are cached.
+    ///
+    /// Before caching, the permissions of outputs are normalized.
+    /// Files in outputs not selected by this method lose their execute bits.
+    /// Because this affects the outputs, it must be reflected in the hash.
+    /// By default, every output may contain executable files.
+    fn executable_outputs(&self) -> ExecutableOutputs
+    {
+        ExecutableOutputs::All
+    }
+
+    /// Whether the action is a test.
+    ///
+    /// The results of tests are recorded even if they fail,
+    /// so that failing tests are not performed again
+    /// until the action hash changes.
+    /// Tests are usually [lint actions][`Outputs::Lint`].
+    fn is_test(&self) -> bool
+    {
+        false
+    }
+
+    /// Files that describe how the action is performed.
+    ///
+    /// When the action fails, these files are included in its
+    /// failure report, so that the failure can be reproduced
+    /// without access to the action graph, such as the command line
+    /// and environment of a command. Each file has a name and contents.
+    /// The names `error`, `inputs`, and `build.log` are reserved.
+    /// By default, there are no such files.
+    fn report_files(&self) -> Vec<(&'static str, Vec<u8>)>
+    {
+        Vec::new()
+    }
+
+    /// The elements of the command of the action, each with a name.
+    ///
+    /// These are recorded with every build, so that when the command
+    /// changes, the elements can be compared by name to explain
+    /// exactly what changed, such as `argument 2` or `environment PATH`.
+    /// Names must be unique. Values are for display only;
+    /// they need not cover everything that is hashed.
+    /// By default, the command has no elements.
+    fn command_elements(&self) -> Vec<(String, String)>
+    {
+        Vec::new()
+    }
+
+    /// Start an interactive shell in the environment of the action.
+    ///
+    /// This is for debugging actions that fail, such as compiler
+    /// invocations. The shell runs in the scratch directory
+    /// with the inputs staged as they would be for [`perform`],
+    /// and uses the standard streams of the calling process.
+    /// There is no build log and any outputs are discarded.
+    /// Returns once the shell exits, with an error if it failed.
+    /// By default, the action cannot be replayed.
+    ///
+    /// [`perform`]: `Self::perform`
+    fn replay(&self, scratch: BorrowedFd, input_paths: &[InputPath])
+        -> std::result::Result<(), Error>
+    {
+        let _ = (scratch, input_paths);
+        Err(Error::NotReplayable)
+    }
+
+    /// Encode the action so that it can be performed elsewhere.
+    ///
+    /// The [remote executor] sends the encoded action to a build server,
+    /// which decodes it and performs the decoded action instead.
+    /// Decoding the encoded action must yield an action
+    /// that is performed the same way and has the same hash.
+    /// By default, the action cannot be encoded,
+    /// and the remote executor performs it locally.
+    ///
+    /// [remote executor]: `crate::executor::RemoteExecutor`
+    fn encode(&self) -> Option<EncodedAction>
+    {
+        None
+    }
 }
 
 /// Extra methods for actions.
@@ -53,6 +226,18 @@ pub trait ActionExt
 {
     /// Whether the action is a lint action.
     fn is_lint(&self) -> bool;
+
+    /// Compute the hash of the action, combined with [`HASH_VERSION`].
+    ///
+    /// This is the key into the action cache.
+    fn versioned_hash(&self, input_hashes: &[Hash]) -> Hash;
+
+    /// Compute the versioned hash of the action
+    /// as if all its inputs were the same.
+    ///
+    /// This identifies the command of the action regardless of its inputs,
+    /// which makes it possible to tell which of the two changed.
+    fn command_hash(&self) -> Hash;
 }
 
 impl<T> ActionExt for T
@@ -62,6 +247,20 @@ impl<T> ActionExt for T
     {
         matches!(self.outputs(), Outputs::Lint)
     }
+
+    fn versioned_hash(&self, input_hashes: &[Hash]) -> Hash
+    {
+        // NOTE: See the manual chapter on avoiding hash collisions.
+        let mut h = Blake3::new();
+        h.put_u64(HASH_VERSION);
+        h.put_hash(self.hash(input_hashes));
+        h.finalize()
+    }
+
+    fn command_hash(&self) -> Hash
+    {
+        self.versioned_hash(&vec![Hash([0; 32]); self.inputs()])
+    }
 }
 
 /// Environment in which an action is to be performed.
@@ -72,6 +271,141 @@ pub struct Perform<'a>
 
     /// Scratch directory which the action may use freely.
     pub scratch: BorrowedFd<'a>,
+
+    /// File descriptor that becomes readable when the build is cancelled.
+    ///
+    /// Actions that take a long time should poll this,
+    /// and stop with [`Error::Cancelled`] when it becomes readable.
+    pub cancel: Option<BorrowedFd<'a>>,
+
+    /// File to copy the build log to while the action is performed.
+    ///
+    /// This allows watching the progress of an action as it happens.
+    /// The build log must still be written to [`build_log`][`Self::build_log`].
+    /// Actions that produce output gradually should honor this;
+    /// other actions may ignore it.
+    pub stream_log: Option<BorrowedFd<'a>>,
+}
+
+/// Resources needed by an action while it is being performed.
+///
+/// These are used only for scheduling and are not enforced.
+/// They do not contribute to the hash of the action.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Resources
+{
+    /// The number of CPUs the action keeps busy.
+    pub cpus: u32,
+
+    /// The number of bytes of memory the action uses at most.
+    pub memory: u64,
+
+    /// Whether no other actions may be performed concurrently.
+    ///
+    /// This is useful for actions that are very heavy,
+    /// or that use some system-wide resource.
+    pub exclusive: bool,
+}
+
+impl Default for Resources
+{
+    fn default() -> Self
+    {
+        Self{cpus: 1, memory: 0, exclusive: false}
+    }
+}
+
+/// When to perform an action again after it failed.
+///
+/// This is meant for actions that fail for reasons outside their control,
+/// such as actions that access the network.
+/// It does not contribute to the hash of the action.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy
+{
+    /// How many times the action is performed at most.
+    pub max_attempts: u32,
+
+    /// The exit codes after which the action is performed again.
+    ///
+    /// If [`None`], the action is performed again after any failure.
+    pub exit_codes: Option<Vec<i32>>,
+}
+
+impl RetryPolicy
+{
+    /// Whether to perform the action again after the given failed attempt.
+    ///
+    /// Attempts are numbered starting from one.
+    pub fn retries(&self, attempt: u32, error: &Error) -> bool
+    {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+        match (&self.exit_codes, error) {
+            (None, _) => true,
+            (Some(exit_codes), Error::ExitStatus(status)) =>
+                status.code().map_or(false, |c| exit_codes.contains(&c)),
+            (Some(_), _) => false,
+        }
+    }
+}
+
+impl Default for RetryPolicy
+{
+    fn default() -> Self
+    {
+        Self{max_attempts: 1, exit_codes: None}
+    }
+}
+
+/// Which outputs of an action remain executable when they are cached.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ExecutableOutputs
+{
+    /// Every output may contain executable files.
+    All,
+
+    /// Only the outputs with the given indices may contain executable files.
+    Only(Vec<usize>),
+}
+
+impl ExecutableOutputs
+{
+    /// Whether the output with the given index may contain executable files.
+    pub fn contains(&self, index: usize) -> bool
+    {
+        match self {
+            Self::All => true,
+            Self::Only(indices) => indices.contains(&index),
+        }
+    }
+}
+
+/// Named group of outputs of an action.
+///
+/// See [`Action::output_groups`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OutputGroup
+{
+    /// The name of the group.
+    pub name: String,
+
+    /// The indices of the outputs in the group.
+    pub outputs: Vec<usize>,
+}
+
+/// Action encoded so that it can be performed elsewhere.
+///
+/// See [`Action::encode`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EncodedAction
+{
+    /// The type of the action, which tells how to decode it.
+    pub kind: String,
+
+    /// The fields of the action, in a format that depends on the type.
+    pub data: Vec<u8>,
 }
 
 /// Path to an input and the directory to which it is relative.
@@ -108,6 +442,26 @@ pub struct Success
     /// See the manual entry on warnings for
     /// the implications of setting this flag.
     pub warnings: bool,
+
+    /// Files used by the action within its [partial inputs].
+    ///
+    /// This must be empty if the action has no partial inputs.
+    ///
+    /// [partial inputs]: `Action::partial_inputs`
+    pub dependencies: Vec<Dependency>,
+}
+
+/// File used by an action within one of its partial inputs.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct Dependency
+{
+    /// The index of the input.
+    pub input: usize,
+
+    /// The path to the file relative to the input.
+    ///
+    /// If [`None`], the file is the input itself.
+    pub path: Option<CString>,
 }
 
 /// Error returned during performing of an action.
@@ -118,9 +472,56 @@ pub enum Error
     #[error("Timeout after {0:?}")]
     Timeout(Duration),
 
+    #[error("Cancelled")]
+    Cancelled,
+
     #[error("{0}")]
     ExitStatus(#[from] ExitStatusError),
 
+    #[error("Output has hash {actual}, but hash {expected} was expected")]
+    HashMismatch{expected: Hash, actual: Hash},
+
+    #[error("Action used {0:?}, which is not within any of its inputs")]
+    UndeclaredDependency(CString),
+
+    #[error("Action cannot be replayed")]
+    NotReplayable,
+
     #[error("Unexpected error: {0}")]
     Unexpected(#[from] anyhow::Error),
 }
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        std::{os::unix::process::ExitStatusExt, process::ExitStatus},
+    };
+
+    #[test]
+    fn retry_policy()
+    {
+        let exit_status = |code| Error::ExitStatus(
+            ExitStatus::from_raw(code << 8).exit_ok().unwrap_err()
+        );
+        let timeout = Error::Timeout(Duration::from_secs(1));
+
+        // By default, actions are not retried.
+        let policy = RetryPolicy::default();
+        assert!(!policy.retries(1, &exit_status(1)));
+
+        // Without exit codes, any failure is retried.
+        let policy = RetryPolicy{max_attempts: 3, exit_codes: None};
+        assert!(policy.retries(1, &exit_status(1)));
+        assert!(policy.retries(2, &timeout));
+        assert!(!policy.retries(3, &timeout));
+
+        // With exit codes, only those exit codes are retried.
+        let policy = RetryPolicy{max_attempts: 3, exit_codes: Some(vec![7])};
+        assert!(policy.retries(1, &exit_status(7)));
+        assert!(!policy.retries(1, &exit_status(1)));
+        assert!(!policy.retries(1, &timeout));
+        assert!(!policy.retries(3, &exit_status(7)));
+    }
+}
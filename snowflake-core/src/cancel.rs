@@ -0,0 +1,138 @@
+//! Cancelling builds that are in progress.
+
+use {
+    os_ext::{
+        SIG_BLOCK,
+        pipe2, pthread_sigmask, sigaddset, sigemptyset,
+        signalfd, signalfd_read,
+    },
+    std::{
+        io,
+        os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
+        sync::atomic::{AtomicI32, Ordering::SeqCst},
+        thread,
+    },
+};
+
+/// The most recent signal that triggered a cancellation, or zero.
+static SIGNAL_RECEIVED: AtomicI32 = AtomicI32::new(0);
+
+/// Request to stop a build that is in progress.
+///
+/// Once cancelled, the driver stops starting new actions,
+/// and actions that are being performed are asked to stop.
+/// Actions find out about cancellation by polling
+/// [the file descriptor][`AsFd::as_fd`], which becomes readable
+/// when cancelled and remains readable afterwards.
+pub struct Cancel
+{
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Cancel
+{
+    /// Create a cancellation that is not cancelled yet.
+    pub fn new() -> io::Result<Self>
+    {
+        let (read, write) = pipe2(libc::O_NONBLOCK)?;
+        Ok(Self{read, write})
+    }
+
+    /// Cancel the build.
+    ///
+    /// Cancelling more than once has no further effect.
+    pub fn cancel(&self)
+    {
+        // If the pipe is full, the build was already cancelled,
+        // so the error can be ignored.
+        // SAFETY: The buffer is valid for reads of one byte.
+        unsafe {
+            libc::write(self.write.as_raw_fd(), [1u8].as_ptr().cast(), 1);
+        }
+    }
+
+    /// Whether the build was cancelled.
+    pub fn is_cancelled(&self) -> bool
+    {
+        let mut pollfd = libc::pollfd{
+            fd: self.read.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: pollfd is a valid array of one pollfd.
+        let result = unsafe { libc::poll(&mut pollfd, 1, 0) };
+        result == 1
+    }
+
+    /// Cancel the build when the process receives SIGINT or SIGTERM.
+    ///
+    /// The signals are blocked and consumed from a signalfd
+    /// by a background thread, so no signal handlers are installed.
+    /// This must be called before any other threads are spawned,
+    /// because only threads spawned afterwards inherit the signal mask.
+    /// Use [`received_signal`] to find out which signal was received.
+    pub fn cancel_on_signals(&'static self) -> io::Result<()>
+    {
+        let mut mask = sigemptyset();
+        sigaddset(&mut mask, libc::SIGINT)?;
+        sigaddset(&mut mask, libc::SIGTERM)?;
+        pthread_sigmask(SIG_BLOCK, Some(&mask))?;
+        let signalfd = signalfd(&mask, 0)?;
+
+        thread::Builder::new()
+            .name("signals".into())
+            .spawn(move || {
+                loop {
+                    match signalfd_read(signalfd.as_fd()) {
+                        Ok(info) => {
+                            let signal = info.ssi_signo as libc::c_int;
+                            SIGNAL_RECEIVED.store(signal, SeqCst);
+                            self.cancel();
+                        },
+                        Err(err) if err.kind() == io::ErrorKind::Interrupted =>
+                            continue,
+                        Err(_) => return,
+                    }
+                }
+            })?;
+
+        Ok(())
+    }
+}
+
+impl AsFd for Cancel
+{
+    fn as_fd(&self) -> BorrowedFd
+    {
+        self.read.as_fd()
+    }
+}
+
+/// The signal that cancelled the build, if any.
+///
+/// See [`Cancel::cancel_on_signals`].
+pub fn received_signal() -> Option<libc::c_int>
+{
+    match SIGNAL_RECEIVED.load(SeqCst) {
+        0 => None,
+        signal => Some(signal),
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn cancel()
+    {
+        let cancel = Cancel::new().unwrap();
+        assert!(!cancel.is_cancelled());
+        cancel.cancel();
+        assert!(cancel.is_cancelled());
+        cancel.cancel();
+        assert!(cancel.is_cancelled());
+    }
+}
@@ -2,17 +2,45 @@
 
 use {
     crate::{
-        action::{self, Action, ActionGraph, Input, InputPath, Perform, Success},
-        label::ActionLabel,
-        state::{ActionCacheEntry, CacheOutputError, State},
+        action::{
+            self, Action, ActionExt, ActionGraph, Dependency,
+            Input, InputPath, Perform, Resources, Success,
+        },
+        cancel::Cancel,
+        executor::Executor,
+        fs_util::read_entries,
+        label::{ActionLabel, ActionOutputLabel},
+        output_tree::sync_file_at,
+        profile::{Profile, SCHEDULER_LANE},
+        state::{
+            ActionCacheEntry, ActionRecord, CacheOutputError, InputDigest,
+            State, TestResult, TestStatus,
+        },
     },
     anyhow::{Context as _},
-    os_ext::{O_RDWR, O_TMPFILE, cstr, openat},
-    snowflake_util::hash::{Hash, hash_file_at},
+    os_ext::{
+        AT_SYMLINK_NOFOLLOW,
+        O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_RDONLY, O_WRONLY,
+        S_IFMT, S_IFREG,
+        cstr, cstr::CStrExt, fstatat, openat,
+    },
+    snowflake_util::{
+        ansi::contains_ansi,
+        hash::{Blake3, Hash, hash_file_at, hash_regular_file},
+    },
     std::{
         borrow::Cow,
-        collections::HashMap,
+        collections::{BTreeSet, HashMap},
+        ffi::{CStr, CString},
+        fmt,
+        fs::File,
+        io::{self, ErrorKind::NotFound, Read, Seek, Write},
+        mem,
         os::unix::io::{AsFd, BorrowedFd, OwnedFd},
+        panic::{self, AssertUnwindSafe},
+        sync::{Condvar, Mutex, mpsc},
+        thread,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     },
     thiserror::Error,
 };
@@ -25,6 +53,80 @@ pub struct Context<'a>
 
     /// The directory that static file inputs are relative to.
     pub source_root: BorrowedFd<'a>,
+
+    /// The executor that performs the actions.
+    pub executor: &'a dyn Executor,
+
+    /// The resources available for performing actions concurrently.
+    pub capacity: Capacity,
+
+    /// Whether to perform each action twice and compare the outputs.
+    ///
+    /// Actions that are not deterministic poison the caches,
+    /// so this is useful for finding them before that happens.
+    /// If the outputs of the two runs differ, the action fails.
+    pub check_determinism: bool,
+
+    /// How many times to perform each test.
+    ///
+    /// Tests that succeed only some of the times are reported as flaky.
+    /// Tests are also performed again when they fail
+    /// if their [retry policy] allows it, which is also counted.
+    ///
+    /// [retry policy]: `Action::retry_policy`
+    pub runs_per_test: u32,
+
+    /// Allows the build to be stopped while it is in progress.
+    ///
+    /// When cancelled, no further actions are started,
+    /// and actions that are not started fail with [`BuildError::Cancelled`].
+    pub cancel: Option<&'a Cancel>,
+
+    /// Where to record timings of the build, if anywhere.
+    pub profile: Option<&'a Profile>,
+
+    /// Action whose build log to copy to a file while it is performed.
+    ///
+    /// This is useful for debugging an action interactively,
+    /// such as a test that hangs. See [`Perform::stream_log`].
+    pub stream_log: Option<(&'a ActionLabel, BorrowedFd<'a>)>,
+
+    /// Whether to write a failure report when an action fails.
+    ///
+    /// The failure report is a directory in the state directory
+    /// with the error, the inputs and their hashes, the build log,
+    /// and the [files that describe the action][`Action::report_files`].
+    /// It can be attached to bug reports and used to reproduce the failure.
+    /// Its path is passed along in [`BuildEvent::FailureReport`].
+    pub failure_reports: bool,
+
+    /// Called for each event that happens during the build.
+    ///
+    /// Because actions are built concurrently,
+    /// this may be called from multiple threads.
+    pub events: &'a (dyn Fn(BuildEvent) + Sync),
+}
+
+/// Resources available for performing actions concurrently.
+///
+/// See [`Action::resources`] for how actions use these.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug)]
+pub struct Capacity
+{
+    pub cpus: u32,
+    pub memory: u64,
+}
+
+impl Capacity
+{
+    /// All the CPUs available to this process, and unlimited memory.
+    pub fn available() -> Self
+    {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        let cpus = cpus.try_into().unwrap_or(u32::MAX);
+        Self{cpus, memory: u64::MAX}
+    }
 }
 
 /// Error that occurs whilst building a collection of actions.
@@ -32,9 +134,11 @@ pub struct Context<'a>
 #[derive(Debug, Error)]
 pub enum DriveError
 {
-    #[error("There are actions that cyclically depend on each other")]
-    // TODO: Which actions?
-    CyclicDependency,
+    /// Each action in the cycle depends on the next,
+    /// and the last action depends on the first.
+    #[error("There are actions that cyclically depend on each other: {}",
+            display_cycle(.0))]
+    CyclicDependency(Vec<ActionLabel>),
 
     #[error("There is an action that depends on a missing action")]
     // TODO: Which actions?
@@ -52,6 +156,51 @@ pub enum BuildError
     #[error("{0}")]
     CacheOutput(#[from] CacheOutputError),
 
+    #[error("Action is not deterministic; these outputs differed \
+             between two runs: {}", display_paths(.0))]
+    Nondeterministic(Vec<CString>),
+
+    #[error("Action is not deterministic; it failed when run again: {0}")]
+    NondeterministicFailure(action::Error),
+
+    /// The test failed when it was last performed,
+    /// and it has not changed since.
+    #[error("{0}")]
+    CachedTestFailure(String),
+
+    #[error("Dependency {0} did not produce this optional output")]
+    AbsentOutput(ActionOutputLabel),
+
+    #[error("Identical action {0} failed")]
+    IdenticalActionFailed(ActionLabel),
+
+    #[error("Build was cancelled")]
+    Cancelled,
+
+    #[error("Unexpected error: {0}")]
+    Unexpected(#[from] anyhow::Error),
+}
+
+/// Error that occurs whilst replaying an action.
+#[allow(missing_docs)]
+#[derive(Debug, Error)]
+pub enum ReplayError
+{
+    #[error("{0}")]
+    Drive(#[from] DriveError),
+
+    #[error("Action {0} is not part of the build")]
+    NoSuchAction(ActionLabel),
+
+    #[error("Dependency {0} is not built; build it first")]
+    DependencyNotBuilt(ActionLabel),
+
+    #[error("{0}")]
+    Build(#[from] BuildError),
+
+    #[error("{0}")]
+    Perform(#[from] action::Error),
+
     #[error("Unexpected error: {0}")]
     Unexpected(#[from] anyhow::Error),
 }
@@ -82,112 +231,546 @@ pub enum Outcome<'a>
     Skipped{failed_dependency: &'a ActionLabel},
 }
 
-/// Build all actions in an action graph.
-pub fn drive<'a>(context: &Context, graph: &'a ActionGraph)
-    -> Result<HashMap<&'a ActionLabel, Outcome<'a>>, DriveError>
+/// The outcome of a dry run of an action.
+#[allow(missing_docs)]
+#[derive(Debug)]
+pub enum DryRunOutcome<'a>
 {
-    let linear = prepare(graph)?;
+    /// The action is in the action cache, so it would not be performed.
+    CacheHit{cache_entry: ActionCacheEntry},
 
-    let mut outcomes = HashMap::new();
+    /// The action is not in the action cache, so it would be performed.
+    Perform{reason: PerformReason<'a>},
+}
 
-    for (label, action, inputs) in linear {
-        let outcome = build(context, &outcomes, action, inputs);
-        outcomes.insert(label, outcome);
+/// Why a dry run found that an action would be performed.
+#[allow(missing_docs)]
+#[derive(Debug)]
+pub enum PerformReason<'a>
+{
+    /// There is no record of the action ever being built.
+    NeverBuilt,
+
+    /// The command of the action changed since it was last built.
+    ///
+    /// The elements of the command that changed are included.
+    /// If none are, the change is in a part of the command
+    /// that is not described by [`Action::command_elements`].
+    ChangedCommand{elements: Vec<ChangedElement>},
+
+    /// Inputs of the action changed since it was last built.
+    ChangedInputs{inputs: Vec<ChangedInput>},
+
+    /// A dependency would be performed, so its outputs are not known yet.
+    Dependency{dependency: &'a ActionLabel},
+
+    /// Neither the command nor the inputs changed since the last build,
+    /// yet the action is not cached, for example because it failed,
+    /// or because a file it used within a partial input changed.
+    NotCached,
+}
+
+impl fmt::Display for PerformReason<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Self::NeverBuilt =>
+                write!(f, "never built"),
+            Self::ChangedCommand{..} =>
+                write!(f, "changed command"),
+            Self::ChangedInputs{inputs} => {
+                write!(f, "changed input")?;
+                if inputs.len() != 1 {
+                    write!(f, "s")?;
+                }
+                for (i, ChangedInput{index, ..}) in inputs.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{separator}{index}")?;
+                }
+                Ok(())
+            },
+            Self::Dependency{dependency} =>
+                write!(f, "dependency {dependency} would be performed"),
+            Self::NotCached =>
+                write!(f, "not cached"),
+        }
     }
+}
 
-    Ok(outcomes)
+/// Element of the command of an action that changed since the last build.
+///
+/// See [`Action::command_elements`].
+#[allow(missing_docs)]
+#[derive(Debug, Eq, PartialEq)]
+pub struct ChangedElement
+{
+    pub name: String,
+
+    /// The value at the last build, or [`None`] if it was added.
+    pub previous: Option<String>,
+
+    /// The current value, or [`None`] if it was removed.
+    pub current: Option<String>,
 }
 
-/// Topologically sort the action graph.
-fn prepare(graph: &ActionGraph)
-    -> Result<Vec<(&ActionLabel, &dyn Action, &[Input])>, DriveError>
+/// Input of an action that changed since the last build.
+#[allow(missing_docs)]
+#[derive(Debug, Eq, PartialEq)]
+pub struct ChangedInput
 {
-    fn toposort<'a>(
-        linear: &mut Vec<(&'a ActionLabel, &'a dyn Action, &'a [Input])>,
-        // The state table keeps track of visited actions.
-        // An false entry means the action is currently being visited.
-        // A true entry means the action was visited in the past.
-        // These states are used for detecting cycles
-        // and avoiding duplicates respectively.
-        state: &mut HashMap<&'a ActionLabel, bool>,
-        graph: &'a ActionGraph,
-        label: &'a ActionLabel,
-    ) -> Result<(), DriveError>
+    pub index: usize,
+    pub previous: Hash,
+    pub current: Hash,
+}
+
+/// Summary of what happened during a build.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Statistics
+{
+    /// The number of actions that were performed.
+    pub performed: usize,
+
+    /// The number of actions that were found in the action cache.
+    pub action_cache_hits: usize,
+
+    /// The number of outputs and build logs that were performed,
+    /// but that turned out to be in the output cache already.
+    pub output_cache_hits: u64,
+
+    pub failed: usize,
+    pub skipped: usize,
+
+    /// How long the build took.
+    pub wall_time: Duration,
+}
+
+impl Statistics
+{
+    /// Compute statistics from the outcomes of a build.
+    ///
+    /// Output cache hits are not evident from the outcomes,
+    /// so they must be counted separately; see [`State::output_cache_hits`].
+    pub fn new(
+        outcomes:          &HashMap<&ActionLabel, Outcome>,
+        output_cache_hits: u64,
+        wall_time:         Duration,
+    ) -> Self
     {
-        match state.get(label) {
-            Some(false) => Err(DriveError::CyclicDependency),
-            Some(true)  => Ok(()),
-            None =>
-                if let Some((action, inputs)) = graph.actions.get(label) {
-                    state.insert(label, false);
-                    for input in inputs.iter().flat_map(Input::dependency) {
-                        toposort(linear, state, graph, &input.action)?;
+        let mut statistics = Self{
+            output_cache_hits,
+            wall_time,
+            ..Self::default()
+        };
+        for outcome in outcomes.values() {
+            match outcome {
+                Outcome::Success{cache_hit: true, ..} =>
+                    statistics.action_cache_hits += 1,
+                Outcome::Success{cache_hit: false, ..} =>
+                    statistics.performed += 1,
+                Outcome::Failed{build_log, error} => {
+                    // Without a build log, the action was not performed.
+                    // Cached test failures have the build log of the test.
+                    let cached =
+                        matches!(error, BuildError::CachedTestFailure(..));
+                    if build_log.is_some() && !cached {
+                        statistics.performed += 1;
                     }
-                    state.insert(label, true);
-                    linear.push((label, &**action, inputs));
-                    Ok(())
-                } else {
-                    Err(DriveError::DanglingDependency)
+                    statistics.failed += 1;
                 },
+                Outcome::Skipped{..} =>
+                    statistics.skipped += 1,
+            }
         }
+        statistics
     }
+}
 
-    let mut linear = Vec::new();
-    let mut state = HashMap::new();
-    for action in graph.actions.keys() {
-        toposort(&mut linear, &mut state, graph, action)?;
+impl fmt::Display for Statistics
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let Self{performed, action_cache_hits, output_cache_hits,
+                 failed, skipped, wall_time} = self;
+        write!(f, "{performed} performed, \
+                   {action_cache_hits} action cache hits, \
+                   {output_cache_hits} output cache hits, \
+                   {failed} failed, {skipped} skipped, \
+                   in {:.2}s", wall_time.as_secs_f64())
     }
-    Ok(linear)
 }
 
-/// Build an action.
-fn build<'a>(
-    context:  &Context,
-    outcomes: &HashMap<&ActionLabel, Outcome<'a>>,
-    action:   &dyn Action,
-    inputs:   &'a [Input],
-) -> Outcome<'a>
+/// Something that happened during a build.
+#[allow(missing_docs)]
+#[derive(Debug)]
+pub enum BuildEvent<'a>
 {
-    match build_inner(context, outcomes, action, inputs) {
-        Ok(outcome) => outcome,
-        Err(error) => Outcome::Failed{build_log: None, error},
+    /// An action was performed.
+    ///
+    /// Attempts are numbered starting from one.
+    /// If the attempt failed and the [retry policy] of the action
+    /// allows it, the action will be performed again.
+    ///
+    /// [retry policy]: `Action::retry_policy`
+    Attempt{
+        label: &'a ActionLabel,
+        attempt: u32,
+        build_log: Hash,
+        error: Option<&'a action::Error>,
+        retry: bool,
+    },
+
+    /// The result of a test is known.
+    ///
+    /// If the result is cached, the test was not performed.
+    TestResult{
+        label: &'a ActionLabel,
+        result: &'a TestResult,
+        cached: bool,
+    },
+
+    /// The action is identical to another action in the build.
+    ///
+    /// Only the other action is built,
+    /// and this action receives its result.
+    Merged{
+        label: &'a ActionLabel,
+        original: &'a ActionLabel,
+    },
+
+    /// A failure report was written for an action that failed.
+    ///
+    /// See [`Context::failure_reports`].
+    FailureReport{
+        label: &'a ActionLabel,
+        report: io::Result<CString>,
+    },
+
+    /// All actions were built, failed, or skipped.
+    ///
+    /// This is the final event of each call to [`drive`].
+    Finished{
+        statistics: Statistics,
+    },
+}
+
+impl fmt::Display for BuildEvent<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Self::Attempt{label, attempt, error: None, ..} =>
+                write!(f, "{label} attempt {attempt} succeeded"),
+            Self::Attempt{label, attempt, error: Some(error), retry, ..} => {
+                write!(f, "{label} attempt {attempt} failed: {error}")?;
+                if *retry { write!(f, ", retrying")?; }
+                Ok(())
+            },
+            Self::TestResult{label, result, cached} => {
+                let TestResult{status, duration, error, ..} = result;
+                let duration = duration.as_secs_f64();
+                write!(f, "{label} {status} in {duration:.2}s")?;
+                if let Some(error) = error { write!(f, ": {error}")?; }
+                if *cached { write!(f, " (cached)")?; }
+                Ok(())
+            },
+            Self::Merged{label, original} =>
+                write!(f, "{label} is identical to {original}, \
+                           sharing its result"),
+            Self::FailureReport{label, report: Ok(path)} =>
+                write!(f, "{label} failure report written to {}",
+                       path.to_string_lossy()),
+            Self::FailureReport{label, report: Err(error)} =>
+                write!(f, "{label} failure report could not be written: \
+                           {error}"),
+            Self::Finished{statistics} =>
+                write!(f, "Build finished: {statistics}"),
+        }
     }
 }
 
-fn build_inner<'a>(
+/// Build all actions in an action graph.
+///
+/// Actions whose dependencies have been built are built concurrently,
+/// as long as the [resources] they need fit in [`Context::capacity`].
+/// An action that needs more than the capacity is built on its own.
+/// Identical actions under different labels are built only once;
+/// see [`BuildEvent::Merged`].
+/// Once done, [`Statistics`] about the build are sent as the final event.
+///
+/// [resources]: `Action::resources`
+pub fn drive<'a>(context: &Context, graph: &'a ActionGraph)
+    -> Result<HashMap<&'a ActionLabel, Outcome<'a>>, DriveError>
+{
+    // The state directory counts output cache hits since it was opened,
+    // which may include those of earlier builds.
+    let start = Instant::now();
+    let output_cache_hits = context.state.output_cache_hits();
+    let linear = prepare(graph)?;
+    let mut scheduler = Scheduler::new(context.capacity, &linear);
+
+    let mut outcomes = HashMap::new();
+    let in_flight = InFlight::default();
+
+    // Each action being built occupies a lane in the profile.
+    // Lanes are reused, so they correspond to workers.
+    let mut lanes = Vec::new();
+
+    // The number of threads building actions, including those
+    // that wait for an identical action and so hold no resources.
+    let mut building = 0;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+
+        loop {
+            while !is_cancelled(context) {
+                let index = match scheduler.next() {
+                    Some(index) => index,
+                    None => break,
+                };
+                let (label, action, inputs) = linear[index];
+                let input_paths =
+                    collect_input_paths(context, &outcomes, inputs);
+                let input_paths = match input_paths {
+                    Ok(Ok(input_paths)) => input_paths,
+                    Ok(Err(failed_dependency)) => {
+                        let outcome = Outcome::Skipped{failed_dependency};
+                        scheduler.finish(index);
+                        outcomes.insert(label, outcome);
+                        profile_instant(context, SCHEDULER_LANE, "scheduler",
+                                        || format!("{label} skipped"));
+                        continue;
+                    },
+                    Err(error) => {
+                        let outcome = Outcome::Failed{build_log: None, error};
+                        scheduler.finish(index);
+                        outcomes.insert(label, outcome);
+                        continue;
+                    },
+                };
+                let lane = match lanes.iter().position(|busy| !busy) {
+                    Some(lane) => { lanes[lane] = true; lane },
+                    None => { lanes.push(true); lanes.len() - 1 },
+                };
+                building += 1;
+                profile_running(context, &scheduler);
+                let sender = sender.clone();
+                let in_flight = &in_flight;
+                scope.spawn(move || {
+                    let merged = || {
+                        sender.send(Message::Merged{index}).unwrap();
+                    };
+                    // Panics are forwarded so the driver does not hang.
+                    let worker = Worker{in_flight, merged: &merged,
+                                        lane: lane + 1};
+                    let start = Instant::now();
+                    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+                        build(context, &worker, label, action,
+                              inputs, input_paths)
+                    }));
+                    profile_span(context, lane + 1, "action", start,
+                                 || label.to_string());
+                    let finished = Message::Finished{index, lane, outcome};
+                    sender.send(finished).unwrap();
+                });
+            }
+
+            if outcomes.len() == linear.len() {
+                break;
+            }
+
+            // Only cancellation leaves actions not started with none building.
+            if building == 0 {
+                for &(label, _, _) in &linear {
+                    outcomes.entry(label).or_insert_with(|| {
+                        let error = BuildError::Cancelled;
+                        Outcome::Failed{build_log: None, error}
+                    });
+                }
+                break;
+            }
+
+            let message = receiver.recv()
+                .expect("An action should be being built");
+            match message {
+                Message::Merged{index} => {
+                    scheduler.release(index);
+                    profile_running(context, &scheduler);
+                },
+                Message::Finished{index, lane, outcome} => {
+                    let outcome =
+                        outcome.unwrap_or_else(|p| panic::resume_unwind(p));
+                    scheduler.finish(index);
+                    building -= 1;
+                    lanes[lane] = false;
+                    profile_running(context, &scheduler);
+                    outcomes.insert(linear[index].0, outcome);
+                },
+            }
+        }
+    });
+
+    let output_cache_hits =
+        context.state.output_cache_hits() - output_cache_hits;
+    let statistics =
+        Statistics::new(&outcomes, output_cache_hits, start.elapsed());
+    (context.events)(BuildEvent::Finished{statistics});
+
+    Ok(outcomes)
+}
+
+/// Find out which actions in an action graph would be performed.
+///
+/// Like [`drive`], this computes action hashes and consults the caches,
+/// but it does not perform any actions and it does not modify the state.
+pub fn dry_run<'a>(context: &Context, graph: &'a ActionGraph)
+    -> Result<
+        HashMap<&'a ActionLabel, Result<DryRunOutcome<'a>, BuildError>>,
+        DriveError,
+    >
+{
+    let linear = prepare(graph)?;
+
+    let mut outcomes = HashMap::new();
+
+    for (label, action, inputs) in linear {
+        let outcome = dry_run_one(context, &outcomes, label, action, inputs);
+        outcomes.insert(label, outcome);
+    }
+
+    Ok(outcomes)
+}
+
+fn dry_run_one<'a>(
     context:  &Context,
-    outcomes: &HashMap<&ActionLabel, Outcome<'a>>,
+    outcomes: &HashMap<&ActionLabel, Result<DryRunOutcome<'a>, BuildError>>,
+    label:    &ActionLabel,
     action:   &dyn Action,
     inputs:   &'a [Input],
-) -> Result<Outcome<'a>, BuildError>
+) -> Result<DryRunOutcome<'a>, BuildError>
 {
-    let input_paths = collect_input_paths(context, outcomes, inputs)?;
+    let input_paths = cached_input_paths(context, outcomes, inputs, false)?;
     let input_paths = match input_paths {
         Ok(input_paths) => input_paths,
-        Err(fd) => return Ok(Outcome::Skipped{failed_dependency: fd}),
+        Err(dependency) => {
+            let reason = PerformReason::Dependency{dependency};
+            return Ok(DryRunOutcome::Perform{reason});
+        },
     };
-    let action_hash = compute_action_hash(action, &input_paths)?;
-    if let Some(cache_entry) = check_action_cache(context, action_hash)? {
-        return Ok(Outcome::Success{cache_entry, cache_hit: true});
+
+    let input_hashes =
+        compute_input_hashes(context, action, inputs, &input_paths, false)?;
+    let action_hash = action.versioned_hash(&input_hashes);
+    let cache_entry =
+        check_action_cache(context, action, action_hash, &input_paths)?;
+    if let Some(cache_entry) = cache_entry {
+        return Ok(DryRunOutcome::CacheHit{cache_entry});
     }
-    let build_log = create_build_log(context)?;
+
+    let record = context.state.recorded_action(label)                           .with_context(|| "Read action record")?;
+    let reason = match record {
+        None => PerformReason::NeverBuilt,
+        Some(record) if record.command != action.command_hash() => {
+            let elements = diff_command_elements(
+                &record.command_elements,
+                &action.command_elements(),
+            );
+            PerformReason::ChangedCommand{elements}
+        },
+        Some(record) => {
+            let inputs: Vec<_> =
+                record.inputs.iter().zip(&input_hashes).enumerate()
+                .filter(|(_, (previous, current))| previous != current)
+                .map(|(index, (&previous, &current))|
+                    ChangedInput{index, previous, current})
+                .collect();
+            if inputs.is_empty() {
+                PerformReason::NotCached
+            } else {
+                PerformReason::ChangedInputs{inputs}
+            }
+        },
+    };
+    Ok(DryRunOutcome::Perform{reason})
+}
+
+/// Compare the elements of two commands by name.
+///
+/// Changed and added elements are returned in their current order,
+/// followed by removed elements in their previous order.
+fn diff_command_elements(
+    previous: &[(String, String)],
+    current:  &[(String, String)],
+) -> Vec<ChangedElement>
+{
+    let previous_map: HashMap<&str, &str> =
+        previous.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+    let current_map: HashMap<&str, &str> =
+        current.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+
+    let changed = current.iter()
+        .filter(|(name, value)|
+            previous_map.get(name.as_str()) != Some(&value.as_str()))
+        .map(|(name, value)| ChangedElement{
+            name: name.clone(),
+            previous: previous_map.get(name.as_str()).map(|&v| v.to_owned()),
+            current: Some(value.clone()),
+        });
+
+    let removed = previous.iter()
+        .filter(|(name, _)| !current_map.contains_key(name.as_str()))
+        .map(|(name, value)| ChangedElement{
+            name: name.clone(),
+            previous: Some(value.clone()),
+            current: None,
+        });
+
+    changed.chain(removed).collect()
+}
+
+/// Start an interactive shell in the environment of an action.
+///
+/// The inputs of the action are staged into a new scratch directory,
+/// which requires its dependencies to be in the action cache,
+/// so the action must have been built up to its dependencies.
+/// Unlike [`drive`], this bypasses the executor, because the shell
+/// must run in the current process. See [`Action::replay`].
+pub fn replay(context: &Context, graph: &ActionGraph, label: &ActionLabel)
+    -> Result<(), ReplayError>
+{
+    let (action, inputs) = graph.actions.get(label)
+        .ok_or_else(|| ReplayError::NoSuchAction(label.clone()))?;
+
+    let outcomes = dry_run(context, graph)?;
+    let input_paths = cached_input_paths(context, &outcomes, inputs, true)?;
+    let input_paths = match input_paths {
+        Ok(input_paths) => input_paths,
+        Err(dependency) =>
+            return Err(ReplayError::DependencyNotBuilt(dependency.clone())),
+    };
+
     let scratch = context.state.new_scratch_dir()                               .with_context(|| "Create scratch directory")?;
-    let result = perform_action(action, &input_paths, &build_log, &scratch);
-    let build_log = context.state.cache_build_log(build_log)                    .with_context(|| "Move build log to output cache")?;
-    match result {
-        Ok(success) => cache_action(context, action, action_hash, build_log, &scratch, &success),
-        Err(error) => Ok(Outcome::Failed{build_log: Some(build_log), error: error.into()}),
-    }
+    action.replay(scratch.as_fd(), &input_paths)?;
+
+    Ok(())
 }
 
-/// Compute the path of each input.
+/// Compute the path of each input from the outcomes of a dry run.
 ///
-/// If inputs are missing due to unfortunate outcomes of dependencies,
-/// this function returns early with the dependency that failed.
-fn collect_input_paths<'a, 'b>(
-    context:  &'a Context,
-    outcomes: &HashMap<&ActionLabel, Outcome<'b>>,
-    inputs:   &'b [Input],
+/// Like [`collect_input_paths`], but dependencies must be cache hits.
+/// If a dependency would be performed or cannot be checked,
+/// this function returns early with that dependency.
+///
+/// Inline inputs are only written to the output cache if `store_inline`.
+/// Otherwise their paths may not exist, which is fine for dry runs,
+/// because [`compute_input_hashes`] hashes them by their contents.
+fn cached_input_paths<'a, 'b>(
+    context:      &'a Context,
+    outcomes:     &HashMap<&ActionLabel, Result<DryRunOutcome, BuildError>>,
+    inputs:       &'b [Input],
+    store_inline: bool,
 ) -> Result<Result<Vec<InputPath<'a, 'b>>, &'b ActionLabel>, BuildError>
 {
     let mut input_paths = Vec::with_capacity(inputs.len());
@@ -196,19 +779,22 @@ fn collect_input_paths<'a, 'b>(
         match input {
             Input::Dependency(label) => {
                 let outcome = outcomes.get(&label.action)
-                    .expect("Action should have been built before");
+                    .expect("Action should have been dry run before");
                 match outcome {
-                    Outcome::Success{cache_entry, ..} => {
+                    Ok(DryRunOutcome::CacheHit{cache_entry}) => {
                         let hash = cache_entry.outputs.get(label.output)
                             .expect("Action refers to non-existent output");
-                        let (dirfd, path) = context.state.cached_output(*hash)  .with_context(|| "Retrieve dependency from output cache")?;
+                        let hash = match hash {
+                            Some(hash) => *hash,
+                            None => return Err(
+                                BuildError::AbsentOutput(label.clone())),
+                        };
+                        let (dirfd, path) = context.state.cached_output(hash)   .with_context(|| "Retrieve dependency from output cache")?;
                         let path = Cow::Owned(path);
                         input_paths.push(InputPath{dirfd, path});
                     },
-                    Outcome::Failed{..} =>
+                    Ok(DryRunOutcome::Perform{..}) | Err(..) =>
                         return Ok(Err(&label.action)),
-                    Outcome::Skipped{failed_dependency} =>
-                        return Ok(Err(failed_dependency)),
                 }
             },
             Input::StaticFile(path) => {
@@ -216,81 +802,1098 @@ fn collect_input_paths<'a, 'b>(
                 let path = Cow::Borrowed(path.as_ref());
                 input_paths.push(InputPath{dirfd, path});
             },
+            Input::Inline(contents) => {
+                let hash = if store_inline {
+                    context.state.cache_contents(contents)                      .with_context(|| "Write inline input to output cache")?
+                } else {
+                    hash_regular_file(contents, false)
+                };
+                let (dirfd, path) = context.state.cached_output(hash)           .with_context(|| "Retrieve inline input from output cache")?;
+                let path = Cow::Owned(path);
+                input_paths.push(InputPath{dirfd, path});
+            },
         }
     }
 
     Ok(Ok(input_paths))
 }
 
-/// Compute the hash of an action, which is its key into the action cache.
-fn compute_action_hash(action: &dyn Action, input_paths: &[InputPath])
-    -> Result<Hash, BuildError>
+/// Topologically sort the action graph.
+///
+/// The graph is traversed iteratively rather than recursively,
+/// so that long chains of dependencies cannot overflow the call stack.
+/// Actions are visited in label order, so the result is deterministic.
+fn prepare(graph: &ActionGraph)
+    -> Result<Vec<(&ActionLabel, &dyn Action, &[Input])>, DriveError>
 {
-    let mut input_hashes = Vec::with_capacity(input_paths.len());
+    let mut linear = Vec::with_capacity(graph.actions.len());
 
-    for InputPath{dirfd, path} in input_paths {
-        let hash = hash_file_at(Some(*dirfd), path)                             .with_context(|| "Compute hash of input")?;
-        input_hashes.push(hash);
+    // The state table keeps track of visited actions.
+    // An false entry means the action is currently being visited.
+    // A true entry means the action was visited in the past.
+    // These states are used for detecting cycles
+    // and avoiding duplicates respectively.
+    let mut state = HashMap::new();
+
+    // The actions currently being visited, in the order they were entered,
+    // each with the dependencies that are yet to be visited.
+    // When a cycle is detected, it can be read off from this stack.
+    let mut stack = Vec::new();
+
+    let mut roots: Vec<&ActionLabel> = graph.actions.keys().collect();
+    roots.sort();
+
+    for root in roots {
+        if state.contains_key(root) {
+            continue;
+        }
+
+        let (action, inputs) = &graph.actions[root];
+        state.insert(root, false);
+        stack.push((root, &**action, &inputs[..],
+                    inputs.iter().flat_map(Input::dependency)));
+
+        while let Some((_, _, _, dependencies)) = stack.last_mut() {
+            if let Some(dependency) = dependencies.next() {
+                let label = &dependency.action;
+                match state.get(label) {
+                    Some(false) => {
+                        let start = stack.iter()
+                            .position(|entry| entry.0 == label)
+                            .expect("Action being visited should be on stack");
+                        let cycle = stack[start ..].iter()
+                            .map(|entry| entry.0.clone())
+                            .collect();
+                        return Err(DriveError::CyclicDependency(cycle));
+                    },
+                    Some(true) => (),
+                    None => {
+                        let (action, inputs) = graph.actions.get(label)
+                            .ok_or(DriveError::DanglingDependency)?;
+                        state.insert(label, false);
+                        stack.push((label, &**action, &inputs[..],
+                                    inputs.iter().flat_map(Input::dependency)));
+                    },
+                }
+            } else {
+                let (label, action, inputs, _) = stack.pop().unwrap();
+                state.insert(label, true);
+                linear.push((label, action, inputs));
+            }
+        }
     }
 
-    Ok(action.hash(&input_hashes))
+    Ok(linear)
 }
 
-/// Look up the action in the action cache, in order to skip the build.
-fn check_action_cache(context: &Context, action_hash: Hash)
-    -> Result<Option<ActionCacheEntry>, BuildError>
+/// Format a dependency cycle for use in an error message.
+fn display_cycle(cycle: &[ActionLabel]) -> String
 {
-    let cache_entry = context.state.cached_action(action_hash)                  .with_context(|| "Look up action in action cache")?;
-    Ok(cache_entry)
+    let mut result = String::new();
+    for label in cycle.iter().chain(cycle.first()) {
+        if !result.is_empty() {
+            result.push_str(" -> ");
+        }
+        result.push_str(&label.to_string());
+    }
+    result
 }
 
-/// Create the file that will store the build log.
-fn create_build_log(context: &Context) -> Result<OwnedFd, BuildError>
+/// Decides which actions to build next.
+///
+/// Actions are identified by their index in the topological order.
+/// Of the actions whose dependencies have been built,
+/// the scheduler picks the first whose resources fit.
+/// An exclusive action that does not fit yet prevents
+/// further actions from starting, so that it is not starved.
+struct Scheduler
 {
-    let state_dir = context.state.as_fd();
-    let file = openat(Some(state_dir), cstr!(b"."), O_TMPFILE | O_RDWR, 0o644)  .with_context(|| "Create build log")?;
-    Ok(file)
+    capacity: Capacity,
+
+    /// The resources needed by each action.
+    resources: Vec<Resources>,
+
+    /// The number of dependencies of each action that are not built yet.
+    pending: Vec<usize>,
+
+    /// The actions that depend on each action.
+    dependents: Vec<Vec<usize>>,
+
+    /// The actions whose dependencies have all been built,
+    /// but which have not been started.
+    ready: BTreeSet<usize>,
+
+    /// The actions that were started and still hold their resources.
+    reserved: Vec<bool>,
+
+    /// The resources in use by the actions being built.
+    used: Resources,
+
+    /// The number of actions being built that hold their resources.
+    running: usize,
 }
 
-/// Perform the action.
-fn perform_action(
-    action: &dyn Action,
-    input_paths: &[InputPath],
-    build_log: &OwnedFd,
-    scratch: &OwnedFd,
-) -> action::Result
+impl Scheduler
 {
-    let perform = Perform{
-        build_log: build_log.as_fd(),
-        scratch: scratch.as_fd(),
-    };
-    action.perform(&perform, input_paths)
-}
+    fn new(
+        capacity: Capacity,
+        linear:   &[(&ActionLabel, &dyn Action, &[Input])],
+    ) -> Self
+    {
+        let indices: HashMap<&ActionLabel, usize> =
+            linear.iter().enumerate()
+            .map(|(index, (label, _, _))| (*label, index))
+            .collect();
 
-/// Insert the outputs and action into the caches.
-fn cache_action<'a>(
+        let mut resources = Vec::with_capacity(linear.len());
+        let mut pending = Vec::with_capacity(linear.len());
+        let mut dependents = vec![Vec::new(); linear.len()];
+        let mut ready = BTreeSet::new();
+
+        for (index, (_, action, inputs)) in linear.iter().enumerate() {
+            let mut dependencies: Vec<usize> =
+                inputs.iter()
+                .flat_map(Input::dependency)
+                .map(|dependency| indices[&dependency.action])
+                .collect();
+            dependencies.sort();
+            dependencies.dedup();
+
+            for &dependency in &dependencies {
+                dependents[dependency].push(index);
+            }
+            if dependencies.is_empty() {
+                ready.insert(index);
+            }
+
+            resources.push(action.resources());
+            pending.push(dependencies.len());
+        }
+
+        let reserved = vec![false; linear.len()];
+        let used = Resources{cpus: 0, memory: 0, exclusive: false};
+        Self{capacity, resources, pending, dependents, ready, reserved, used,
+             running: 0}
+    }
+
+    /// Pick an action to start building and reserve its resources.
+    fn next(&mut self) -> Option<usize>
+    {
+        let mut next = None;
+        for &index in &self.ready {
+            let resources = self.resources[index];
+            if self.fits(resources) {
+                next = Some(index);
+                break;
+            }
+            if resources.exclusive {
+                break;
+            }
+        }
+
+        let index = next?;
+        let resources = self.resources[index];
+        self.ready.remove(&index);
+        self.reserved[index] = true;
+        self.used.cpus += resources.cpus;
+        self.used.memory += resources.memory;
+        self.used.exclusive |= resources.exclusive;
+        self.running += 1;
+        Some(index)
+    }
+
+    /// Whether an action can start given the actions being built.
+    fn fits(&self, resources: Resources) -> bool
+    {
+        if self.running == 0 {
+            return true;
+        }
+        let cpus = self.used.cpus.checked_add(resources.cpus);
+        let memory = self.used.memory.checked_add(resources.memory);
+        !self.used.exclusive && !resources.exclusive
+            && cpus.map_or(false, |cpus| cpus <= self.capacity.cpus)
+            && memory.map_or(false, |memory| memory <= self.capacity.memory)
+    }
+
+    /// Release the resources of an action that no longer needs them.
+    ///
+    /// The action is not considered built, so its dependents do not start.
+    /// This is used for actions that wait for an identical action.
+    fn release(&mut self, index: usize)
+    {
+        if !mem::replace(&mut self.reserved[index], false) {
+            return;
+        }
+        let resources = self.resources[index];
+        self.used.cpus -= resources.cpus;
+        self.used.memory -= resources.memory;
+        self.used.exclusive = false;
+        self.running -= 1;
+    }
+
+    /// Release the resources of an action that finished building,
+    /// unless they were already released, and start its dependents.
+    fn finish(&mut self, index: usize)
+    {
+        self.release(index);
+
+        for &dependent in &self.dependents[index] {
+            self.pending[dependent] -= 1;
+            if self.pending[dependent] == 0 {
+                self.ready.insert(dependent);
+            }
+        }
+    }
+}
+
+/// Sent to the driver by the threads that build actions.
+enum Message<'a>
+{
+    /// The action waits for an identical action to finish,
+    /// so its resources can be used by other actions meanwhile.
+    Merged{index: usize},
+
+    /// The action finished building.
+    Finished{index: usize, lane: usize, outcome: thread::Result<Outcome<'a>>},
+}
+
+/// Actions that are being or have been built, by action hash.
+///
+/// Different labels may refer to identical actions,
+/// for example when two rules generate the same command.
+/// Such actions are built only once per build.
+/// The others wait for it to finish and then consult the caches,
+/// which then contain its result if it succeeded.
+#[derive(Default)]
+struct InFlight<'a>
+{
+    /// The label that builds each action,
+    /// and whether it finished building it.
+    actions: Mutex<HashMap<[u8; 32], (&'a ActionLabel, bool)>>,
+
+    /// Notified whenever an action finishes.
+    finished: Condvar,
+}
+
+impl<'a> InFlight<'a>
+{
+    /// Start building an action.
+    ///
+    /// If an identical action was already started,
+    /// returns the label of that action instead.
+    /// The action finishes when the returned guard is dropped,
+    /// which also happens when building the action panics.
+    fn begin(&self, hash: Hash, label: &'a ActionLabel)
+        -> Result<Building<'_, 'a>, &'a ActionLabel>
+    {
+        let mut actions = self.actions.lock().unwrap();
+        match actions.get(&hash.0) {
+            Some((original, _)) => Err(original),
+            None => {
+                actions.insert(hash.0, (label, false));
+                Ok(Building{in_flight: self, hash})
+            },
+        }
+    }
+
+    /// Wait for an identical action to finish.
+    fn wait(&self, hash: Hash)
+    {
+        let actions = self.actions.lock().unwrap();
+        let _actions = self.finished
+            .wait_while(actions, |actions| !actions[&hash.0].1)
+            .unwrap();
+    }
+}
+
+/// Guard returned by [`InFlight::begin`].
+struct Building<'i, 'a>
+{
+    in_flight: &'i InFlight<'a>,
+    hash: Hash,
+}
+
+impl Drop for Building<'_, '_>
+{
+    fn drop(&mut self)
+    {
+        // Do not panic on a poisoned lock; the waiters must be woken.
+        let mut actions = self.in_flight.actions.lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some((_, finished)) = actions.get_mut(&self.hash.0) {
+            *finished = true;
+        }
+        self.in_flight.finished.notify_all();
+    }
+}
+
+/// Whether the build was cancelled.
+fn is_cancelled(context: &Context) -> bool
+{
+    context.cancel.map_or(false, Cancel::is_cancelled)
+}
+
+/// Record the number of actions being built in the profile, if any.
+fn profile_running(context: &Context, scheduler: &Scheduler)
+{
+    if let Some(profile) = context.profile {
+        profile.counter("running", scheduler.running as u64, Instant::now());
+    }
+}
+
+/// Record a span from the given instant until now in the profile, if any.
+fn profile_span(
+    context:  &Context,
+    lane:     usize,
+    category: &'static str,
+    start:    Instant,
+    name:     impl FnOnce() -> String,
+)
+{
+    if let Some(profile) = context.profile {
+        profile.span(lane, category, name(), start, Instant::now());
+    }
+}
+
+/// Record that something happened now in the profile, if any.
+fn profile_instant(
+    context:  &Context,
+    lane:     usize,
+    category: &'static str,
+    name:     impl FnOnce() -> String,
+)
+{
+    if let Some(profile) = context.profile {
+        profile.instant(lane, category, name(), Instant::now());
+    }
+}
+
+/// The thread on which an action is built.
+struct Worker<'w, 'a>
+{
+    /// The actions being built by all threads.
+    in_flight: &'w InFlight<'a>,
+
+    /// Called if the action merges into an identical action,
+    /// before waiting for that action to finish.
+    merged: &'w dyn Fn(),
+
+    /// The lane of the profile on which timings are recorded.
+    lane: usize,
+}
+
+/// Build an action whose inputs are available.
+fn build<'a>(
+    context:     &Context,
+    worker:      &Worker<'_, 'a>,
+    label:       &'a ActionLabel,
+    action:      &dyn Action,
+    inputs:      &[Input],
+    input_paths: Vec<InputPath>,
+) -> Outcome<'a>
+{
+    let result =
+        build_inner(context, worker, label, action, inputs, input_paths);
+    match result {
+        Ok(outcome) => outcome,
+        Err(error) => Outcome::Failed{build_log: None, error},
+    }
+}
+
+fn build_inner<'a>(
+    context:     &Context,
+    worker:      &Worker<'_, 'a>,
+    label:       &'a ActionLabel,
+    action:      &dyn Action,
+    inputs:      &[Input],
+    input_paths: Vec<InputPath>,
+) -> Result<Outcome<'a>, BuildError>
+{
+    let Worker{in_flight, merged, lane} = *worker;
+    let start = Instant::now();
+    let input_hashes =
+        compute_input_hashes(context, action, inputs, &input_paths, true)?;
+    let action_hash = action.versioned_hash(&input_hashes);
+    record_action(context, label, action, input_hashes.clone())?;
+
+    // Identical actions are built only once,
+    // and the others share the result through the caches.
+    let _building = match in_flight.begin(action_hash, label) {
+        Ok(building) => building,
+        Err(original) => {
+            (context.events)(BuildEvent::Merged{label, original});
+            merged();
+            in_flight.wait(action_hash);
+            let cache_entry = check_action_cache(context, action, action_hash,
+                                                 &input_paths)?;
+            return Ok(match cache_entry {
+                Some(cache_entry) =>
+                    Outcome::Success{cache_entry, cache_hit: true},
+                None => {
+                    let error =
+                        BuildError::IdenticalActionFailed(original.clone());
+                    Outcome::Failed{build_log: None, error}
+                },
+            });
+        },
+    };
+
+    let cache_entry =
+        check_action_cache(context, action, action_hash, &input_paths)?;
+    let test_result = check_test_result(context, action, action_hash)?;
+    profile_span(context, lane, "cache", start,
+                 || format!("{label} check caches"));
+    if let Some(result) = &test_result {
+        if cache_entry.is_some() || result.status == TestStatus::Failed {
+            (context.events)(BuildEvent::TestResult{
+                label, result, cached: true,
+            });
+        }
+    }
+    if let Some(cache_entry) = cache_entry {
+        profile_instant(context, lane, "cache",
+                        || format!("{label} cache hit"));
+        return Ok(Outcome::Success{cache_entry, cache_hit: true});
+    }
+    if let Some(TestResult{status, build_log, error: Some(error), ..})
+        = test_result {
+        if status == TestStatus::Failed {
+            let error = BuildError::CachedTestFailure(error);
+            return Ok(Outcome::Failed{build_log: Some(build_log), error});
+        }
+    }
+
+    let stream_log = context.stream_log
+        .filter(|(stream_label, _)| *stream_label == label)
+        .map(|(_, stream_log)| stream_log);
+
+    // Tests may be performed multiple times to find out if they are flaky.
+    // Of all attempts, the most recent success and failure are kept.
+    let runs = if action.is_test() { context.runs_per_test.max(1) } else { 1 };
+    let retry_policy = action.retry_policy();
+    let (mut passed, mut failed) = (None, None);
+    let (mut passes, mut failures) = (0, 0);
+    let mut duration = Duration::ZERO;
+    for _ in 0 .. runs {
+        if is_cancelled(context) && (passed.is_some() || failed.is_some()) {
+            break;
+        }
+        for attempt in 1 .. {
+            let build_log = create_build_log(context)?;
+            let scratch = context.state.new_scratch_dir()                       .with_context(|| "Create scratch directory")?;
+            let start = Instant::now();
+            let result = perform_action(context, action, &input_paths,
+                                        &build_log, &scratch, stream_log);
+            duration += start.elapsed();
+            profile_span(context, lane, "perform", start,
+                         || format!("{label} attempt {attempt}"));
+            let build_log_ansi = detect_ansi(&build_log)                        .with_context(|| "Read build log")?;
+            let build_log = context.state.cache_build_log(build_log)            .with_context(|| "Move build log to output cache")?;
+            let error = result.as_ref().err();
+            let retry =
+                error.map_or(false, |e| retry_policy.retries(attempt, e))
+                && !is_cancelled(context);
+            (context.events)(BuildEvent::Attempt{
+                label, attempt, build_log, error, retry,
+            });
+            match result {
+                Ok(success) => {
+                    passes += 1;
+                    passed =
+                        Some((build_log, build_log_ansi, scratch, success));
+                    break;
+                },
+                Err(error) => {
+                    failures += 1;
+                    failed = Some((build_log, build_log_ansi, error));
+                    if !retry { break; }
+                },
+            }
+        }
+    }
+
+    // A cancelled test may have been stopped, so its result is meaningless.
+    if action.is_test() && !is_cancelled(context) {
+        let status = TestStatus::from_attempts(passes, failures);
+        let test_result = match (&passed, &failed) {
+            (_, Some((build_log, build_log_ansi, error))) => TestResult{
+                status,
+                build_log: *build_log,
+                build_log_ansi: *build_log_ansi,
+                exit_code: match error {
+                    action::Error::ExitStatus(status) => status.code(),
+                    _ => None,
+                },
+                error: Some(error.to_string()),
+                duration,
+            },
+            (Some((build_log, build_log_ansi, _, _)), None) => TestResult{
+                status,
+                build_log: *build_log,
+                build_log_ansi: *build_log_ansi,
+                exit_code: Some(0),
+                error: None,
+                duration,
+            },
+            (None, None) => unreachable!("Actions are performed at least once"),
+        };
+        context.state.record_test_result(action_hash, &test_result)             .with_context(|| "Record test result")?;
+        (context.events)(BuildEvent::TestResult{
+            label, result: &test_result, cached: false,
+        });
+    }
+
+    match (passed, failed) {
+        (Some((build_log, build_log_ansi, scratch, success)), _) => {
+            if context.check_determinism {
+                let result = check_determinism(context, action, &input_paths,
+                                               &scratch, &success);
+                if let Err(error) = result {
+                    let build_log = Some(build_log);
+                    return Ok(Outcome::Failed{build_log, error});
+                }
+            }
+            let start = Instant::now();
+            let build_log = (build_log, build_log_ansi);
+            let outcome = cache_action(context, action, action_hash,
+                                       &input_paths, build_log, &scratch,
+                                       &success);
+            profile_span(context, lane, "cache", start,
+                         || format!("{label} cache outputs"));
+            outcome
+        },
+        (None, Some((build_log, _, error))) => {
+            if context.failure_reports {
+                let report = write_failure_report(
+                    context, label, action, &input_paths,
+                    &input_hashes, build_log, &error,
+                );
+                (context.events)(BuildEvent::FailureReport{label, report});
+            }
+            let build_log = Some(build_log);
+            Ok(Outcome::Failed{build_log, error: error.into()})
+        },
+        (None, None) =>
+            unreachable!("Actions are performed at least once"),
+    }
+}
+
+/// Write a failure report for an action that failed.
+///
+/// See [`Context::failure_reports`].
+/// Returns the path to the failure report.
+fn write_failure_report(
+    context:      &Context,
+    label:        &ActionLabel,
+    action:       &dyn Action,
+    input_paths:  &[InputPath],
+    input_hashes: &[Hash],
+    build_log:    Hash,
+    error:        &action::Error,
+) -> io::Result<CString>
+{
+    let (report, path) = context.state.new_failure_report(label)?;
+    let report = Some(report.as_fd());
+
+    let write = |name: &CStr, contents: &[u8]| {
+        let flags = O_CREAT | O_EXCL | O_WRONLY;
+        let file = openat(report, name, flags, 0o644)?;
+        File::from(file).write_all(contents)
+    };
+
+    write(cstr!(b"error"), format!("{error}\n").as_bytes())?;
+
+    // Each line lists the index, hash, and path of an input.
+    let mut inputs = Vec::new();
+    for (i, (input_path, hash)) in
+        input_paths.iter().zip(input_hashes).enumerate() {
+        let path = input_path.path.to_string_lossy();
+        writeln!(inputs, "{i} {hash} {path}")?;
+    }
+    write(cstr!(b"inputs"), &inputs)?;
+
+    for (name, contents) in action.report_files() {
+        let name = CString::new(name)
+            .expect("Report file name should not contain nul");
+        write(&name, &contents)?;
+    }
+
+    let (cache, cached) = context.state.cached_output(build_log)?;
+    sync_file_at(Some(cache), &cached, report, cstr!(b"build.log"))?;
+
+    Ok(path)
+}
+
+/// Compute the path of each input.
+///
+/// If inputs are missing due to unfortunate outcomes of dependencies,
+/// this function returns early with the dependency that failed.
+fn collect_input_paths<'a, 'b>(
+    context:  &'a Context,
+    outcomes: &HashMap<&ActionLabel, Outcome<'b>>,
+    inputs:   &'b [Input],
+) -> Result<Result<Vec<InputPath<'a, 'b>>, &'b ActionLabel>, BuildError>
+{
+    let mut input_paths = Vec::with_capacity(inputs.len());
+
+    for input in inputs {
+        match input {
+            Input::Dependency(label) => {
+                let outcome = outcomes.get(&label.action)
+                    .expect("Action should have been built before");
+                match outcome {
+                    Outcome::Success{cache_entry, ..} => {
+                        let hash = cache_entry.outputs.get(label.output)
+                            .expect("Action refers to non-existent output");
+                        let hash = match hash {
+                            Some(hash) => *hash,
+                            None => return Err(
+                                BuildError::AbsentOutput(label.clone())),
+                        };
+                        let (dirfd, path) = context.state.cached_output(hash)   .with_context(|| "Retrieve dependency from output cache")?;
+                        let path = Cow::Owned(path);
+                        input_paths.push(InputPath{dirfd, path});
+                    },
+                    Outcome::Failed{..} =>
+                        return Ok(Err(&label.action)),
+                    Outcome::Skipped{failed_dependency} =>
+                        return Ok(Err(failed_dependency)),
+                }
+            },
+            Input::StaticFile(path) => {
+                let dirfd = context.source_root;
+                let path = Cow::Borrowed(path.as_ref());
+                input_paths.push(InputPath{dirfd, path});
+            },
+            Input::Inline(contents) => {
+                let hash = context.state.cache_contents(contents)               .with_context(|| "Write inline input to output cache")?;
+                let (dirfd, path) = context.state.cached_output(hash)           .with_context(|| "Retrieve inline input from output cache")?;
+                let path = Cow::Owned(path);
+                input_paths.push(InputPath{dirfd, path});
+            },
+        }
+    }
+
+    Ok(Ok(input_paths))
+}
+
+/// Compute the hash of each input.
+///
+/// The hash of the action, which is its key into the action cache,
+/// is computed from these by [`Action::hash`].
+/// [Partial inputs] are not hashed; an all-zero hash is used instead.
+/// Inline inputs are hashed by their contents, as cached outputs would be,
+/// so their paths need not exist.
+/// The digests of static files are only recorded if `record_digests`.
+///
+/// [Partial inputs]: `Action::partial_inputs`
+fn compute_input_hashes(
+    context:        &Context,
+    action:         &dyn Action,
+    inputs:         &[Input],
+    input_paths:    &[InputPath],
+    record_digests: bool,
+) -> Result<Vec<Hash>, BuildError>
+{
+    let partial_inputs = action.partial_inputs();
+
+    let mut input_hashes = Vec::with_capacity(input_paths.len());
+
+    let inputs = inputs.iter().zip(input_paths);
+    for (i, (input, InputPath{dirfd, path})) in inputs.enumerate() {
+        if partial_inputs.contains(&i) {
+            input_hashes.push(Hash([0; 32]));
+            continue;
+        }
+        // Outputs in the output cache never change, but static files do.
+        let hash = match input {
+            Input::Dependency(..) =>
+                hash_file_at(Some(*dirfd), path),
+            Input::StaticFile(..) => {
+                let now = record_digests.then(SystemTime::now);
+                hash_static_file(context.state, *dirfd, path, now)
+            },
+            Input::Inline(contents) =>
+                Ok(hash_regular_file(contents, false)),
+        }                                                                       .with_context(|| "Compute hash of input")?;
+        input_hashes.push(hash);
+    }
+
+    Ok(input_hashes)
+}
+
+/// How long ago a file must have been modified for its digest to be stored.
+///
+/// On file systems with coarse timestamps, a file modified right after
+/// it was hashed may end up with the same modification time.
+/// Such files are hashed again on the next build rather than trusted.
+const RACY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Compute the hash of a static file, reusing its input digest if fresh.
+///
+/// Only regular files have their digests stored;
+/// the metadata of a directory does not reflect changes to its entries.
+/// The digest is stored only if `now` is given and the file
+/// did not change within [`RACY_INTERVAL`] before `now`.
+fn hash_static_file(
+    state: &State,
+    dirfd: BorrowedFd,
+    path:  &CStr,
+    now:   Option<SystemTime>,
+) -> io::Result<Hash>
+{
+    let statbuf = fstatat(Some(dirfd), path, AT_SYMLINK_NOFOLLOW)?;
+    if statbuf.st_mode & S_IFMT != S_IFREG {
+        return hash_file_at(Some(dirfd), path);
+    }
+
+    match state.recorded_input_digest(path)? {
+        Some(digest) if digest.is_fresh(&statbuf) => return Ok(digest.hash),
+        _ => { },
+    }
+
+    let hash = hash_file_at(Some(dirfd), path)?;
+
+    // Files with timestamps before the epoch, or too far after it,
+    // are hashed on every build rather than risk a wrong comparison.
+    let time = |sec: i64, nsec: i64| {
+        let sec = u64::try_from(sec).ok()?;
+        UNIX_EPOCH.checked_add(Duration::new(sec, nsec as u32))
+    };
+    let mtime = time(statbuf.st_mtime, statbuf.st_mtime_nsec);
+    let ctime = time(statbuf.st_ctime, statbuf.st_ctime_nsec);
+    let changed = mtime.zip(ctime).and_then(|(mtime, ctime)|
+        mtime.max(ctime).checked_add(RACY_INTERVAL));
+    if let (Some(changed), Some(now)) = (changed, now) {
+        if changed < now {
+            let digest = InputDigest::new(&statbuf, hash);
+            state.record_input_digest(path, &digest)?;
+        }
+    }
+
+    Ok(hash)
+}
+
+/// Compute the key into the action cache for an action with partial inputs.
+///
+/// This combines the action hash with the contents of
+/// the files the action used within its partial inputs.
+fn compute_dependencies_hash(
+    action_hash:  Hash,
+    dependencies: &[Dependency],
+    input_paths:  &[InputPath],
+) -> io::Result<Hash>
+{
+    // NOTE: See the manual chapter on avoiding hash collisions.
+
+    let mut h = Blake3::new();
+    h.put_hash(action_hash);
+    h.put_usize(dependencies.len());
+
+    for Dependency{input, path} in dependencies {
+        let InputPath{dirfd, path: input_path} = &input_paths[*input];
+        let hash = match path {
+            Some(path) => hash_file_at(Some(*dirfd), &input_path.join(path))?,
+            None => hash_file_at(Some(*dirfd), input_path)?,
+        };
+        h.put_usize(*input);
+        h.put_bool(path.is_some());
+        if let Some(path) = path {
+            h.put_cstr(path);
+        }
+        h.put_hash(hash);
+    }
+
+    Ok(h.finalize())
+}
+
+/// Record the command and inputs of the action in the state directory.
+///
+/// The existing record is only replaced if it is different,
+/// to avoid writing to the state directory for every action on every build.
+fn record_action(
+    context:      &Context,
+    label:        &ActionLabel,
+    action:       &dyn Action,
+    input_hashes: Vec<Hash>,
+) -> Result<(), BuildError>
+{
+    let record = ActionRecord{
+        command: action.command_hash(),
+        inputs: input_hashes,
+        command_elements: action.command_elements(),
+    };
+    let previous = context.state.recorded_action(label)                         .with_context(|| "Read action record")?;
+    if previous.as_ref() != Some(&record) {
+        context.state.record_action(label, &record)                             .with_context(|| "Write action record")?;
+    }
+    Ok(())
+}
+
+/// Look up the action in the action cache, in order to skip the build.
+///
+/// For actions with partial inputs, the key into the action cache
+/// also includes the files the action used when it was last performed.
+fn check_action_cache(
     context:     &Context,
     action:      &dyn Action,
     action_hash: Hash,
-    build_log:   Hash,
+    input_paths: &[InputPath],
+) -> Result<Option<ActionCacheEntry>, BuildError>
+{
+    let mut cache_key = action_hash;
+
+    if !action.partial_inputs().is_empty() {
+        let dependencies = context.state.discovered_dependencies(action_hash)   .with_context(|| "Read discovered dependencies")?;
+        let Some(dependencies) = dependencies
+            else { return Ok(None) };
+        let hash =
+            compute_dependencies_hash(action_hash, &dependencies, input_paths);
+        cache_key = match hash {
+            // The action will find out which files it uses instead.
+            Err(err) if err.kind() == NotFound => return Ok(None),
+            hash => hash                                                        .with_context(|| "Compute hash of discovered dependencies")?,
+        };
+    }
+
+    let cache_entry = context.state.cached_action(cache_key)                    .with_context(|| "Look up action in action cache")?;
+    Ok(cache_entry)
+}
+
+/// Look up the most recent result of a test.
+///
+/// Test results are keyed by action hash, which for actions
+/// with partial inputs does not identify the files they used.
+/// So the results of such tests are never looked up.
+fn check_test_result(
+    context:     &Context,
+    action:      &dyn Action,
+    action_hash: Hash,
+) -> Result<Option<TestResult>, BuildError>
+{
+    if !action.is_test() || !action.partial_inputs().is_empty() {
+        return Ok(None);
+    }
+    let result = context.state.recorded_test_result(action_hash)                .with_context(|| "Read test result")?;
+    Ok(result)
+}
+
+/// Create the file that will store the build log.
+fn create_build_log(context: &Context) -> Result<OwnedFd, BuildError>
+{
+    let file = context.state.new_scratch_file()                                 .with_context(|| "Create build log")?;
+    Ok(file)
+}
+
+/// Check whether the build log contains ANSI escape sequences.
+fn detect_ansi(build_log: &OwnedFd) -> io::Result<bool>
+{
+    let mut build_log = File::from(build_log.try_clone()?);
+    build_log.rewind()?;
+    let mut contents = Vec::new();
+    build_log.read_to_end(&mut contents)?;
+    Ok(contains_ansi(&contents))
+}
+
+/// Perform the action using the executor.
+fn perform_action(
+    context: &Context,
+    action: &dyn Action,
+    input_paths: &[InputPath],
+    build_log: &OwnedFd,
+    scratch: &OwnedFd,
+    stream_log: Option<BorrowedFd>,
+) -> action::Result
+{
+    let perform = Perform{
+        build_log: build_log.as_fd(),
+        scratch: scratch.as_fd(),
+        cancel: context.cancel.map(AsFd::as_fd),
+        stream_log,
+    };
+    context.executor.perform(action, &perform, input_paths)
+}
+
+/// Perform the action again and compare the outputs to the first run.
+///
+/// Failures of the second run are retried as those of the first run are.
+/// Only a failure that the retry policy gives up on
+/// counts as nondeterminism; cancellation does not.
+fn check_determinism(
+    context:     &Context,
+    action:      &dyn Action,
+    input_paths: &[InputPath],
+    scratch:     &OwnedFd,
+    success:     &Success,
+) -> Result<(), BuildError>
+{
+    let retry_policy = action.retry_policy();
+    let mut attempt = 1;
+    let (scratch_2, success_2) = loop {
+        let build_log = create_build_log(context)?;
+        let scratch_2 = context.state.new_scratch_dir()                         .with_context(|| "Create scratch directory")?;
+        let result = perform_action(context, action, input_paths,
+                                    &build_log, &scratch_2, None);
+        match result {
+            Ok(success_2) => break (scratch_2, success_2),
+            Err(error @ action::Error::Cancelled) =>
+                return Err(BuildError::Perform(error)),
+            Err(error) if is_cancelled(context) =>
+                return Err(BuildError::Perform(error)),
+            Err(error) if retry_policy.retries(attempt, &error) =>
+                attempt += 1,
+            Err(error) =>
+                return Err(BuildError::NondeterministicFailure(error)),
+        }
+    };
+
+    let mut differences = Vec::new();
+    let outputs = success.output_paths.iter().zip(&success_2.output_paths);
+    for (output_path, output_path_2) in outputs {
+        diff_outputs(
+            scratch.as_fd(), output_path,
+            scratch_2.as_fd(), output_path_2,
+            &mut differences,
+        )                                                                       .with_context(|| "Compare outputs of two runs")?;
+    }
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(BuildError::Nondeterministic(differences))
+    }
+}
+
+/// Find the files that differ between two outputs.
+///
+/// If both outputs are directories, they are compared recursively,
+/// so that only the files within them that differ are reported.
+/// Paths are reported as they are, or would be, in the first output.
+fn diff_outputs(
+    dirfd:       BorrowedFd,
+    path:        &CStr,
+    dirfd_2:     BorrowedFd,
+    path_2:      &CStr,
+    differences: &mut Vec<CString>,
+) -> io::Result<()>
+{
+    // Optional outputs may be missing from both runs.
+    let hash = match hash_file_at(Some(dirfd), path) {
+        Ok(hash) => Some(hash),
+        Err(err) if err.kind() == NotFound => None,
+        Err(err) => return Err(err),
+    };
+    let hash_2 = match hash_file_at(Some(dirfd_2), path_2) {
+        Ok(hash_2) => Some(hash_2),
+        Err(err) if err.kind() == NotFound => None,
+        Err(err) => return Err(err),
+    };
+    if hash_2 == hash {
+        return Ok(());
+    }
+
+    let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+    let (dir, dir_2) = match (openat(Some(dirfd), path, flags, 0),
+                              openat(Some(dirfd_2), path_2, flags, 0)) {
+        (Ok(dir), Ok(dir_2)) => (dir, dir_2),
+        _ => {
+            differences.push(path.to_owned());
+            return Ok(());
+        },
+    };
+
+    let entries = read_entries(dir.as_fd())?;
+    let entries_2 = read_entries(dir_2.as_fd())?;
+    for name in &entries {
+        diff_outputs(dirfd, &path.join(name),
+                     dirfd_2, &path_2.join(name), differences)?;
+    }
+    for name in &entries_2 {
+        if entries.binary_search(name).is_err() {
+            differences.push(path.join(name));
+        }
+    }
+
+    Ok(())
+}
+
+/// Format paths for use in an error message.
+fn display_paths(paths: &[CString]) -> String
+{
+    let paths: Vec<_> =
+        paths.iter().map(|path| path.to_string_lossy()).collect();
+    paths.join(", ")
+}
+
+/// Insert the outputs and action into the caches.
+fn cache_action<'a>(
+    context:     &Context,
+    action:      &dyn Action,
+    action_hash: Hash,
+    input_paths: &[InputPath],
+    build_log:   (Hash, bool),
     scratch:     &OwnedFd,
     success:     &Success,
 ) -> Result<Outcome<'a>, BuildError>
 {
+    let (build_log, build_log_ansi) = build_log;
     let outputs = cache_outputs(context, action, scratch, success)?;
     let warnings = success.warnings;
-    let cache_entry = ActionCacheEntry{build_log, outputs, warnings};
-    context.state.cache_action(action_hash, &cache_entry)                       .with_context(|| "Insert action into action cache")?;
+    let cache_entry =
+        ActionCacheEntry{build_log, build_log_ansi, outputs, warnings};
+    let cache_key = record_dependencies(context, action, action_hash,
+                                        input_paths, success)?;
+    context.state.cache_action(cache_key, &cache_entry)                         .with_context(|| "Insert action into action cache")?;
     Ok(Outcome::Success{cache_entry, cache_hit: false})
 }
 
+/// Record the files the action used within its partial inputs.
+///
+/// Returns the key into the action cache for the action.
+fn record_dependencies(
+    context:     &Context,
+    action:      &dyn Action,
+    action_hash: Hash,
+    input_paths: &[InputPath],
+    success:     &Success,
+) -> Result<Hash, BuildError>
+{
+    let partial_inputs = action.partial_inputs();
+
+    // Can only be triggered by a faulty implementation of Action::perform.
+    // So there is no need to return a user-facing error for this.
+    assert!(
+        success.dependencies.iter()
+            .all(|dependency| partial_inputs.contains(&dependency.input)),
+        "Action must only report dependencies within partial inputs",
+    );
+
+    if partial_inputs.is_empty() {
+        return Ok(action_hash);
+    }
+
+    let mut dependencies = success.dependencies.clone();
+    dependencies.sort();
+    dependencies.dedup();
+
+    context.state.record_dependencies(action_hash, &dependencies)               .with_context(|| "Record discovered dependencies")?;
+    let cache_key =
+        compute_dependencies_hash(action_hash, &dependencies, input_paths)      .with_context(|| "Compute hash of discovered dependencies")?;
+    Ok(cache_key)
+}
+
+/// How many times to try caching an output that fails transiently.
+///
+/// See [`CacheOutputError::is_transient`].
+const CACHE_OUTPUT_ATTEMPTS: u32 = 3;
+
 /// Move every output to the output cache and return their hashes.
 fn cache_outputs(
     context: &Context,
     action:  &dyn Action,
     scratch: &OwnedFd,
     success: &Success,
-) -> Result<Vec<Hash>, BuildError>
+) -> Result<Vec<Option<Hash>>, BuildError>
 {
     let scratch = scratch.as_fd();
     let count = action.outputs().get();
@@ -300,14 +1903,854 @@ fn cache_outputs(
     assert_eq!(success.output_paths.len(), count,
         "Action must produce as many outputs as declared");
 
+    let executable_outputs = action.executable_outputs();
+    let optional_outputs = action.optional_outputs();
     let mut output_hashes = Vec::with_capacity(count);
 
-    for output_path in &success.output_paths {
+    for (i, output_path) in success.output_paths.iter().enumerate() {
         // Outputs are placed by the action in the scratch directory.
         // And the output path is relative to the scratch directory.
-        let hash = context.state.cache_output(Some(scratch), output_path)?;
-        output_hashes.push(hash);
+        if optional_outputs.contains(&i) {
+            let flags = AT_SYMLINK_NOFOLLOW;
+            match fstatat(Some(scratch), output_path, flags) {
+                Err(err) if err.kind() == NotFound => {
+                    output_hashes.push(None);
+                    continue;
+                },
+                result => { result                                              .with_context(|| "Check for optional output")?; },
+            }
+        }
+        let executable = executable_outputs.contains(i);
+        let mut attempt = 1;
+        let hash = loop {
+            let result = context.state
+                .cache_output(Some(scratch), output_path, executable);
+            match result {
+                Err(err) if err.is_transient()
+                    && attempt < CACHE_OUTPUT_ATTEMPTS => attempt += 1,
+                result => break result?,
+            }
+        };
+        output_hashes.push(Some(hash));
     }
 
     Ok(output_hashes)
 }
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{action::Outputs, label::ActionOutputLabel},
+        std::assert_matches::assert_matches,
+    };
+
+    /// Action that is never performed.
+    struct Dummy(usize);
+
+    impl Action for Dummy
+    {
+        fn inputs(&self) -> usize { self.0 }
+        fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(1) }
+        fn perform(&self, _: &Perform, _: &[InputPath]) -> action::Result
+            { unreachable!() }
+        fn hash(&self, _: &[Hash]) -> Hash { unreachable!() }
+    }
+
+    #[test]
+    fn statistics()
+    {
+        let labels: Vec<_> = (0 .. 5).map(|action| ActionLabel{action})
+            .collect();
+        let cache_entry = || ActionCacheEntry{
+            build_log: Hash([0; 32]),
+            build_log_ansi: false,
+            outputs: vec![],
+            warnings: false,
+        };
+        let error = || BuildError::Nondeterministic(vec![]);
+        let outcomes = HashMap::from([
+            (&labels[0], Outcome::Success{cache_entry: cache_entry(),
+                                         cache_hit: true}),
+            (&labels[1], Outcome::Success{cache_entry: cache_entry(),
+                                         cache_hit: false}),
+            (&labels[2], Outcome::Failed{build_log: Some(Hash([0; 32])),
+                                        error: error()}),
+            (&labels[3], Outcome::Failed{build_log: None, error: error()}),
+            (&labels[4], Outcome::Skipped{failed_dependency: &labels[3]}),
+        ]);
+
+        let wall_time = Duration::from_secs(1);
+        let statistics = Statistics::new(&outcomes, 4, wall_time);
+        assert_eq!(statistics, Statistics{
+            performed: 2,
+            action_cache_hits: 1,
+            output_cache_hits: 4,
+            failed: 2,
+            skipped: 1,
+            wall_time,
+        });
+    }
+
+    #[test]
+    fn hash_static_file_digest()
+    {
+        use {
+            os_ext::{
+                O_CREAT, O_WRONLY,
+                cstr, cstring, mkdtemp, open, timespec, utimensat,
+            },
+            std::io::Write,
+        };
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dir = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = dir.as_fd();
+        let state = State::open(&path).unwrap();
+        let file = cstr!(b"file");
+
+        let write = |contents: &[u8]| {
+            let flags = O_CREAT | O_WRONLY | libc::O_TRUNC;
+            let fd = openat(Some(dirfd), file, flags, 0o644).unwrap();
+            File::from(fd).write_all(contents).unwrap();
+        };
+        let now = || Some(SystemTime::now());
+        let later = || Some(SystemTime::now() + RACY_INTERVAL * 2);
+
+        // A file that changed just now is hashed but not recorded.
+        write(b"hello");
+        let hello = hash_file_at(Some(dirfd), file).unwrap();
+        let hash = hash_static_file(&state, dirfd, file, now());
+        assert_eq!(hash.unwrap(), hello);
+        assert!(state.recorded_input_digest(file).unwrap().is_none());
+
+        // Without a current time, as in dry runs, nothing is recorded.
+        let hash = hash_static_file(&state, dirfd, file, None);
+        assert_eq!(hash.unwrap(), hello);
+        assert!(state.recorded_input_digest(file).unwrap().is_none());
+
+        // Once the file is old enough, its digest is recorded.
+        let hash = hash_static_file(&state, dirfd, file, later());
+        assert_eq!(hash.unwrap(), hello);
+        let digest = state.recorded_input_digest(file).unwrap().unwrap();
+        assert_eq!(digest.hash, hello);
+
+        // A fresh digest is used instead of hashing the file.
+        let fake = InputDigest{hash: Hash([1; 32]), ..digest};
+        state.record_input_digest(file, &fake).unwrap();
+        let hash = hash_static_file(&state, dirfd, file, later());
+        assert_eq!(hash.unwrap(), Hash([1; 32]));
+
+        // A stale digest is ignored.
+        write(b"goodbye");
+        let goodbye = hash_file_at(Some(dirfd), file).unwrap();
+        let hash = hash_static_file(&state, dirfd, file, later());
+        assert_eq!(hash.unwrap(), goodbye);
+
+        // A file modified before the epoch is hashed but not recorded.
+        let old = cstr!(b"old");
+        let fd = openat(Some(dirfd), old, O_CREAT | O_WRONLY, 0o644).unwrap();
+        File::from(fd).write_all(b"hello").unwrap();
+        let before_epoch = timespec{tv_sec: -86400, tv_nsec: 0};
+        utimensat(Some(dirfd), old, &[before_epoch, before_epoch], 0)
+            .unwrap();
+        let hash = hash_static_file(&state, dirfd, old, later());
+        assert_eq!(hash.unwrap(), hello);
+        assert!(state.recorded_input_digest(old).unwrap().is_none());
+    }
+
+    /// Create a context with default settings for performing actions.
+    fn test_context<'a>(state: &'a State, source_root: BorrowedFd<'a>)
+        -> Context<'a>
+    {
+        Context{
+            state,
+            source_root,
+            executor: &crate::executor::LocalExecutor,
+            capacity: Capacity{cpus: 1, memory: 0},
+            check_determinism: false,
+            runs_per_test: 1,
+            cancel: None,
+            profile: None,
+            stream_log: None,
+            failure_reports: false,
+            events: &|_| { },
+        }
+    }
+
+    #[test]
+    fn optional_outputs()
+    {
+        use os_ext::{S_IFREG, cstr, cstring, mkdtemp, mknodat, open};
+
+        /// Action with one required and one optional output.
+        struct Optional;
+
+        impl Action for Optional
+        {
+            fn inputs(&self) -> usize { 0 }
+            fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(2) }
+            fn optional_outputs(&self) -> Vec<usize> { vec![1] }
+            fn perform(&self, _: &Perform, _: &[InputPath]) -> action::Result
+                { unreachable!() }
+            fn hash(&self, _: &[Hash]) -> Hash { unreachable!() }
+        }
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let state = State::open(&path).unwrap();
+        let context = test_context(&state, source_root.as_fd());
+        let success = Success{
+            output_paths: vec![cstring!(b"required"), cstring!(b"optional")],
+            warnings: false,
+            dependencies: vec![],
+        };
+
+        // A missing optional output is recorded as absent.
+        let scratch = state.new_scratch_dir().unwrap();
+        let dirfd = Some(scratch.as_fd());
+        mknodat(dirfd, cstr!(b"required"), S_IFREG | 0o644, 0).unwrap();
+        let hashes =
+            cache_outputs(&context, &Optional, &scratch, &success).unwrap();
+        assert_matches!(hashes[..], [Some(_), None]);
+
+        // A present optional output is cached.
+        let scratch = state.new_scratch_dir().unwrap();
+        let dirfd = Some(scratch.as_fd());
+        mknodat(dirfd, cstr!(b"required"), S_IFREG | 0o644, 0).unwrap();
+        mknodat(dirfd, cstr!(b"optional"), S_IFREG | 0o644, 0).unwrap();
+        let hashes =
+            cache_outputs(&context, &Optional, &scratch, &success).unwrap();
+        assert_matches!(hashes[..], [Some(_), Some(_)]);
+
+        // A missing required output is an error.
+        let scratch = state.new_scratch_dir().unwrap();
+        let result = cache_outputs(&context, &Optional, &scratch, &success);
+        assert_matches!(
+            result,
+            Err(BuildError::CacheOutput(CacheOutputError::Missing)),
+        );
+
+        // Dependents of an absent output fail.
+        let cache_entry = ActionCacheEntry{
+            build_log: Hash([0; 32]),
+            build_log_ansi: false,
+            outputs: vec![Some(Hash([1; 32])), None],
+            warnings: false,
+        };
+        let label = ActionLabel{action: 0};
+        let outcomes = HashMap::from([
+            (&label, Outcome::Success{cache_entry, cache_hit: true}),
+        ]);
+        let output = ActionOutputLabel{action: label.clone(), output: 1};
+        let inputs = [Input::Dependency(output)];
+        let result = collect_input_paths(&context, &outcomes, &inputs);
+        assert!(matches!(result, Err(BuildError::AbsentOutput(..))));
+    }
+
+    #[test]
+    fn inline_input()
+    {
+        use os_ext::{cstring, mkdtemp, open};
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let state = State::open(&path).unwrap();
+        let context = test_context(&state, source_root.as_fd());
+
+        // The contents are written to the output cache.
+        let inputs = [Input::Inline(b"--flag".to_vec())];
+        let outcomes = HashMap::new();
+        let input_paths = collect_input_paths(&context, &outcomes, &inputs)
+            .unwrap().unwrap();
+        let InputPath{dirfd, path} = &input_paths[0];
+        let file = openat(Some(*dirfd), path, O_RDONLY, 0).unwrap();
+        let mut contents = Vec::new();
+        File::from(file).read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"--flag");
+
+        // The input is hashed by its contents.
+        let input_hashes =
+            compute_input_hashes(&context, &Dummy(1), &inputs, &input_paths,
+                                 true).unwrap();
+        let (dirfd, path) = state.cached_output(input_hashes[0]).unwrap();
+        assert_eq!(hash_file_at(Some(dirfd), &path).unwrap(), input_hashes[0]);
+
+        // Dry runs hash the contents without writing them.
+        let inputs = [Input::Inline(b"--dry-run".to_vec())];
+        let outcomes = HashMap::new();
+        let input_paths =
+            cached_input_paths(&context, &outcomes, &inputs, false)
+                .unwrap().unwrap();
+        let input_hashes =
+            compute_input_hashes(&context, &Dummy(1), &inputs, &input_paths,
+                                 false).unwrap();
+        let (dirfd, path) = state.cached_output(input_hashes[0]).unwrap();
+        let statbuf = fstatat(Some(dirfd), &path, AT_SYMLINK_NOFOLLOW);
+        assert_eq!(statbuf.err().map(|err| err.kind()), Some(NotFound));
+        let input_paths =
+            collect_input_paths(&context, &HashMap::new(), &inputs)
+                .unwrap().unwrap();
+        let InputPath{dirfd, path} = &input_paths[0];
+        assert_eq!(hash_file_at(Some(*dirfd), path).unwrap(), input_hashes[0]);
+    }
+
+    #[test]
+    fn explain_reasons()
+    {
+        use os_ext::{S_IFREG, cstr, cstring, mkdtemp, mknodat, open};
+
+        /// Action with a command made of the given elements.
+        struct Command(Vec<(&'static str, &'static str)>);
+
+        impl Action for Command
+        {
+            fn inputs(&self) -> usize { 2 }
+            fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(1) }
+            fn perform(&self, _: &Perform, _: &[InputPath]) -> action::Result
+                { unreachable!() }
+            fn hash(&self, input_hashes: &[Hash]) -> Hash
+            {
+                let mut h = Blake3::new();
+                h.put_slice(&self.0, |h, (n, v)| h.put_str(n).put_str(v));
+                h.put_slice(input_hashes, |h, i| h.put_hash(*i));
+                h.finalize()
+            }
+            fn command_elements(&self) -> Vec<(String, String)>
+            {
+                self.0.iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect()
+            }
+        }
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(source_root.as_fd());
+        mknodat(dirfd, cstr!(b"a"), S_IFREG | 0o644, 0).unwrap();
+        mknodat(dirfd, cstr!(b"b"), S_IFREG | 0o644, 0).unwrap();
+        let state = State::open(&path).unwrap();
+        let context = test_context(&state, source_root.as_fd());
+
+        let label = ActionLabel{action: 0};
+        let inputs = [
+            Input::StaticFile("a".try_into().unwrap()),
+            Input::StaticFile("b".try_into().unwrap()),
+        ];
+        let previous = Command(vec![
+            ("program", "cc"),
+            ("argument 0", "-O1"),
+            ("environment PATH", "/bin"),
+        ]);
+        state.record_action(&label, &ActionRecord{
+            command: previous.command_hash(),
+            inputs: vec![Hash([0; 32]), Hash([0; 32])],
+            command_elements: previous.command_elements(),
+        }).unwrap();
+
+        let reason = |action: &Command| {
+            let outcomes = HashMap::new();
+            let outcome =
+                dry_run_one(&context, &outcomes, &label, action, &inputs);
+            match outcome.unwrap() {
+                DryRunOutcome::Perform{reason} => reason,
+                outcome => panic!("{outcome:?}"),
+            }
+        };
+
+        // Every changed, added, and removed element is reported.
+        let current = Command(vec![
+            ("program", "cc"),
+            ("argument 0", "-O2"),
+            ("environment HOME", "/root"),
+        ]);
+        let element =
+            |name: &str, previous: Option<&str>, current: Option<&str>|
+                ChangedElement{
+                    name: name.to_owned(),
+                    previous: previous.map(str::to_owned),
+                    current: current.map(str::to_owned),
+                };
+        assert_matches!(
+            reason(&current),
+            PerformReason::ChangedCommand{elements}
+                if elements == [
+                    element("argument 0", Some("-O1"), Some("-O2")),
+                    element("environment HOME", None, Some("/root")),
+                    element("environment PATH", Some("/bin"), None),
+                ]
+        );
+
+        // Every changed input is reported.
+        assert_matches!(
+            reason(&previous),
+            PerformReason::ChangedInputs{inputs}
+                if inputs.iter().map(|i| i.index).eq([0, 1])
+        );
+    }
+
+    #[test]
+    fn failure_report()
+    {
+        use os_ext::{cstr, cstring, mkdtemp, open};
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let state = State::open(&path).unwrap();
+        let context = test_context(&state, source_root.as_fd());
+
+        // Prepare the failed action.
+        let label = ActionLabel{action: 0};
+        let inputs = [Input::Inline(b"--flag".to_vec())];
+        let input_paths =
+            collect_input_paths(&context, &HashMap::new(), &inputs)
+            .unwrap().unwrap();
+        let input_hashes =
+            compute_input_hashes(&context, &Dummy(1), &inputs, &input_paths,
+                                 true).unwrap();
+        let build_log = state.cache_contents(b"oops\n").unwrap();
+        let error = action::Error::Timeout(Duration::from_secs(1));
+
+        // Writing a report again replaces the previous one.
+        for _ in 0 .. 2 {
+            let report = write_failure_report(
+                &context, &label, &Dummy(1), &input_paths,
+                &input_hashes, build_log, &error,
+            ).unwrap();
+
+            let read = |name: &CStr| {
+                let file = open(&report.join(name), O_RDONLY, 0).unwrap();
+                let mut contents = String::new();
+                File::from(file).read_to_string(&mut contents).unwrap();
+                contents
+            };
+            assert_eq!(read(cstr!(b"error")), "Timeout after 1s\n");
+            assert_eq!(read(cstr!(b"build.log")), "oops\n");
+            assert_eq!(
+                read(cstr!(b"inputs")),
+                format!("0 {} {}\n", input_hashes[0],
+                        input_paths[0].path.to_string_lossy()),
+            );
+        }
+    }
+
+    #[test]
+    fn replay()
+    {
+        use {
+            os_ext::{cstring, mkdtemp, open},
+            std::sync::{Arc, Mutex},
+        };
+
+        /// Action that records the contents of its input when replayed.
+        struct Replayable(Arc<Mutex<Vec<Vec<u8>>>>);
+
+        impl Action for Replayable
+        {
+            fn inputs(&self) -> usize { 1 }
+            fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(1) }
+            fn perform(&self, _: &Perform, _: &[InputPath]) -> action::Result
+                { unreachable!() }
+            fn hash(&self, _: &[Hash]) -> Hash { Hash([0; 32]) }
+            fn replay(&self, _: BorrowedFd, input_paths: &[InputPath])
+                -> Result<(), action::Error>
+            {
+                let InputPath{dirfd, path} = &input_paths[0];
+                let file = openat(Some(*dirfd), path, O_RDONLY, 0).unwrap();
+                let mut contents = Vec::new();
+                File::from(file).read_to_end(&mut contents).unwrap();
+                self.0.lock().unwrap().push(contents);
+                Ok(())
+            }
+        }
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let state = State::open(&path).unwrap();
+        let context = test_context(&state, source_root.as_fd());
+
+        // Action 1 depends on action 0, which is not built.
+        let replayed = Arc::new(Mutex::new(Vec::new()));
+        let action = || -> Box<dyn Action> {
+            Box::new(Replayable(replayed.clone()))
+        };
+        let dependency = ActionOutputLabel{action: ActionLabel{action: 0},
+                                           output: 0};
+        let graph = ActionGraph{
+            actions: [
+                (ActionLabel{action: 0},
+                 (action(), vec![Input::Inline(b"hello".to_vec())])),
+                (ActionLabel{action: 1},
+                 (action(), vec![Input::Dependency(dependency)])),
+            ].into_iter().collect(),
+            artifacts: Default::default(),
+        };
+
+        // The inputs are staged for the action.
+        super::replay(&context, &graph, &ActionLabel{action: 0}).unwrap();
+        assert_eq!(*replayed.lock().unwrap(), [b"hello"]);
+
+        // Dependencies must be built first.
+        let result = super::replay(&context, &graph, &ActionLabel{action: 1});
+        assert_matches!(
+            result,
+            Err(ReplayError::DependencyNotBuilt(ActionLabel{action: 0})),
+        );
+
+        // Only actions in the graph can be replayed.
+        let result = super::replay(&context, &graph, &ActionLabel{action: 2});
+        assert_matches!(result, Err(ReplayError::NoSuchAction(..)));
+    }
+
+    #[test]
+    fn identical_actions()
+    {
+        use {
+            os_ext::{cstring, mkdtemp, mknodat, open},
+            std::sync::{Arc, atomic::{AtomicUsize, Ordering::SeqCst}},
+        };
+
+        /// Action that counts how often it is performed.
+        struct Counting
+        {
+            performed: Arc<AtomicUsize>,
+            fails: bool,
+        }
+
+        impl Action for Counting
+        {
+            fn inputs(&self) -> usize { 0 }
+            fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(1) }
+            fn perform(&self, perform: &Perform, _: &[InputPath])
+                -> action::Result
+            {
+                self.performed.fetch_add(1, SeqCst);
+                if self.fails {
+                    return Err(action::Error::Cancelled);
+                }
+                let output = cstring!(b"output");
+                mknodat(Some(perform.scratch), &output, S_IFREG | 0o644, 0)
+                    .unwrap();
+                let output_paths = vec![output];
+                Ok(Success{output_paths, warnings: false, dependencies: vec![]})
+            }
+            fn hash(&self, _: &[Hash]) -> Hash { Hash([self.fails as u8; 32]) }
+        }
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let state = State::open(&path).unwrap();
+        let merged = Mutex::new(Vec::new());
+        let finished = Mutex::new(None);
+        let context = Context{
+            events: &|event| {
+                assert!(finished.lock().unwrap().is_none(), "{event}");
+                match event {
+                    BuildEvent::Merged{label, original} => {
+                        let merge = (label.action, original.action);
+                        merged.lock().unwrap().push(merge);
+                    },
+                    BuildEvent::Finished{statistics} =>
+                        *finished.lock().unwrap() = Some(statistics),
+                    _ => (),
+                }
+            },
+            ..test_context(&state, source_root.as_fd())
+        };
+
+        // Actions 0 and 1 succeed, actions 2 and 3 fail.
+        let performed = Arc::new(AtomicUsize::new(0));
+        let action = |fails| -> Box<dyn Action> {
+            Box::new(Counting{performed: performed.clone(), fails})
+        };
+        let graph = ActionGraph{
+            actions: (0 .. 4)
+                .map(|i| (ActionLabel{action: i}, (action(i >= 2), vec![])))
+                .collect(),
+            artifacts: Default::default(),
+        };
+
+        // Each distinct action is performed once,
+        // and both labels receive its result.
+        let outcomes = drive(&context, &graph).unwrap();
+        assert_eq!(performed.load(SeqCst), 2);
+        assert_eq!(*merged.lock().unwrap(), [(1, 0), (3, 2)]);
+        let statistics = finished.lock().unwrap().unwrap();
+        assert_eq!(statistics.performed, 2);
+        assert_eq!(statistics.action_cache_hits, 1);
+        assert_eq!(statistics.failed, 2);
+        let labels: Vec<_> = (0 .. 4).map(|action| ActionLabel{action})
+            .collect();
+        let outcome = |action: usize| &outcomes[&&labels[action]];
+        assert_matches!(outcome(0), Outcome::Success{cache_hit: false, ..});
+        assert_matches!(outcome(1), Outcome::Success{cache_hit: true, ..});
+        assert_matches!(outcome(2), Outcome::Failed{..});
+        assert_matches!(
+            outcome(3),
+            Outcome::Failed{error: BuildError::IdenticalActionFailed(
+                ActionLabel{action: 2}), ..},
+        );
+
+        // The statistics of a build do not include earlier builds.
+        assert!(statistics.output_cache_hits > 0);
+        *finished.lock().unwrap() = None;
+        drive(&context, &graph).unwrap();
+        let again = finished.lock().unwrap().unwrap();
+        assert_eq!(statistics.output_cache_hits + again.output_cache_hits,
+                   state.output_cache_hits());
+    }
+
+    #[test]
+    fn check_determinism_retries()
+    {
+        use {
+            crate::action::RetryPolicy,
+            os_ext::{cstring, mkdtemp, mknodat, open},
+            std::sync::atomic::{AtomicUsize, Ordering::SeqCst},
+        };
+
+        /// Action whose second run fails once.
+        struct Flaky
+        {
+            performed: AtomicUsize,
+            cancels: bool,
+        }
+
+        impl Action for Flaky
+        {
+            fn inputs(&self) -> usize { 0 }
+            fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(1) }
+            fn retry_policy(&self) -> RetryPolicy
+            {
+                RetryPolicy{max_attempts: 2, exit_codes: None}
+            }
+            fn perform(&self, perform: &Perform, _: &[InputPath])
+                -> action::Result
+            {
+                if self.performed.fetch_add(1, SeqCst) == 1 {
+                    return Err(if self.cancels {
+                        action::Error::Cancelled
+                    } else {
+                        action::Error::Timeout(Duration::from_secs(1))
+                    });
+                }
+                let output = cstring!(b"output");
+                mknodat(Some(perform.scratch), &output, S_IFREG | 0o644, 0)
+                    .unwrap();
+                let output_paths = vec![output];
+                Ok(Success{output_paths, warnings: false, dependencies: vec![]})
+            }
+            fn hash(&self, _: &[Hash]) -> Hash
+                { Hash([self.cancels as u8; 32]) }
+        }
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let state = State::open(&path).unwrap();
+        let context = Context{
+            check_determinism: true,
+            ..test_context(&state, source_root.as_fd())
+        };
+
+        let action = |cancels| -> Box<dyn Action> {
+            Box::new(Flaky{performed: AtomicUsize::new(0), cancels})
+        };
+        let graph = ActionGraph{
+            actions: (0 .. 2)
+                .map(|i| (ActionLabel{action: i}, (action(i == 1), vec![])))
+                .collect(),
+            artifacts: Default::default(),
+        };
+        let outcomes = drive(&context, &graph).unwrap();
+        let labels: Vec<_> = (0 .. 2).map(|action| ActionLabel{action})
+            .collect();
+
+        // A failed second run is retried before it counts as nondeterminism.
+        assert_matches!(outcomes[&&labels[0]], Outcome::Success{..});
+
+        // Cancellation of the second run is reported as it is.
+        assert_matches!(
+            outcomes[&&labels[1]],
+            Outcome::Failed{
+                error: BuildError::Perform(action::Error::Cancelled),
+                ..
+            },
+        );
+    }
+
+    /// Create an action graph from a dependency list.
+    fn graph(dependencies: &[&[usize]]) -> ActionGraph
+    {
+        let actions =
+            dependencies.iter().enumerate()
+            .map(|(action, dependencies)| {
+                let inputs: Vec<Input> =
+                    dependencies.iter()
+                    .map(|&action| ActionOutputLabel{
+                        action: ActionLabel{action},
+                        output: 0,
+                    })
+                    .map(Input::Dependency)
+                    .collect();
+                let action_: Box<dyn Action> = Box::new(Dummy(inputs.len()));
+                (ActionLabel{action}, (action_, inputs))
+            })
+            .collect();
+        ActionGraph{actions, artifacts: Default::default()}
+    }
+
+    #[test]
+    fn prepare_order()
+    {
+        let graph = graph(&[&[1, 2], &[2], &[]]);
+        let linear = prepare(&graph).unwrap();
+        let labels: Vec<usize> = linear.iter().map(|e| e.0.action).collect();
+        assert_eq!(labels, [2, 1, 0]);
+    }
+
+    #[test]
+    fn prepare_cycle()
+    {
+        let graph = graph(&[&[3], &[2], &[0, 3], &[1], &[]]);
+        let result = prepare(&graph).map(drop);
+        let expected: Vec<ActionLabel> =
+            [0, 3, 1, 2].into_iter().map(|action| ActionLabel{action}).collect();
+        assert_matches!(&result, Err(DriveError::CyclicDependency(c))
+                                 if c == &expected);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "There are actions that cyclically depend on each other: \
+             #0 -> #3 -> #1 -> #2 -> #0",
+        );
+    }
+
+    #[test]
+    fn prepare_self_cycle()
+    {
+        let graph = graph(&[&[], &[1]]);
+        let result = prepare(&graph).map(drop);
+        let expected = vec![ActionLabel{action: 1}];
+        assert_matches!(result, Err(DriveError::CyclicDependency(c))
+                                if c == expected);
+    }
+
+    #[test]
+    fn prepare_long_chain()
+    {
+        // This would overflow the stack with a recursive traversal.
+        const LENGTH: usize = 100_000;
+        let dependencies: Vec<Vec<usize>> =
+            (0 .. LENGTH).map(|i| (i + 1 .. LENGTH).take(1).collect()).collect();
+        let dependencies: Vec<&[usize]> =
+            dependencies.iter().map(Vec::as_slice).collect();
+        let graph = graph(&dependencies);
+        let linear = prepare(&graph).unwrap();
+        assert_eq!(linear.len(), LENGTH);
+        assert_eq!(linear[0].0.action, LENGTH - 1);
+    }
+
+    #[test]
+    fn scheduler()
+    {
+        // Action 0 depends on actions 1 and 2, which are independent.
+        let graph = graph(&[&[1, 2], &[], &[]]);
+        let linear = prepare(&graph).unwrap();
+        let labels: Vec<usize> = linear.iter().map(|e| e.0.action).collect();
+        assert_eq!(labels, [1, 2, 0]);
+
+        // Independent actions are started concurrently,
+        // dependent actions only when their dependencies are built.
+        let capacity = Capacity{cpus: 2, memory: u64::MAX};
+        let mut scheduler = Scheduler::new(capacity, &linear);
+        assert_eq!(scheduler.next(), Some(0));
+        assert_eq!(scheduler.next(), Some(1));
+        assert_eq!(scheduler.next(), None);
+        scheduler.finish(0);
+        assert_eq!(scheduler.next(), None);
+        scheduler.finish(1);
+        assert_eq!(scheduler.next(), Some(2));
+        scheduler.finish(2);
+
+        // Actions that do not fit together are started one by one.
+        let mut scheduler = Scheduler::new(capacity, &linear);
+        scheduler.resources[0].cpus = 2;
+        assert_eq!(scheduler.next(), Some(0));
+        assert_eq!(scheduler.next(), None);
+        scheduler.finish(0);
+        assert_eq!(scheduler.next(), Some(1));
+
+        // Actions that need more than the capacity are started alone.
+        let mut scheduler = Scheduler::new(capacity, &linear);
+        scheduler.resources[0].cpus = 3;
+        assert_eq!(scheduler.next(), Some(0));
+        assert_eq!(scheduler.next(), None);
+        scheduler.finish(0);
+        assert_eq!(scheduler.next(), Some(1));
+
+        // Exclusive actions run alone and are not starved.
+        let capacity = Capacity{cpus: 8, memory: u64::MAX};
+        let graph = self::graph(&[&[], &[], &[]]);
+        let linear = prepare(&graph).unwrap();
+        let mut scheduler = Scheduler::new(capacity, &linear);
+        scheduler.resources[1].exclusive = true;
+        assert_eq!(scheduler.next(), Some(0));
+        assert_eq!(scheduler.next(), None);
+        scheduler.finish(0);
+        assert_eq!(scheduler.next(), Some(1));
+        assert_eq!(scheduler.next(), None);
+        scheduler.finish(1);
+        assert_eq!(scheduler.next(), Some(2));
+
+        // Released actions make room for others, but are not built yet.
+        let capacity = Capacity{cpus: 1, memory: u64::MAX};
+        let graph = self::graph(&[&[1], &[], &[]]);
+        let linear = prepare(&graph).unwrap();
+        let labels: Vec<usize> = linear.iter().map(|e| e.0.action).collect();
+        assert_eq!(labels, [1, 0, 2]);
+        let mut scheduler = Scheduler::new(capacity, &linear);
+        assert_eq!(scheduler.next(), Some(0));
+        assert_eq!(scheduler.next(), None);
+        scheduler.release(0);
+        assert_eq!(scheduler.running, 0);
+        assert_eq!(scheduler.next(), Some(2));
+        scheduler.finish(0);
+        assert_eq!(scheduler.running, 1);
+        assert_eq!(scheduler.next(), None);
+        scheduler.finish(2);
+        assert_eq!(scheduler.next(), Some(1));
+    }
+
+    #[test]
+    fn diff_outputs_reports_differing_files()
+    {
+        use os_ext::{S_IFREG, cstr, cstring, mkdirat, mkdtemp, mknodat};
+
+        // Create two outputs that differ in some files.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dir = openat(None, &path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+        for run in [cstr!(b"1"), cstr!(b"2")] {
+            let mode = if run == cstr!(b"1") { 0o644 } else { 0o755 };
+            mkdirat(dirfd, run, 0o755).unwrap();
+            let run = openat(dirfd, run, O_DIRECTORY | O_RDONLY, 0).unwrap();
+            let run = Some(run.as_fd());
+            mknodat(run, cstr!(b"file"),       S_IFREG | 0o644, 0).unwrap();
+            mkdirat(run, cstr!(b"dir"),                  0o755   ).unwrap();
+            mknodat(run, cstr!(b"dir/same"),   S_IFREG | 0o644, 0).unwrap();
+            mknodat(run, cstr!(b"dir/mode"),   S_IFREG | mode,  0).unwrap();
+        }
+        mknodat(dirfd, cstr!(b"2/dir/extra"), S_IFREG | 0o644, 0).unwrap();
+
+        let diff = |path: &CStr| {
+            let mut differences = Vec::new();
+            diff_outputs(dir.as_fd(), &cstr!(b"1/").join(path),
+                         dir.as_fd(), &cstr!(b"2/").join(path),
+                         &mut differences).unwrap();
+            differences
+        };
+        assert_eq!(diff(cstr!(b"file")), Vec::<CString>::new());
+        assert_eq!(diff(cstr!(b"dir")), [cstring!(b"1/dir/mode"),
+                                         cstring!(b"1/dir/extra")]);
+    }
+}
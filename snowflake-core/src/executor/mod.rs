@@ -0,0 +1,47 @@
+//! Performing actions on behalf of the driver.
+
+pub use self::{protocol::{ReadExt, WriteExt}, remote::*};
+
+use crate::action::{self, Action, InputPath, Perform};
+
+mod protocol;
+mod remote;
+
+/// Strategy for performing actions.
+///
+/// The driver does not call [`Action::perform`] directly;
+/// it goes through an executor instead.
+/// This allows the driver to be oblivious to
+/// where and how actions are actually performed.
+///
+/// Executors must be [`Sync`] because the driver
+/// performs independent actions concurrently.
+pub trait Executor: Sync
+{
+    /// Perform an action.
+    ///
+    /// The arguments and the result have the same meaning
+    /// as those of [`Action::perform`].
+    fn perform(
+        &self,
+        action: &dyn Action,
+        perform: &Perform,
+        input_paths: &[InputPath],
+    ) -> action::Result;
+}
+
+/// Executor that performs actions in the current process.
+pub struct LocalExecutor;
+
+impl Executor for LocalExecutor
+{
+    fn perform(
+        &self,
+        action: &dyn Action,
+        perform: &Perform,
+        input_paths: &[InputPath],
+    ) -> action::Result
+    {
+        action.perform(perform, input_paths)
+    }
+}
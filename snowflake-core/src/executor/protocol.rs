@@ -0,0 +1,378 @@
+use {
+    crate::fs_util::read_entries,
+    os_ext::{
+        AT_SYMLINK_NOFOLLOW,
+        O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_RDONLY, O_WRONLY,
+        S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_IXUSR,
+        fstatat, mkdirat, openat, readlinkat, symlinkat,
+    },
+    snowflake_util::{basename::Basename, hash::Hash},
+    std::{
+        ffi::{CStr, CString},
+        fs::File,
+        io::{self, ErrorKind::{InvalidData, UnexpectedEof}, Read, Write},
+        os::unix::io::{AsFd, BorrowedFd},
+    },
+};
+
+/// Methods for writing values in the remote execution protocol.
+///
+/// Integers are written in little-endian order,
+/// and byte strings are preceded by their length.
+/// Actions may use these to implement [`Action::encode`],
+/// and [`ReadExt`] to decode what they encoded.
+///
+/// [`Action::encode`]: `crate::action::Action::encode`
+#[allow(missing_docs)]
+pub trait WriteExt: Write
+{
+    fn put_bool(&mut self, value: bool) -> io::Result<()>
+    {
+        self.put_u8(value as u8)
+    }
+
+    fn put_u8(&mut self, value: u8) -> io::Result<()>
+    {
+        self.write_all(&[value])
+    }
+
+    fn put_u64(&mut self, value: u64) -> io::Result<()>
+    {
+        self.write_all(&value.to_le_bytes())
+    }
+
+    fn put_usize(&mut self, value: usize) -> io::Result<()>
+    {
+        self.put_u64(value as u64)
+    }
+
+    fn put_hash(&mut self, hash: Hash) -> io::Result<()>
+    {
+        self.write_all(&hash.0)
+    }
+
+    fn put_bytes(&mut self, value: &[u8]) -> io::Result<()>
+    {
+        self.put_usize(value.len())?;
+        self.write_all(value)
+    }
+
+    fn put_str(&mut self, value: &str) -> io::Result<()>
+    {
+        self.put_bytes(value.as_bytes())
+    }
+
+    fn put_cstr(&mut self, value: &CStr) -> io::Result<()>
+    {
+        self.put_bytes(value.to_bytes())
+    }
+
+    /// Write the length of a slice followed by each of its elements.
+    fn put_slice<F, T>(&mut self, value: &[T], mut f: F) -> io::Result<()>
+        where F: FnMut(&mut Self, &T) -> io::Result<()>
+            , Self: Sized
+    {
+        self.put_usize(value.len())?;
+        for value in value {
+            f(self, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T> WriteExt for T
+    where T: Write + ?Sized
+{
+}
+
+/// Methods for reading values written with [`WriteExt`].
+///
+/// Values that [`WriteExt`] could not have written
+/// are rejected with [`InvalidData`].
+#[allow(missing_docs)]
+pub trait ReadExt: Read
+{
+    fn get_bool(&mut self) -> io::Result<bool>
+    {
+        match self.get_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("Invalid boolean")),
+        }
+    }
+
+    fn get_u8(&mut self) -> io::Result<u8>
+    {
+        let mut buf = [0; 1];
+        self.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn get_u64(&mut self) -> io::Result<u64>
+    {
+        let mut buf = [0; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn get_usize(&mut self) -> io::Result<usize>
+    {
+        usize::try_from(self.get_u64()?)
+            .map_err(|_| invalid_data("Integer is too large"))
+    }
+
+    fn get_hash(&mut self) -> io::Result<Hash>
+    {
+        let mut hash = Hash([0; 32]);
+        self.read_exact(&mut hash.0)?;
+        Ok(hash)
+    }
+
+    fn get_bytes(&mut self) -> io::Result<Vec<u8>>
+    {
+        // The length is not trusted to preallocate the buffer,
+        // so the buffer grows only as bytes actually arrive.
+        let len = self.get_u64()?;
+        let mut buf = Vec::new();
+        Read::take(&mut *self, len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(UnexpectedEof.into());
+        }
+        Ok(buf)
+    }
+
+    fn get_string(&mut self) -> io::Result<String>
+    {
+        String::from_utf8(self.get_bytes()?)
+            .map_err(|_| invalid_data("String is not valid UTF-8"))
+    }
+
+    fn get_cstring(&mut self) -> io::Result<CString>
+    {
+        CString::new(self.get_bytes()?)
+            .map_err(|_| invalid_data("String contains a nul byte"))
+    }
+
+    /// Read a slice written with [`WriteExt::put_slice`].
+    fn get_vec<F, T, E>(&mut self, mut f: F) -> Result<Vec<T>, E>
+        where F: FnMut(&mut Self) -> Result<T, E>
+            , E: From<io::Error>
+            , Self: Sized
+    {
+        let mut vec = Vec::new();
+        for _ in 0 .. self.get_u64()? {
+            vec.push(f(self)?);
+        }
+        Ok(vec)
+    }
+}
+
+impl<T> ReadExt for T
+    where T: Read + ?Sized
+{
+}
+
+/// Create an error about a malformed message.
+pub (super) fn invalid_data(message: &'static str) -> io::Error
+{
+    io::Error::new(InvalidData, message)
+}
+
+// Byte which indicates the type of file.
+const FILE_TYPE_REG: u8 = 0;
+const FILE_TYPE_DIR: u8 = 1;
+const FILE_TYPE_LNK: u8 = 2;
+
+/// Write a file, and if it is a directory, all its entries.
+///
+/// Files are described the same way as when they are hashed
+/// (see [`hash_file_at`]), so only what is hashed is transferred.
+///
+/// [`hash_file_at`]: `snowflake_util::hash::hash_file_at`
+pub (super) fn put_file_at(
+    writer: &mut impl Write,
+    dirfd:  Option<BorrowedFd>,
+    path:   &CStr,
+) -> io::Result<()>
+{
+    let statbuf = fstatat(dirfd, path, AT_SYMLINK_NOFOLLOW)?;
+    match statbuf.st_mode & S_IFMT {
+        S_IFREG => {
+            writer.put_u8(FILE_TYPE_REG)?;
+            writer.put_bool(statbuf.st_mode & S_IXUSR != 0)?;
+            let size = statbuf.st_size as u64;
+            writer.put_u64(size)?;
+            let file = openat(dirfd, path, O_NOFOLLOW | O_RDONLY, 0)?;
+            let copied = io::copy(&mut File::from(file).take(size), writer)?;
+            if copied != size {
+                return Err(io::Error::other("File shrunk while sending it"));
+            }
+        },
+        S_IFDIR => {
+            writer.put_u8(FILE_TYPE_DIR)?;
+            let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+            let dir = openat(dirfd, path, flags, 0)?;
+            let entries = read_entries(dir.as_fd())?;
+            writer.put_usize(entries.len())?;
+            for name in entries {
+                writer.put_cstr(&name)?;
+                put_file_at(writer, Some(dir.as_fd()), &name)?;
+            }
+        },
+        S_IFLNK => {
+            writer.put_u8(FILE_TYPE_LNK)?;
+            writer.put_cstr(&readlinkat(dirfd, path)?)?;
+        },
+        _ =>
+            return Err(io::Error::other("File has an unsupported type")),
+    }
+    Ok(())
+}
+
+/// How deeply directories received with [`get_file_at`] may be nested.
+///
+/// Each level of nesting is a recursive call, and the nesting is
+/// chosen by the peer, so without a limit a peer could overflow the stack.
+const MAX_DEPTH: usize = 512;
+
+/// Create a file written with [`put_file_at`].
+///
+/// The file must not exist yet.
+/// Names of directory entries must be basenames,
+/// so that files cannot be created outside the given path.
+/// Directories nested more than [`MAX_DEPTH`] levels deep are rejected.
+pub (super) fn get_file_at(
+    reader: &mut impl Read,
+    dirfd:  Option<BorrowedFd>,
+    path:   &CStr,
+) -> io::Result<()>
+{
+    get_file_at_depth(reader, dirfd, path, 0)
+}
+
+fn get_file_at_depth(
+    reader: &mut impl Read,
+    dirfd:  Option<BorrowedFd>,
+    path:   &CStr,
+    depth:  usize,
+) -> io::Result<()>
+{
+    match reader.get_u8()? {
+        FILE_TYPE_REG => {
+            let executable = reader.get_bool()?;
+            let size = reader.get_u64()?;
+            let flags = O_CREAT | O_EXCL | O_NOFOLLOW | O_WRONLY;
+            let mode = if executable { 0o755 } else { 0o644 };
+            let file = openat(dirfd, path, flags, mode)?;
+            let mut file = File::from(file);
+            let copied = io::copy(&mut reader.by_ref().take(size), &mut file)?;
+            if copied != size {
+                return Err(UnexpectedEof.into());
+            }
+        },
+        FILE_TYPE_DIR => {
+            if depth == MAX_DEPTH {
+                return Err(invalid_data("Directories are nested too deeply"));
+            }
+            mkdirat(dirfd, path, 0o755)?;
+            let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+            let dir = openat(dirfd, path, flags, 0)?;
+            for _ in 0 .. reader.get_u64()? {
+                let name = Basename::new(reader.get_cstring()?)
+                    .map_err(|_| invalid_data("Entry name is not a basename"))?;
+                get_file_at_depth(reader, Some(dir.as_fd()), &name,
+                                  depth + 1)?;
+            }
+        },
+        FILE_TYPE_LNK => {
+            let target = reader.get_cstring()?;
+            symlinkat(&target, dirfd, path)?;
+        },
+        _ =>
+            return Err(invalid_data("Invalid file type")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        os_ext::{cstr, cstring, mkdtemp},
+        snowflake_util::hash::hash_file_at,
+    };
+
+    #[test]
+    fn values()
+    {
+        let mut buf = Vec::new();
+        buf.put_bool(true).unwrap();
+        buf.put_usize(300).unwrap();
+        buf.put_hash(Hash([7; 32])).unwrap();
+        buf.put_str("hello").unwrap();
+        buf.put_cstr(cstr!(b"world")).unwrap();
+        buf.put_slice(&[1, 2], |buf, &n| buf.put_u64(n)).unwrap();
+
+        let mut reader = &buf[..];
+        assert!(reader.get_bool().unwrap());
+        assert_eq!(reader.get_usize().unwrap(), 300);
+        assert_eq!(reader.get_hash().unwrap(), Hash([7; 32]));
+        assert_eq!(reader.get_string().unwrap(), "hello");
+        assert_eq!(reader.get_cstring().unwrap(), cstring!(b"world"));
+        let vec: io::Result<_> = reader.get_vec(|reader| reader.get_u64());
+        assert_eq!(vec.unwrap(), [1, 2]);
+        assert!(reader.is_empty());
+
+        // Lengths beyond the end of the message are not trusted.
+        let mut reader = &u64::MAX.to_le_bytes()[..];
+        assert_eq!(reader.get_bytes().unwrap_err().kind(), UnexpectedEof);
+        let mut reader = &[2][..];
+        assert_eq!(reader.get_bool().unwrap_err().kind(), InvalidData);
+    }
+
+    #[test]
+    fn files()
+    {
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dir = openat(None, &path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+
+        // Send a directory with every supported type of file.
+        mkdirat(dirfd, cstr!(b"source"), 0o755).unwrap();
+        mkdirat(dirfd, cstr!(b"source/empty"), 0o755).unwrap();
+        symlinkat(cstr!(b"target"), dirfd, cstr!(b"source/link")).unwrap();
+        for (name, mode) in [(cstr!(b"source/data"), 0o644),
+                             (cstr!(b"source/tool"), 0o755)] {
+            let flags = O_CREAT | O_WRONLY;
+            let file = openat(dirfd, name, flags, mode).unwrap();
+            File::from(file).write_all(name.to_bytes()).unwrap();
+        }
+        let mut buf = Vec::new();
+        put_file_at(&mut buf, dirfd, cstr!(b"source")).unwrap();
+
+        // The received directory is identical to the sent one.
+        get_file_at(&mut &buf[..], dirfd, cstr!(b"target")).unwrap();
+        assert_eq!(
+            hash_file_at(dirfd, cstr!(b"target")).unwrap(),
+            hash_file_at(dirfd, cstr!(b"source")).unwrap(),
+        );
+
+        // Entries cannot escape the received directory.
+        let mut buf = vec![FILE_TYPE_DIR];
+        buf.put_usize(1).unwrap();
+        buf.put_cstr(cstr!(b"..")).unwrap();
+        let result = get_file_at(&mut &buf[..], dirfd, cstr!(b"escape"));
+        assert_eq!(result.unwrap_err().kind(), InvalidData);
+
+        // Directories cannot be nested arbitrarily deeply.
+        let mut buf = Vec::new();
+        for _ in 0 ..= MAX_DEPTH {
+            buf.put_u8(FILE_TYPE_DIR).unwrap();
+            buf.put_usize(1).unwrap();
+            buf.put_cstr(cstr!(b"d")).unwrap();
+        }
+        let result = get_file_at(&mut &buf[..], dirfd, cstr!(b"deep"));
+        assert_eq!(result.unwrap_err().kind(), InvalidData);
+    }
+}
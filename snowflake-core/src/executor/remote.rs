@@ -0,0 +1,823 @@
+use {
+    super::{
+        Executor, LocalExecutor,
+        protocol::{ReadExt, WriteExt, get_file_at, invalid_data, put_file_at},
+    },
+    crate::{
+        action::{
+            self, Action, Dependency, EncodedAction, Error, InputPath,
+            Perform, Success,
+        },
+        state::State,
+    },
+    anyhow::Context,
+    os_ext::{
+        AT_SYMLINK_NOFOLLOW,
+        cstr, fstatat, pipe2,
+        io::BorrowedFdExt,
+    },
+    snowflake_util::{
+        hash::{Hash, hash_file_at},
+        workspace_path::WorkspacePath,
+    },
+    std::{
+        borrow::Cow,
+        ffi::CString,
+        fs::File,
+        io::{
+            self, BufReader, BufWriter, ErrorKind::NotFound,
+            Read, Seek, Write,
+        },
+        net::{SocketAddr, TcpStream},
+        os::unix::{
+            io::{AsFd, AsRawFd, BorrowedFd},
+            process::ExitStatusExt,
+        },
+        process::ExitStatus,
+        thread,
+        time::Duration,
+    },
+};
+
+// Byte which indicates the type of message sent by the server
+// while and after performing the action.
+const MESSAGE_LOG:     u8 = 0;
+const MESSAGE_SUCCESS: u8 = 1;
+const MESSAGE_ERROR:   u8 = 2;
+
+// Byte which indicates the type of error.
+const ERROR_TIMEOUT:               u8 = 0;
+const ERROR_CANCELLED:             u8 = 1;
+const ERROR_EXIT_STATUS:           u8 = 2;
+const ERROR_HASH_MISMATCH:         u8 = 3;
+const ERROR_UNDECLARED_DEPENDENCY: u8 = 4;
+const ERROR_NOT_REPLAYABLE:        u8 = 5;
+const ERROR_UNEXPECTED:            u8 = 6;
+
+/// Executor that performs actions on a build server.
+///
+/// For each action, the executor connects to the build server
+/// and sends it the [encoded action][`Action::encode`]
+/// and the hashes of the inputs. The server replies with
+/// the inputs it does not have yet, which the executor then sends.
+/// While the server performs the action, it streams the build log back,
+/// and when it is done, it sends the complete build log, followed by
+/// the hashes and contents of the outputs, or by the error.
+/// The executor places the outputs in the scratch directory,
+/// where the driver expects them. See [`serve_connection`]
+/// for the other side of the protocol.
+///
+/// Actions that cannot be encoded are performed locally.
+/// If the build is cancelled, the executor closes the connection,
+/// which in turn cancels the action on the build server.
+pub struct RemoteExecutor
+{
+    /// The address of the build server.
+    pub address: SocketAddr,
+}
+
+impl Executor for RemoteExecutor
+{
+    fn perform(
+        &self,
+        action: &dyn Action,
+        perform: &Perform,
+        input_paths: &[InputPath],
+    ) -> action::Result
+    {
+        match action.encode() {
+            Some(encoded) =>
+                self.perform_remotely(action, &encoded, perform, input_paths),
+            None =>
+                LocalExecutor.perform(action, perform, input_paths),
+        }
+    }
+}
+
+impl RemoteExecutor
+{
+    fn perform_remotely(
+        &self,
+        action: &dyn Action,
+        encoded: &EncodedAction,
+        perform: &Perform,
+        input_paths: &[InputPath],
+    ) -> action::Result
+    {
+        let address = self.address;
+        let stream = TcpStream::connect(address)                                .with_context(|| format!("Connect to build server {address}"))?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+
+        // Send the action and the hashes of its inputs.
+        let mut input_hashes = Vec::with_capacity(input_paths.len());
+        for InputPath{dirfd, path} in input_paths {
+            let hash = hash_file_at(Some(*dirfd), path)                         .with_context(|| format!("Hash input {path:?}"))?;
+            input_hashes.push(hash);
+        }
+        send_request(&mut writer, encoded, &input_hashes)                       .with_context(|| "Send action to build server")?;
+
+        // Send the inputs that the build server does not have.
+        let missing = receive_missing(&mut reader, input_paths.len())           .with_context(|| "Receive missing inputs from build server")?;
+        for index in missing {
+            let InputPath{dirfd, path} = &input_paths[index];
+            put_file_at(&mut writer, Some(*dirfd), path)                        .with_context(|| format!("Send input {path:?} to build server"))?;
+        }
+        writer.flush()                                                          .with_context(|| "Send inputs to build server")?;
+
+        let build_log = perform.build_log.try_to_owned()                        .with_context(|| "Duplicate build log")?;
+        let mut build_log = File::from(build_log);
+        let stream_log = perform.stream_log.map(BorrowedFdExt::try_to_owned);
+        let stream_log = stream_log.transpose()                                 .with_context(|| "Duplicate stream log")?;
+        let mut stream_log = stream_log.map(File::from);
+
+        loop {
+            // Only wait if there is no buffered message left.
+            if reader.buffer().is_empty() {
+                let cancel = perform.cancel;
+                let cancelled = wait_for_message(stream.as_fd(), cancel)        .with_context(|| "Wait for build server")?;
+                if cancelled {
+                    return Err(Error::Cancelled);
+                }
+            }
+
+            let message = reader.get_u8()                                       .with_context(|| "Receive message from build server")?;
+            match message {
+                MESSAGE_LOG => {
+                    let chunk = reader.get_bytes()                              .with_context(|| "Receive build log from build server")?;
+                    if let Some(stream_log) = &mut stream_log {
+                        stream_log.write_all(&chunk)                            .with_context(|| "Write to stream log")?;
+                    }
+                },
+                MESSAGE_SUCCESS | MESSAGE_ERROR => {
+                    let contents = reader.get_bytes()                           .with_context(|| "Receive build log from build server")?;
+                    build_log.write_all(&contents)                              .with_context(|| "Write build log")?;
+                    return if message == MESSAGE_SUCCESS {
+                        let outputs = action.outputs().get();
+                        let partial_inputs = action.partial_inputs();
+                        receive_success(&mut reader, perform.scratch,
+                                        outputs, &partial_inputs)
+                    } else {
+                        let error = get_error(&mut reader)                      .with_context(|| "Receive error from build server")?;
+                        Err(error)
+                    };
+                },
+                _ => {
+                    let error = invalid_data("Invalid message type");
+                    return Err(anyhow::Error::from(error))                      .with_context(|| "Receive message from build server")
+                        .map_err(Error::from);
+                },
+            }
+        }
+    }
+}
+
+/// Send the encoded action and the hashes of its inputs.
+fn send_request(
+    writer: &mut impl Write,
+    encoded: &EncodedAction,
+    input_hashes: &[Hash],
+) -> io::Result<()>
+{
+    writer.put_str(&encoded.kind)?;
+    writer.put_bytes(&encoded.data)?;
+    writer.put_usize(input_hashes.len())?;
+    for hash in input_hashes {
+        writer.put_hash(*hash)?;
+    }
+    writer.flush()
+}
+
+/// Receive the indices of the inputs that the build server does not have.
+fn receive_missing(reader: &mut impl Read, inputs: usize)
+    -> io::Result<Vec<usize>>
+{
+    let mut missing = Vec::new();
+    for _ in 0 .. reader.get_u64()? {
+        let index = reader.get_usize()?;
+        if index >= inputs {
+            return Err(invalid_data("Input index is out of bounds"));
+        }
+        missing.push(index);
+    }
+    Ok(missing)
+}
+
+/// Receive the outputs of an action that succeeded.
+///
+/// The outputs are placed in the scratch directory,
+/// and checked against the hashes sent by the build server.
+fn receive_success(
+    reader:         &mut impl Read,
+    scratch:        BorrowedFd,
+    outputs:        usize,
+    partial_inputs: &[usize],
+) -> action::Result
+{
+    let warnings = reader.get_bool()                                            .with_context(|| "Receive warnings from build server")?;
+
+    let count = reader.get_usize()                                              .with_context(|| "Receive outputs from build server")?;
+    if count != outputs {
+        let error = invalid_data("Wrong number of outputs");
+        return Err(anyhow::Error::from(error))                                  .with_context(|| "Receive outputs from build server")
+            .map_err(Error::from);
+    }
+
+    // Absent outputs are not created, like optional outputs
+    // that an action performed locally did not produce.
+    let mut output_paths = Vec::with_capacity(outputs);
+    for index in 0 .. outputs {
+        let path = CString::new(format!("output-{index}")).unwrap();
+        let present = reader.get_bool()                                         .with_context(|| "Receive outputs from build server")?;
+        if present {
+            let expected = reader.get_hash()                                    .with_context(|| "Receive outputs from build server")?;
+            get_file_at(reader, Some(scratch), &path)                           .with_context(|| format!("Receive output {index} from build server"))?;
+            let actual = hash_file_at(Some(scratch), &path)                     .with_context(|| format!("Hash output {index}"))?;
+            if actual != expected {
+                return Err(Error::HashMismatch{expected, actual});
+            }
+        }
+        output_paths.push(path);
+    }
+
+    let mut dependencies = Vec::new();
+    for _ in 0 .. reader.get_u64()                                              .with_context(|| "Receive dependencies from build server")? {
+        let dependency = get_dependency(reader, partial_inputs)                 .with_context(|| "Receive dependencies from build server")?;
+        dependencies.push(dependency);
+    }
+
+    Ok(Success{output_paths, warnings, dependencies})
+}
+
+/// Wait until the build server sends a message.
+///
+/// Returns whether the build was cancelled before that happened.
+fn wait_for_message(stream: BorrowedFd, cancel: Option<BorrowedFd>)
+    -> io::Result<bool>
+{
+    // Negative file descriptors are ignored by poll.
+    let mut pollfds = [
+        libc::pollfd{
+            fd: stream.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd{
+            fd: cancel.map_or(-1, |cancel| cancel.as_raw_fd()),
+            events: libc::POLLIN,
+            revents: 0,
+        },
+    ];
+
+    loop {
+        // SAFETY: pollfds is a valid array of two pollfds.
+        let nfds = pollfds.len() as libc::nfds_t;
+        let poll = unsafe { libc::poll(pollfds.as_mut_ptr(), nfds, -1) };
+        if poll == -1 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error);
+        }
+        return Ok(pollfds[1].revents != 0);
+    }
+}
+
+/// Perform an action on behalf of a [`RemoteExecutor`].
+///
+/// This reads the request of the executor from the connection,
+/// decodes the action with the given function, and performs it.
+/// Inputs are kept in the output cache of the state directory,
+/// so that they need not be sent again for later actions.
+/// The action is performed in a scratch directory of the state directory.
+/// If the executor closes the connection, the action is cancelled.
+/// The scratch directories created for the connection are removed
+/// when it ends, even if it fails, because a build server is long-running.
+///
+/// Errors that occur while performing the action are sent to the executor.
+/// Errors communicating with the executor are returned.
+pub fn serve_connection(
+    state:  &State,
+    decode: &dyn Fn(&EncodedAction) -> anyhow::Result<Box<dyn Action>>,
+    stream: TcpStream,
+) -> io::Result<()>
+{
+    let mut scratches = Vec::new();
+    let result = serve_request(state, decode, &stream, &mut scratches);
+    let mut removed = Ok(());
+    for name in &scratches {
+        removed = removed.and(state.remove_scratch(name));
+    }
+    result.and(removed)
+}
+
+/// Serve the request of the executor on the connection.
+///
+/// The names of the scratch directories created for the request
+/// are pushed onto `scratches`, so that the caller can remove them.
+fn serve_request(
+    state:     &State,
+    decode:    &dyn Fn(&EncodedAction) -> anyhow::Result<Box<dyn Action>>,
+    stream:    &TcpStream,
+    scratches: &mut Vec<CString>,
+) -> io::Result<()>
+{
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+
+    // Receive the action and the hashes of its inputs.
+    let encoded = EncodedAction{
+        kind: reader.get_string()?,
+        data: reader.get_bytes()?,
+    };
+    let mut input_hashes = Vec::new();
+    for _ in 0 .. reader.get_u64()? {
+        input_hashes.push(reader.get_hash()?);
+    }
+
+    // Ask for the inputs that are not in the output cache.
+    let mut missing = Vec::new();
+    for (index, hash) in input_hashes.iter().enumerate() {
+        let (cache, path) = state.cached_output(*hash)?;
+        match fstatat(Some(cache), &path, AT_SYMLINK_NOFOLLOW) {
+            Ok(_) => { },
+            Err(err) if err.kind() == NotFound => missing.push(index),
+            Err(err) => return Err(err),
+        }
+    }
+    writer.put_usize(missing.len())?;
+    for &index in &missing {
+        writer.put_usize(index)?;
+    }
+    writer.flush()?;
+
+    // Receive the missing inputs and insert them into the output cache.
+    // An input that does not have the expected hash fails the action,
+    // but the remaining inputs must still be received.
+    let mut input_error = None;
+    for index in missing {
+        let (scratch, name) = state.new_named_scratch_dir()?;
+        scratches.push(name);
+        get_file_at(&mut reader, Some(scratch.as_fd()), cstr!(b"input"))?;
+        let actual = state.cache_output(Some(scratch.as_fd()),
+                                        cstr!(b"input"), true);
+        let expected = input_hashes[index];
+        match actual {
+            Ok(actual) if actual == expected => { },
+            Ok(actual) => input_error = Some(anyhow::anyhow!(
+                "Input {index} has hash {actual}, \
+                 but hash {expected} was expected")),
+            Err(err) => input_error = Some(anyhow::Error::from(err)
+                .context(format!("Cache input {index}"))),
+        }
+    }
+
+    let build_log = state.new_scratch_file()?;
+    let (scratch, name) = state.new_named_scratch_dir()?;
+    scratches.push(name);
+    let result = match input_error {
+        Some(error) => Err(Error::from(error)),
+        None => perform_for_client(state, decode, &encoded, &input_hashes,
+                                   stream, build_log.as_fd(), scratch.as_fd()),
+    };
+
+    // Send the complete build log, followed by the outcome.
+    let mut build_log = File::from(build_log);
+    let mut contents = Vec::new();
+    build_log.rewind()?;
+    build_log.read_to_end(&mut contents)?;
+    match result {
+        Ok(success) => {
+            writer.put_u8(MESSAGE_SUCCESS)?;
+            writer.put_bytes(&contents)?;
+            send_success(&mut writer, scratch.as_fd(), &success)?;
+        },
+        Err(error) => {
+            writer.put_u8(MESSAGE_ERROR)?;
+            writer.put_bytes(&contents)?;
+            put_error(&mut writer, &error)?;
+        },
+    }
+    writer.flush()
+}
+
+/// Decode and perform the action for a [`RemoteExecutor`].
+///
+/// The stream log is forwarded to the executor as it is written.
+/// The connection itself serves as the cancellation file descriptor,
+/// as it becomes readable when the executor closes it.
+fn perform_for_client(
+    state:        &State,
+    decode:       &dyn Fn(&EncodedAction) -> anyhow::Result<Box<dyn Action>>,
+    encoded:      &EncodedAction,
+    input_hashes: &[Hash],
+    stream:       &TcpStream,
+    build_log:    BorrowedFd,
+    scratch:      BorrowedFd,
+) -> action::Result
+{
+    let action = decode(encoded)                                                .with_context(|| format!("Decode {} action", encoded.kind))?;
+    if action.inputs() != input_hashes.len() {
+        return Err(Error::from(anyhow::anyhow!(
+            "Action has {} inputs, but {} were sent",
+            action.inputs(), input_hashes.len())));
+    }
+
+    let mut input_paths = Vec::with_capacity(input_hashes.len());
+    for hash in input_hashes {
+        let (dirfd, path) = state.cached_output(*hash)                          .with_context(|| "Open output cache")?;
+        input_paths.push(InputPath{dirfd, path: Cow::Owned(path)});
+    }
+
+    let (stream_r, stream_w) = pipe2(0)                                         .with_context(|| "Create pipe for stream log")?;
+    thread::scope(|scope| {
+        let forward = scope.spawn(|| forward_stream_log(File::from(stream_r),
+                                                        stream));
+        let perform = Perform{
+            build_log,
+            scratch,
+            cancel: Some(stream.as_fd()),
+            stream_log: Some(stream_w.as_fd()),
+        };
+        let result = LocalExecutor.perform(&*action, &perform, &input_paths);
+        drop(stream_w);
+        forward.join().unwrap();
+        result
+    })
+}
+
+/// Send everything written to the stream log to the executor.
+///
+/// If the executor is gone, the stream log is still read until the end,
+/// so that the action does not block on writing to it.
+fn forward_stream_log(mut stream_log: File, mut stream: &TcpStream)
+{
+    let mut connected = true;
+    let mut buf = [0; 4096];
+    loop {
+        let nread = match stream_log.read(&mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(nread) => nread,
+        };
+        if connected {
+            let mut message = vec![MESSAGE_LOG];
+            message.put_bytes(&buf[.. nread]).unwrap();
+            connected = stream.write_all(&message).is_ok();
+        }
+    }
+}
+
+/// Send the hashes and contents of the outputs and the dependencies.
+fn send_success(writer: &mut impl Write, scratch: BorrowedFd, success: &Success)
+    -> io::Result<()>
+{
+    let Success{output_paths, warnings, dependencies} = success;
+    writer.put_bool(*warnings)?;
+    writer.put_usize(output_paths.len())?;
+    for path in output_paths {
+        match fstatat(Some(scratch), path, AT_SYMLINK_NOFOLLOW) {
+            Err(err) if err.kind() == NotFound => writer.put_bool(false)?,
+            Err(err) => return Err(err),
+            Ok(_) => {
+                writer.put_bool(true)?;
+                writer.put_hash(hash_file_at(Some(scratch), path)?)?;
+                put_file_at(writer, Some(scratch), path)?;
+            },
+        }
+    }
+    writer.put_usize(dependencies.len())?;
+    for dependency in dependencies {
+        put_dependency(writer, dependency)?;
+    }
+    Ok(())
+}
+
+fn put_dependency(writer: &mut impl Write, dependency: &Dependency)
+    -> io::Result<()>
+{
+    let Dependency{input, path} = dependency;
+    writer.put_usize(*input)?;
+    writer.put_bool(path.is_some())?;
+    if let Some(path) = path {
+        writer.put_cstr(path)?;
+    }
+    Ok(())
+}
+
+/// Receive a dependency written with [`put_dependency`].
+///
+/// The driver trusts dependencies to be within partial inputs,
+/// and hashes the files they refer to, so the dependencies that
+/// the build server sends must be checked before they are returned.
+fn get_dependency(reader: &mut impl Read, partial_inputs: &[usize])
+    -> io::Result<Dependency>
+{
+    let input = reader.get_usize()?;
+    if !partial_inputs.contains(&input) {
+        return Err(invalid_data("Dependency is not within a partial input"));
+    }
+    let path = if reader.get_bool()? { Some(reader.get_cstring()?) }
+               else { None };
+    if let Some(path) = &path {
+        // Rejects absolute paths and paths with `..` components,
+        // which could refer to files outside the input.
+        if WorkspacePath::new(path.as_c_str()).is_err() {
+            return Err(invalid_data("Dependency path is not relative"));
+        }
+    }
+    Ok(Dependency{input, path})
+}
+
+fn put_error(writer: &mut impl Write, error: &Error) -> io::Result<()>
+{
+    match error {
+        Error::Timeout(timeout) => {
+            writer.put_u8(ERROR_TIMEOUT)?;
+            writer.put_u64(timeout.as_secs())?;
+            writer.put_u64(timeout.subsec_nanos().into())?;
+        },
+        Error::Cancelled =>
+            writer.put_u8(ERROR_CANCELLED)?,
+        Error::ExitStatus(status) => {
+            writer.put_u8(ERROR_EXIT_STATUS)?;
+            let status = status.into_status().into_raw();
+            writer.put_u64(status as u32 as u64)?;
+        },
+        Error::HashMismatch{expected, actual} => {
+            writer.put_u8(ERROR_HASH_MISMATCH)?;
+            writer.put_hash(*expected)?;
+            writer.put_hash(*actual)?;
+        },
+        Error::UndeclaredDependency(path) => {
+            writer.put_u8(ERROR_UNDECLARED_DEPENDENCY)?;
+            writer.put_cstr(path)?;
+        },
+        Error::NotReplayable =>
+            writer.put_u8(ERROR_NOT_REPLAYABLE)?,
+        Error::Unexpected(error) => {
+            writer.put_u8(ERROR_UNEXPECTED)?;
+            writer.put_str(&format!("{error:#}"))?;
+        },
+    }
+    Ok(())
+}
+
+fn get_error(reader: &mut impl Read) -> io::Result<Error>
+{
+    Ok(match reader.get_u8()? {
+        ERROR_TIMEOUT => {
+            let secs = reader.get_u64()?;
+            let nanos = u32::try_from(reader.get_u64()?).ok()
+                .filter(|&nanos| nanos < 1_000_000_000)
+                .ok_or_else(|| invalid_data("Invalid timeout"))?;
+            Error::Timeout(Duration::new(secs, nanos))
+        },
+        ERROR_CANCELLED =>
+            Error::Cancelled,
+        ERROR_EXIT_STATUS => {
+            let status = u32::try_from(reader.get_u64()?)
+                .map_err(|_| invalid_data("Invalid exit status"))?;
+            let status = ExitStatus::from_raw(status as i32).exit_ok()
+                .err()
+                .ok_or_else(|| invalid_data("Exit status is successful"))?;
+            Error::ExitStatus(status)
+        },
+        ERROR_HASH_MISMATCH => {
+            let expected = reader.get_hash()?;
+            let actual = reader.get_hash()?;
+            Error::HashMismatch{expected, actual}
+        },
+        ERROR_UNDECLARED_DEPENDENCY =>
+            Error::UndeclaredDependency(reader.get_cstring()?),
+        ERROR_NOT_REPLAYABLE =>
+            Error::NotReplayable,
+        ERROR_UNEXPECTED => {
+            let message = reader.get_string()?;
+            Error::Unexpected(anyhow::Error::msg(message))
+        },
+        _ =>
+            return Err(invalid_data("Invalid error type")),
+    })
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::{action::Outputs, cancel::Cancel, fs_util::read_entries},
+        os_ext::{
+            O_CREAT, O_DIRECTORY, O_RDONLY, O_RDWR,
+            cstr::CStrExt, cstring, mkdirat, mkdtemp, openat,
+        },
+        std::{
+            net::TcpListener,
+            sync::atomic::{AtomicBool, Ordering::SeqCst},
+        },
+    };
+
+    /// Action that greets its input, or waits to be cancelled.
+    struct Greet
+    {
+        exit_code: u8,
+        cancelled: &'static AtomicBool,
+    }
+
+    impl Action for Greet
+    {
+        fn inputs(&self) -> usize { 1 }
+        fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(2) }
+        fn optional_outputs(&self) -> Vec<usize> { vec![1] }
+        fn partial_inputs(&self) -> Vec<usize> { vec![0] }
+        fn hash(&self, _: &[Hash]) -> Hash { unreachable!() }
+
+        fn perform(&self, perform: &Perform, input_paths: &[InputPath])
+            -> action::Result
+        {
+            let InputPath{dirfd, path} = &input_paths[0];
+            let input = openat(Some(*dirfd), path, O_RDONLY, 0).unwrap();
+            let mut name = String::new();
+            File::from(input).read_to_string(&mut name).unwrap();
+
+            let log = |fd: BorrowedFd, message: &str| {
+                let mut file = File::from(fd.try_to_owned().unwrap());
+                file.write_all(message.as_bytes()).unwrap();
+            };
+            log(perform.build_log, "Greeting\n");
+            log(perform.stream_log.unwrap(), "Greeting soon\n");
+
+            match self.exit_code {
+                0 => { },
+                255 => {
+                    wait_for_message(perform.cancel.unwrap(), None).unwrap();
+                    self.cancelled.store(true, SeqCst);
+                    return Err(Error::Cancelled);
+                },
+                code => {
+                    let status = ExitStatus::from_raw((code as i32) << 8);
+                    let status = status.exit_ok().unwrap_err();
+                    return Err(Error::ExitStatus(status));
+                },
+            }
+
+            let flags = O_CREAT | O_RDWR;
+            let output = openat(Some(perform.scratch), cstr!(b"greeting"),
+                                flags, 0o644).unwrap();
+            let greeting = format!("Hello, {name}!");
+            File::from(output).write_all(greeting.as_bytes()).unwrap();
+
+            Ok(Success{
+                output_paths: vec![cstring!(b"greeting"), cstring!(b"absent")],
+                warnings: true,
+                dependencies: vec![Dependency{input: 0, path: None}],
+            })
+        }
+
+        fn encode(&self) -> Option<EncodedAction>
+        {
+            Some(EncodedAction{
+                kind: "Greet".into(),
+                data: vec![self.exit_code],
+            })
+        }
+    }
+
+    #[test]
+    fn perform_remotely()
+    {
+        static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dir = openat(None, &path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+
+        // The build server has a state directory of its own.
+        mkdirat(dirfd, cstr!(b"server"), 0o755).unwrap();
+        let state = State::open(&path.join(cstr!(b"server"))).unwrap();
+        let decode = |encoded: &EncodedAction| -> anyhow::Result<_> {
+            assert_eq!(encoded.kind, "Greet");
+            let action = Greet{exit_code: encoded.data[0],
+                               cancelled: &CANCELLED};
+            Ok(Box::new(action) as Box<dyn Action>)
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let executor = RemoteExecutor{address: listener.local_addr().unwrap()};
+
+        let input = openat(dirfd, cstr!(b"input"), O_CREAT | O_RDWR, 0o644)
+            .unwrap();
+        File::from(input).write_all(b"world").unwrap();
+        let input_paths = [InputPath{
+            dirfd: dir.as_fd(),
+            path: Cow::Borrowed(cstr!(b"input")),
+        }];
+
+        // Perform the action with a fresh scratch directory and logs.
+        let perform = |exit_code, cancel: Option<&Cancel>| {
+            let name = format!("client-{exit_code}");
+            let name = CString::new(name).unwrap();
+            mkdirat(dirfd, &name, 0o755).unwrap();
+            let scratch = openat(dirfd, &name, O_DIRECTORY | O_RDONLY, 0)
+                .unwrap();
+            let open_log = |suffix: &[u8]| {
+                let path = CString::new([name.as_bytes(), suffix].concat())
+                    .unwrap();
+                openat(dirfd, &path, O_CREAT | O_RDWR, 0o644).unwrap()
+            };
+            let build_log = open_log(b".log");
+            let stream_log = open_log(b".stream");
+            let perform = Perform{
+                build_log: build_log.as_fd(),
+                scratch: scratch.as_fd(),
+                cancel: cancel.map(AsFd::as_fd),
+                stream_log: Some(stream_log.as_fd()),
+            };
+            let action = Greet{exit_code, cancelled: &CANCELLED};
+            let result = executor.perform(&action, &perform, &input_paths);
+            let read_log = |log| {
+                let mut file = File::from(log);
+                let mut contents = String::new();
+                file.rewind().unwrap();
+                file.read_to_string(&mut contents).unwrap();
+                contents
+            };
+            (result, scratch, read_log(build_log), read_log(stream_log))
+        };
+
+        thread::scope(|scope| {
+            let server = scope.spawn(|| {
+                for _ in 0 .. 3 {
+                    let (stream, _) = listener.accept().unwrap();
+                    let _ = serve_connection(&state, &decode, stream);
+                }
+            });
+
+            // Outputs, logs, warnings, and dependencies are sent back.
+            let (result, scratch, build_log, stream_log) = perform(0, None);
+            let success = result.unwrap();
+            assert!(success.warnings);
+            assert_eq!(success.dependencies,
+                       [Dependency{input: 0, path: None}]);
+            assert_eq!(build_log, "Greeting\n");
+            assert_eq!(stream_log, "Greeting soon\n");
+            let greeting = openat(Some(scratch.as_fd()),
+                                  &success.output_paths[0], O_RDONLY, 0)
+                .unwrap();
+            let mut contents = String::new();
+            File::from(greeting).read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "Hello, world!");
+            let absent = fstatat(Some(scratch.as_fd()),
+                                 &success.output_paths[1], 0);
+            assert_eq!(absent.unwrap_err().kind(), NotFound);
+
+            // The input was kept by the build server.
+            let hash = hash_file_at(dirfd, cstr!(b"input")).unwrap();
+            let (cache, cached) = state.cached_output(hash).unwrap();
+            fstatat(Some(cache), &cached, 0).unwrap();
+
+            // Errors are sent back too.
+            let (result, _, build_log, _) = perform(3, None);
+            let Err(Error::ExitStatus(status)) = result
+                else { panic!("{result:?}") };
+            assert_eq!(status.code(), Some(3));
+            assert_eq!(build_log, "Greeting\n");
+
+            // Cancelling the build cancels the action on the build server.
+            let cancel = Cancel::new().unwrap();
+            cancel.cancel();
+            let (result, _, _, _) = perform(255, Some(&cancel));
+            assert!(matches!(result, Err(Error::Cancelled)), "{result:?}");
+            server.join().unwrap();
+            assert!(CANCELLED.load(SeqCst));
+
+            // The build server left no scratch directories behind.
+            let scratches = path.join(cstr!(b"server/scratches"));
+            let scratches = openat(None, &scratches,
+                                   O_DIRECTORY | O_RDONLY, 0).unwrap();
+            assert!(read_entries(scratches.as_fd()).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn dependencies()
+    {
+        let get = |input, path: Option<&[u8]>| {
+            let path = path.map(|path| CString::new(path).unwrap());
+            let mut buf = Vec::new();
+            put_dependency(&mut buf, &Dependency{input, path}).unwrap();
+            get_dependency(&mut &buf[..], &[1]).map_err(|err| err.kind())
+        };
+
+        // Dependencies within partial inputs are accepted.
+        assert!(get(1, None).is_ok());
+        assert!(get(1, Some(b"include/stdio.h")).is_ok());
+
+        // Others could make the client hash arbitrary files, or panic.
+        assert_eq!(get(0, None).unwrap_err(), io::ErrorKind::InvalidData);
+        assert_eq!(get(2, None).unwrap_err(), io::ErrorKind::InvalidData);
+        assert_eq!(get(1, Some(b"/etc/shadow")).unwrap_err(),
+                   io::ErrorKind::InvalidData);
+        assert_eq!(get(1, Some(b"../../etc/shadow")).unwrap_err(),
+                   io::ErrorKind::InvalidData);
+    }
+}
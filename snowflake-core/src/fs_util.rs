@@ -0,0 +1,82 @@
+//! Working with trees of files.
+
+use {
+    os_ext::{
+        AT_SYMLINK_NOFOLLOW, O_DIRECTORY, O_NOFOLLOW, O_RDONLY,
+        S_IFDIR, S_IFLNK, S_IFMT,
+        cstr, fdopendir, fstatat, fsync, openat, readdir,
+        io::BorrowedFdExt,
+    },
+    std::{
+        ffi::{CStr, CString},
+        io,
+        os::unix::io::{AsFd, BorrowedFd},
+    },
+};
+
+/// Compute how much disk space removing a file would free.
+///
+/// This counts the blocks allocated to the file,
+/// and if it is a directory, to all its entries, recursively.
+/// Files with other hard links are not counted,
+/// because removing them does not free their blocks.
+pub (crate) fn disk_usage_at(dirfd: Option<BorrowedFd>, path: &CStr)
+    -> io::Result<u64>
+{
+    let statbuf = fstatat(dirfd, path, AT_SYMLINK_NOFOLLOW)?;
+    let file_type = statbuf.st_mode & S_IFMT;
+    if file_type != S_IFDIR && statbuf.st_nlink != 1 {
+        return Ok(0);
+    }
+
+    // st_blocks is in units of 512 bytes, regardless of the file system.
+    let mut usage = statbuf.st_blocks as u64 * 512;
+    if file_type == S_IFDIR {
+        let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+        let dir = openat(dirfd, path, flags, 0)?;
+        for name in read_entries(dir.as_fd())? {
+            usage += disk_usage_at(Some(dir.as_fd()), &name)?;
+        }
+    }
+
+    Ok(usage)
+}
+
+/// Flush a file, and if it is a directory, all its entries, to disk.
+///
+/// Symbolic links cannot be opened, so they are skipped.
+/// They are flushed along with the directory that contains them.
+pub (crate) fn fsync_all_at(dirfd: Option<BorrowedFd>, path: &CStr)
+    -> io::Result<()>
+{
+    let statbuf = fstatat(dirfd, path, AT_SYMLINK_NOFOLLOW)?;
+    let file = match statbuf.st_mode & S_IFMT {
+        S_IFDIR => {
+            let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+            let dir = openat(dirfd, path, flags, 0)?;
+            for name in read_entries(dir.as_fd())? {
+                fsync_all_at(Some(dir.as_fd()), &name)?;
+            }
+            dir
+        },
+        S_IFLNK => return Ok(()),
+        _ => openat(dirfd, path, O_NOFOLLOW | O_RDONLY, 0)?,
+    };
+    fsync(file.as_fd())
+}
+
+/// Read the entries of a directory in sorted order.
+pub fn read_entries(dir: BorrowedFd) -> io::Result<Vec<CString>>
+{
+    let mut stream = fdopendir(dir.try_to_owned()?)?;
+    let mut entries = Vec::new();
+    while let Some(dirent) = readdir(&mut stream)? {
+        let d_name = dirent.d_name;
+        if d_name.as_ref() != cstr!(b".") &&
+            d_name.as_ref() != cstr!(b"..") {
+            entries.push(d_name);
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
@@ -0,0 +1,368 @@
+//! Finding source files with glob patterns.
+
+use {
+    crate::fs_util::read_entries,
+    os_ext::{
+        AT_SYMLINK_NOFOLLOW,
+        O_DIRECTORY, O_NOFOLLOW, O_RDONLY,
+        S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
+        cstr, fstatat, openat,
+    },
+    snowflake_util::hash::{Blake3, Hash},
+    std::{
+        ffi::CString,
+        io,
+        os::unix::io::{AsFd, BorrowedFd},
+    },
+    thiserror::Error,
+};
+
+/// Compiled glob pattern.
+///
+/// A glob pattern is a relative path whose components may contain
+/// the wildcards `*`, which matches any sequence of bytes, and `?`,
+/// which matches any single byte. A component that is exactly `**`
+/// matches any number of directories, including none.
+#[derive(Clone, Debug)]
+pub struct Pattern
+{
+    components: Vec<Component>,
+}
+
+#[derive(Clone, Debug)]
+enum Component
+{
+    /// The `**` component.
+    AnyDirectories,
+
+    /// Any other component.
+    Wildcard(Vec<Token>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Token
+{
+    Literal(u8),
+    AnyByte,
+    AnyBytes,
+}
+
+/// Returned when a glob pattern could not be compiled.
+#[derive(Debug, Error)]
+#[error("Glob pattern is empty or absolute, \
+         or has a component that is empty, `.`, or `..`")]
+pub struct PatternError;
+
+impl Pattern
+{
+    /// Compile a glob pattern.
+    pub fn new(pattern: &[u8]) -> Result<Self, PatternError>
+    {
+        if pattern.is_empty() || pattern.starts_with(b"/") {
+            return Err(PatternError);
+        }
+
+        let mut components = Vec::new();
+        for component in pattern.split(|&b| b == b'/') {
+            let component = match component {
+                b"" | b"." | b".." => return Err(PatternError),
+                b"**" => Component::AnyDirectories,
+                _ => Component::Wildcard(
+                    component.iter()
+                        .map(|&b| match b {
+                            b'*' => Token::AnyBytes,
+                            b'?' => Token::AnyByte,
+                            _    => Token::Literal(b),
+                        })
+                        .collect()
+                ),
+            };
+            components.push(component);
+        }
+
+        Ok(Self{components})
+    }
+}
+
+/// Result of [`glob`].
+#[derive(Debug)]
+pub struct Glob
+{
+    /// The matching paths, relative to the searched directory.
+    ///
+    /// The paths are sorted bytewise, so they do not depend
+    /// on the order in which the file system lists directory entries.
+    pub paths: Vec<CString>,
+
+    /// Hash of all the directory listings that were read.
+    ///
+    /// Adding, removing, or renaming a file that could match the patterns
+    /// changes this hash, whereas changing the contents of files does not.
+    /// Anything computed from the matching paths must include this hash
+    /// among its inputs, so that it is recomputed when files are added.
+    pub listing: Hash,
+}
+
+/// Find the files that match any of the included patterns
+/// and none of the excluded patterns.
+///
+/// Only directories that could contain matching files are read.
+/// Directories themselves are never part of the result,
+/// and symbolic links are not followed.
+pub fn glob(
+    dirfd:   Option<BorrowedFd>,
+    include: &[Pattern],
+    exclude: &[Pattern],
+) -> io::Result<Glob>
+{
+    let mut walk = Walk{include, exclude, paths: Vec::new(),
+                        listing: Blake3::new()};
+
+    let include_states = initial_states(include);
+    let exclude_states = initial_states(exclude);
+    if !include_states.is_empty() {
+        let flags = O_DIRECTORY | O_RDONLY;
+        let dir = openat(dirfd, cstr!(b"."), flags, 0)?;
+        walk.walk_dir(dir.as_fd(), b"", &include_states, &exclude_states)?;
+    }
+
+    walk.paths.sort();
+    Ok(Glob{paths: walk.paths, listing: walk.listing.finalize()})
+}
+
+/// Position in a pattern: the index of the pattern and of its component.
+type State = (usize, usize);
+
+// Byte which indicates the type of file in the listing hash.
+const FILE_TYPE_REG:   u8 = 0;
+const FILE_TYPE_DIR:   u8 = 1;
+const FILE_TYPE_LNK:   u8 = 2;
+const FILE_TYPE_OTHER: u8 = 3;
+
+struct Walk<'a>
+{
+    include: &'a [Pattern],
+    exclude: &'a [Pattern],
+    paths:   Vec<CString>,
+    listing: Blake3,
+}
+
+impl Walk<'_>
+{
+    fn walk_dir(
+        &mut self,
+        dir:     BorrowedFd,
+        prefix:  &[u8],
+        include: &[State],
+        exclude: &[State],
+    ) -> io::Result<()>
+    {
+        let mut entries = Vec::new();
+        for name in read_entries(dir)? {
+            let statbuf = fstatat(Some(dir), &name, AT_SYMLINK_NOFOLLOW)?;
+            let file_type = match statbuf.st_mode & S_IFMT {
+                S_IFREG => FILE_TYPE_REG,
+                S_IFDIR => FILE_TYPE_DIR,
+                S_IFLNK => FILE_TYPE_LNK,
+                _       => FILE_TYPE_OTHER,
+            };
+            entries.push((name, file_type));
+        }
+
+        // NOTE: See the manual chapter on avoiding hash collisions.
+        self.listing.put_bytes(prefix);
+        self.listing.put_slice(&entries, |h, (name, file_type)| {
+            h.put_cstr(name).put_u8(*file_type)
+        });
+
+        for (name, file_type) in entries {
+            let name_bytes = name.as_bytes();
+            let include = step(self.include, include, name_bytes);
+            let exclude = step(self.exclude, exclude, name_bytes);
+
+            let path = [prefix, name_bytes].concat();
+
+            if file_type == FILE_TYPE_DIR {
+                if !include.is_empty() {
+                    let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+                    let subdir = openat(Some(dir), &name, flags, 0)?;
+                    let subdir = subdir.as_fd();
+                    let prefix = [&path[..], b"/"].concat();
+                    self.walk_dir(subdir, &prefix, &include, &exclude)?;
+                }
+            } else if is_match(self.include, &include)
+                && !is_match(self.exclude, &exclude) {
+                let path = CString::new(path)
+                    .expect("Directory entries should not contain nul");
+                self.paths.push(path);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The states before any path components are matched.
+fn initial_states(patterns: &[Pattern]) -> Vec<State>
+{
+    closure(patterns, (0 .. patterns.len()).map(|p| (p, 0)).collect())
+}
+
+/// The states after matching a path component.
+fn step(patterns: &[Pattern], states: &[State], name: &[u8]) -> Vec<State>
+{
+    let mut next = Vec::new();
+    for &(p, c) in states {
+        match patterns[p].components.get(c) {
+            None => { },
+            Some(Component::AnyDirectories) =>
+                next.push((p, c)),
+            Some(Component::Wildcard(tokens)) =>
+                if wildcard_matches(tokens, name) { next.push((p, c + 1)) },
+        }
+    }
+    closure(patterns, next)
+}
+
+/// Add the states reachable by letting `**` match no directories.
+fn closure(patterns: &[Pattern], mut states: Vec<State>) -> Vec<State>
+{
+    let mut i = 0;
+    while i < states.len() {
+        let (p, c) = states[i];
+        if let Some(Component::AnyDirectories) = patterns[p].components.get(c) {
+            states.push((p, c + 1));
+        }
+        i += 1;
+    }
+    states.sort();
+    states.dedup();
+    states
+}
+
+/// Whether any of the states is at the end of its pattern.
+fn is_match(patterns: &[Pattern], states: &[State]) -> bool
+{
+    states.iter().any(|&(p, c)| c == patterns[p].components.len())
+}
+
+/// Match a single path component against a wildcard.
+fn wildcard_matches(tokens: &[Token], name: &[u8]) -> bool
+{
+    let (mut t, mut n) = (0, 0);
+
+    // Where to resume when the most recent `*` must match one more byte.
+    let mut backtrack = None;
+
+    while n < name.len() {
+        match tokens.get(t) {
+            Some(Token::AnyBytes) => {
+                t += 1;
+                backtrack = Some((t, n));
+            },
+            Some(Token::AnyByte) => {
+                t += 1;
+                n += 1;
+            },
+            Some(Token::Literal(b)) if *b == name[n] => {
+                t += 1;
+                n += 1;
+            },
+            _ => {
+                let Some((bt, bn)) = backtrack else { return false };
+                t = bt;
+                n = bn + 1;
+                backtrack = Some((bt, bn + 1));
+            },
+        }
+    }
+
+    tokens[t ..].iter().all(|token| matches!(token, Token::AnyBytes))
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        os_ext::{O_WRONLY, cstring, mkdirat, mkdtemp, mknodat, symlinkat},
+        std::{fs::File, io::Write},
+    };
+
+    #[test]
+    fn wildcards()
+    {
+        let examples: &[(&[u8], &[u8], bool)] = &[
+            (b"*",       b"main.cpp",  true),
+            (b"*.cpp",   b"main.cpp",  true),
+            (b"*.cpp",   b"main.hpp",  false),
+            (b"m*n.cpp", b"main.cpp",  true),
+            (b"m*n.cpp", b"man.cpp",   true),
+            (b"m*n.cpp", b"mn.cpp",    true),
+            (b"m*n.cpp", b"mai.cpp",   false),
+            (b"*a*a*",   b"banana",    true),
+            (b"*a*a*b",  b"banana",    false),
+            (b"ma?n.*",  b"main.cpp",  true),
+            (b"ma?n.*",  b"man.cpp",   false),
+            (b"main",    b"main.cpp",  false),
+        ];
+        for &(pattern, name, expected) in examples {
+            let Component::Wildcard(tokens) =
+                &Pattern::new(pattern).unwrap().components[0]
+                else { unreachable!() };
+            let actual = wildcard_matches(tokens, name);
+            assert_eq!(actual, expected, "{pattern:?} {name:?}");
+        }
+    }
+
+    #[test]
+    fn bad_patterns()
+    {
+        for pattern in [&b""[..], b"/src", b"src//main.cpp", b"./src",
+                        b"src/../main.cpp", b"src/"] {
+            assert!(Pattern::new(pattern).is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn example()
+    {
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dir = openat(None, &path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+
+        mkdirat(dirfd, cstr!(b"src"),                               0o755   ).unwrap();
+        mkdirat(dirfd, cstr!(b"src/util"),                          0o755   ).unwrap();
+        mkdirat(dirfd, cstr!(b"src/util.cpp"),                      0o755   ).unwrap();
+        mknodat(dirfd, cstr!(b"src/main.cpp"),            S_IFREG | 0o644, 0).unwrap();
+        mknodat(dirfd, cstr!(b"src/test_main.cpp"),       S_IFREG | 0o644, 0).unwrap();
+        mknodat(dirfd, cstr!(b"src/util/string.cpp"),     S_IFREG | 0o644, 0).unwrap();
+        mknodat(dirfd, cstr!(b"src/util/string.hpp"),     S_IFREG | 0o644, 0).unwrap();
+        mknodat(dirfd, cstr!(b"src/util/test_string.cpp"), S_IFREG | 0o644, 0).unwrap();
+        mknodat(dirfd, cstr!(b"main.cpp"),                S_IFREG | 0o644, 0).unwrap();
+        symlinkat(cstr!(b"main.cpp"), dirfd, cstr!(b"src/link.cpp")).unwrap();
+
+        let include = [Pattern::new(b"src/**/*.cpp").unwrap()];
+        let exclude = [Pattern::new(b"**/test_*.cpp").unwrap()];
+
+        let before = glob(dirfd, &include, &exclude).unwrap();
+        assert_eq!(before.paths, [
+            cstring!(b"src/link.cpp"),
+            cstring!(b"src/main.cpp"),
+            cstring!(b"src/util/string.cpp"),
+        ]);
+
+        // Changing file contents does not change the listing.
+        let file = openat(dirfd, cstr!(b"src/main.cpp"), O_WRONLY, 0).unwrap();
+        File::from(file).write_all(b"int main;").unwrap();
+        let after = glob(dirfd, &include, &exclude).unwrap();
+        assert_eq!(after.listing, before.listing);
+
+        // Adding a file changes the listing.
+        mknodat(dirfd, cstr!(b"src/util/vector.cpp"), S_IFREG | 0o644, 0)
+            .unwrap();
+        let after = glob(dirfd, &include, &exclude).unwrap();
+        assert_ne!(after.listing, before.listing);
+        assert_eq!(after.paths.len(), 4);
+    }
+}
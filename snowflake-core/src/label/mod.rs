@@ -4,7 +4,7 @@ mod display;
 
 /// Identifies an action.
 #[allow(missing_docs)]
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ActionLabel
 {
     pub action: usize,
@@ -12,7 +12,7 @@ pub struct ActionLabel
 
 /// Identifies an output of an action.
 #[allow(missing_docs)]
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ActionOutputLabel
 {
     pub action: ActionLabel,
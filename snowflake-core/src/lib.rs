@@ -9,10 +9,133 @@
 #![feature(io_error_other)]
 #![feature(io_safety)]
 #![feature(once_cell)]
+#![feature(scoped_threads)]
 #![feature(type_ascription)]
 #![warn(missing_docs)]
 
 pub mod action;
+pub mod cancel;
 pub mod drive;
+pub mod executor;
+pub mod fs_util;
+pub mod glob;
 pub mod label;
+pub mod output_tree;
+pub mod profile;
 pub mod state;
+
+use {
+    self::{
+        action::ActionGraph,
+        drive::{BuildEvent, Capacity, Context, DriveError, Outcome, drive},
+        executor::LocalExecutor,
+        label::{ActionLabel, ActionOutputLabel},
+        state::State,
+    },
+    std::{collections::HashMap, os::unix::io::BorrowedFd},
+};
+
+/// Build the given artifacts of an action graph with default settings.
+///
+/// This is the entry point for programs that embed Snowflake,
+/// such as editor plugins, and receive progress as [`BuildEvent`]s
+/// rather than by running the command line interface.
+/// Actions are performed locally, using all available CPUs.
+/// Actions that are not needed for the artifacts are [pruned].
+/// For control over the other settings, use [`drive`] directly.
+///
+/// [pruned]: `ActionGraph::prune`
+pub fn build<'a>(
+    state:       &State,
+    source_root: BorrowedFd,
+    graph:       &'a mut ActionGraph,
+    artifacts:   impl IntoIterator<Item=ActionOutputLabel>,
+    sink:        &(dyn Fn(BuildEvent) + Sync),
+) -> Result<HashMap<&'a ActionLabel, Outcome<'a>>, DriveError>
+{
+    graph.artifacts = artifacts.into_iter().collect();
+    graph.prune();
+
+    let context = Context{
+        state,
+        source_root,
+        executor: &LocalExecutor,
+        capacity: Capacity::available(),
+        check_determinism: false,
+        runs_per_test: 1,
+        cancel: None,
+        profile: None,
+        stream_log: None,
+        failure_reports: false,
+        events: sink,
+    };
+
+    drive(&context, graph)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        crate::action::{self, Action, Outputs, Perform, InputPath, Success},
+        os_ext::{O_CREAT, O_DIRECTORY, O_PATH, O_WRONLY, cstring, mkdtemp,
+                 open, openat},
+        snowflake_util::hash::Hash,
+        std::{os::unix::io::AsFd, sync::Mutex},
+    };
+
+    /// Action that creates an empty output file.
+    struct Touch(u8);
+
+    impl Action for Touch
+    {
+        fn inputs(&self) -> usize { 0 }
+        fn outputs(&self) -> Outputs<usize> { Outputs::Outputs(1) }
+        fn perform(&self, perform: &Perform, _: &[InputPath]) -> action::Result
+        {
+            let path = cstring!(b"output");
+            let flags = O_CREAT | O_WRONLY;
+            openat(Some(perform.scratch), &path, flags, 0o644).unwrap();
+            Ok(Success{output_paths: vec![path], warnings: false,
+                       dependencies: vec![]})
+        }
+        fn hash(&self, _: &[Hash]) -> Hash { Hash([self.0; 32]) }
+    }
+
+    #[test]
+    fn build_artifacts()
+    {
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+        let source_root = open(&path, O_DIRECTORY | O_PATH, 0).unwrap();
+
+        let labels = [ActionLabel{action: 0}, ActionLabel{action: 1}];
+        let mut graph = ActionGraph{
+            actions: labels.iter().enumerate()
+                .map(|(i, label)| {
+                    let action: Box<dyn Action> = Box::new(Touch(i as u8));
+                    (label.clone(), (action, vec![]))
+                })
+                .collect(),
+            artifacts: Default::default(),
+        };
+        let artifact = ActionOutputLabel{action: labels[0].clone(), output: 0};
+
+        let events = Mutex::new(Vec::new());
+        let sink = |event: BuildEvent| {
+            events.lock().unwrap().push(event.to_string());
+        };
+        let outcomes =
+            build(&state, source_root.as_fd(), &mut graph, [artifact], &sink)
+                .unwrap();
+
+        // Only the action that produces the artifact was built.
+        assert_eq!(outcomes.len(), 1);
+        assert!(matches!(outcomes[&labels[0]], Outcome::Success{..}));
+        let events = events.into_inner().unwrap();
+        assert_eq!(events.len(), 2, "{events:?}");
+        assert_eq!(events[0], "#0 attempt 1 succeeded");
+        assert!(events[1].starts_with("Build finished: 1 performed, "));
+    }
+}
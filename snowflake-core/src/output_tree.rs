@@ -0,0 +1,410 @@
+//! Assembling cached outputs into directories.
+
+use {
+    crate::{fs_util::read_entries, state::State},
+    os_ext::{
+        AT_REMOVEDIR, AT_SYMLINK_NOFOLLOW,
+        O_CREAT, O_DIRECTORY, O_EXCL, O_NOFOLLOW, O_RDONLY, O_TRUNC, O_WRONLY,
+        S_IFDIR, S_IFLNK, S_IFMT, S_IFREG,
+        fstatat, linkat, mkdirat, openat, readlinkat, renameat2, stat,
+        symlinkat, unlinkat,
+        io::reflink_or_copy,
+    },
+    snowflake_util::{
+        basename::Basename,
+        hash::{Hash, hash_file_at},
+    },
+    std::{
+        ffi::{CStr, CString},
+        fs::File,
+        io::{
+            self,
+            ErrorKind::{AlreadyExists, InvalidData, InvalidInput, NotFound},
+            Read, Write,
+        },
+        os::unix::io::{AsFd, BorrowedFd},
+    },
+};
+
+// Paths to the files in the output tree that are not outputs.
+// TODO: Replace with cstr! macro once from_ptr is const.
+const MANIFEST: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b".snowflake-output-tree\0") };
+const MANIFEST_TEMPORARY: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b".snowflake-output-tree~\0") };
+
+/// Make a directory contain the given cached outputs.
+///
+/// Each entry gives the name in the directory of a cached output.
+/// The directory is created if it does not exist.
+/// Files that are already up to date are left alone,
+/// and outdated files are replaced.
+///
+/// The names of the entries are listed in a manifest in the directory.
+/// Entries listed in the manifest of an earlier call
+/// that are not in the given entries are removed, recursively.
+/// Other files are never removed, so that pointing the output tree
+/// at a directory such as the workspace cannot destroy anything.
+/// For the same reason, a directory that is not empty
+/// but has no manifest is refused, as is a given entry that
+/// already exists in the directory but is not in the manifest.
+///
+/// Regular files are hard linked from the output cache,
+/// so they must not be modified. If hard linking is impossible,
+/// for example because the directory is on a different file system,
+/// they are copied instead.
+pub fn assemble_output_tree(
+    state:   &State,
+    dirfd:   Option<BorrowedFd>,
+    path:    &CStr,
+    entries: &[(Basename<CString>, Hash)],
+) -> io::Result<()>
+{
+    match mkdirat(dirfd, path, 0o755) {
+        Err(err) if err.kind() == AlreadyExists => { },
+        result => result?,
+    }
+    let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+    let dir = openat(dirfd, path, flags, 0)?;
+    let dir = dir.as_fd();
+
+    let existing = read_entries(dir)?;
+    let previous = match read_manifest(dir)? {
+        Some(previous) => previous,
+        None if existing.is_empty() => Vec::new(),
+        None => return Err(io::Error::new(InvalidInput,
+            "Output tree is not empty and was not assembled by Snowflake")),
+    };
+
+    for (name, _) in entries {
+        let name: &CStr = name;
+        if name == MANIFEST || name == MANIFEST_TEMPORARY {
+            return Err(io::Error::new(InvalidInput,
+                format!("Output tree entry {name:?} is reserved")));
+        }
+        if existing.iter().any(|e| **e == *name)
+            && !previous.iter().any(|p| ***p == *name) {
+            return Err(io::Error::new(AlreadyExists,
+                format!("Output tree already contains {name:?}, \
+                         which was not assembled by Snowflake")));
+        }
+    }
+
+    // List both the previous and the new entries until they are in place,
+    // so that if assembling is interrupted, the next call still
+    // knows which files it may remove.
+    let mut listed = previous.clone();
+    for (name, _) in entries {
+        if !listed.contains(name) {
+            listed.push(name.clone());
+        }
+    }
+    write_manifest(dir, &listed)?;
+
+    for name in &previous {
+        if !entries.iter().any(|(entry, _)| entry == name) {
+            match remove_all_at(Some(dir), name) {
+                Err(err) if err.kind() == NotFound => { },
+                result => result?,
+            }
+        }
+    }
+
+    for (name, hash) in entries {
+        let (cache, cached) = state.cached_output(*hash)?;
+        sync_file_at(Some(cache), &cached, Some(dir), name)?;
+    }
+
+    let names: Vec<_> = entries.iter().map(|(name, _)| name.clone()).collect();
+    write_manifest(dir, &names)?;
+
+    Ok(())
+}
+
+/// Read the names in the manifest of an output tree, if it has one.
+///
+/// The names are checked to be basenames, because they are removed,
+/// and the manifest could have been written by anyone.
+fn read_manifest(dir: BorrowedFd)
+    -> io::Result<Option<Vec<Basename<CString>>>>
+{
+    let file = match openat(Some(dir), MANIFEST, O_NOFOLLOW | O_RDONLY, 0) {
+        Ok(file) => file,
+        Err(err) if err.kind() == NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut manifest = Vec::new();
+    File::from(file).read_to_end(&mut manifest)?;
+
+    // Each name is terminated by a nul byte.
+    let Some(manifest) = manifest.strip_suffix(b"\0") else {
+        return Ok(Some(Vec::new()));
+    };
+    let names = manifest.split(|&b| b == 0)
+        .map(|name| Basename::new(CString::new(name).unwrap()))
+        .collect::<Result<_, _>>()
+        .map_err(|_| io::Error::new(InvalidData,
+            "Output tree manifest has a name that is not a basename"))?;
+    Ok(Some(names))
+}
+
+/// Atomically replace the manifest of an output tree.
+fn write_manifest(dir: BorrowedFd, names: &[Basename<CString>])
+    -> io::Result<()>
+{
+    let flags = O_CREAT | O_NOFOLLOW | O_TRUNC | O_WRONLY;
+    let file = openat(Some(dir), MANIFEST_TEMPORARY, flags, 0o644)?;
+    let mut file = io::BufWriter::new(File::from(file));
+    for name in names {
+        file.write_all(name.as_bytes_with_nul())?;
+    }
+    file.flush()?;
+    renameat2(Some(dir), MANIFEST_TEMPORARY, Some(dir), MANIFEST, 0)
+}
+
+/// Make the target file equal to the source file.
+///
+/// If the target file does not exist, this copies the source file.
+pub (crate) fn sync_file_at(
+    source_dirfd: Option<BorrowedFd>,
+    source_path:  &CStr,
+    target_dirfd: Option<BorrowedFd>,
+    target_path:  &CStr,
+) -> io::Result<()>
+{
+    let flags = AT_SYMLINK_NOFOLLOW;
+    let source = fstatat(source_dirfd, source_path, flags)?;
+    let target = match fstatat(target_dirfd, target_path, flags) {
+        Ok(target) => Some(target),
+        Err(err) if err.kind() == NotFound => None,
+        Err(err) => return Err(err),
+    };
+    let target_type = target.as_ref().map(|t| t.st_mode & S_IFMT);
+
+    match source.st_mode & S_IFMT {
+        S_IFREG => {
+            if let Some(target) = &target {
+                if is_same_regular_file(source_dirfd, source_path, &source,
+                                        target_dirfd, target_path, target)? {
+                    return Ok(());
+                }
+                remove_all_at(target_dirfd, target_path)?;
+            }
+            link_or_copy_at(source_dirfd, source_path, &source,
+                            target_dirfd, target_path)?;
+        },
+        S_IFLNK => {
+            let link = readlinkat(source_dirfd, source_path)?;
+            if target_type == Some(S_IFLNK) &&
+                readlinkat(target_dirfd, target_path)? == link {
+                return Ok(());
+            }
+            if target.is_some() {
+                remove_all_at(target_dirfd, target_path)?;
+            }
+            symlinkat(&link, target_dirfd, target_path)?;
+        },
+        S_IFDIR => {
+            if target.is_some() && target_type != Some(S_IFDIR) {
+                remove_all_at(target_dirfd, target_path)?;
+            }
+            if target_type != Some(S_IFDIR) {
+                mkdirat(target_dirfd, target_path, 0o755)?;
+            }
+
+            let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+            let source_dir = openat(source_dirfd, source_path, flags, 0)?;
+            let target_dir = openat(target_dirfd, target_path, flags, 0)?;
+            let source_dirfd = Some(source_dir.as_fd());
+            let target_dirfd = Some(target_dir.as_fd());
+            let source_entries = read_entries(source_dir.as_fd())?;
+
+            for name in read_entries(target_dir.as_fd())? {
+                if source_entries.binary_search(&name).is_err() {
+                    remove_all_at(target_dirfd, &name)?;
+                }
+            }
+
+            for name in source_entries {
+                sync_file_at(source_dirfd, &name, target_dirfd, &name)?;
+            }
+        },
+        _ =>
+            unreachable!("Cached outputs can only be of supported types"),
+    }
+
+    Ok(())
+}
+
+/// Whether the target file is a regular file equal to the source file.
+///
+/// Hard links to the source file are recognized by their inode.
+/// Copies, made when hard linking was impossible,
+/// are recognized by their hash.
+fn is_same_regular_file(
+    source_dirfd: Option<BorrowedFd>,
+    source_path:  &CStr,
+    source:       &stat,
+    target_dirfd: Option<BorrowedFd>,
+    target_path:  &CStr,
+    target:       &stat,
+) -> io::Result<bool>
+{
+    if source.st_dev == target.st_dev && source.st_ino == target.st_ino {
+        return Ok(true);
+    }
+    if target.st_mode & S_IFMT != S_IFREG || target.st_size != source.st_size {
+        return Ok(false);
+    }
+    let source_hash = hash_file_at(source_dirfd, source_path)?;
+    let target_hash = hash_file_at(target_dirfd, target_path)?;
+    Ok(source_hash == target_hash)
+}
+
+/// Hard link a regular file, or copy it if that fails.
+fn link_or_copy_at(
+    source_dirfd: Option<BorrowedFd>,
+    source_path:  &CStr,
+    source:       &stat,
+    target_dirfd: Option<BorrowedFd>,
+    target_path:  &CStr,
+) -> io::Result<()>
+{
+    let linked = linkat(
+        source_dirfd, source_path,
+        target_dirfd, target_path,
+        0,
+    );
+    match linked {
+        Err(err) if err.raw_os_error() == Some(libc::EXDEV) => { },
+        result => return result,
+    }
+
+    let mode = source.st_mode & 0o777;
+    let flags = O_CREAT | O_EXCL | O_NOFOLLOW | O_WRONLY;
+    let source = openat(source_dirfd, source_path, O_RDONLY, 0)?;
+    let target = openat(target_dirfd, target_path, flags, mode)?;
+    reflink_or_copy(source.as_fd(), target.as_fd())
+}
+
+/// Remove a file, and if it is a directory, all its entries.
+pub (crate) fn remove_all_at(dirfd: Option<BorrowedFd>, path: &CStr)
+    -> io::Result<()>
+{
+    let statbuf = fstatat(dirfd, path, AT_SYMLINK_NOFOLLOW)?;
+    if statbuf.st_mode & S_IFMT != S_IFDIR {
+        return unlinkat(dirfd, path, 0);
+    }
+
+    let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+    let dir = openat(dirfd, path, flags, 0)?;
+    for name in read_entries(dir.as_fd())? {
+        remove_all_at(Some(dir.as_fd()), &name)?;
+    }
+    unlinkat(dirfd, path, AT_REMOVEDIR)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        os_ext::{cstr, cstr::CStrExt, cstring, mkdtemp, mknodat},
+    };
+
+    #[test]
+    fn assemble()
+    {
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dir = openat(None, &path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+
+        mkdirat(dirfd, cstr!(b"state"), 0o755).unwrap();
+        let state = State::open(&path.join(cstr!(b"state"))).unwrap();
+        let scratch = state.new_scratch_dir().unwrap();
+        let scratch = Some(scratch.as_fd());
+
+        // Create some outputs and move them to the output cache.
+        mknodat(scratch, cstr!(b"file"),          S_IFREG | 0o644, 0).unwrap();
+        mkdirat(scratch, cstr!(b"directory"),               0o755   ).unwrap();
+        mknodat(scratch, cstr!(b"directory/a"),   S_IFREG | 0o755, 0).unwrap();
+        symlinkat(cstr!(b"a"), scratch, cstr!(b"directory/b")).unwrap();
+        let file = state.cache_output(scratch, cstr!(b"file"), true).unwrap();
+        let directory =
+            state.cache_output(scratch, cstr!(b"directory"), true).unwrap();
+
+        let basename = |name: &CStr| Basename::new(CString::from(name))
+            .unwrap();
+        let entries = [
+            (basename(cstr!(b"file1")), file),
+            (basename(cstr!(b"file2")), file),
+            (basename(cstr!(b"directory")), directory),
+            (basename(cstr!(b"removed")), file),
+        ];
+        let assemble = |entries: &[_]| {
+            assemble_output_tree(&state, dirfd, cstr!(b"out"), entries)
+        };
+        assemble(&entries).unwrap();
+
+        // Tamper with the output tree, and add a file of the user.
+        unlinkat(dirfd, cstr!(b"out/file2"), 0).unwrap();
+        mknodat(dirfd, cstr!(b"out/file2"),     S_IFREG | 0o600, 0).unwrap();
+        mkdirat(dirfd, cstr!(b"out/directory/c"),         0o755   ).unwrap();
+        mknodat(dirfd, cstr!(b"out/user"),      S_IFREG | 0o644, 0).unwrap();
+
+        let entries = &entries[.. 3];
+        for _ in 0 .. 2 {
+            assemble(entries).unwrap();
+
+            // The output tree contains exactly the outputs,
+            // and the files that were not assembled are kept.
+            let out = openat(dirfd, cstr!(b"out"), O_DIRECTORY | O_RDONLY, 0)
+                .unwrap();
+            let names = read_entries(out.as_fd()).unwrap();
+            assert_eq!(names, [cstring!(b".snowflake-output-tree"),
+                               cstring!(b"directory"), cstring!(b"file1"),
+                               cstring!(b"file2"), cstring!(b"user")]);
+            for (name, hash) in entries {
+                let actual = hash_file_at(Some(out.as_fd()), name).unwrap();
+                assert_eq!(actual, *hash, "{name:?}");
+            }
+        }
+
+        // Files that were not assembled are never replaced.
+        let entries = [(basename(cstr!(b"user")), file)];
+        let result = assemble(&entries);
+        assert_eq!(result.unwrap_err().kind(), AlreadyExists);
+
+        // Directories with files but no manifest are refused.
+        let result = assemble_output_tree(&state, None, &path, &entries);
+        assert_eq!(result.unwrap_err().kind(), InvalidInput);
+        fstatat(dirfd, cstr!(b"state"), 0).unwrap();
+    }
+
+    #[test]
+    fn sync_copy()
+    {
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let dir = openat(None, &path, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let dirfd = Some(dir.as_fd());
+
+        // A copy is up to date, like a hard link would be.
+        for name in [cstr!(b"source"), cstr!(b"target")] {
+            let file = openat(dirfd, name, O_CREAT | O_WRONLY, 0o644).unwrap();
+            File::from(file).write_all(b"contents").unwrap();
+        }
+        let before = fstatat(dirfd, cstr!(b"target"), 0).unwrap();
+        sync_file_at(dirfd, cstr!(b"source"), dirfd, cstr!(b"target"))
+            .unwrap();
+        let after = fstatat(dirfd, cstr!(b"target"), 0).unwrap();
+        assert_eq!(after.st_ino, before.st_ino);
+
+        // A copy with different contents is replaced.
+        let file = openat(dirfd, cstr!(b"target"), O_WRONLY, 0).unwrap();
+        File::from(file).write_all(b"CONTENTS").unwrap();
+        sync_file_at(dirfd, cstr!(b"source"), dirfd, cstr!(b"target"))
+            .unwrap();
+        assert_eq!(hash_file_at(dirfd, cstr!(b"target")).unwrap(),
+                   hash_file_at(dirfd, cstr!(b"source")).unwrap());
+    }
+}
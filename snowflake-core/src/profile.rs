@@ -0,0 +1,162 @@
+//! Recording timings of builds for analysis.
+
+use {
+    serde::Serialize,
+    std::{
+        io::{self, Write},
+        sync::Mutex,
+        time::Instant,
+    },
+};
+
+/// The lane on which scheduler events are recorded.
+///
+/// Workers use lanes starting from one.
+pub const SCHEDULER_LANE: usize = 0;
+
+/// Timings recorded during a build.
+///
+/// The timings can be written in [Chrome's trace event format],
+/// which can be viewed with chrome://tracing or Perfetto.
+/// Events are recorded on *lanes*, which are displayed as threads.
+///
+/// [Chrome's trace event format]: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+pub struct Profile
+{
+    /// When the profile started; timestamps are relative to this.
+    start: Instant,
+
+    /// The events recorded so far.
+    events: Mutex<Vec<TraceEvent>>,
+}
+
+/// Event in Chrome's trace event format.
+#[derive(Serialize)]
+struct TraceEvent
+{
+    name: String,
+    cat: &'static str,
+    ph: &'static str,
+    ts: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dur: Option<u64>,
+    pid: u32,
+    tid: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    args: Option<serde_json::Value>,
+}
+
+impl Profile
+{
+    /// Create an empty profile that starts now.
+    pub fn new() -> Self
+    {
+        Self{start: Instant::now(), events: Mutex::new(Vec::new())}
+    }
+
+    /// Record something that took place between two instants.
+    pub fn span(
+        &self,
+        lane:     usize,
+        category: &'static str,
+        name:     String,
+        start:    Instant,
+        end:      Instant,
+    )
+    {
+        let ts = self.timestamp(start);
+        let dur = end.saturating_duration_since(start).as_micros() as u64;
+        let dur = Some(dur);
+        self.push(TraceEvent{name, cat: category, ph: "X", ts, dur, pid: 1,
+                             tid: lane, args: None});
+    }
+
+    /// Record something that happened at an instant.
+    pub fn instant(
+        &self,
+        lane:     usize,
+        category: &'static str,
+        name:     String,
+        at:       Instant,
+    )
+    {
+        let ts = self.timestamp(at);
+        self.push(TraceEvent{name, cat: category, ph: "i", ts, dur: None,
+                             pid: 1, tid: lane, args: None});
+    }
+
+    /// Record the value of a counter at an instant.
+    pub fn counter(&self, name: &str, value: u64, at: Instant)
+    {
+        let ts = self.timestamp(at);
+        let mut args = serde_json::Map::new();
+        args.insert(name.to_owned(), value.into());
+        let args = Some(args.into());
+        self.push(TraceEvent{name: name.to_owned(), cat: "scheduler",
+                             ph: "C", ts, dur: None, pid: 1,
+                             tid: SCHEDULER_LANE, args});
+    }
+
+    /// Write the profile in Chrome's trace event format.
+    pub fn write_trace(&self, writer: impl Write) -> io::Result<()>
+    {
+        let events = self.events.lock().unwrap();
+        let trace = serde_json::json!({
+            "traceEvents": &*events,
+            "displayTimeUnit": "ms",
+        });
+        serde_json::to_writer(writer, &trace)?;
+        Ok(())
+    }
+
+    fn timestamp(&self, at: Instant) -> u64
+    {
+        at.saturating_duration_since(self.start).as_micros() as u64
+    }
+
+    fn push(&self, event: TraceEvent)
+    {
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+impl Default for Profile
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {super::*, std::time::Duration};
+
+    #[test]
+    fn write_trace()
+    {
+        let profile = Profile::new();
+        let start = profile.start + Duration::from_micros(10);
+        let end = start + Duration::from_micros(5);
+        profile.span(1, "action", "#0".into(), start, end);
+        profile.instant(1, "cache", "#0 cache hit".into(), end);
+        profile.counter("running", 1, start);
+
+        let mut trace = Vec::new();
+        profile.write_trace(&mut trace).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&trace).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["ph"], "X");
+        assert_eq!(events[0]["ts"], 10);
+        assert_eq!(events[0]["dur"], 5);
+        assert_eq!(events[0]["tid"], 1);
+        assert_eq!(events[1]["ph"], "i");
+        assert_eq!(events[1]["ts"], 15);
+        assert_eq!(events[2]["ph"], "C");
+        assert_eq!(events[2]["args"]["running"], 1);
+        assert_eq!(events[2]["tid"], SCHEDULER_LANE);
+    }
+}
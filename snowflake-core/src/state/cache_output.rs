@@ -1,13 +1,25 @@
 use {
-    super::{State, hash_to_path, ok_if_already_exists},
+    super::{State, hash_to_path},
+    crate::{
+        fs_util::read_entries,
+        output_tree::{remove_all_at, sync_file_at},
+    },
     bitflags::bitflags,
     os_ext::{
-        S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_ISGID, S_ISUID, S_ISVTX,
+        AT_SYMLINK_NOFOLLOW,
+        O_DIRECTORY, O_NOFOLLOW, O_RDONLY,
+        S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_ISGID, S_ISUID, S_ISVTX, S_IXUSR,
         RENAME_NOREPLACE,
-        renameat2, stat,
+        fchmodat, fstatat, openat, renameat2, stat, timespec, utimensat,
     },
     snowflake_util::hash::{Hash, hash_file_at_with},
-    std::{ffi::CStr, fmt, io, os::unix::io::BorrowedFd},
+    std::{
+        ffi::CStr,
+        fmt,
+        io::{self, ErrorKind::{AlreadyExists, NotFound}},
+        os::unix::io::{AsFd, BorrowedFd},
+        sync::atomic::Ordering::SeqCst,
+    },
     thiserror::Error,
 };
 
@@ -22,8 +34,18 @@ impl State
         &self,
         dirfd: Option<BorrowedFd>,
         pathname: &CStr,
+        executable: bool,
     ) -> Result<Hash, CacheOutputError>
     {
+        // Normalize the metadata of the output.
+        // This is the first thing to look at the output,
+        // so this is where we find out that it does not exist.
+        Self::normalize_output(dirfd, pathname, executable)
+            .map_err(|err| match err.kind() {
+                NotFound => CacheOutputError::Missing,
+                _ => CacheOutputError::from(err),
+            })?;
+
         // Hash the output and check its properties.
         let hash = hash_file_at_with(dirfd, pathname, |statbuf| {
             let error = Self::check_output(statbuf);
@@ -36,15 +58,115 @@ impl State
 
         // Move the output to the cache.
         let cache = self.output_cache_dir()?;
-        renameat2(
+        self.sync_before_rename(dirfd, pathname)?;
+        let renamed = renameat2(
             dirfd, pathname,
             Some(cache), &hash_to_path(&hash),
             RENAME_NOREPLACE,
-        ).or_else(ok_if_already_exists)?;
+        );
+        match renamed {
+            Err(err) if err.raw_os_error() == Some(libc::EXDEV) =>
+                self.copy_output(dirfd, pathname, &hash, executable)?,
+            Err(err) if err.kind() == AlreadyExists =>
+                self.count_output_cache_hit(),
+            result => {
+                result?;
+                self.sync_after_rename(cache)?;
+            },
+        }
 
         Ok(hash)
     }
 
+    /// Move an output to the cache from a different file system.
+    ///
+    /// The output is copied next to its final location in the cache,
+    /// then renamed into place, so the cache never contains a partial copy.
+    /// Afterwards the original output is removed, as a rename would have.
+    fn copy_output(&self, dirfd: Option<BorrowedFd>, pathname: &CStr,
+                   hash: &Hash, executable: bool) -> io::Result<()>
+    {
+        let cache_dir = self.output_cache_dir()?;
+        let cache = Some(cache_dir);
+        let temporary = self.fresh_scratch();
+
+        // Copying does not preserve modification times,
+        // so the copy must be normalized again.
+        sync_file_at(dirfd, pathname, cache, &temporary)?;
+        Self::normalize_output(cache, &temporary, executable)?;
+        self.sync_before_rename(cache, &temporary)?;
+
+        let renamed = renameat2(
+            cache, &temporary,
+            cache, &hash_to_path(hash),
+            RENAME_NOREPLACE,
+        );
+        match renamed {
+            Err(err) if err.kind() == AlreadyExists => {
+                self.count_output_cache_hit();
+                remove_all_at(cache, &temporary)?;
+            },
+            result => {
+                result?;
+                self.sync_after_rename(cache_dir)?;
+            },
+        }
+
+        remove_all_at(dirfd, pathname)
+    }
+
+    fn count_output_cache_hit(&self)
+    {
+        self.output_cache_hits.fetch_add(1, SeqCst);
+    }
+
+    /// Normalize the metadata of an output, so that it is deterministic.
+    ///
+    /// Regular files are made read-only, and they remain executable only
+    /// if `executable` is set and they were executable by their owner.
+    /// Directories get permissions 755. The setuid, setgid, and sticky
+    /// bits are cleared, and modification times are set to the epoch.
+    ///
+    /// Regular files with multiple hard links are left alone,
+    /// because changing them would change files outside the output.
+    /// Such files, and files of unsupported types, fail the check
+    /// in [`check_output`][`Self::check_output`] afterwards.
+    fn normalize_output(
+        dirfd: Option<BorrowedFd>,
+        pathname: &CStr,
+        executable: bool,
+    ) -> io::Result<()>
+    {
+        let statbuf = fstatat(dirfd, pathname, AT_SYMLINK_NOFOLLOW)?;
+        let file_type = statbuf.st_mode & S_IFMT;
+
+        let mode = match file_type {
+            S_IFREG if statbuf.st_nlink != 1 => return Ok(()),
+            S_IFREG if executable && statbuf.st_mode & S_IXUSR != 0 =>
+                Some(0o555),
+            S_IFREG => Some(0o444),
+            S_IFDIR => Some(0o755),
+            S_IFLNK => None,  // Symlinks have no permissions.
+            _       => return Ok(()),
+        };
+        if let Some(mode) = mode {
+            fchmodat(dirfd, pathname, mode, 0)?;
+        }
+
+        if file_type == S_IFDIR {
+            let flags = O_DIRECTORY | O_NOFOLLOW | O_RDONLY;
+            let dir = openat(dirfd, pathname, flags, 0)?;
+            for name in read_entries(dir.as_fd())? {
+                let dirfd = Some(dir.as_fd());
+                Self::normalize_output(dirfd, &name, executable)?;
+            }
+        }
+
+        // Directories last, as changing their entries changes their times.
+        let epoch = timespec{tv_sec: 0, tv_nsec: 0};
+        utimensat(dirfd, pathname, &[epoch, epoch], AT_SYMLINK_NOFOLLOW)
+    }
+
     /// Check that the properties of an output look reasonable.
     fn check_output(&stat{st_mode, st_nlink, ..}: &stat) -> OutputError
     {
@@ -58,12 +180,12 @@ impl State
         if st_mode & S_ISGID != 0 { err |= E::SETGID_BIT; }
         if st_mode & S_ISVTX != 0 { err |= E::STICKY_BIT; }
 
-        // The set of allowed permissions was chosen conservatively.
-        // A future version might allow more combinations of permissions.
+        // Outputs are normalized before they are checked,
+        // so other permissions indicate something went wrong.
         if
             match file_type {
                 // NOTE: When changing this, also change Display impl.
-                S_IFREG => !matches!(st_mode & 0o777, 0o755 | 0o644),
+                S_IFREG => !matches!(st_mode & 0o777, 0o555 | 0o444),
                 S_IFDIR => !matches!(st_mode & 0o777, 0o755),
                 S_IFLNK => false,  // Symlinks have no permissions.
                 _       => false,  // Bad type handled below.
@@ -94,15 +216,39 @@ impl State
 /* -------------------------------------------------------------------------- */
 
 /// Error returned when caching an output.
-#[allow(missing_docs)]
+///
+/// The variants tell apart failures that are the fault of the action
+/// from failures that are not, so that the driver can react to them.
 #[derive(Debug, Error)]
 pub enum CacheOutputError
 {
-    #[error("{0}")]
-    Io(io::Error),
+    /// The output does not exist, so the action did not create it.
+    #[error("Action did not create the output")]
+    Missing,
 
+    /// The output exists but does not qualify for caching.
     #[error("{0}")]
     Output(#[from] OutputError),
+
+    /// An I/O error that may not occur again when retrying,
+    /// such as running out of file descriptors.
+    #[error("{0}")]
+    Transient(io::Error),
+
+    /// Any other I/O error.
+    #[error("{0}")]
+    Io(io::Error),
+}
+
+impl CacheOutputError
+{
+    /// Whether caching the output again may succeed.
+    ///
+    /// Caching an output is idempotent, so this is always safe to do.
+    pub fn is_transient(&self) -> bool
+    {
+        matches!(self, Self::Transient(..))
+    }
 }
 
 impl From<io::Error> for CacheOutputError
@@ -111,9 +257,13 @@ impl From<io::Error> for CacheOutputError
     {
         let inner: Option<&mut OutputError> =
             other.get_mut().and_then(|err| err.downcast_mut());
-        match inner {
-            Some(err) => Self::Output(*err),
-            None => Self::Io(other),
+        if let Some(err) = inner {
+            return Self::Output(*err);
+        }
+        match other.raw_os_error() {
+            Some(libc::EAGAIN | libc::EINTR | libc::EMFILE |
+                 libc::ENFILE | libc::ENOMEM) => Self::Transient(other),
+            _ => Self::Io(other),
         }
     }
 }
@@ -147,7 +297,7 @@ impl fmt::Display for OutputError
         if c(Self::SETGID_BIT) { write!(f, "the setgid bit set, ")?; }
         if c(Self::STICKY_BIT) { write!(f, "the sticky bit set, ")?; }
         if c(Self::BAD_PERMISSIONS) {
-            write!(f, "regular file permissions other than 755 or 644 or \
+            write!(f, "regular file permissions other than 555 or 444 or \
                        directory permissions other than 755, ")?;
         }
         if c(Self::BAD_FILE_TYPE) {
@@ -197,28 +347,78 @@ mod tests
         fn test_case(state: &State, scratch: Option<BorrowedFd>,
                      path: &CStr, expected: Oe)
         {
-            let actual = state.cache_output(scratch, &path);
+            let actual = state.cache_output(scratch, &path, true);
             assert_matches!(actual, Err(Coe::Output(err)) if err == expected);
         }
 
         // Create a bunch of bad files.
-        mknodat(scratch, cstr!(b"setuid"),  S_IFREG | S_ISUID | 0o644, 0).unwrap();
+        mknodat(scratch, cstr!(b"fifo"),  S_IFIFO | 0o644, 0).unwrap();
+        mknodat(scratch, cstr!(b"link1"), S_IFREG | 0o444, 0).unwrap();
+        linkat(scratch, cstr!(b"link1"), scratch, cstr!(b"link2"), 0).unwrap();
+
+        // Test that caching each file reports the correct error.
+        test_case(&state, scratch, cstr!(b"fifo"),  Oe::BAD_FILE_TYPE);
+        test_case(&state, scratch, cstr!(b"link1"), Oe::MULTIPLE_HARD_LINKS);
+        test_case(&state, scratch, cstr!(b"link2"), Oe::MULTIPLE_HARD_LINKS);
+
+        // Test that caching a file that does not exist reports that.
+        let actual = state.cache_output(scratch, cstr!(b"missing"), true);
+        assert_matches!(actual, Err(Coe::Missing));
+    }
+
+    #[test]
+    fn io_errors()
+    {
+        let error = |errnum| {
+            CacheOutputError::from(io::Error::from_raw_os_error(errnum))
+        };
+        assert!(error(libc::EINTR).is_transient());
+        assert!(error(libc::EMFILE).is_transient());
+        assert!(!error(libc::EIO).is_transient());
+        assert_matches!(error(libc::EIO), CacheOutputError::Io(..));
+    }
+
+    #[test]
+    fn normalize()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+
+        // Create scratch directory.
+        let state = State::open(&path).unwrap();
+        let scratch = state.new_scratch_dir().unwrap();
+        let scratch = Some(scratch.as_fd());
+
+        // Check that a file is cached with the given permissions.
+        #[track_caller]
+        fn test_case(state: &State, scratch: Option<BorrowedFd>,
+                     path: &CStr, executable: bool, expected: u32)
+        {
+            let hash = state.cache_output(scratch, path, executable).unwrap();
+            let (cache, cached) = state.cached_output(hash).unwrap();
+            let flags = AT_SYMLINK_NOFOLLOW;
+            let statbuf = fstatat(Some(cache), &cached, flags).unwrap();
+            assert_eq!(statbuf.st_mode & 0o7777, expected);
+            assert_eq!(statbuf.st_mtime, 0);
+        }
+
+        // Create a bunch of files with unusual metadata.
+        mknodat(scratch, cstr!(b"setuid"),  S_IFREG | S_ISUID | 0o755, 0).unwrap();
         mknodat(scratch, cstr!(b"setgid"),  S_IFREG | S_ISGID | 0o644, 0).unwrap();
         mknodat(scratch, cstr!(b"sticky"),  S_IFREG | S_ISVTX | 0o644, 0).unwrap();
-        mknodat(scratch, cstr!(b"regperm"), S_IFREG |           0o600, 0).unwrap();
+        mknodat(scratch, cstr!(b"regperm"), S_IFREG |           0o700, 0).unwrap();
+        mknodat(scratch, cstr!(b"noexec"),  S_IFREG |           0o755, 0).unwrap();
         mkdirat(scratch, cstr!(b"dirperm"),                     0o700   ).unwrap();
-        mknodat(scratch, cstr!(b"fifo"),    S_IFIFO |           0o644, 0).unwrap();
-        mknodat(scratch, cstr!(b"link1"),   S_IFREG |           0o644, 0).unwrap();
-        linkat(scratch, cstr!(b"link1"), scratch, cstr!(b"link2"), 0).unwrap();
 
-        // Test that caching each file reports the correct error.
-        test_case(&state, scratch, cstr!(b"setuid"),  Oe::SETUID_BIT);
-        test_case(&state, scratch, cstr!(b"setgid"),  Oe::SETGID_BIT);
-        test_case(&state, scratch, cstr!(b"sticky"),  Oe::STICKY_BIT);
-        test_case(&state, scratch, cstr!(b"regperm"), Oe::BAD_PERMISSIONS);
-        test_case(&state, scratch, cstr!(b"dirperm"), Oe::BAD_PERMISSIONS);
-        test_case(&state, scratch, cstr!(b"fifo"),    Oe::BAD_FILE_TYPE);
-        test_case(&state, scratch, cstr!(b"link1"),   Oe::MULTIPLE_HARD_LINKS);
-        test_case(&state, scratch, cstr!(b"link2"),   Oe::MULTIPLE_HARD_LINKS);
+        // Test that caching each file normalizes its metadata.
+        test_case(&state, scratch, cstr!(b"setuid"),  true,  0o555);
+        test_case(&state, scratch, cstr!(b"setgid"),  true,  0o444);
+        test_case(&state, scratch, cstr!(b"sticky"),  true,  0o444);
+        test_case(&state, scratch, cstr!(b"regperm"), true,  0o555);
+        test_case(&state, scratch, cstr!(b"noexec"),  false, 0o444);
+        test_case(&state, scratch, cstr!(b"dirperm"), true,  0o755);
+
+        // Files that normalized to the same contents were cached once.
+        assert_eq!(state.output_cache_hits(), 3);
     }
 }
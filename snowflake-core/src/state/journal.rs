@@ -0,0 +1,385 @@
+use {
+    super::{Durability, State, read_json_file},
+    os_ext::{
+        LOCK_EX, LOCK_UN, O_APPEND, O_CREAT, O_RDONLY, O_RDWR,
+        fdatasync, flock, openat, unlinkat,
+    },
+    serde::{Deserialize, Serialize},
+    snowflake_util::hash::{Blake3, Hash},
+    std::{
+        collections::HashMap,
+        ffi::CStr,
+        fs::File,
+        io::{self, ErrorKind::NotFound, Read, Write},
+        os::unix::io::{AsFd, BorrowedFd},
+        sync::{Mutex, MutexGuard},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+// Paths to the files in the journal directory.
+// TODO: Replace with cstr! macro once from_ptr is const.
+const JOURNAL_LOG: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"log\0") };
+const JOURNAL_SNAPSHOT: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"snapshot\0") };
+
+/// Once the log has this many records, it is compacted into the snapshot.
+const COMPACT_THRESHOLD: usize = 4096;
+
+/// Each record in the log is preceded by the length of its payload
+/// and the Blake3 hash of its payload, which serves as a checksum.
+const HEADER_SIZE: usize = 4 + 32;
+
+/* -------------------------------------------------------------------------- */
+/*                               Journal records                              */
+/* -------------------------------------------------------------------------- */
+
+/// Record of an insertion into the action cache.
+///
+/// The journal consists of a snapshot and an append-only log.
+/// Each record in the log is checksummed, so that a record that was
+/// partially written when Snowflake crashed can be detected and removed.
+/// When the log grows large, it is compacted into the snapshot,
+/// keeping only the most recent record for each action.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct JournalRecord
+{
+    /// The key into the action cache of the inserted entry.
+    pub action: Hash,
+
+    /// The hash of each output of the action.
+    ///
+    /// See [`ActionCacheEntry::outputs`].
+    ///
+    /// [`ActionCacheEntry::outputs`]: `super::ActionCacheEntry::outputs`
+    pub outputs: Vec<Option<Hash>>,
+
+    /// When the entry was inserted, in seconds since the Unix epoch.
+    pub time: u64,
+}
+
+impl JournalRecord
+{
+    /// Create a record of an insertion that happens now.
+    pub fn now(action: Hash, outputs: Vec<Option<Hash>>) -> Self
+    {
+        let since_epoch = SystemTime::now().duration_since(UNIX_EPOCH);
+        let time = since_epoch.unwrap_or_default().as_secs();
+        Self{action, outputs, time}
+    }
+}
+
+/// Open handle to the journal log.
+pub (super) struct JournalLog
+{
+    file: File,
+
+    /// The number of records in the log.
+    records: usize,
+}
+
+impl JournalLog
+{
+    /// Call a function while holding an exclusive lock on the log file.
+    ///
+    /// The journal directory is shared by all Snowflake instances
+    /// that use the state directory, so the log must be locked
+    /// while it is appended to, truncated, or read along with the snapshot.
+    /// Otherwise, one process could truncate away records
+    /// that another process just appended.
+    fn locked<F, R>(&mut self, f: F) -> io::Result<R>
+        where F: FnOnce(&mut Self) -> io::Result<R>
+    {
+        flock(self.file.as_fd(), LOCK_EX)?;
+        let result = f(self);
+        flock(self.file.as_fd(), LOCK_UN)?;
+        result
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                           Journal implementation                           */
+/* -------------------------------------------------------------------------- */
+
+impl State
+{
+    /// Handle to the journal directory.
+    fn journal_dir(&self) -> io::Result<BorrowedFd>
+    {
+        self.ensure_open_dir_once(&self.journal_dir, super::JOURNAL_DIR)
+    }
+
+    /// Lock the journal log, opening it if it was not yet opened.
+    ///
+    /// When the log is opened, any partially written record
+    /// at the end of the log is removed,
+    /// so that new records are not appended after it.
+    fn journal_log(&self) -> io::Result<MutexGuard<JournalLog>>
+    {
+        let log = self.journal_log.get_or_try_init(|| {
+            let dir = self.journal_dir()?;
+            let flags = O_APPEND | O_CREAT | O_RDWR;
+            let file = openat(Some(dir), JOURNAL_LOG, flags, 0o644)?;
+            let mut log = JournalLog{file: File::from(file), records: 0};
+
+            // Other processes only write to the log while holding the lock,
+            // so a partially written record must be from a crashed process.
+            log.locked(|log| {
+                let mut buf = Vec::new();
+                log.file.read_to_end(&mut buf)?;
+                let (records, valid) = decode_records(&buf);
+                if valid < buf.len() {
+                    log.file.set_len(valid as u64)?;
+                }
+                log.records = records.len();
+                Ok(())
+            })?;
+
+            io::Result::Ok(Mutex::new(log))
+        })?;
+        Ok(log.lock().unwrap())
+    }
+
+    /// Append a record to the journal.
+    ///
+    /// Once the log has grown large enough,
+    /// this also compacts the log into the snapshot.
+    pub fn journal_record(&self, record: &JournalRecord) -> io::Result<()>
+    {
+        let payload = serde_json::to_vec(record)?;
+        let mut buf = Vec::with_capacity(HEADER_SIZE + payload.len());
+        buf.extend((payload.len() as u32).to_le_bytes());
+        buf.extend(checksum(&payload));
+        buf.extend(payload);
+
+        self.journal_log()?.locked(|log| {
+            log.file.write_all(&buf)?;
+
+            // Torn records are removed when the log is opened,
+            // so flushing is only needed to not lose the record.
+            if self.durability == Durability::Full {
+                fdatasync(log.file.as_fd())?;
+            }
+
+            log.records += 1;
+            if log.records >= COMPACT_THRESHOLD {
+                self.compact_journal(log)?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Read the most recent record for each action in the journal.
+    ///
+    /// The records are returned in the order in which
+    /// the actions were first recorded.
+    pub fn journal(&self) -> io::Result<Vec<JournalRecord>>
+    {
+        self.journal_log()?.locked(|log| self.read_journal(log))
+    }
+
+    /// Implementation of [`journal`][`Self::journal`].
+    ///
+    /// Takes the locked log to ensure no records are appended meanwhile.
+    /// The log must also be [locked][`JournalLog::locked`]
+    /// so that other processes cannot compact the journal meanwhile.
+    fn read_journal(&self, _log: &JournalLog)
+        -> io::Result<Vec<JournalRecord>>
+    {
+        let dir = self.journal_dir()?;
+
+        let snapshot: Vec<JournalRecord> =
+            read_json_file(dir, JOURNAL_SNAPSHOT)?.unwrap_or_default();
+
+        let file = openat(Some(dir), JOURNAL_LOG, O_RDONLY, 0)?;
+        let mut buf = Vec::new();
+        File::from(file).read_to_end(&mut buf)?;
+        let (log, _) = decode_records(&buf);
+
+        let mut records: Vec<JournalRecord> = Vec::new();
+        let mut indices = HashMap::new();
+        for record in snapshot.into_iter().chain(log) {
+            match indices.get(&record.action.0) {
+                Some(&index) => records[index] = record,
+                None => {
+                    indices.insert(record.action.0, records.len());
+                    records.push(record);
+                },
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Remove all records from the journal.
+    pub (super) fn clear_journal(&self) -> io::Result<()>
+    {
+        let dir = self.journal_dir()?;
+        self.journal_log()?.locked(|log| {
+            match unlinkat(Some(dir), JOURNAL_SNAPSHOT, 0) {
+                Err(err) if err.kind() == NotFound => { },
+                result => result?,
+            }
+            log.file.set_len(0)?;
+            log.records = 0;
+            Ok(())
+        })
+    }
+
+    /// Replace the snapshot with the contents of the journal,
+    /// then empty the log.
+    ///
+    /// The log must be [locked][`JournalLog::locked`].
+    fn compact_journal(&self, log: &mut JournalLog) -> io::Result<()>
+    {
+        let records = self.read_journal(log)?;
+        let dir = self.journal_dir()?;
+        self.replace_json_file(dir, JOURNAL_SNAPSHOT, &records)?;
+
+        // If Snowflake crashes before the log is emptied,
+        // the records in the log are applied to the new snapshot again.
+        // Because only the most recent record for each action is kept,
+        // this gives the same result.
+        log.file.set_len(0)?;
+        log.records = 0;
+
+        Ok(())
+    }
+}
+
+/// Decode the records in the log.
+///
+/// Decoding stops at the first record that is incomplete or corrupt.
+/// Returns the records and the number of bytes they take up.
+fn decode_records(buf: &[u8]) -> (Vec<JournalRecord>, usize)
+{
+    let mut records = Vec::new();
+    let mut offset = 0;
+
+    while buf.len() - offset >= HEADER_SIZE {
+        let header = &buf[offset .. offset + HEADER_SIZE];
+        let len = u32::from_le_bytes(header[.. 4].try_into().unwrap());
+        let start = offset + HEADER_SIZE;
+        let payload = match buf.get(start .. start + len as usize) {
+            Some(payload) => payload,
+            None => break,
+        };
+        if checksum(payload) != header[4 ..] {
+            break;
+        }
+        match serde_json::from_slice(payload) {
+            Ok(record) => records.push(record),
+            Err(_) => break,
+        }
+        offset = start + payload.len();
+    }
+
+    (records, offset)
+}
+
+fn checksum(payload: &[u8]) -> [u8; 32]
+{
+    let mut h = Blake3::new();
+    h.update(payload);
+    h.finalize().0
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Tests                                   */
+/* -------------------------------------------------------------------------- */
+
+#[cfg(test)]
+mod tests
+{
+    use {
+        super::*,
+        os_ext::{O_WRONLY, cstr, cstr::CStrExt, cstring, mkdtemp},
+        std::{sync::mpsc, thread, time::Duration},
+    };
+
+    fn record(action: u8, output: u8) -> JournalRecord
+    {
+        let outputs = vec![Some(Hash([output; 32]))];
+        JournalRecord{action: Hash([action; 32]), outputs, time: 0}
+    }
+
+    #[test]
+    fn journal()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+
+        // Only the most recent record for each action is kept.
+        state.journal_record(&record(0, 1)).unwrap();
+        state.journal_record(&record(2, 3)).unwrap();
+        state.journal_record(&record(0, 4)).unwrap();
+        let expected = vec![record(0, 4), record(2, 3)];
+        assert_eq!(state.journal().unwrap(), expected);
+
+        // The records survive compaction.
+        state.compact_journal(&mut state.journal_log().unwrap()).unwrap();
+        assert_eq!(state.journal_log().unwrap().records, 0);
+        assert_eq!(state.journal().unwrap(), expected);
+
+        // The records survive reopening the state directory.
+        state.journal_record(&record(5, 6)).unwrap();
+        let state = State::open(&path).unwrap();
+        let expected = vec![record(0, 4), record(2, 3), record(5, 6)];
+        assert_eq!(state.journal().unwrap(), expected);
+    }
+
+    #[test]
+    fn torn_tail()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+        state.journal_record(&record(0, 1)).unwrap();
+
+        // Simulate a crash while appending a record.
+        let log = path.join(cstr!(b"journal/log"));
+        let log = os_ext::open(&log, O_APPEND | O_WRONLY, 0).unwrap();
+        File::from(log).write_all(&[1, 2, 3]).unwrap();
+
+        // The partial record is removed when the log is opened,
+        // so records appended afterwards can be read.
+        let state = State::open(&path).unwrap();
+        state.journal_record(&record(2, 3)).unwrap();
+        let state = State::open(&path).unwrap();
+        let expected = vec![record(0, 1), record(2, 3)];
+        assert_eq!(state.journal().unwrap(), expected);
+    }
+
+    #[test]
+    fn locked()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+        state.journal_record(&record(0, 1)).unwrap();
+
+        // Simulate another process that is writing to the log.
+        let log = path.join(cstr!(b"journal/log"));
+        let log = os_ext::open(&log, O_RDONLY, 0).unwrap();
+        flock(log.as_fd(), LOCK_EX).unwrap();
+
+        // Appending a record waits for the other process.
+        let (sender, receiver) = mpsc::channel();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                state.journal_record(&record(2, 3)).unwrap();
+                sender.send(()).unwrap();
+            });
+            thread::sleep(Duration::from_millis(100));
+            assert!(receiver.try_recv().is_err());
+            flock(log.as_fd(), LOCK_UN).unwrap();
+            receiver.recv().unwrap();
+        });
+
+        let expected = vec![record(0, 1), record(2, 3)];
+        assert_eq!(state.journal().unwrap(), expected);
+    }
+}
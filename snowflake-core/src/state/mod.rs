@@ -1,28 +1,39 @@
 //! Working with state directories.
 
-pub use self::cache_output::*;
+pub use self::{cache_output::*, journal::*};
 
 use {
+    crate::{
+        action::Dependency,
+        fs_util::{disk_usage_at, fsync_all_at, read_entries},
+        label::ActionLabel,
+        output_tree::remove_all_at,
+    },
     os_ext::{
-        AT_SYMLINK_FOLLOW,
-        O_DIRECTORY, O_PATH, O_RDONLY, O_TMPFILE, O_WRONLY,
-        cstr, linkat, mkdirat, open, openat,
+        AT_SYMLINK_FOLLOW, AT_SYMLINK_NOFOLLOW,
+        O_DIRECTORY, O_PATH, O_RDONLY, O_RDWR, O_TMPFILE, O_WRONLY,
+        O_CREAT, O_EXCL,
+        cstr, fstatat, fsync, linkat, mkdirat, open, openat, readlink,
+        renameat2, stat,
         io::magic_link,
     },
     serde::{Deserialize, Serialize},
-    snowflake_util::hash::Hash,
+    snowflake_util::hash::{Blake3, Hash},
     std::{
         ffi::{CStr, CString},
+        fmt,
         fs::File,
         io::{self, BufReader, ErrorKind::{AlreadyExists, NotFound}, Write},
         lazy::SyncOnceCell,
         os::unix::io::{AsFd, BorrowedFd, OwnedFd},
-        sync::atomic::{AtomicU32, Ordering::SeqCst},
+        sync::{Mutex, atomic::{AtomicU32, AtomicU64, Ordering::SeqCst}},
+        time::Duration,
     },
     uuid::Uuid,
 };
 
 mod cache_output;
+mod journal;
 
 // Paths to the different components of the state directory.
 // TODO: Replace with cstr! macro once from_ptr is const.
@@ -32,6 +43,18 @@ const ACTION_CACHE_DIR: &CStr =
     unsafe { CStr::from_bytes_with_nul_unchecked(b"action-cache\0") };
 const OUTPUT_CACHE_DIR: &CStr =
     unsafe { CStr::from_bytes_with_nul_unchecked(b"output-cache\0") };
+const ACTION_RECORDS_DIR: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"action-records\0") };
+const DEPENDENCIES_DIR: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"dependencies\0") };
+const TEST_RESULTS_DIR: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"test-results\0") };
+const INPUT_DIGESTS_DIR: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"input-digests\0") };
+const JOURNAL_DIR: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"journal\0") };
+const FAILURE_REPORTS_DIR: &CStr =
+    unsafe { CStr::from_bytes_with_nul_unchecked(b"failure-reports\0") };
 
 /// Handle to a state directory.
 pub struct State
@@ -39,10 +62,28 @@ pub struct State
     /// Handle to the state directory.
     state_dir: OwnedFd,
 
+    /// Path to the scratches directory, if not in the state directory.
+    scratches_path: Option<CString>,
+
+    /// Path to the output cache, if not in the state directory.
+    output_cache_path: Option<CString>,
+
+    /// How hard to try to keep the caches intact after a crash.
+    durability: Durability,
+
     // Handles to the different components of the state directory.
-    scratches_dir:    SyncOnceCell<OwnedFd>,
-    action_cache_dir: SyncOnceCell<OwnedFd>,
-    output_cache_dir: SyncOnceCell<OwnedFd>,
+    scratches_dir:      SyncOnceCell<OwnedFd>,
+    action_cache_dir:   SyncOnceCell<OwnedFd>,
+    output_cache_dir:   SyncOnceCell<OwnedFd>,
+    action_records_dir: SyncOnceCell<OwnedFd>,
+    dependencies_dir:   SyncOnceCell<OwnedFd>,
+    test_results_dir:   SyncOnceCell<OwnedFd>,
+    input_digests_dir:  SyncOnceCell<OwnedFd>,
+    journal_dir:        SyncOnceCell<OwnedFd>,
+    failure_reports_dir: SyncOnceCell<OwnedFd>,
+
+    /// The journal log, opened when it is first used.
+    journal_log: SyncOnceCell<Mutex<JournalLog>>,
 
     /// Identifies this instance of Snowflake.
     ///
@@ -52,6 +93,102 @@ pub struct State
 
     /// Name of the next scratch file to create.
     next_scratch: AtomicU32,
+
+    /// The number of outputs that were already in the output cache.
+    output_cache_hits: AtomicU64,
+}
+
+/// Options for opening a state directory.
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions
+{
+    /// Where to create scratch files.
+    ///
+    /// By default, scratch files are created in the state directory.
+    /// Placing them elsewhere, such as on a tmpfs, can speed up builds.
+    /// If the scratches directory is on a different file system
+    /// than the state directory, outputs are copied into the cache
+    /// rather than renamed. The directory is created if it does not exist.
+    pub scratches_dir: Option<CString>,
+
+    /// Where to store the output cache.
+    ///
+    /// By default, the output cache is in the state directory.
+    /// Because the output cache is content-addressed, it can be shared
+    /// by the state directories of many workspaces, such as one
+    /// in `~/.cache/snowflake`, while their action caches remain separate.
+    /// If the output cache is on a different file system
+    /// than the scratches directory, outputs are copied into the cache
+    /// rather than renamed. The directory is created if it does not exist.
+    ///
+    /// Entries may be removed from a shared output cache at any time,
+    /// for example to free up disk space. Action cache entries
+    /// that refer to removed outputs are then ignored, and the
+    /// actions are performed again. Builds that are running
+    /// while an output they use is removed may fail.
+    pub output_cache_dir: Option<CString>,
+
+    /// How hard to try to keep the caches intact after a crash.
+    pub durability: Durability,
+}
+
+/// How hard to try to keep the state directory intact after a crash.
+///
+/// Entries are always written under a temporary name
+/// and then renamed into place, so other processes never see
+/// partial entries. But unless the entries are flushed to disk first,
+/// the rename may reach the disk before their contents do,
+/// and after a power loss or kernel crash the entries may be empty.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Durability
+{
+    /// Never flush anything to disk.
+    ///
+    /// This is the fastest, but a crash may corrupt the caches.
+    /// Useful on throwaway machines, such as CI runners.
+    None,
+
+    /// Flush entries to disk before they are renamed into place.
+    ///
+    /// After a crash, recent entries may be missing,
+    /// but entries that are present are intact.
+    /// This is the default.
+    Flush,
+
+    /// Also flush the directory after an entry is renamed into place.
+    ///
+    /// Once an entry is inserted, it survives a crash.
+    Full,
+}
+
+// Deriving this requires #[default], which our toolchain lacks.
+#[allow(clippy::derivable_impls)]
+impl Default for Durability
+{
+    fn default() -> Self
+    {
+        Self::Flush
+    }
+}
+
+/// Part of a state directory that can be cleaned.
+///
+/// See [`State::clean`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CleanScope
+{
+    /// Scratch files, including those of other Snowflake instances.
+    Scratches,
+
+    /// The action cache and the journal.
+    ActionCache,
+
+    /// The output cache.
+    ///
+    /// Entries in the action cache refer to outputs,
+    /// so unless the output cache is shared,
+    /// the action cache must be cleaned along with it.
+    OutputCache,
 }
 
 /// Cached information about an action.
@@ -63,14 +200,24 @@ pub struct ActionCacheEntry
     /// This enables finding the build log in the output cache.
     pub build_log: Hash,
 
+    /// Whether the build log contains ANSI escape sequences.
+    ///
+    /// This is the case when the action produced colored output.
+    /// The build log is stored as captured, so when displaying it
+    /// somewhere that does not support colors, they must be stripped.
+    #[serde(default)]
+    pub build_log_ansi: bool,
+
     /// The hash of each output of the action.
     ///
     /// The number of outputs must equal [`Action::outputs`]
     /// and their indices must match those in [output labels].
+    /// [Optional outputs] that were not produced are [`None`].
     ///
     /// [`Action::outputs`]: `crate::action::Action::outputs`
     /// [output labels]: `crate::label::ActionOutputLabel`
-    pub outputs: Vec<Hash>,
+    /// [Optional outputs]: `crate::action::Action::optional_outputs`
+    pub outputs: Vec<Option<Hash>>,
 
     /// Whether warnings were emitted by the action.
     ///
@@ -79,6 +226,163 @@ pub struct ActionCacheEntry
     pub warnings: bool,
 }
 
+/// Record of the most recent attempt at building an action.
+///
+/// Action records are keyed by action label rather than action hash.
+/// They are used to explain why an action needs to be built again.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ActionRecord
+{
+    /// The hash of the action, computed with all-zero input hashes.
+    ///
+    /// This identifies the command of the action
+    /// independently of the inputs of the action.
+    pub command: Hash,
+
+    /// The hash of each input of the action.
+    pub inputs: Vec<Hash>,
+
+    /// The elements of the command of the action.
+    ///
+    /// See [`Action::command_elements`] for how they are used.
+    /// Records written before elements were recorded have none.
+    ///
+    /// [`Action::command_elements`]: `crate::action::Action::command_elements`
+    #[serde(default)]
+    pub command_elements: Vec<(String, String)>,
+}
+
+/// Result of the most recent run of a test.
+///
+/// Test results are keyed by action hash.
+/// See [`Action::is_test`] for how they are used.
+///
+/// [`Action::is_test`]: `crate::action::Action::is_test`
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TestResult
+{
+    /// Whether the test passed.
+    pub status: TestStatus,
+
+    /// The hash of the build log of the test.
+    ///
+    /// Like the other fields, for tests that did not pass,
+    /// this describes the most recent failed attempt.
+    pub build_log: Hash,
+
+    /// Whether the build log contains ANSI escape sequences.
+    pub build_log_ansi: bool,
+
+    /// The exit code of the test.
+    ///
+    /// This is [`None`] if the test did not exit normally,
+    /// for example because it timed out or was killed by a signal.
+    pub exit_code: Option<i32>,
+
+    /// Why the test failed, or [`None`] if it passed.
+    pub error: Option<String>,
+
+    /// How long it took to run the test, summed over all attempts.
+    pub duration: Duration,
+}
+
+/// The hash of a static file, and the metadata it had when hashed.
+///
+/// Input digests are keyed by the path of the file.
+/// If the file still has the same metadata, it is assumed
+/// to still have the same contents, so it need not be hashed again.
+/// Besides the size and modification time, the change time is included,
+/// since it also changes when the permissions change, which are hashed.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct InputDigest
+{
+    /// The device that contains the file.
+    pub device: u64,
+
+    /// The inode number of the file.
+    pub inode: u64,
+
+    /// The size of the file in bytes.
+    pub size: i64,
+
+    /// The modification time, as seconds and nanoseconds.
+    pub mtime: (i64, i64),
+
+    /// The change time, as seconds and nanoseconds.
+    pub ctime: (i64, i64),
+
+    /// The hash of the file, as computed by [`hash_file_at`].
+    ///
+    /// [`hash_file_at`]: `snowflake_util::hash::hash_file_at`
+    pub hash: Hash,
+}
+
+impl InputDigest
+{
+    /// Create an input digest for a file with the given metadata.
+    pub fn new(statbuf: &stat, hash: Hash) -> Self
+    {
+        Self{
+            device: statbuf.st_dev,
+            inode: statbuf.st_ino,
+            size: statbuf.st_size,
+            mtime: (statbuf.st_mtime, statbuf.st_mtime_nsec),
+            ctime: (statbuf.st_ctime, statbuf.st_ctime_nsec),
+            hash,
+        }
+    }
+
+    /// Whether the file still has the metadata it had when hashed.
+    pub fn is_fresh(&self, statbuf: &stat) -> bool
+    {
+        *self == Self::new(statbuf, self.hash)
+    }
+}
+
+/// Whether a test passed.
+///
+/// A test may be performed multiple times, because of
+/// its [retry policy] or because it is run repeatedly.
+///
+/// [retry policy]: `crate::action::Action::retry_policy`
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum TestStatus
+{
+    /// Every attempt at performing the test succeeded.
+    Passed,
+
+    /// Some attempts at performing the test succeeded and some failed.
+    Flaky,
+
+    /// Every attempt at performing the test failed.
+    Failed,
+}
+
+impl TestStatus
+{
+    /// Classify a test given how many of its attempts succeeded and failed.
+    pub fn from_attempts(passes: u32, failures: u32) -> Self
+    {
+        match (passes, failures) {
+            (_, 0) => Self::Passed,
+            (0, _) => Self::Failed,
+            (_, _) => Self::Flaky,
+        }
+    }
+}
+
+impl fmt::Display for TestStatus
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self {
+            Self::Passed => write!(f, "PASSED"),
+            Self::Flaky  => write!(f, "FLAKY"),
+            Self::Failed => write!(f, "FAILED"),
+        }
+    }
+}
+
 impl State
 {
     /// Open a state directory.
@@ -87,16 +391,36 @@ impl State
     /// Components of the state directory are not opened immediately;
     /// they are opened when they are first used.
     pub fn open(path: &CStr) -> io::Result<Self>
+    {
+        Self::open_with_options(path, &OpenOptions::default())
+    }
+
+    /// Open a state directory with the given options.
+    ///
+    /// See [`open`][`Self::open`] for details.
+    pub fn open_with_options(path: &CStr, options: &OpenOptions)
+        -> io::Result<Self>
     {
         let state_dir = open(path, O_DIRECTORY | O_PATH, 0)?;
 
         let this = Self{
             state_dir,
-            scratches_dir:    SyncOnceCell::new(),
-            action_cache_dir: SyncOnceCell::new(),
-            output_cache_dir: SyncOnceCell::new(),
-            next_scratch:     AtomicU32::new(0),
-            unique_id:        Uuid::new_v4(),
+            scratches_path:     options.scratches_dir.clone(),
+            output_cache_path:  options.output_cache_dir.clone(),
+            durability:         options.durability,
+            scratches_dir:      SyncOnceCell::new(),
+            action_cache_dir:   SyncOnceCell::new(),
+            output_cache_dir:   SyncOnceCell::new(),
+            action_records_dir: SyncOnceCell::new(),
+            dependencies_dir:   SyncOnceCell::new(),
+            test_results_dir:   SyncOnceCell::new(),
+            input_digests_dir:  SyncOnceCell::new(),
+            journal_dir:        SyncOnceCell::new(),
+            failure_reports_dir: SyncOnceCell::new(),
+            journal_log:        SyncOnceCell::new(),
+            next_scratch:       AtomicU32::new(0),
+            output_cache_hits:  AtomicU64::new(0),
+            unique_id:          Uuid::new_v4(),
         };
 
         Ok(this)
@@ -108,7 +432,11 @@ impl State
     /// A scratch file is a temporary file for use while building.
     fn scratches_dir(&self) -> io::Result<BorrowedFd>
     {
-        self.ensure_open_dir_once(&self.scratches_dir, SCRATCHES_DIR)
+        let cell = &self.scratches_dir;
+        match &self.scratches_path {
+            None => self.ensure_open_dir_once(cell, SCRATCHES_DIR),
+            Some(path) => ensure_open_dir_at_once(cell, None, path),
+        }
     }
 
     /// Generate a unique name for a scratch file.
@@ -119,15 +447,94 @@ impl State
         CString::new(name).unwrap()
     }
 
+    /// Remove the scratch files created through this handle.
+    ///
+    /// Scratch files are normally left behind for inspection.
+    /// This is useful when a build is cancelled,
+    /// because then the scratch files are of no interest.
+    /// Scratch files of other Snowflake instances are left alone.
+    pub fn remove_scratches(&self) -> io::Result<()>
+    {
+        let scratches_dir = Some(self.scratches_dir()?);
+        let flags = O_DIRECTORY | O_RDONLY;
+        let dir = openat(scratches_dir, cstr!(b"."), flags, 0)?;
+        let prefix = format!("{}-", self.unique_id);
+        for name in read_entries(dir.as_fd())? {
+            if name.as_bytes().starts_with(prefix.as_bytes()) {
+                remove_all_at(scratches_dir, &name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove everything in part of the state directory.
+    ///
+    /// Returns how many bytes of disk space were freed.
+    /// This must not be called while a build is in progress.
+    pub fn clean(&self, scope: CleanScope) -> io::Result<u64>
+    {
+        let dir = match scope {
+            CleanScope::Scratches => self.scratches_dir()?,
+            CleanScope::ActionCache => {
+                self.clear_journal()?;
+                self.action_cache_dir()?
+            },
+            CleanScope::OutputCache => self.output_cache_dir()?,
+        };
+
+        let flags = O_DIRECTORY | O_RDONLY;
+        let dir = openat(Some(dir), cstr!(b"."), flags, 0)?;
+        let mut freed = 0;
+        for name in read_entries(dir.as_fd())? {
+            freed += disk_usage_at(Some(dir.as_fd()), &name)?;
+            remove_all_at(Some(dir.as_fd()), &name)?;
+        }
+
+        Ok(freed)
+    }
+
     /// Create and open a new scratch directory.
     ///
     /// The scratch directory starts out empty.
     pub fn new_scratch_dir(&self) -> io::Result<OwnedFd>
+    {
+        self.new_named_scratch_dir().map(|(dir, _)| dir)
+    }
+
+    /// Create and open a new scratch directory, and return its name.
+    ///
+    /// Unlike the directories created with [`new_scratch_dir`],
+    /// which are left behind for inspection,
+    /// these can be removed with [`remove_scratch`] when done.
+    ///
+    /// [`new_scratch_dir`]: `Self::new_scratch_dir`
+    /// [`remove_scratch`]: `Self::remove_scratch`
+    pub fn new_named_scratch_dir(&self) -> io::Result<(OwnedFd, CString)>
     {
         let scratches_dir = self.scratches_dir()?;
         let path = self.fresh_scratch();
         mkdirat(Some(scratches_dir), &path, 0o755)?;
-        openat(Some(scratches_dir), &path, O_DIRECTORY | O_PATH, 0)
+        let dir = openat(Some(scratches_dir), &path, O_DIRECTORY | O_PATH, 0)?;
+        Ok((dir, path))
+    }
+
+    /// Remove a scratch file or directory with the given name,
+    /// along with everything in it.
+    pub fn remove_scratch(&self, name: &CStr) -> io::Result<()>
+    {
+        remove_all_at(Some(self.scratches_dir()?), name)
+    }
+
+    /// Create an anonymous scratch file.
+    ///
+    /// The file is created with `O_TMPFILE` in the scratches directory,
+    /// so it can later be linked with [`new_scratch_link`].
+    ///
+    /// [`new_scratch_link`]: `Self::new_scratch_link`
+    pub fn new_scratch_file(&self) -> io::Result<OwnedFd>
+    {
+        let scratches_dir = self.scratches_dir()?;
+        openat(Some(scratches_dir), cstr!(b"."), O_TMPFILE | O_RDWR, 0o644)
     }
 
     /// Link a file in the scratches directory.
@@ -157,6 +564,9 @@ impl State
     ///
     /// The entry is stored at the given action hash.
     /// If the entry already exists, nothing is changed.
+    /// Either way, the insertion is recorded in the [journal].
+    ///
+    /// [journal]: `Self::journal`
     pub fn cache_action(&self, hash: Hash, entry: &ActionCacheEntry)
         -> io::Result<()>
     {
@@ -170,6 +580,9 @@ impl State
         let mut file = File::from(file);
         serde_json::to_writer(&mut file, entry)?;
         file.flush()?;
+        if self.durability != Durability::None {
+            fsync(file.as_fd())?;
+        }
 
         // Create the file in the action cache.
         linkat(
@@ -177,6 +590,10 @@ impl State
             Some(cache), &CString::new(hash.to_string()).unwrap(),
             AT_SYMLINK_FOLLOW,
         ).or_else(ok_if_already_exists)?;
+        self.sync_after_rename(cache)?;
+
+        let record = JournalRecord::now(hash, entry.outputs.clone());
+        self.journal_record(&record)?;
 
         Ok(())
     }
@@ -185,39 +602,68 @@ impl State
     ///
     /// If there is no entry for the given action,
     /// this method returns [`None`].
+    /// If the output cache is shared, this method also returns [`None`]
+    /// when the build log or any of the outputs of the entry
+    /// were removed from the output cache.
     pub fn cached_action(&self, hash: Hash)
         -> io::Result<Option<ActionCacheEntry>>
     {
         let cache = self.action_cache_dir()?;
         let pathname = &CString::new(hash.to_string()).unwrap();
-        match openat(Some(cache), pathname, O_RDONLY, 0) {
-            Ok(file) => {
-                let file = File::from(file);
-                let file = BufReader::new(file);
-                let entry = serde_json::from_reader(file)?;
-                Ok(Some(entry: ActionCacheEntry))
-            },
-            Err(err) if err.kind() == NotFound => Ok(None),
-            Err(err) => Err(err),
+        let entry: ActionCacheEntry =
+            match openat(Some(cache), pathname, O_RDONLY, 0) {
+                Ok(file) => {
+                    let file = File::from(file);
+                    let file = BufReader::new(file);
+                    serde_json::from_reader(file)?
+                },
+                Err(err) if err.kind() == NotFound => return Ok(None),
+                Err(err) => return Err(err),
+            };
+
+        if self.output_cache_path.is_some() {
+            let hashes = [entry.build_log].into_iter()
+                .chain(entry.outputs.iter().flatten().copied());
+            for hash in hashes {
+                let (dirfd, path) = self.cached_output(hash)?;
+                match fstatat(Some(dirfd), &path, AT_SYMLINK_NOFOLLOW) {
+                    Ok(_) => { },
+                    Err(err) if err.kind() == NotFound => return Ok(None),
+                    Err(err) => return Err(err),
+                }
+            }
         }
+
+        Ok(Some(entry))
     }
 
     /// Handle to the output cache.
     fn output_cache_dir(&self) -> io::Result<BorrowedFd>
     {
-        self.ensure_open_dir_once(&self.output_cache_dir, OUTPUT_CACHE_DIR)
+        let cell = &self.output_cache_dir;
+        match &self.output_cache_path {
+            None => self.ensure_open_dir_once(cell, OUTPUT_CACHE_DIR),
+            Some(path) => ensure_open_dir_at_once(cell, None, path),
+        }
     }
 
     /// Move a file to the output cache.
     ///
-    /// This method computes the hash of the file
+    /// This method first normalizes the metadata of the file,
+    /// so that the cache does not depend on permissions and times.
+    /// Files remain executable only if `executable` is set.
+    /// Then it computes the hash of the file
     /// and checks that it qualifies for caching.
     /// Then it renames the file so it is in the cache.
     /// If an equivalent file was already cached, the file is not renamed.
-    pub fn cache_output(&self, dirfd: Option<BorrowedFd>, pathname: &CStr)
-        -> Result<Hash, CacheOutputError>
+    pub fn cache_output(
+        &self,
+        dirfd: Option<BorrowedFd>,
+        pathname: &CStr,
+        executable: bool,
+    ) -> Result<Hash, CacheOutputError>
     {
-        self.cache_output_impl(dirfd, pathname)
+        self.cache_output_impl(dirfd, pathname, executable)
     }
 
     /// Insert a build log into the output cache.
@@ -235,14 +681,35 @@ impl State
 
         drop(build_log);
 
-        match self.cache_output(Some(scratches_dir), &build_log_path) {
+        use CacheOutputError as E;
+        match self.cache_output(Some(scratches_dir), &build_log_path, false) {
             Ok(hash) => Ok(hash),
-            Err(CacheOutputError::Io(err)) => Err(err),
-            Err(CacheOutputError::Output(err)) =>
+            Err(E::Io(err) | E::Transient(err)) => Err(err),
+            Err(err @ (E::Missing | E::Output(_))) =>
                 panic!("Build logs should always qualify for caching: {err}"),
         }
     }
 
+    /// Insert a regular file with the given contents into the output cache.
+    ///
+    /// The file is written to a scratch file first,
+    /// which is then inserted like a build log.
+    pub fn cache_contents(&self, contents: &[u8]) -> io::Result<Hash>
+    {
+        let mut file = File::from(self.new_scratch_file()?);
+        file.write_all(contents)?;
+        self.cache_build_log(file.into())
+    }
+
+    /// The number of outputs that were already in the output cache
+    /// when they were inserted, since the state directory was opened.
+    ///
+    /// This includes build logs.
+    pub fn output_cache_hits(&self) -> u64
+    {
+        self.output_cache_hits.load(SeqCst)
+    }
+
     /// Obtain the path to a cached output.
     ///
     /// Returns the file descriptor for the output cache
@@ -261,6 +728,188 @@ impl State
         Ok((dirfd, path))
     }
 
+    /// Handle to the action records directory.
+    fn action_records_dir(&self) -> io::Result<BorrowedFd>
+    {
+        self.ensure_open_dir_once(&self.action_records_dir, ACTION_RECORDS_DIR)
+    }
+
+    /// Replace the action record for an action.
+    pub fn record_action(&self, label: &ActionLabel, record: &ActionRecord)
+        -> io::Result<()>
+    {
+        let records = self.action_records_dir()?;
+        self.replace_json_file(records, &label_to_path(label), record)
+    }
+
+    /// Read the action record for an action.
+    ///
+    /// If the action was never attempted to be built,
+    /// this method returns [`None`].
+    pub fn recorded_action(&self, label: &ActionLabel)
+        -> io::Result<Option<ActionRecord>>
+    {
+        let records = self.action_records_dir()?;
+        read_json_file(records, &label_to_path(label))
+    }
+
+    /// Handle to the dependencies directory.
+    fn dependencies_dir(&self) -> io::Result<BorrowedFd>
+    {
+        self.ensure_open_dir_once(&self.dependencies_dir, DEPENDENCIES_DIR)
+    }
+
+    /// Replace the dependencies discovered for an action.
+    ///
+    /// The dependencies are stored at the given action hash,
+    /// which does not include the contents of [partial inputs].
+    ///
+    /// [partial inputs]: `crate::action::Action::partial_inputs`
+    pub fn record_dependencies(&self, hash: Hash, dependencies: &[Dependency])
+        -> io::Result<()>
+    {
+        let dir = self.dependencies_dir()?;
+        self.replace_json_file(dir, &hash_to_path(&hash), dependencies)
+    }
+
+    /// Read the dependencies discovered for an action.
+    ///
+    /// If the action was never performed,
+    /// this method returns [`None`].
+    pub fn discovered_dependencies(&self, hash: Hash)
+        -> io::Result<Option<Vec<Dependency>>>
+    {
+        let dir = self.dependencies_dir()?;
+        read_json_file(dir, &hash_to_path(&hash))
+    }
+
+    /// Handle to the test results directory.
+    fn test_results_dir(&self) -> io::Result<BorrowedFd>
+    {
+        self.ensure_open_dir_once(&self.test_results_dir, TEST_RESULTS_DIR)
+    }
+
+    /// Replace the test result for a test.
+    pub fn record_test_result(&self, hash: Hash, result: &TestResult)
+        -> io::Result<()>
+    {
+        let dir = self.test_results_dir()?;
+        self.replace_json_file(dir, &hash_to_path(&hash), result)
+    }
+
+    /// Read the test result for a test.
+    ///
+    /// If the test was never performed,
+    /// this method returns [`None`].
+    pub fn recorded_test_result(&self, hash: Hash)
+        -> io::Result<Option<TestResult>>
+    {
+        let dir = self.test_results_dir()?;
+        read_json_file(dir, &hash_to_path(&hash))
+    }
+
+    /// Handle to the input digests directory.
+    fn input_digests_dir(&self) -> io::Result<BorrowedFd>
+    {
+        self.ensure_open_dir_once(&self.input_digests_dir, INPUT_DIGESTS_DIR)
+    }
+
+    /// Replace the input digest for a static file.
+    ///
+    /// The path is that of the file relative to the source root.
+    pub fn record_input_digest(&self, path: &CStr, digest: &InputDigest)
+        -> io::Result<()>
+    {
+        let dir = self.input_digests_dir()?;
+        self.replace_json_file(dir, &input_path_to_path(path), digest)
+    }
+
+    /// Read the input digest for a static file.
+    ///
+    /// If the file was never hashed, this method returns [`None`].
+    /// The caller must check that the digest is still fresh.
+    pub fn recorded_input_digest(&self, path: &CStr)
+        -> io::Result<Option<InputDigest>>
+    {
+        let dir = self.input_digests_dir()?;
+        read_json_file(dir, &input_path_to_path(path))
+    }
+
+    /// Handle to the failure reports directory.
+    fn failure_reports_dir(&self) -> io::Result<BorrowedFd>
+    {
+        let cell = &self.failure_reports_dir;
+        self.ensure_open_dir_once(cell, FAILURE_REPORTS_DIR)
+    }
+
+    /// Create an empty failure report directory for an action.
+    ///
+    /// Only the most recent failure report of each action is kept,
+    /// so any previous failure report of the action is removed.
+    /// Returns a handle to the directory and its absolute path.
+    pub fn new_failure_report(&self, label: &ActionLabel)
+        -> io::Result<(OwnedFd, CString)>
+    {
+        let dir = self.failure_reports_dir()?;
+        let path = label_to_path(label);
+        match remove_all_at(Some(dir), &path) {
+            Err(err) if err.kind() == NotFound => { },
+            result => result?,
+        }
+        mkdirat(Some(dir), &path, 0o755)?;
+        let report = openat(Some(dir), &path, O_DIRECTORY | O_PATH, 0)?;
+        let report_path = readlink(&magic_link(report.as_fd()))?;
+        Ok((report, report_path))
+    }
+
+    /// Atomically replace a file with the JSON encoding of a value.
+    fn replace_json_file<T>(&self, dirfd: BorrowedFd, path: &CStr, value: &T)
+        -> io::Result<()>
+        where T: Serialize + ?Sized
+    {
+        // Write the value to a temporary file.
+        let temporary = self.fresh_scratch();
+        let flags = O_CREAT | O_EXCL | O_WRONLY;
+        let file = openat(Some(dirfd), &temporary, flags, 0o644)?;
+        let mut file = File::from(file);
+        serde_json::to_writer(&mut file, value)?;
+        file.flush()?;
+        self.sync_before_rename(Some(dirfd), &temporary)?;
+
+        // Atomically replace the previous file, if any.
+        renameat2(Some(dirfd), &temporary, Some(dirfd), path, 0)?;
+        self.sync_after_rename(dirfd)?;
+
+        Ok(())
+    }
+
+    /// Flush a file to disk before it is renamed into place,
+    /// if the durability calls for that.
+    ///
+    /// Directories are flushed recursively.
+    fn sync_before_rename(&self, dirfd: Option<BorrowedFd>, path: &CStr)
+        -> io::Result<()>
+    {
+        match self.durability {
+            Durability::None => Ok(()),
+            Durability::Flush | Durability::Full => fsync_all_at(dirfd, path),
+        }
+    }
+
+    /// Flush a directory to disk after a file was renamed into it,
+    /// if the durability calls for that.
+    fn sync_after_rename(&self, dirfd: BorrowedFd) -> io::Result<()>
+    {
+        match self.durability {
+            Durability::None | Durability::Flush => Ok(()),
+            Durability::Full => {
+                let flags = O_DIRECTORY | O_RDONLY;
+                let dir = openat(Some(dirfd), cstr!(b"."), flags, 0)?;
+                fsync(dir.as_fd())
+            },
+        }
+    }
+
     /// Ensure that a directory exists and open it.
     fn ensure_open_dir_once<'a>(
         &self,
@@ -268,13 +917,7 @@ impl State
         path: &CStr,
     ) -> io::Result<BorrowedFd<'a>>
     {
-        let owned_fd = cell.get_or_try_init(|| {
-            let dirfd = Some(self.state_dir.as_fd());
-            mkdirat(dirfd, path, 0o755)
-                .or_else(ok_if_already_exists)?;
-            openat(dirfd, path, O_DIRECTORY | O_PATH, 0)
-        })?;
-        Ok(owned_fd.as_fd())
+        ensure_open_dir_at_once(cell, Some(self.state_dir.as_fd()), path)
     }
 }
 
@@ -286,12 +929,59 @@ impl AsFd for State
     }
 }
 
+/// Ensure that a directory exists and open it, relative to `dirfd`.
+fn ensure_open_dir_at_once<'a>(
+    cell:  &'a SyncOnceCell<OwnedFd>,
+    dirfd: Option<BorrowedFd>,
+    path:  &CStr,
+) -> io::Result<BorrowedFd<'a>>
+{
+    let owned_fd = cell.get_or_try_init(|| {
+        mkdirat(dirfd, path, 0o755)
+            .or_else(ok_if_already_exists)?;
+        openat(dirfd, path, O_DIRECTORY | O_PATH, 0)
+    })?;
+    Ok(owned_fd.as_fd())
+}
+
 fn hash_to_path(hash: &Hash) -> CString
 {
     CString::new(hash.to_string())
         .expect("Hash as Display should not write nul")
 }
 
+fn input_path_to_path(path: &CStr) -> CString
+{
+    // Paths may contain slashes, so they cannot be used directly.
+    let mut h = Blake3::new();
+    h.put_cstr(path);
+    hash_to_path(&h.finalize())
+}
+
+fn label_to_path(label: &ActionLabel) -> CString
+{
+    CString::new(label.action.to_string())
+        .expect("usize as Display should not write nul")
+}
+
+/// Read a file written by [`State::replace_json_file`].
+///
+/// If there is no such file, this function returns [`None`].
+fn read_json_file<T>(dirfd: BorrowedFd, path: &CStr) -> io::Result<Option<T>>
+    where T: for<'de> Deserialize<'de>
+{
+    match openat(Some(dirfd), path, O_RDONLY, 0) {
+        Ok(file) => {
+            let file = File::from(file);
+            let file = BufReader::new(file);
+            let value = serde_json::from_reader(file)?;
+            Ok(Some(value))
+        },
+        Err(err) if err.kind() == NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
 fn ok_if_already_exists(err: io::Error) -> io::Result<()>
 {
     if err.kind() == AlreadyExists {
@@ -306,7 +996,12 @@ mod tests
 {
     use {
         super::*,
-        os_ext::{O_CREAT, O_WRONLY, cstr, cstring, mkdtemp, readlink},
+        os_ext::{
+            O_CREAT, O_WRONLY, S_IFREG,
+            cstr, cstr::CStrExt, cstring, fstatat, mkdtemp, mknodat, readlink,
+            symlinkat,
+        },
+        snowflake_util::hash::hash_file_at,
         std::{os::unix::io::AsFd},
     };
 
@@ -337,6 +1032,193 @@ mod tests
         ).unwrap();
     }
 
+    #[test]
+    fn remove_scratches()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+
+        // Create scratch files, and one of another instance.
+        let scratch = state.new_scratch_dir().unwrap();
+        let scratch = Some(scratch.as_fd());
+        mkdirat(scratch, cstr!(b"dir"), 0o755).unwrap();
+        let other = State::open(&path).unwrap();
+        other.new_scratch_dir().unwrap();
+
+        // Only the scratch files of this instance are removed.
+        state.remove_scratches().unwrap();
+        let scratches = path.join(SCRATCHES_DIR);
+        let scratches = open(&scratches, O_DIRECTORY | O_RDONLY, 0).unwrap();
+        let entries = read_entries(scratches.as_fd()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].to_bytes()
+            .starts_with(other.unique_id.to_string().as_bytes()));
+    }
+
+    #[test]
+    fn separate_scratches_dir()
+    {
+        // Create state directory and scratches directory.
+        // The scratches directory is on a different file system if
+        // /dev/shm is mounted, which exercises copying into the cache.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let scratches = cstring!(b"/dev/shm/snowflake-test-XXXXXX");
+        let scratches = mkdtemp(scratches).unwrap();
+        let scratches = scratches.join(cstr!(b"scratches"));
+        let options = OpenOptions{
+            scratches_dir: Some(scratches.clone()),
+            ..OpenOptions::default()
+        };
+        let state = State::open_with_options(&path, &options).unwrap();
+
+        // Create outputs in a scratch directory.
+        let scratch = state.new_scratch_dir().unwrap();
+        let scratch = Some(scratch.as_fd());
+        let magic_link = magic_link(scratch.unwrap());
+        let scratch_path = readlink(&magic_link).unwrap();
+        assert!(scratch_path.to_bytes().starts_with(scratches.to_bytes()));
+        mknodat(scratch, cstr!(b"file"),     S_IFREG | 0o644, 0).unwrap();
+        mkdirat(scratch, cstr!(b"dir"),                0o755   ).unwrap();
+        mknodat(scratch, cstr!(b"dir/file"), S_IFREG | 0o755, 0).unwrap();
+
+        // Move the outputs to the cache.
+        for name in [cstr!(b"file"), cstr!(b"dir")] {
+            let expected = hash_file_at(scratch, name).unwrap();
+            let actual = state.cache_output(scratch, name, true).unwrap();
+            assert_eq!(actual, expected);
+
+            // The original output is gone, as after a rename.
+            let statbuf = fstatat(scratch, name, 0);
+            assert_eq!(statbuf.unwrap_err().kind(), NotFound);
+
+            // The output is in the cache.
+            let (dirfd, path) = state.cached_output(actual).unwrap();
+            let cached = hash_file_at(Some(dirfd), &path).unwrap();
+            assert_eq!(cached, expected);
+        }
+    }
+
+    #[test]
+    fn shared_output_cache()
+    {
+        // Create two state directories that share an output cache.
+        let output_cache = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX"));
+        let output_cache = output_cache.unwrap().join(cstr!(b"output-cache"));
+        let options = OpenOptions{
+            output_cache_dir: Some(output_cache.clone()),
+            ..OpenOptions::default()
+        };
+        let states = [(), ()].map(|()| {
+            let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX"));
+            State::open_with_options(&path.unwrap(), &options).unwrap()
+        });
+
+        // Cache the same output through both state directories.
+        let mut hashes = Vec::new();
+        for state in &states {
+            let scratch = state.new_scratch_dir().unwrap();
+            let scratch = Some(scratch.as_fd());
+            mknodat(scratch, cstr!(b"file"), S_IFREG | 0o644, 0).unwrap();
+            hashes.push(state.cache_output(scratch, cstr!(b"file"), false)
+                .unwrap());
+        }
+        assert_eq!(hashes[0], hashes[1]);
+        assert_eq!(states[0].output_cache_hits(), 0);
+        assert_eq!(states[1].output_cache_hits(), 1);
+
+        // The output is stored in the shared output cache.
+        let (dirfd, path) = states[0].cached_output(hashes[0]).unwrap();
+        let magic_link = magic_link(dirfd);
+        assert_eq!(readlink(&magic_link).unwrap(), output_cache);
+        fstatat(Some(dirfd), &path, 0).unwrap();
+
+        // The action cache is not shared.
+        let entry = ActionCacheEntry{
+            build_log: hashes[0],
+            build_log_ansi: false,
+            outputs: vec![Some(hashes[0])],
+            warnings: false,
+        };
+        states[0].cache_action(Hash([0; 32]), &entry).unwrap();
+        assert!(states[0].cached_action(Hash([0; 32])).unwrap().is_some());
+        assert!(states[1].cached_action(Hash([0; 32])).unwrap().is_none());
+
+        // Entries that refer to removed outputs are ignored.
+        let flags = O_DIRECTORY | O_RDONLY;
+        let dir = openat(Some(dirfd), cstr!(b"."), flags, 0).unwrap();
+        remove_all_at(Some(dir.as_fd()), &path).unwrap();
+        assert!(states[0].cached_action(Hash([0; 32])).unwrap().is_none());
+    }
+
+    #[test]
+    fn durability()
+    {
+        for durability in [Durability::None, Durability::Flush,
+                           Durability::Full] {
+            // Create state directory.
+            let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX"));
+            let options = OpenOptions{durability, ..OpenOptions::default()};
+            let state = State::open_with_options(&path.unwrap(), &options)
+                .unwrap();
+
+            // Create outputs in a scratch directory.
+            let scratch = state.new_scratch_dir().unwrap();
+            let scratch = Some(scratch.as_fd());
+            mkdirat(scratch, cstr!(b"dir"), 0o755).unwrap();
+            mknodat(scratch, cstr!(b"dir/file"), S_IFREG | 0o644, 0).unwrap();
+            symlinkat(cstr!(b"file"), scratch, cstr!(b"dir/link")).unwrap();
+
+            // Every kind of entry can be inserted and retrieved.
+            let hash = state.cache_output(scratch, cstr!(b"dir"), false)
+                .unwrap();
+            state.cached_output(hash).unwrap();
+            let entry = ActionCacheEntry{
+                build_log: hash,
+                build_log_ansi: false,
+                outputs: vec![Some(hash)],
+                warnings: false,
+            };
+            state.cache_action(hash, &entry).unwrap();
+            assert!(state.cached_action(hash).unwrap().is_some());
+            state.record_dependencies(hash, &[]).unwrap();
+            assert!(state.discovered_dependencies(hash).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn clean()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+
+        // Fill the scratches directory and the caches.
+        let scratch = state.new_scratch_dir().unwrap();
+        let scratch = Some(scratch.as_fd());
+        mknodat(scratch, cstr!(b"file"), S_IFREG | 0o644, 0).unwrap();
+        let hash = state.cache_contents(b"contents").unwrap();
+        let entry = ActionCacheEntry{
+            build_log: hash,
+            build_log_ansi: false,
+            outputs: vec![],
+            warnings: false,
+        };
+        state.cache_action(Hash([0; 32]), &entry).unwrap();
+
+        // Each scope frees the space taken up by its directory.
+        for scope in [CleanScope::Scratches, CleanScope::ActionCache,
+                      CleanScope::OutputCache] {
+            assert_ne!(state.clean(scope).unwrap(), 0);
+            assert_eq!(state.clean(scope).unwrap(), 0);
+        }
+        assert!(state.cached_action(Hash([0; 32])).unwrap().is_none());
+        assert!(state.journal().unwrap().is_empty());
+        let (dirfd, path) = state.cached_output(hash).unwrap();
+        let statbuf = fstatat(Some(dirfd), &path, 0);
+        assert_eq!(statbuf.unwrap_err().kind(), NotFound);
+    }
+
     #[test]
     fn action_cache()
     {
@@ -348,7 +1230,8 @@ mod tests
         let hash = Hash([0; 32]);
         let entry = ActionCacheEntry{
             build_log: Hash([1; 32]),
-            outputs: vec![Hash([2; 32]), Hash([3; 32])],
+            build_log_ansi: false,
+            outputs: vec![Some(Hash([2; 32])), None],
             warnings: true,
         };
 
@@ -362,4 +1245,69 @@ mod tests
         // Retrieving a non-existent action should return None.
         assert!(state.cached_action(Hash([4; 32])).unwrap().is_none());
     }
+
+    #[test]
+    fn action_records()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+
+        let label = ActionLabel{action: 0};
+        let record = |command| ActionRecord{
+            command: Hash([command; 32]),
+            inputs: vec![],
+            command_elements: vec![],
+        };
+        let (record_0, record_1) = (record(0), record(1));
+
+        // Unrecorded actions have no record.
+        assert_eq!(state.recorded_action(&label).unwrap(), None);
+
+        // Recording an action replaces the previous record.
+        state.record_action(&label, &record_0).unwrap();
+        assert_eq!(state.recorded_action(&label).unwrap(), Some(record_0));
+        state.record_action(&label, &record_1).unwrap();
+        assert_eq!(state.recorded_action(&label).unwrap(), Some(record_1));
+
+        // Other actions are unaffected.
+        let other = ActionLabel{action: 1};
+        assert_eq!(state.recorded_action(&other).unwrap(), None);
+    }
+
+    #[test]
+    fn test_results()
+    {
+        // Create state directory.
+        let path = mkdtemp(cstring!(b"/tmp/snowflake-test-XXXXXX")).unwrap();
+        let state = State::open(&path).unwrap();
+
+        let hash = Hash([0; 32]);
+        let result = TestResult{
+            status: TestStatus::Failed,
+            build_log: Hash([1; 32]),
+            build_log_ansi: false,
+            exit_code: Some(1),
+            error: Some("Action failed".into()),
+            duration: Duration::from_millis(1500),
+        };
+
+        // Tests that never ran have no result.
+        assert_eq!(state.recorded_test_result(hash).unwrap(), None);
+
+        // Recorded results can be read back, including failures.
+        state.record_test_result(hash, &result).unwrap();
+        let recorded = state.recorded_test_result(hash).unwrap();
+        assert_eq!(recorded, Some(result));
+    }
+
+    #[test]
+    fn test_status()
+    {
+        assert_eq!(TestStatus::from_attempts(1, 0), TestStatus::Passed);
+        assert_eq!(TestStatus::from_attempts(3, 0), TestStatus::Passed);
+        assert_eq!(TestStatus::from_attempts(2, 1), TestStatus::Flaky);
+        assert_eq!(TestStatus::from_attempts(1, 2), TestStatus::Flaky);
+        assert_eq!(TestStatus::from_attempts(0, 1), TestStatus::Failed);
+    }
 }
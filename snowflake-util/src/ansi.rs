@@ -0,0 +1,92 @@
+//! Working with ANSI escape sequences in build logs.
+
+use std::borrow::Cow;
+
+const ESC: u8 = 0x1B;
+const BEL: u8 = 0x07;
+
+/// Whether the text contains any ANSI escape sequences.
+pub fn contains_ansi(text: &[u8]) -> bool
+{
+    text.contains(&ESC)
+}
+
+/// Remove all ANSI escape sequences from the text.
+///
+/// Control sequences (such as those that set colors),
+/// operating system commands (such as those that set the window title),
+/// and other two-byte escape sequences are recognized.
+/// An incomplete escape sequence at the end of the text is removed as well.
+pub fn strip_ansi(text: &[u8]) -> Cow<[u8]>
+{
+    if !contains_ansi(text) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        if text[i] != ESC {
+            result.push(text[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        match text.get(i) {
+            // Control sequence: parameter bytes, intermediate bytes,
+            // and a final byte in the range 0x40–0x7E.
+            Some(b'[') => {
+                i += 1;
+                while i < text.len() && !(0x40 ..= 0x7E).contains(&text[i]) {
+                    i += 1;
+                }
+                i += 1;
+            },
+            // Operating system command: terminated by BEL or ESC \.
+            Some(b']') => {
+                i += 1;
+                while i < text.len() && text[i] != BEL && text[i] != ESC {
+                    i += 1;
+                }
+                if text.get(i) == Some(&ESC) {
+                    i += 1;
+                }
+                i += 1;
+            },
+            // Any other escape sequence consists of a single byte.
+            Some(_) => i += 1,
+            None => { },
+        }
+    }
+
+    Cow::Owned(result)
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn examples()
+    {
+        let examples: &[(&[u8], &[u8])] = &[
+            (b"plain", b"plain"),
+            (b"\x1b[1;31merror:\x1b[0m oops", b"error: oops"),
+            (b"\x1b[K\x1b[?25lhidden", b"hidden"),
+            (b"\x1b]0;title\x07text", b"text"),
+            (b"\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\", b"link"),
+            (b"\x1bcreset", b"reset"),
+            (b"truncated\x1b[1;3", b"truncated"),
+            (b"truncated\x1b", b"truncated"),
+        ];
+        for &(text, expected) in examples {
+            let actual = strip_ansi(text);
+            assert_eq!(
+                actual.as_ref(), expected,
+                "{:?}", String::from_utf8_lossy(text),
+            );
+            assert_eq!(contains_ansi(text), text != expected);
+        }
+    }
+}
@@ -1,6 +1,14 @@
 //! Basenames of pathnames.
 
-use {std::{ffi::CStr, fmt, ops::Deref}, thiserror::Error};
+use {
+    std::{
+        ffi::{CStr, CString, OsString},
+        fmt,
+        ops::Deref,
+        os::unix::ffi::OsStringExt,
+    },
+    thiserror::Error,
+};
 
 /// Basename of a pathname.
 #[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -15,9 +23,24 @@ pub struct Basename<T>
 /// Returned when a basename could not be validated.
 ///
 /// See [`Basename::new`] for the restrictions on basenames.
-#[derive(Debug, Error)]
-#[error("Basename is empty, `.`, or `..`, or contains `/`")]
-pub struct BasenameError;
+/// The variants tell which restriction was violated,
+/// so that they can be reported to the user.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum BasenameError
+{
+    #[error("Basename is empty")]
+    Empty,
+
+    #[error("Basename is `.` or `..`")]
+    Dots,
+
+    #[error("Basename contains `/`")]
+    Slash,
+
+    #[error("Basename contains a nul byte")]
+    Nul,
+}
 
 impl<T> Basename<T>
     where T: AsRef<CStr>
@@ -26,22 +49,89 @@ impl<T> Basename<T>
     ///
     /// Returns an error if the basename is invalid.
     /// A basename is invalid if it is empty, `.`, or `..`, or contains `/`.
+    /// C strings cannot contain nul bytes; when converting from
+    /// other strings with [`TryFrom`], those are rejected too.
     pub fn new(inner: T) -> Result<Self, BasenameError>
     {
         let bytes = inner.as_ref().to_bytes();
 
-        if matches!(bytes, b"" | b"." | b"..") {
-            return Err(BasenameError);
+        if bytes.is_empty() {
+            return Err(BasenameError::Empty);
+        }
+
+        if matches!(bytes, b"." | b"..") {
+            return Err(BasenameError::Dots);
         }
 
         if bytes.contains(&b'/') {
-            return Err(BasenameError);
+            return Err(BasenameError::Slash);
         }
 
         Ok(Self{inner})
     }
 }
 
+impl<'a> TryFrom<&'a CStr> for Basename<&'a CStr>
+{
+    type Error = BasenameError;
+
+    fn try_from(value: &'a CStr) -> Result<Self, Self::Error>
+    {
+        Self::new(value)
+    }
+}
+
+impl TryFrom<CString> for Basename<CString>
+{
+    type Error = BasenameError;
+
+    fn try_from(value: CString) -> Result<Self, Self::Error>
+    {
+        Self::new(value)
+    }
+}
+
+impl TryFrom<Vec<u8>> for Basename<CString>
+{
+    type Error = BasenameError;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error>
+    {
+        let value = CString::new(value).map_err(|_| BasenameError::Nul)?;
+        Self::new(value)
+    }
+}
+
+impl TryFrom<&str> for Basename<CString>
+{
+    type Error = BasenameError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error>
+    {
+        Self::try_from(value.as_bytes().to_vec())
+    }
+}
+
+impl TryFrom<String> for Basename<CString>
+{
+    type Error = BasenameError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error>
+    {
+        Self::try_from(value.into_bytes())
+    }
+}
+
+impl TryFrom<OsString> for Basename<CString>
+{
+    type Error = BasenameError;
+
+    fn try_from(value: OsString) -> Result<Self, Self::Error>
+    {
+        Self::try_from(value.into_vec())
+    }
+}
+
 impl<T> Deref for Basename<T>
     where T: ?Sized
 {
@@ -70,23 +160,45 @@ mod tests
     #[test]
     fn examples()
     {
+        use BasenameError::*;
+
         let examples = [
             // Valid basenames.
-            (cstr!(b"hello"), true),
-            (cstr!(b"message.txt"), true),
-            (cstr!(b"Hello, world!"), true),
+            (cstr!(b"hello"), None),
+            (cstr!(b"message.txt"), None),
+            (cstr!(b"Hello, world!"), None),
+            (cstr!(b"..."), None),
 
             // Invalid basenames.
-            (cstr!(b""), false),
-            (cstr!(b"."), false),
-            (cstr!(b".."), false),
-            (cstr!(b"/"), false),
-            (cstr!(b"/etc/passwd"), false),
-            (cstr!(b"common/nix/nixpkgs"), false),
+            (cstr!(b""), Some(Empty)),
+            (cstr!(b"."), Some(Dots)),
+            (cstr!(b".."), Some(Dots)),
+            (cstr!(b"/"), Some(Slash)),
+            (cstr!(b"/etc/passwd"), Some(Slash)),
+            (cstr!(b"common/nix/nixpkgs"), Some(Slash)),
         ];
 
-        for (cstr, valid) in examples {
-            assert!(Basename::new(cstr).is_ok() == valid, "{cstr:?}");
+        for (cstr, error) in examples {
+            assert_eq!(Basename::new(cstr).err(), error, "{cstr:?}");
         }
     }
+
+    #[test]
+    fn conversions()
+    {
+        let basename = Basename::try_from("main.o").unwrap();
+        assert_eq!(*basename, CString::new("main.o").unwrap());
+
+        let basename = Basename::try_from(OsString::from("main.o")).unwrap();
+        assert_eq!(*basename, CString::new("main.o").unwrap());
+
+        let basename = Basename::try_from(cstr!(b"main.o")).unwrap();
+        assert_eq!(*basename, cstr!(b"main.o"));
+
+        let result = Basename::try_from(String::from("main\0.o"));
+        assert_eq!(result.err(), Some(BasenameError::Nul));
+
+        let result = Basename::try_from("src/main.o");
+        assert_eq!(result.err(), Some(BasenameError::Slash));
+    }
 }
@@ -48,6 +48,19 @@ pub fn hash_file_at(dirfd: Option<BorrowedFd>, path: &CStr)
     hash_file_at_with(dirfd, path, |_| Ok(()))
 }
 
+/// Compute the hash of a regular file with the given contents.
+///
+/// This is what [`hash_file_at`] would return for such a file,
+/// but the file need not exist.
+pub fn hash_regular_file(contents: &[u8], executable: bool) -> Hash
+{
+    let mut blake3 = Blake3::new();
+    write_reg_header(&mut blake3, executable, contents.len() as u64)
+        .and_then(|()| blake3.write_all(contents))
+        .expect("Writing to a hasher should not fail");
+    blake3.finalize()
+}
+
 /// Like [`hash_file_at`], but with customizable extra checks.
 ///
 /// Hashing a file already stats every file to be hashed,
@@ -96,15 +109,9 @@ fn write_reg_at(
     statbuf: &stat,
 ) -> io::Result<()>
 {
-    // Write file type.
-    writer.write_all(&[FILE_TYPE_REG])?;
-
-    // Write whether file is executable.
+    // Write file metadata.
     let executable = statbuf.st_mode & S_IXUSR != 0;
-    writer.write_all(&[executable as u8])?;
-
-    // Write file size.
-    writer.write_all(&(statbuf.st_size as u64).to_le_bytes())?;
+    write_reg_header(writer, executable, statbuf.st_size as u64)?;
 
     // Write file contents.
     let file = openat(dirfd, path, O_NOFOLLOW | O_RDONLY, 0)?;
@@ -114,6 +121,20 @@ fn write_reg_at(
     Ok(())
 }
 
+/// Write the metadata of a regular file that precedes its contents.
+fn write_reg_header(writer: &mut impl Write, executable: bool, size: u64)
+    -> io::Result<()>
+{
+    // Write file type.
+    writer.write_all(&[FILE_TYPE_REG])?;
+
+    // Write whether file is executable.
+    writer.write_all(&[executable as u8])?;
+
+    // Write file size.
+    writer.write_all(&size.to_le_bytes())
+}
+
 /// Write a directory.
 fn write_dir_at(
     writer: &mut impl Write,
@@ -221,5 +242,9 @@ mod tests
 
         let hash = hash_file_at(None, path).unwrap();
         assert_eq!(hash, expected_hash);
+
+        let path = cstr!(b"testdata/hash_file_at/regular.txt");
+        let hash = hash_regular_file(b"Hello, world!\n", false);
+        assert_eq!(hash, hash_file_at(None, path).unwrap());
     }
 }
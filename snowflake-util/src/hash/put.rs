@@ -1,4 +1,7 @@
-use {super::{Blake3, Hash}, std::ffi::CStr};
+use {
+    super::{Blake3, Hash},
+    std::{ffi::CStr, os::unix::ffi::OsStrExt, path::Path},
+};
 
 /// Convenient methods for writing values.
 ///
@@ -54,6 +57,11 @@ impl Blake3
         self.update(value.to_bytes_with_nul())
     }
 
+    pub fn put_path(&mut self, value: &Path) -> &mut Self
+    {
+        self.put_bytes(value.as_os_str().as_bytes())
+    }
+
     pub fn put_slice<F, T>(&mut self, value: &[T], mut f: F) -> &mut Self
         where F: for<'a> FnMut(&'a mut Self, &T) -> &'a mut Self
     {
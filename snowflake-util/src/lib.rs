@@ -27,5 +27,7 @@ macro_rules! see_manual
     };
 }
 
+pub mod ansi;
 pub mod basename;
 pub mod hash;
+pub mod workspace_path;
@@ -0,0 +1,159 @@
+//! Pathnames relative to the workspace.
+
+use {
+    std::{
+        ffi::{CStr, CString},
+        fmt,
+        ops::Deref,
+    },
+    thiserror::Error,
+};
+
+/// Pathname relative to the workspace.
+///
+/// Unlike arbitrary pathnames, workspace paths cannot refer to files
+/// outside the workspace, and each file has exactly one workspace path.
+/// This rules out path traversal through inputs,
+/// and prevents the same file from being hashed under different paths.
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+#[repr(transparent)]
+pub struct WorkspacePath<T>
+    where T: ?Sized
+{
+    // INVARIANT: See the restrictions `new` imposes.
+    inner: T,
+}
+
+/// Returned when a workspace path could not be validated.
+///
+/// See [`WorkspacePath::new`] for the restrictions on workspace paths.
+#[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Eq, Error, PartialEq)]
+pub enum WorkspacePathError
+{
+    #[error("Workspace path is empty")]
+    Empty,
+
+    #[error("Workspace path is absolute")]
+    Absolute,
+
+    #[error("Workspace path has an empty component or a trailing `/`")]
+    EmptyComponent,
+
+    #[error("Workspace path has a `.` or `..` component")]
+    Dots,
+
+    #[error("Workspace path contains a nul byte")]
+    Nul,
+}
+
+impl<T> WorkspacePath<T>
+    where T: AsRef<CStr>
+{
+    /// Create a workspace path from a string.
+    ///
+    /// Returns an error if the workspace path is invalid.
+    /// A workspace path is invalid if it is empty or absolute,
+    /// or if it has a component that is empty, `.`, or `..`.
+    /// Empty components arise from consecutive or trailing slashes.
+    /// Paths are not normalized; such paths are rejected instead.
+    pub fn new(inner: T) -> Result<Self, WorkspacePathError>
+    {
+        let bytes = inner.as_ref().to_bytes();
+
+        if bytes.is_empty() {
+            return Err(WorkspacePathError::Empty);
+        }
+
+        if bytes.starts_with(b"/") {
+            return Err(WorkspacePathError::Absolute);
+        }
+
+        for component in bytes.split(|&b| b == b'/') {
+            match component {
+                b"" => return Err(WorkspacePathError::EmptyComponent),
+                b"." | b".." => return Err(WorkspacePathError::Dots),
+                _ => (),
+            }
+        }
+
+        Ok(Self{inner})
+    }
+}
+
+impl TryFrom<CString> for WorkspacePath<CString>
+{
+    type Error = WorkspacePathError;
+
+    fn try_from(value: CString) -> Result<Self, Self::Error>
+    {
+        Self::new(value)
+    }
+}
+
+impl TryFrom<&str> for WorkspacePath<CString>
+{
+    type Error = WorkspacePathError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error>
+    {
+        let value = CString::new(value)
+            .map_err(|_| WorkspacePathError::Nul)?;
+        Self::new(value)
+    }
+}
+
+impl<T> Deref for WorkspacePath<T>
+    where T: ?Sized
+{
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target
+    {
+        &self.inner
+    }
+}
+
+impl<T> fmt::Debug for WorkspacePath<T>
+    where T: fmt::Debug + ?Sized
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        <T as fmt::Debug>::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use {super::*, os_ext::cstr};
+
+    #[test]
+    fn examples()
+    {
+        use WorkspacePathError::*;
+
+        let examples = [
+            // Valid workspace paths.
+            (cstr!(b"README.md"), None),
+            (cstr!(b"snowflake-website/index.html"), None),
+            (cstr!(b"a/.hidden/..."), None),
+
+            // Invalid workspace paths.
+            (cstr!(b""), Some(Empty)),
+            (cstr!(b"/etc/passwd"), Some(Absolute)),
+            (cstr!(b"src//main.rs"), Some(EmptyComponent)),
+            (cstr!(b"src/"), Some(EmptyComponent)),
+            (cstr!(b"."), Some(Dots)),
+            (cstr!(b"./src"), Some(Dots)),
+            (cstr!(b"src/../../etc/passwd"), Some(Dots)),
+        ];
+
+        for (cstr, error) in examples {
+            assert_eq!(WorkspacePath::new(cstr).err(), error, "{cstr:?}");
+        }
+
+        let result = WorkspacePath::try_from("src\0/main.rs");
+        assert_eq!(result.err(), Some(Nul));
+    }
+}
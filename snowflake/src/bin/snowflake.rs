@@ -1,23 +1,330 @@
 #![feature(concat_bytes)]
+#![feature(exit_status_error)]
 #![feature(io_safety)]
 #![feature(let_chains)]
+#![feature(scoped_threads)]
 
 use {
-    os_ext::{O_DIRECTORY, O_PATH, cstr, cstring, mkdir, open},
+    os_ext::{O_DIRECTORY, O_PATH, O_RDONLY, cstr, cstring, mkdir, open, openat},
     regex::bytes::Regex,
     snowflake_actions::*,
-    snowflake_core::{action::*, drive::{self, drive}, label::*, state::State},
-    snowflake_util::basename::*,
+    snowflake_core::{
+        action::*,
+        cancel::{Cancel, received_signal},
+        drive::{
+            self, BuildEvent, Capacity, ChangedElement, ChangedInput,
+            DryRunOutcome, Outcome, PerformReason, ReplayError,
+            drive, dry_run,
+        },
+        executor::{Executor, LocalExecutor, RemoteExecutor, serve_connection},
+        label::*,
+        output_tree::assemble_output_tree,
+        profile::Profile,
+        state::{CleanScope, State, TestStatus},
+    },
+    snowflake_util::{ansi::strip_ansi, basename::*, workspace_path::*},
     std::{
-        ffi::CString,
-        io::ErrorKind::AlreadyExists,
+        borrow::Cow,
+        collections::HashMap,
+        ffi::{CStr, CString},
+        fs::File,
+        io::{self, ErrorKind::AlreadyExists, Read, Write},
+        net::{SocketAddr, TcpListener},
         os::unix::io::AsFd,
+        path::PathBuf,
+        process::exit,
+        sync::Mutex,
+        thread,
         time::Duration,
     },
 };
 
+/// What to do, as given on the command line.
+enum Command
+{
+    /// Build all actions.
+    Build
+    {
+        /// Report which actions would be performed, without performing them.
+        dry_run: bool,
+
+        /// Assemble the artifacts into this directory after building.
+        output_tree: Option<CString>,
+
+        /// Assemble only artifacts in these output groups, if any.
+        output_groups: Vec<String>,
+
+        /// Perform each action twice and compare the outputs.
+        check_determinism: bool,
+
+        /// Write timings of the build to this file.
+        profile: Option<PathBuf>,
+
+        /// Print the build log of this action while it is performed.
+        stream_logs: Option<ActionLabel>,
+
+        /// Perform actions on the build server at this address.
+        remote: Option<SocketAddr>,
+    },
+
+    /// Perform tests whose inputs changed and summarize the results.
+    Test
+    {
+        /// The tests to perform, or all tests if empty.
+        labels: Vec<ActionLabel>,
+
+        /// How many times to perform each test, to detect flaky tests.
+        runs_per_test: u32,
+
+        /// Print the build log of this test while it is performed.
+        stream_logs: Option<ActionLabel>,
+
+        /// Perform tests on the build server at this address.
+        remote: Option<SocketAddr>,
+    },
+
+    /// Print the build log of a cached action.
+    Log
+    {
+        label: ActionLabel,
+
+        /// Strip ANSI escape sequences from the build log.
+        no_color: bool,
+    },
+
+    /// Print a textual description of the action graph.
+    DumpGraph,
+
+    /// Explain why an action would be performed.
+    Explain
+    {
+        label: ActionLabel,
+    },
+
+    /// Remove parts of the state directory.
+    Clean
+    {
+        scopes: Vec<CleanScope>,
+    },
+
+    /// Start a shell in the environment of an action.
+    Replay
+    {
+        label: ActionLabel,
+    },
+
+    /// Perform actions on behalf of remote executors.
+    Serve
+    {
+        address: SocketAddr,
+    },
+}
+
+impl Command
+{
+    fn parse() -> Self
+    {
+        let mut arguments = std::env::args().skip(1).peekable();
+
+        if arguments.peek().map(String::as_str) == Some("test") {
+            arguments.next();
+            let mut labels = Vec::new();
+            let mut runs_per_test = 1;
+            let mut stream_logs = None;
+            let mut remote = None;
+            while let Some(argument) = arguments.next() {
+                match argument.as_str() {
+                    "--runs-per-test" => {
+                        let runs = arguments.next().map(|n| n.parse());
+                        let Some(Ok(runs @ 1 ..)) = runs
+                            else { usage(&argument) };
+                        runs_per_test = runs;
+                    },
+                    "--stream-logs" => {
+                        let Some(label) = arguments.next()
+                            else { usage(&argument) };
+                        stream_logs = Some(parse_label(&label));
+                    },
+                    "--remote" => {
+                        let Some(address) = arguments.next()
+                            else { usage(&argument) };
+                        remote = Some(parse_address(&address));
+                    },
+                    _ => labels.push(parse_label(&argument)),
+                }
+            }
+            return Self::Test{labels, runs_per_test, stream_logs, remote};
+        }
+
+        if arguments.peek().map(String::as_str) == Some("dump-graph") {
+            arguments.next();
+            if let Some(argument) = arguments.next() {
+                usage(&argument);
+            }
+            return Self::DumpGraph;
+        }
+
+        if arguments.peek().map(String::as_str) == Some("explain") {
+            arguments.next();
+            let Some(label) = arguments.next() else { usage("explain") };
+            if let Some(argument) = arguments.next() {
+                usage(&argument);
+            }
+            return Self::Explain{label: parse_label(&label)};
+        }
+
+        if arguments.peek().map(String::as_str) == Some("replay") {
+            arguments.next();
+            let Some(label) = arguments.next() else { usage("replay") };
+            if let Some(argument) = arguments.next() {
+                usage(&argument);
+            }
+            return Self::Replay{label: parse_label(&label)};
+        }
+
+        if arguments.peek().map(String::as_str) == Some("serve") {
+            arguments.next();
+            let Some(address) = arguments.next() else { usage("serve") };
+            if let Some(argument) = arguments.next() {
+                usage(&argument);
+            }
+            return Self::Serve{address: parse_address(&address)};
+        }
+
+        if arguments.peek().map(String::as_str) == Some("clean") {
+            arguments.next();
+            let mut scratches = false;
+            let mut action_cache = false;
+            let mut output_cache = false;
+            for argument in arguments {
+                match argument.as_str() {
+                    "--scratches" => scratches = true,
+                    "--action-cache" => action_cache = true,
+                    "--output-cache" => output_cache = true,
+                    "--all" => {
+                        scratches = true;
+                        action_cache = true;
+                        output_cache = true;
+                    },
+                    _ => usage(&argument),
+                }
+            }
+
+            // The action cache refers to outputs in the output cache.
+            action_cache |= output_cache;
+
+            let scopes: Vec<_> = [
+                (scratches, CleanScope::Scratches),
+                (action_cache, CleanScope::ActionCache),
+                (output_cache, CleanScope::OutputCache),
+            ].into_iter().filter(|(enabled, _)| *enabled)
+                .map(|(_, scope)| scope).collect();
+            if scopes.is_empty() {
+                usage("clean");
+            }
+            return Self::Clean{scopes};
+        }
+
+        if arguments.peek().map(String::as_str) != Some("log") {
+            let mut dry_run = false;
+            let mut output_tree = None;
+            let mut output_groups = Vec::new();
+            let mut check_determinism = false;
+            let mut profile = None;
+            let mut stream_logs = None;
+            let mut remote = None;
+            while let Some(argument) = arguments.next() {
+                match argument.as_str() {
+                    "--dry-run" => dry_run = true,
+                    "--check-determinism" => check_determinism = true,
+                    "-o" | "--output-tree" => {
+                        let Some(path) = arguments.next()
+                            else { usage(&argument) };
+                        let Ok(path) = CString::new(path)
+                            else { usage(&argument) };
+                        output_tree = Some(path);
+                    },
+                    "--output-group" => {
+                        let Some(group) = arguments.next()
+                            else { usage(&argument) };
+                        output_groups.push(group);
+                    },
+                    "--profile" => {
+                        let Some(path) = arguments.next()
+                            else { usage(&argument) };
+                        profile = Some(PathBuf::from(path));
+                    },
+                    "--stream-logs" => {
+                        let Some(label) = arguments.next()
+                            else { usage(&argument) };
+                        stream_logs = Some(parse_label(&label));
+                    },
+                    "--remote" => {
+                        let Some(address) = arguments.next()
+                            else { usage(&argument) };
+                        remote = Some(parse_address(&address));
+                    },
+                    _ => usage(&argument),
+                }
+            }
+            return Self::Build{dry_run, output_tree, output_groups,
+                               check_determinism, profile, stream_logs,
+                               remote};
+        }
+
+        arguments.next();
+        let mut label = None;
+        let mut no_color = false;
+        for argument in arguments {
+            match argument.as_str() {
+                "--no-color" => no_color = true,
+                _ if label.is_none() => label = Some(parse_label(&argument)),
+                _ => usage(&argument),
+            }
+        }
+        let Some(label) = label else { usage("log") };
+        Self::Log{label, no_color}
+    }
+}
+
+/// Parse an action label as displayed, such as `#3`.
+fn parse_label(argument: &str) -> ActionLabel
+{
+    let action = argument.strip_prefix('#').unwrap_or(argument).parse();
+    let Ok(action) = action else { usage(argument) };
+    ActionLabel{action}
+}
+
+/// Parse the address of a build server, such as `127.0.0.1:4000`.
+fn parse_address(argument: &str) -> SocketAddr
+{
+    let Ok(address) = argument.parse() else { usage(argument) };
+    address
+}
+
+fn usage(argument: &str) -> !
+{
+    eprintln!("snowflake: unexpected argument: {argument}");
+    eprintln!("usage: snowflake [--dry-run] [--check-determinism] [-o DIR] \
+                                [--output-group NAME...] [--profile FILE] \
+                                [--stream-logs LABEL] [--remote ADDRESS]");
+    eprintln!("       snowflake test [--runs-per-test N] \
+                                [--stream-logs LABEL] [--remote ADDRESS] \
+                                [LABEL...]");
+    eprintln!("       snowflake log [--no-color] LABEL");
+    eprintln!("       snowflake dump-graph");
+    eprintln!("       snowflake explain LABEL");
+    eprintln!("       snowflake replay LABEL");
+    eprintln!("       snowflake clean [--scratches] [--action-cache] \
+                                [--output-cache] [--all]");
+    eprintln!("       snowflake serve ADDRESS");
+    exit(1);
+}
+
 fn main()
 {
+    let command = Command::parse();
+
     let gnum4_path = CString::new(concat!("PATH=", env!("SNOWFLAKE_GNUM4"), "/bin")).unwrap();
     let minify = CString::new(concat!(env!("SNOWFLAKE_MINIFY"), "/bin/minify")).unwrap();
     let sassc = CString::new(concat!(env!("SNOWFLAKE_SASSC"), "/bin/sassc")).unwrap();
@@ -43,6 +350,8 @@ fn main()
                         outputs: Outputs::Outputs(vec![
                             Basename::new(cstring!(b"stylesheet.css")).unwrap(),
                         ]),
+                        optional_outputs: vec![],
+                        output_groups: vec![],
                         program: sassc,
                         arguments: vec![
                             cstring!(b"sassc"),
@@ -50,11 +359,19 @@ fn main()
                             cstring!(b"stylesheet.css"),
                         ],
                         environment: vec![],
+                        passthrough: vec![],
                         timeout: Duration::from_secs(1),
                         warnings: Some(Regex::new("^WARNING:").unwrap()),
+                        depfile: None,
+                        log_paths: None,
+                        resources: Resources::default(),
+                        retry_policy: RetryPolicy::default(),
+                        network: false,
+                        writable_inputs: false,
+                        allowed_syscalls: vec![],
                     }) as Box<dyn Action>,
                     vec![
-                        Input::StaticFile(cstring!(b"snowflake-website/stylesheet.scss")),
+                        Input::StaticFile(WorkspacePath::new(cstring!(b"snowflake-website/stylesheet.scss")).unwrap()),
                     ],
                 ),
             ),
@@ -69,6 +386,8 @@ fn main()
                         outputs: Outputs::Outputs(vec![
                             Basename::new(cstring!(b"index.html")).unwrap(),
                         ]),
+                        optional_outputs: vec![],
+                        output_groups: vec![],
                         program: cstring!(b"/bin/sh"),
                         arguments: vec![
                             cstring!(b"bash"),
@@ -78,11 +397,19 @@ fn main()
                         environment: vec![
                             gnum4_path,
                         ],
+                        passthrough: vec![],
                         timeout: Duration::from_secs(1),
                         warnings: None,
+                        depfile: None,
+                        log_paths: None,
+                        resources: Resources::default(),
+                        retry_policy: RetryPolicy::default(),
+                        network: false,
+                        writable_inputs: false,
+                        allowed_syscalls: vec![],
                     }) as Box<dyn Action>,
                     vec![
-                        Input::StaticFile(cstring!(b"snowflake-website/index.html")),
+                        Input::StaticFile(WorkspacePath::new(cstring!(b"snowflake-website/index.html")).unwrap()),
                         Input::Dependency(action_sassc_output_css.clone()),
                     ],
                 ),
@@ -97,6 +424,8 @@ fn main()
                         outputs: Outputs::Outputs(vec![
                             Basename::new(cstring!(b"index.min.html")).unwrap(),
                         ]),
+                        optional_outputs: vec![],
+                        output_groups: vec![],
                         program: minify,
                         arguments: vec![
                             cstring!(b"minify"),
@@ -105,8 +434,16 @@ fn main()
                             cstring!(b"index.html"),
                         ],
                         environment: vec![],
+                        passthrough: vec![],
                         timeout: Duration::from_secs(1),
                         warnings: None,
+                        depfile: None,
+                        log_paths: None,
+                        resources: Resources::default(),
+                        retry_policy: RetryPolicy::default(),
+                        network: false,
+                        writable_inputs: false,
+                        allowed_syscalls: vec![],
                     }) as Box<dyn Action>,
                     vec![
                         Input::Dependency(action_inject_css_output_html),
@@ -117,6 +454,15 @@ fn main()
         artifacts: [action_minify_output_min_html].into_iter().collect(),
     };
 
+    if let Command::DumpGraph = command {
+        action_graph.write_dump(io::stdout().lock()).unwrap();
+        return;
+    }
+
+    if let Command::Test{labels, ..} = &command {
+        select_tests(&mut action_graph, labels);
+    }
+
     action_graph.prune();
 
     if let Err(err) = mkdir(cstr!(b".snowflake"), 0o755)
@@ -124,10 +470,421 @@ fn main()
         panic!("{:?}", err);
     }
     let state = State::open(cstr!(b".snowflake")).unwrap();
+    if let Command::Clean{scopes} = &command {
+        clean(&state, scopes);
+        return;
+    }
+    if let Command::Serve{address} = &command {
+        serve(&state, *address);
+        return;
+    }
     let source_root = open(cstr!(b"."), O_DIRECTORY | O_PATH, 0).unwrap();
-    let context = drive::Context{state: &state, source_root: source_root.as_fd()};
+    let check_determinism =
+        matches!(command, Command::Build{check_determinism: true, ..});
+    let runs_per_test = match command {
+        Command::Test{runs_per_test, ..} => runs_per_test,
+        _ => 1,
+    };
+    let test_results = Mutex::new(Vec::new());
+    let cancel: &'static Cancel = Box::leak(Box::new(Cancel::new().unwrap()));
+    cancel.cancel_on_signals().unwrap();
+    let profile = Profile::new();
+    let profile_path = match &command {
+        Command::Build{profile, ..} => profile.clone(),
+        _ => None,
+    };
+    let stream_logs = match &command {
+        Command::Build{stream_logs, ..} => stream_logs.clone(),
+        Command::Test{stream_logs, ..} => stream_logs.clone(),
+        Command::Log{..} | Command::DumpGraph | Command::Explain{..}
+            | Command::Clean{..} | Command::Replay{..}
+            | Command::Serve{..} => None,
+    };
+    let remote_executor;
+    let executor: &dyn Executor = match &command {
+        Command::Build{remote: Some(address), ..}
+            | Command::Test{remote: Some(address), ..} => {
+            remote_executor = RemoteExecutor{address: *address};
+            &remote_executor
+        },
+        _ => &LocalExecutor,
+    };
+    let stderr = io::stderr();
+    let context = drive::Context{
+        state: &state,
+        source_root: source_root.as_fd(),
+        executor,
+        capacity: Capacity::available(),
+        check_determinism,
+        runs_per_test,
+        cancel: Some(cancel),
+        profile: profile_path.as_ref().map(|_| &profile),
+        stream_log: stream_logs.as_ref().map(|l| (l, stderr.as_fd())),
+        failure_reports: true,
+        events: &|event| {
+            if let BuildEvent::TestResult{label, result, cached} = &event {
+                let summary = ((*label).clone(), result.status, *cached);
+                test_results.lock().unwrap().push(summary);
+            }
+            eprintln!("{event}");
+        },
+    };
+
+    let (dry_run_only, output_tree, output_groups) = match command {
+        Command::Build{dry_run, output_tree, output_groups, ..} =>
+            (dry_run, output_tree, output_groups),
+        Command::Test{..} => {
+            let outcomes = drive(&context, &action_graph).unwrap();
+            exit_if_cancelled(&state, cancel);
+            let test_results = test_results.into_inner().unwrap();
+            print_test_summary(&action_graph, &outcomes, test_results);
+        },
+        Command::Log{label, no_color} => {
+            print_log(&context, &action_graph, &label, no_color);
+            return;
+        },
+        Command::DumpGraph =>
+            unreachable!("The action graph was dumped before building"),
+        Command::Clean{..} =>
+            unreachable!("The state directory was cleaned before building"),
+        Command::Serve{..} =>
+            unreachable!("Actions were served instead of building"),
+        Command::Explain{label} => {
+            explain(&context, &action_graph, &label);
+            return;
+        },
+        Command::Replay{label} =>
+            replay(&context, &action_graph, &label),
+    };
+
+    if dry_run_only {
+        let outcomes = dry_run(&context, &action_graph).unwrap();
+        let mut outcomes: Vec<_> = outcomes.into_iter().collect();
+        outcomes.sort_by_key(|(label, _)| *label);
+        for (label, outcome) in outcomes {
+            match outcome {
+                Ok(DryRunOutcome::CacheHit{..}) =>
+                    println!("{label} is up to date"),
+                Ok(DryRunOutcome::Perform{reason}) =>
+                    println!("{label} would be performed: {reason}"),
+                Err(err) =>
+                    println!("{label} cannot be checked: {err:#}"),
+            }
+        }
+        return;
+    }
+
     let result = drive(&context, &action_graph);
+    exit_if_cancelled(&state, cancel);
 
     println!("{}", action_graph);
     println!("{:#?}", result);
+
+    if let Some(profile_path) = profile_path {
+        let file = File::create(profile_path).unwrap();
+        profile.write_trace(io::BufWriter::new(file)).unwrap();
+    }
+
+    if let Some(output_tree) = output_tree {
+        let outcomes = result.unwrap();
+        assemble_artifacts(&context, &action_graph, &outcomes,
+                           &output_tree, &output_groups);
+    }
+}
+
+/// Clean the given parts of the state directory
+/// and print how much disk space was freed.
+fn clean(state: &State, scopes: &[CleanScope])
+{
+    for &scope in scopes {
+        let what = match scope {
+            CleanScope::Scratches => "scratch files",
+            CleanScope::ActionCache => "the action cache",
+            CleanScope::OutputCache => "the output cache",
+        };
+        match state.clean(scope) {
+            Ok(freed) => println!("Freed {} from {what}", format_size(freed)),
+            Err(err) => {
+                eprintln!("snowflake: cannot clean {what}: {err}");
+                exit(1);
+            },
+        }
+    }
+}
+
+/// Perform actions on behalf of remote executors connecting to the address.
+///
+/// Each connection is served on its own thread, until the process is killed.
+/// Only actions that [`decode_action`] supports can be performed.
+fn serve(state: &State, address: SocketAddr)
+{
+    let listener = TcpListener::bind(address).unwrap_or_else(|err| {
+        eprintln!("snowflake: cannot listen on {address}: {err}");
+        exit(1);
+    });
+    eprintln!("snowflake: serving on {address}");
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("snowflake: cannot accept connection: {err}");
+                    continue;
+                },
+            };
+            scope.spawn(move || {
+                if let Err(err) =
+                    serve_connection(state, &decode_action, stream) {
+                    eprintln!("snowflake: cannot serve connection: {err}");
+                }
+            });
+        }
+    });
+}
+
+/// Format a number of bytes for humans, such as `1.5 MiB`.
+fn format_size(bytes: u64) -> String
+{
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+/// If the build was cancelled, clean up and exit.
+///
+/// When cancelled by a signal, the exit status is
+/// that of a shell whose child was killed by the signal.
+fn exit_if_cancelled(state: &State, cancel: &Cancel)
+{
+    if !cancel.is_cancelled() {
+        return;
+    }
+    if let Err(err) = state.remove_scratches() {
+        eprintln!("snowflake: cannot remove scratch files: {err}");
+    }
+    eprintln!("snowflake: build was cancelled");
+    exit(128 + received_signal().unwrap_or(0));
+}
+
+/// Assemble the artifacts of a build into an output tree.
+///
+/// Each artifact is named after its action label, without the `#`,
+/// followed by the name of the output, or its index if it has no name.
+/// If output groups are given, only artifacts in those groups are assembled.
+/// Optional outputs that were not produced are omitted.
+/// Artifacts of actions that were not built successfully are omitted.
+fn assemble_artifacts(
+    context: &drive::Context,
+    graph: &ActionGraph,
+    outcomes: &HashMap<&ActionLabel, Outcome>,
+    path: &CStr,
+    groups: &[String],
+)
+{
+    let mut entries = Vec::new();
+    for artifact in &graph.artifacts {
+        let (action, _) = &graph.actions[&artifact.action];
+        if !groups.is_empty() && !action.output_groups().iter().any(|g|
+            groups.contains(&g.name) && g.outputs.contains(&artifact.output)) {
+            continue;
+        }
+        let Some(Outcome::Success{cache_entry, ..}) =
+            outcomes.get(&artifact.action)
+        else {
+            eprintln!("snowflake: {artifact} was not built");
+            continue;
+        };
+        let Some(hash) = cache_entry.outputs[artifact.output]
+            else { continue };
+        let mut name = format!("{}.", artifact.action.action).into_bytes();
+        match action.output_names() {
+            Some(names) => name.extend(names[artifact.output].to_bytes()),
+            None => name.extend(artifact.output.to_string().bytes()),
+        }
+        let name = Basename::new(CString::new(name).unwrap()).unwrap();
+        entries.push((name, hash));
+    }
+    let result = assemble_output_tree(context.state, None, path, &entries);
+    if let Err(err) = result {
+        eprintln!("snowflake: cannot assemble output tree {path:?}: {err}");
+        exit(1);
+    }
+}
+
+/// Remove all lint actions other than the given tests.
+///
+/// Artifacts are removed too, so that after pruning,
+/// only the tests and what they depend on remain.
+/// If no labels are given, all tests are kept.
+fn select_tests(graph: &mut ActionGraph, labels: &[ActionLabel])
+{
+    graph.artifacts.clear();
+    graph.actions.retain(|label, (action, _)| {
+        !action.is_lint() || (action.is_test()
+            && (labels.is_empty() || labels.contains(label)))
+    });
+}
+
+/// Print whether each test passed and exit with the appropriate status.
+///
+/// Flaky tests do not fail the build, but they are reported.
+/// Tests that were skipped or could not be performed count as failed.
+fn print_test_summary(
+    graph: &ActionGraph,
+    outcomes: &HashMap<&ActionLabel, Outcome>,
+    test_results: Vec<(ActionLabel, TestStatus, bool)>,
+) -> !
+{
+    let mut tests: Vec<_> = graph.actions.iter()
+        .filter(|(_, (action, _))| action.is_test())
+        .map(|(label, _)| label)
+        .collect();
+    tests.sort();
+
+    let (mut passed, mut flaky, mut failed) = (0, 0, 0);
+    for label in tests {
+        let result = test_results.iter().find(|r| &r.0 == label);
+        let (status, cached) = match (result, &outcomes[label]) {
+            (Some((_, status, cached)), _) => (Some(*status), *cached),
+            (None, Outcome::Success{..}) => (Some(TestStatus::Passed), true),
+            (None, _) => (None, false),
+        };
+        match status {
+            Some(TestStatus::Passed) => passed += 1,
+            Some(TestStatus::Flaky) => flaky += 1,
+            Some(TestStatus::Failed) | None => failed += 1,
+        }
+        let status = status.map_or("SKIPPED".to_owned(), |s| s.to_string());
+        let cached = if cached { " (cached)" } else { "" };
+        println!("{status:<7} {label}{cached}");
+    }
+    println!("{passed} passed, {flaky} flaky, {failed} failed");
+
+    exit(if failed == 0 { 0 } else { 1 });
+}
+
+/// Explain why an action would be performed.
+///
+/// If it would be performed because a dependency would be performed,
+/// the dependency is explained too, until the original cause is found.
+fn explain(context: &drive::Context, graph: &ActionGraph, label: &ActionLabel)
+{
+    let outcomes = dry_run(context, graph).unwrap();
+    let mut label = label;
+    loop {
+        let Some(outcome) = outcomes.get(label) else {
+            eprintln!("snowflake: {label} is not part of the build");
+            exit(1);
+        };
+        match outcome {
+            Ok(DryRunOutcome::CacheHit{..}) =>
+                println!("{label} is up to date"),
+            Ok(DryRunOutcome::Perform{reason}) => {
+                println!("{label} would be performed: {reason}");
+                match reason {
+                    PerformReason::Dependency{dependency} => {
+                        label = dependency;
+                        continue;
+                    },
+                    PerformReason::ChangedCommand{elements} => {
+                        for element in elements {
+                            let ChangedElement{name, previous, current} =
+                                element;
+                            match (previous, current) {
+                                (Some(previous), Some(current)) => println!(
+                                    "  {name}: {previous:?} -> {current:?}"),
+                                (None, Some(current)) => println!(
+                                    "  {name}: added {current:?}"),
+                                (Some(previous), None) => println!(
+                                    "  {name}: removed {previous:?}"),
+                                (None, None) => { },
+                            }
+                        }
+                    },
+                    PerformReason::ChangedInputs{inputs: changed} => {
+                        let (_, inputs) = &graph.actions[label];
+                        for ChangedInput{index, previous, current} in changed {
+                            match &inputs[*index] {
+                                Input::Dependency(dependency) => println!(
+                                    "  input {index}: output {dependency}"),
+                                Input::StaticFile(path) => println!(
+                                    "  input {index}: static file {path:?}"),
+                                Input::Inline(..) => println!(
+                                    "  input {index}: inline contents"),
+                            }
+                            println!("    previous hash: {previous}");
+                            println!("    current hash:  {current}");
+                        }
+                    },
+                    _ => { },
+                }
+            },
+            Err(err) =>
+                println!("{label} cannot be checked: {err:#}"),
+        }
+        return;
+    }
+}
+
+/// Start a shell in the environment of an action
+/// and exit with the exit status of the shell.
+///
+/// Signals that would cancel a build are ignored,
+/// so that commands run from the shell can be interrupted.
+fn replay(context: &drive::Context, graph: &ActionGraph, label: &ActionLabel)
+    -> !
+{
+    eprintln!("snowflake: starting a shell in the environment of {label}");
+    eprintln!("snowflake: run \"$@\" to run its command, exit when done");
+    let result = drive::replay(context, graph, label);
+    if let Err(err) = context.state.remove_scratches() {
+        eprintln!("snowflake: cannot remove scratch files: {err}");
+    }
+    match result {
+        Ok(()) => exit(0),
+        Err(ReplayError::Perform(Error::ExitStatus(status))) =>
+            exit(status.code().unwrap_or(1)),
+        Err(err) => {
+            eprintln!("snowflake: cannot replay {label}: {err}");
+            exit(1);
+        },
+    }
+}
+
+/// Print the build log of a cached action.
+fn print_log(
+    context: &drive::Context,
+    graph: &ActionGraph,
+    label: &ActionLabel,
+    no_color: bool,
+)
+{
+    let outcomes = dry_run(context, graph).unwrap();
+    let Some(Ok(DryRunOutcome::CacheHit{cache_entry})) = outcomes.get(label)
+    else {
+        eprintln!("snowflake: {label} has no cached build log");
+        exit(1);
+    };
+
+    let (dirfd, path) =
+        context.state.cached_output(cache_entry.build_log).unwrap();
+    let file = openat(Some(dirfd), &path, O_RDONLY, 0).unwrap();
+    let mut build_log = Vec::new();
+    File::from(file).read_to_end(&mut build_log).unwrap();
+
+    let build_log = if no_color && cache_entry.build_log_ansi {
+        strip_ansi(&build_log)
+    } else {
+        Cow::Borrowed(&build_log[..])
+    };
+    io::stdout().write_all(&build_log).unwrap();
 }